@@ -9,7 +9,7 @@
 
 use qlog::QlogStreamer;
 
-use neqo_common::{self as common, hex, qlog::NeqoQlog, Datagram, Role};
+use neqo_common::{self as common, hex, qlog::NeqoQlog, udp, Datagram, Role};
 use neqo_crypto::{
     constants::{TLS_AES_128_GCM_SHA256, TLS_AES_256_GCM_SHA384, TLS_CHACHA20_POLY1305_SHA256},
     init, AuthenticationStatus, Cipher,
@@ -19,7 +19,8 @@ use neqo_http3::{
 };
 use neqo_qpack::QpackSettings;
 use neqo_transport::{
-    Connection, ConnectionId, Error as TransportError, FixedConnectionIdManager, QuicVersion,
+    AppError, Connection, ConnectionId, Error as TransportError, FixedConnectionIdManager,
+    QuicVersion,
 };
 
 use std::cell::RefCell;
@@ -163,7 +164,7 @@ impl Args {
 
 fn emit_datagram(socket: &UdpSocket, d: Option<Datagram>) -> io::Result<()> {
     if let Some(d) = d {
-        let sent = socket.send(&d[..])?;
+        let sent = udp::send_datagram(socket, &d)?;
         if sent != d.len() {
             eprintln!("Unable to send all {} bytes of datagram", d.len());
         }
@@ -233,7 +234,7 @@ fn process_loop(
                 Output::Datagram(dgram) => {
                     if let Err(e) = emit_datagram(&socket, Some(dgram)) {
                         eprintln!("UDP write error: {}", e);
-                        client.close(Instant::now(), 0, e.to_string());
+                        client.close(Instant::now(), AppError(0), e.to_string());
                         exiting = true;
                         break;
                     }
@@ -255,21 +256,21 @@ fn process_loop(
             return Ok(client.state());
         }
 
-        match socket.recv(&mut buf[..]) {
+        match udp::recv_datagram(socket, *local_addr, &mut buf[..]) {
             Err(ref err)
                 if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::Interrupted => {}
             Err(err) => {
                 eprintln!("UDP error: {}", err);
                 exit(1)
             }
-            Ok(sz) => {
-                if sz == buf.len() {
+            Ok(d) => {
+                if d.len() == buf.len() {
                     eprintln!("Received more than {} bytes", buf.len());
                     continue;
                 }
-                if sz > 0 {
-                    let d = Datagram::new(*remote_addr, *local_addr, &buf[..sz]);
-                    client.process_input(d, Instant::now());
+                if !d.is_empty() && d.source() == *remote_addr {
+                    let now = Instant::now();
+                    client.process_input(d.received(now), now);
                 }
             }
         };
@@ -332,6 +333,17 @@ impl<'a> Handler<'a> {
         }
     }
 
+    /// Forget about a stream that is done.  This closes the connection and
+    /// returns `false` if there is nothing left to download.
+    fn finish_stream(&mut self, client: &mut Http3Client, stream_id: u64) -> bool {
+        self.streams.remove(&stream_id);
+        if self.streams.is_empty() && self.url_queue.is_empty() {
+            client.close(Instant::now(), AppError(0), "kthxbye!");
+            return false;
+        }
+        true
+    }
+
     fn handle(&mut self, client: &mut Http3Client) -> Res<bool> {
         while let Some(event) = client.next_event() {
             match event {
@@ -386,20 +398,39 @@ impl<'a> Handler<'a> {
                                 break;
                             }
 
-                            if sz == 0 {
+                            // A short read means that nothing more can be read for now.
+                            // If trailers ended the stream, it is already gone.
+                            if sz < data.len() {
                                 break;
                             }
                         },
                     }
 
-                    if stream_done {
-                        self.streams.remove(&stream_id);
-                        if self.streams.is_empty() && self.url_queue.is_empty() {
-                            client.close(Instant::now(), 0, "kthxbye!");
+                    if stream_done && !self.finish_stream(client, stream_id) {
+                        return Ok(false);
+                    }
+                }
+                Http3ClientEvent::TrailersReady {
+                    stream_id,
+                    headers,
+                    fin,
+                } => match self.streams.get(&stream_id) {
+                    Some(out_file) => {
+                        if out_file.is_none() {
+                            println!("READ TRAILERS[{}]: fin={} {:?}", stream_id, fin, headers);
+                            if fin {
+                                println!("<FIN[{}]>", stream_id);
+                            }
+                        }
+                        if fin && !self.finish_stream(client, stream_id) {
                             return Ok(false);
                         }
                     }
-                }
+                    None => {
+                        println!("Data on unexpected stream: {}", stream_id);
+                        return Ok(false);
+                    }
+                },
                 Http3ClientEvent::StateChange(Http3State::Connected)
                 | Http3ClientEvent::RequestsCreatable => {
                     println!("{:?}", event);
@@ -466,6 +497,9 @@ fn client(
                 max_blocked_streams: args.max_blocked_streams,
             },
             max_concurrent_push_streams: args.max_concurrent_push_streams,
+            grease: false,
+            max_header_list_size: None,
+            incremental_headers: false,
         },
     );
 
@@ -579,9 +613,10 @@ fn main() -> Res<()> {
             }
             Ok(s) => s,
         };
-        socket
-            .connect(&remote_addr)
-            .expect("Unable to connect UDP socket");
+        // The socket isn't connected, so that each datagram can be sent with its own TOS byte.
+        if let Err(e) = udp::enable_recv_tos(&socket, local_addr) {
+            eprintln!("Unable to read the TOS byte of received datagrams: {}", e);
+        }
 
         println!(
             "{} Client connecting: {:?} -> {:?}",
@@ -667,11 +702,11 @@ mod old {
 
     use super::{qlog_new, Res};
 
-    use neqo_common::Datagram;
+    use neqo_common::udp;
     use neqo_crypto::AuthenticationStatus;
     use neqo_transport::{
-        Connection, ConnectionEvent, Error, FixedConnectionIdManager, Output, QuicVersion, State,
-        StreamType,
+        AppError, Connection, ConnectionEvent, Error, FixedConnectionIdManager, Output,
+        QuicVersion, State, StreamType,
     };
 
     use super::{emit_datagram, get_output_file, Args};
@@ -783,7 +818,7 @@ mod old {
                                     }
                                     self.streams.remove(&stream_id);
                                     if self.streams.is_empty() && self.url_queue.is_empty() {
-                                        client.close(Instant::now(), 0, "kthxbye!");
+                                        client.close(Instant::now(), AppError(0), "kthxbye!");
                                         return Ok(false);
                                     }
                                 }
@@ -839,7 +874,7 @@ mod old {
                     Output::Datagram(dgram) => {
                         if let Err(e) = emit_datagram(&socket, Some(dgram)) {
                             eprintln!("UDP write error: {}", e);
-                            client.close(Instant::now(), 0, e.to_string());
+                            client.close(Instant::now(), AppError(0), e.to_string());
                             exiting = true;
                             break;
                         }
@@ -861,26 +896,26 @@ mod old {
                 return Ok(client.state().clone());
             }
 
-            let sz = match socket.recv(&mut buf[..]) {
+            let d = match udp::recv_datagram(socket, *local_addr, &mut buf[..]) {
                 Err(ref err)
                     if err.kind() == ErrorKind::WouldBlock
                         || err.kind() == ErrorKind::Interrupted =>
                 {
-                    0
+                    continue;
                 }
                 Err(err) => {
                     eprintln!("UDP error: {}", err);
                     exit(1)
                 }
-                Ok(sz) => sz,
+                Ok(d) => d,
             };
-            if sz == buf.len() {
+            if d.len() == buf.len() {
                 eprintln!("Received more than {} bytes", buf.len());
                 continue;
             }
-            if sz > 0 {
-                let d = Datagram::new(*remote_addr, *local_addr, &buf[..sz]);
-                client.process_input(d, Instant::now());
+            if !d.is_empty() && d.source() == *remote_addr {
+                let now = Instant::now();
+                client.process_input(d.received(now), now);
             }
         }
     }
@@ -936,3 +971,64 @@ mod old {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Args, Handler};
+    use neqo_http3::{Http3Client, Http3Server, Http3ServerEvent};
+    use std::collections::{HashMap, VecDeque};
+    use structopt::StructOpt;
+    use test_fixture::{default_http3_client, default_http3_server, now};
+
+    fn exchange_packets(client: &mut Http3Client, server: &mut Http3Server) {
+        let mut out = client.process(None, now()).dgram();
+        loop {
+            out = server.process(out, now()).dgram();
+            out = client.process(out, now()).dgram();
+            if out.is_none() {
+                break;
+            }
+        }
+    }
+
+    /// A response that ends with trailers finishes the stream, even though
+    /// the last `DataReadable` event doesn't see the end of the stream.
+    #[test]
+    fn response_with_trailers() {
+        let args = Args::from_iter(&["neqo-client", "https://something.com/"]);
+        let mut h = Handler {
+            streams: HashMap::new(),
+            url_queue: VecDeque::from(args.urls.clone()),
+            all_paths: Vec::new(),
+            args: &args,
+        };
+        let mut client = default_http3_client();
+        let mut server = default_http3_server();
+
+        // Handshake, then send the request.
+        while h.url_queue.len() == 1 {
+            exchange_packets(&mut client, &mut server);
+            assert!(h.handle(&mut client).unwrap());
+        }
+        assert_eq!(h.streams.len(), 1);
+        exchange_packets(&mut client, &mut server);
+
+        let mut request = loop {
+            match server.next_event() {
+                Some(Http3ServerEvent::Headers { request, .. }) => break request,
+                Some(_) => {}
+                None => panic!("no request"),
+            }
+        };
+        request
+            .set_response(&[(String::from(":status"), String::from("200"))], &[1, 2])
+            .unwrap();
+        request
+            .send_trailers(&[(String::from("grpc-status"), String::from("0"))])
+            .unwrap();
+        exchange_packets(&mut client, &mut server);
+
+        assert!(!h.handle(&mut client).unwrap());
+        assert!(h.streams.is_empty());
+    }
+}
@@ -4,27 +4,169 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use std::cmp::min;
 use std::net::SocketAddr;
-use std::ops::Deref;
+use std::ops::{Deref, Range};
+use std::sync::Arc;
+use std::time::Instant;
 
 use crate::hex_with_len;
 
+/// The value of the IP Type of Service (IPv4) or Traffic Class (IPv6) field.
+/// The two least significant bits carry the ECN codepoint.
+///
+/// Reading and setting this value on a socket is up to the application; the
+/// `udp` module has functions that do it on Unix.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct IpTos(u8);
+
+impl IpTos {
+    /// Not ECN-Capable Transport.
+    pub const NOT_ECT: Self = Self(0b00);
+    /// ECN-Capable Transport, ECT(1).
+    pub const ECT1: Self = Self(0b01);
+    /// ECN-Capable Transport, ECT(0).
+    pub const ECT0: Self = Self(0b10);
+    /// Congestion Experienced.
+    pub const CE: Self = Self(0b11);
+
+    const ECN_MASK: u8 = 0b11;
+
+    /// The ECN codepoint, without any DSCP bits.
+    #[must_use]
+    pub fn ecn(self) -> Self {
+        Self(self.0 & Self::ECN_MASK)
+    }
+}
+
+impl From<u8> for IpTos {
+    fn from(v: u8) -> Self {
+        Self(v)
+    }
+}
+
+impl From<IpTos> for u8 {
+    fn from(v: IpTos) -> Self {
+        v.0
+    }
+}
+
+/// The payload of a datagram, which is either owned or a range of a buffer
+/// that is shared with other datagrams.  The buffer is an `Arc` so that a
+/// `Datagram` can still be sent to another thread.
+#[derive(Clone)]
+enum Payload {
+    Owned(Vec<u8>),
+    Shared(Arc<[u8]>, Range<usize>),
+}
+
+impl Deref for Payload {
+    type Target = [u8];
+    fn deref(&self) -> &Self::Target {
+        match self {
+            Self::Owned(v) => v,
+            Self::Shared(buf, range) => &buf[range.clone()],
+        }
+    }
+}
+
+impl PartialEq for Payload {
+    fn eq(&self, other: &Self) -> bool {
+        self[..] == other[..]
+    }
+}
+
 #[derive(PartialEq, Clone)]
 pub struct Datagram {
     src: SocketAddr,
     dst: SocketAddr,
-    d: Vec<u8>,
+    tos: IpTos,
+    recv_time: Option<Instant>,
+    d: Payload,
 }
 
 impl Datagram {
+    /// Create a datagram that is not marked as ECN-capable.
     pub fn new<V: Into<Vec<u8>>>(src: SocketAddr, dst: SocketAddr, d: V) -> Self {
+        Self::new_with_tos(src, dst, IpTos::default(), d)
+    }
+
+    /// Create a datagram with the given IP TOS value.  When receiving, this is
+    /// the value read from the socket; when sending, the value is to be set on
+    /// the outgoing packet.  See `IpTos` for how that is done.
+    pub fn new_with_tos<V: Into<Vec<u8>>>(
+        src: SocketAddr,
+        dst: SocketAddr,
+        tos: IpTos,
+        d: V,
+    ) -> Self {
         Self {
             src,
             dst,
-            d: d.into(),
+            tos,
+            recv_time: None,
+            d: Payload::Owned(d.into()),
         }
     }
 
+    /// Create a datagram from part of a shared buffer, such as one that holds
+    /// several datagrams read from a socket.  This does not copy the payload;
+    /// the datagram holds a reference to `buf` instead.
+    /// # Panics
+    /// If `range` is not within `buf`.
+    #[must_use]
+    pub fn new_shared(
+        src: SocketAddr,
+        dst: SocketAddr,
+        tos: IpTos,
+        buf: Arc<[u8]>,
+        range: Range<usize>,
+    ) -> Self {
+        assert!(range.start <= range.end && range.end <= buf.len());
+        Self {
+            src,
+            dst,
+            tos,
+            recv_time: None,
+            d: Payload::Shared(buf, range),
+        }
+    }
+
+    /// Split `data` into datagrams of no more than `max_size` bytes each, in
+    /// order.  The datagrams all have the same addresses and TOS value, and
+    /// they share one copy of `data`.  Empty `data` produces no datagrams.
+    /// # Panics
+    /// If `max_size` is zero.
+    pub fn split<V: Into<Vec<u8>>>(
+        src: SocketAddr,
+        dst: SocketAddr,
+        tos: IpTos,
+        data: V,
+        max_size: usize,
+    ) -> impl Iterator<Item = Self> {
+        assert!(max_size > 0);
+        let buf: Arc<[u8]> = Arc::from(data.into());
+        let len = buf.len();
+        (0..len).step_by(max_size).map(move |start| {
+            let end = min(start + max_size, len);
+            Self::new_shared(src, dst, tos, Arc::clone(&buf), start..end)
+        })
+    }
+
+    /// Set the time that this datagram was received.
+    #[must_use]
+    pub fn received(self, now: Instant) -> Self {
+        Self {
+            recv_time: Some(now),
+            ..self
+        }
+    }
+
+    /// Create a datagram that was received at the given time.
+    pub fn new_at<V: Into<Vec<u8>>>(src: SocketAddr, dst: SocketAddr, d: V, now: Instant) -> Self {
+        Self::new(src, dst, d).received(now)
+    }
+
     #[must_use]
     pub fn source(&self) -> SocketAddr {
         self.src
@@ -34,10 +176,43 @@ impl Datagram {
     pub fn destination(&self) -> SocketAddr {
         self.dst
     }
+
+    #[must_use]
+    pub fn tos(&self) -> IpTos {
+        self.tos
+    }
+
+    /// The time that this datagram was received, if known.
+    #[must_use]
+    pub fn received_at(&self) -> Option<Instant> {
+        self.recv_time
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.d.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.d.is_empty()
+    }
+
+    /// Take the payload.  This only copies the payload if it is shared.
+    #[must_use]
+    pub fn into_data(self) -> Vec<u8> {
+        match self.d {
+            Payload::Owned(v) => v,
+            Payload::Shared(buf, range) => buf[range].to_vec(),
+        }
+    }
 }
 
+/// A datagram dereferences to its payload.  This used to be a `Vec<u8>`, but a
+/// shared payload is only part of a buffer, so it is now a slice.  Code that
+/// needs an owned `Vec<u8>` can use `Datagram::into_data` or `to_vec()`.
 impl Deref for Datagram {
-    type Target = Vec<u8>;
+    type Target = [u8];
     #[must_use]
     fn deref(&self) -> &Self::Target {
         &self.d
@@ -48,10 +223,139 @@ impl std::fmt::Debug for Datagram {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
             f,
-            "Datagram {:?}->{:?}: {}",
+            "Datagram {:?}->{:?} {:?}: {}",
             self.src,
             self.dst,
+            self.tos,
             hex_with_len(&self.d)
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Datagram, IpTos};
+    use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    fn addr() -> SocketAddr {
+        SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 443)
+    }
+
+    #[test]
+    fn default_tos() {
+        let d = Datagram::new(addr(), addr(), vec![1, 2, 3]);
+        assert_eq!(d.tos(), IpTos::NOT_ECT);
+        assert_eq!(u8::from(d.tos()), 0);
+    }
+
+    #[test]
+    fn with_tos() {
+        let tos = IpTos::from(0xb9);
+        let d = Datagram::new_with_tos(addr(), addr(), tos, vec![1, 2, 3]);
+        assert_eq!(d.tos(), tos);
+        assert_eq!(u8::from(d.tos()), 0xb9);
+        assert_eq!(d.tos().ecn(), IpTos::ECT1);
+        assert_eq!(&d[..], &[1, 2, 3]);
+    }
+
+    #[test]
+    fn received_at() {
+        let d = Datagram::new(addr(), addr(), vec![1, 2, 3]);
+        assert_eq!(d.received_at(), None);
+
+        let t = Instant::now() + Duration::from_millis(10);
+        let d = Datagram::new_at(addr(), addr(), vec![1, 2, 3], t);
+        assert_eq!(d.received_at(), Some(t));
+        assert_eq!(d.tos(), IpTos::NOT_ECT);
+        assert_eq!(d.clone().received_at(), Some(t));
+    }
+
+    #[test]
+    fn into_data() {
+        let d = Datagram::new(addr(), addr(), vec![1, 2, 3]);
+        assert_eq!(d.len(), 3);
+        assert!(!d.is_empty());
+        let ptr = d.as_ptr();
+        let v = d.into_data();
+        assert_eq!(v, vec![1, 2, 3]);
+        assert_eq!(v.as_ptr(), ptr);
+
+        let d = Datagram::new(addr(), addr(), Vec::new());
+        assert_eq!(d.len(), 0);
+        assert!(d.is_empty());
+    }
+
+    #[test]
+    fn shared() {
+        let buf: Arc<[u8]> = Arc::from(vec![1, 2, 3, 4, 5, 6]);
+        let t = Instant::now();
+        let first = Datagram::new_shared(addr(), addr(), IpTos::ECT0, Arc::clone(&buf), 0..2);
+        let second =
+            Datagram::new_shared(addr(), addr(), IpTos::ECT0, Arc::clone(&buf), 2..6).received(t);
+        assert_eq!(Arc::strong_count(&buf), 3);
+
+        // Neither datagram has its own copy of the payload.
+        assert_eq!(&first[..], &[1, 2]);
+        assert_eq!(first.as_ptr(), buf.as_ptr());
+        assert_eq!(&second[..], &[3, 4, 5, 6]);
+        assert_eq!(second.as_ptr(), buf[2..].as_ptr());
+        assert_eq!(second.len(), 4);
+        assert_eq!(second.tos(), IpTos::ECT0);
+        assert_eq!(second.received_at(), Some(t));
+
+        // Nor do clones.
+        let copy = first.clone();
+        assert_eq!(copy.as_ptr(), buf.as_ptr());
+        assert_eq!(Arc::strong_count(&buf), 4);
+
+        // Shared datagrams compare equal to owned ones with the same contents.
+        assert_eq!(
+            first,
+            Datagram::new_with_tos(addr(), addr(), IpTos::ECT0, vec![1, 2])
+        );
+        assert_eq!(first.into_data(), vec![1, 2]);
+    }
+
+    #[test]
+    fn split() {
+        let other = SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 4433);
+        let data = (0..=u8::MAX).cycle().take(4000).collect::<Vec<_>>();
+        let dgrams =
+            Datagram::split(addr(), other, IpTos::ECT0, data.clone(), 1200).collect::<Vec<_>>();
+        assert_eq!(
+            dgrams.iter().map(Datagram::len).collect::<Vec<_>>(),
+            vec![1200, 1200, 1200, 400]
+        );
+        assert!(dgrams
+            .iter()
+            .all(|d| d.source() == addr() && d.destination() == other && d.tos() == IpTos::ECT0));
+        let joined = dgrams
+            .iter()
+            .flat_map(|d| d.iter().copied())
+            .collect::<Vec<_>>();
+        assert_eq!(joined, data);
+
+        // An exact multiple doesn't leave an empty datagram at the end.
+        let count =
+            |len| Datagram::split(addr(), other, IpTos::default(), vec![0; len], 1200).count();
+        assert_eq!(count(2400), 2);
+        assert_eq!(count(0), 0);
+    }
+
+    #[test]
+    fn send() {
+        fn assert_send<T: Send>(_: &T) {}
+        let buf: Arc<[u8]> = Arc::from(vec![1, 2, 3]);
+        let d = Datagram::new_shared(addr(), addr(), IpTos::default(), buf, 0..3);
+        assert_send(&d);
+    }
+
+    #[test]
+    #[should_panic]
+    fn shared_out_of_range() {
+        let buf: Arc<[u8]> = Arc::from(vec![1, 2, 3]);
+        let _ = Datagram::new_shared(addr(), addr(), IpTos::default(), buf, 2..4);
+    }
+}
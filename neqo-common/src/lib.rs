@@ -13,9 +13,11 @@ mod incrdecoder;
 pub mod log;
 pub mod qlog;
 pub mod timer;
+#[cfg(unix)]
+pub mod udp;
 
 pub use self::codec::{Decoder, Encoder};
-pub use self::datagram::Datagram;
+pub use self::datagram::{Datagram, IpTos};
 pub use self::incrdecoder::{
     IncrementalDecoderBuffer, IncrementalDecoderIgnore, IncrementalDecoderUint,
 };
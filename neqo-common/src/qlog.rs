@@ -6,9 +6,10 @@
 
 use std::cell::RefCell;
 use std::fmt;
+use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
-use std::time::SystemTime;
+use std::time::{Instant, SystemTime};
 
 use chrono::{DateTime, Utc};
 use qlog::{
@@ -25,7 +26,8 @@ pub struct NeqoQlog {
 }
 
 pub struct NeqoQlogShared {
-    qlog_path: PathBuf,
+    /// The file being written to, if known.
+    qlog_path: Option<PathBuf>,
     streamer: QlogStreamer,
 }
 
@@ -43,7 +45,31 @@ impl NeqoQlog {
         Ok(Self {
             inner: Rc::new(RefCell::new(Some(NeqoQlogShared {
                 streamer,
-                qlog_path: qlog_path.as_ref().to_owned(),
+                qlog_path: Some(qlog_path.as_ref().to_owned()),
+            }))),
+        })
+    }
+
+    /// Create an enabled `NeqoQlog` that streams events to `writer` as they happen.
+    /// # Errors
+    ///
+    /// Will return `qlog::Error` if cannot write to `writer`.
+    pub fn with_writer(writer: Box<dyn Write + Send>, role: Role) -> Result<Self, qlog::Error> {
+        let mut streamer = QlogStreamer::new(
+            qlog::QLOG_VERSION.to_string(),
+            Some(format!("neqo-{} qlog", role)),
+            None,
+            None,
+            Instant::now(),
+            new_trace(role),
+            writer,
+        );
+        streamer.start_log()?;
+
+        Ok(Self {
+            inner: Rc::new(RefCell::new(Some(NeqoQlogShared {
+                streamer,
+                qlog_path: None,
             }))),
         })
     }
@@ -96,7 +122,11 @@ impl Default for NeqoQlog {
 
 impl fmt::Debug for NeqoQlogShared {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "NeqoQlog writing to {}", self.qlog_path.display())
+        if let Some(qlog_path) = &self.qlog_path {
+            write!(f, "NeqoQlog writing to {}", qlog_path.display())
+        } else {
+            write!(f, "NeqoQlog writing to a stream")
+        }
     }
 }
 
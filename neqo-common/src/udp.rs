@@ -0,0 +1,296 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Sending and receiving UDP datagrams with their IP TOS byte, which carries the ECN codepoint.
+// The standard library can't read or set it, so this uses `recvmsg` and `sendmsg` directly.
+
+use std::convert::TryFrom;
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+
+use libc::{c_int, c_uint, c_void, socklen_t};
+
+use crate::{Datagram, IpTos};
+
+// Space for control messages, in words so that it is aligned for `cmsghdr`.  The only control
+// message that is expected is the one with the TOS byte.
+const CONTROL_WORDS: usize = 8;
+
+fn setsockopt_int<S: AsRawFd>(
+    socket: &S,
+    level: c_int,
+    name: c_int,
+    value: c_int,
+) -> io::Result<()> {
+    let rv = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            ptr::addr_of!(value).cast(),
+            socklen::<c_int>(),
+        )
+    };
+    if rv == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+fn socklen<T>() -> socklen_t {
+    socklen_t::try_from(mem::size_of::<T>()).unwrap()
+}
+
+fn int_len() -> c_uint {
+    c_uint::try_from(mem::size_of::<c_int>()).unwrap()
+}
+
+fn tclass_tos(tclass: c_int) -> IpTos {
+    IpTos::from(u8::try_from(tclass & 0xff).unwrap())
+}
+
+fn tos_option(addr: SocketAddr) -> (c_int, c_int) {
+    if is_ipv4(addr) {
+        (libc::IPPROTO_IP, libc::IP_TOS)
+    } else {
+        (libc::IPPROTO_IPV6, libc::IPV6_TCLASS)
+    }
+}
+
+// IPv4 addresses that are mapped to IPv6 use the IPv4 socket options.
+fn is_ipv4(addr: SocketAddr) -> bool {
+    match addr {
+        SocketAddr::V4(..) => true,
+        SocketAddr::V6(a) => a.ip().segments()[..6] == [0, 0, 0, 0, 0, 0xffff],
+    }
+}
+
+fn to_socket_addr(addr: &libc::sockaddr_storage) -> io::Result<SocketAddr> {
+    match c_int::from(addr.ss_family) {
+        libc::AF_INET => {
+            let sin = unsafe { &*ptr::addr_of!(*addr).cast::<libc::sockaddr_in>() };
+            Ok(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr)),
+                u16::from_be(sin.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            let sin6 = unsafe { &*ptr::addr_of!(*addr).cast::<libc::sockaddr_in6>() };
+            Ok(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(sin6.sin6_addr.s6_addr),
+                u16::from_be(sin6.sin6_port),
+                sin6.sin6_flowinfo,
+                sin6.sin6_scope_id,
+            )))
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unknown address family",
+        )),
+    }
+}
+
+fn to_sockaddr(addr: SocketAddr) -> (libc::sockaddr_storage, socklen_t) {
+    let mut storage = unsafe { mem::zeroed::<libc::sockaddr_storage>() };
+    let len = match addr {
+        SocketAddr::V4(a) => {
+            let sin = unsafe { &mut *ptr::addr_of_mut!(storage).cast::<libc::sockaddr_in>() };
+            sin.sin_family = libc::sa_family_t::try_from(libc::AF_INET).unwrap();
+            sin.sin_port = a.port().to_be();
+            sin.sin_addr.s_addr = u32::from(*a.ip()).to_be();
+            socklen::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(a) => {
+            let sin6 = unsafe { &mut *ptr::addr_of_mut!(storage).cast::<libc::sockaddr_in6>() };
+            sin6.sin6_family = libc::sa_family_t::try_from(libc::AF_INET6).unwrap();
+            sin6.sin6_port = a.port().to_be();
+            sin6.sin6_flowinfo = a.flowinfo();
+            sin6.sin6_addr.s6_addr = a.ip().octets();
+            sin6.sin6_scope_id = a.scope_id();
+            socklen::<libc::sockaddr_in6>()
+        }
+    };
+    (storage, len)
+}
+
+/// Ask for the TOS byte of the datagrams that `socket` receives, so that `recv_datagram`
+/// can report it.  `local_addr` is the address that `socket` is bound to.  An IPv6 socket
+/// also reports the TOS byte of IPv4 datagrams, if it receives any.
+/// # Errors
+/// If the socket option can't be set.
+pub fn enable_recv_tos<S: AsRawFd>(socket: &S, local_addr: SocketAddr) -> io::Result<()> {
+    if local_addr.is_ipv4() {
+        setsockopt_int(socket, libc::IPPROTO_IP, libc::IP_RECVTOS, 1)
+    } else {
+        setsockopt_int(socket, libc::IPPROTO_IPV6, libc::IPV6_RECVTCLASS, 1)?;
+        // This fails if the socket is IPv6-only, which is fine.
+        let _ = setsockopt_int(socket, libc::IPPROTO_IP, libc::IP_RECVTOS, 1);
+        Ok(())
+    }
+}
+
+/// Receive a datagram on `socket`, which is bound to `local_addr`.  The datagram has the
+/// TOS byte that it was received with if `enable_recv_tos` was called, otherwise the default.
+/// A datagram that doesn't fit in `buf` is cut short.
+/// # Errors
+/// If `recvmsg` fails, including with `WouldBlock` if no datagram arrives in time.
+pub fn recv_datagram<S: AsRawFd>(
+    socket: &S,
+    local_addr: SocketAddr,
+    buf: &mut [u8],
+) -> io::Result<Datagram> {
+    let mut src = unsafe { mem::zeroed::<libc::sockaddr_storage>() };
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr().cast(),
+        iov_len: buf.len(),
+    };
+    let mut control = [0_u64; CONTROL_WORDS];
+    let mut msg = unsafe { mem::zeroed::<libc::msghdr>() };
+    msg.msg_name = ptr::addr_of_mut!(src).cast();
+    msg.msg_namelen = socklen::<libc::sockaddr_storage>();
+    msg.msg_iov = ptr::addr_of_mut!(iov);
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = mem::size_of_val(&control) as _;
+
+    let rv = unsafe { libc::recvmsg(socket.as_raw_fd(), ptr::addr_of_mut!(msg), 0) };
+    let len = usize::try_from(rv).map_err(|_| io::Error::last_os_error())?;
+
+    let mut tos = IpTos::default();
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(ptr::addr_of!(msg)) };
+    while let Some(c) = unsafe { cmsg.as_ref() } {
+        let data = unsafe { libc::CMSG_DATA(c) };
+        match (c.cmsg_level, c.cmsg_type) {
+            // Linux reports IP_TOS and other systems IP_RECVTOS, with a single byte.
+            (libc::IPPROTO_IP, libc::IP_TOS | libc::IP_RECVTOS) => {
+                tos = IpTos::from(unsafe { *data });
+            }
+            (libc::IPPROTO_IPV6, libc::IPV6_TCLASS) => {
+                let tclass = unsafe { ptr::read_unaligned(data.cast::<c_int>()) };
+                tos = tclass_tos(tclass);
+            }
+            _ => {}
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(ptr::addr_of!(msg), c) };
+    }
+
+    Ok(Datagram::new_with_tos(
+        to_socket_addr(&src)?,
+        local_addr,
+        tos,
+        &buf[..len],
+    ))
+}
+
+/// Send `d` on `socket` to its destination, with its TOS byte.  `socket` must not be connected.
+/// # Errors
+/// If `sendmsg` fails.
+pub fn send_datagram<S: AsRawFd>(socket: &S, d: &Datagram) -> io::Result<usize> {
+    let (mut dst, dst_len) = to_sockaddr(d.destination());
+    let mut iov = libc::iovec {
+        iov_base: d.as_ptr() as *mut c_void,
+        iov_len: d.len(),
+    };
+    let mut control = [0_u64; CONTROL_WORDS];
+    let int_len = int_len();
+    let mut msg = unsafe { mem::zeroed::<libc::msghdr>() };
+    msg.msg_name = ptr::addr_of_mut!(dst).cast();
+    msg.msg_namelen = dst_len;
+    msg.msg_iov = ptr::addr_of_mut!(iov);
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = unsafe { libc::CMSG_SPACE(int_len) } as _;
+
+    let (level, name) = tos_option(d.destination());
+    unsafe {
+        let c = &mut *libc::CMSG_FIRSTHDR(ptr::addr_of!(msg));
+        c.cmsg_level = level;
+        c.cmsg_type = name;
+        c.cmsg_len = libc::CMSG_LEN(int_len) as _;
+        ptr::write_unaligned(
+            libc::CMSG_DATA(c).cast::<c_int>(),
+            c_int::from(u8::from(d.tos())),
+        );
+    }
+
+    let rv = unsafe { libc::sendmsg(socket.as_raw_fd(), ptr::addr_of!(msg), 0) };
+    usize::try_from(rv).map_err(|_| io::Error::last_os_error())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{enable_recv_tos, recv_datagram, send_datagram};
+    use crate::{Datagram, IpTos};
+    use std::net::{SocketAddr, UdpSocket};
+    use std::time::Duration;
+
+    fn socket(addr: &str) -> (UdpSocket, SocketAddr) {
+        let socket = UdpSocket::bind(addr).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let addr = socket.local_addr().unwrap();
+        (socket, addr)
+    }
+
+    fn round_trip(addr: &str) {
+        let (sender, sender_addr) = socket(addr);
+        let (receiver, receiver_addr) = socket(addr);
+        enable_recv_tos(&receiver, receiver_addr).unwrap();
+
+        for tos in &[IpTos::ECT0, IpTos::CE, IpTos::from(0xb9), IpTos::NOT_ECT] {
+            let d = Datagram::new_with_tos(sender_addr, receiver_addr, *tos, vec![1, 2, 3]);
+            assert_eq!(send_datagram(&sender, &d).unwrap(), 3);
+
+            let mut buf = [0; 16];
+            let incoming = recv_datagram(&receiver, receiver_addr, &mut buf).unwrap();
+            assert_eq!(incoming, d);
+            assert_eq!(incoming.tos(), *tos);
+        }
+    }
+
+    #[test]
+    fn round_trip_v4() {
+        round_trip("127.0.0.1:0");
+    }
+
+    #[test]
+    fn round_trip_v6() {
+        round_trip("[::1]:0");
+    }
+
+    #[test]
+    fn round_trip_mapped() {
+        let (sender, sender_addr) = socket("[::]:0");
+        let (receiver, receiver_addr) = socket("[::]:0");
+        enable_recv_tos(&receiver, receiver_addr).unwrap();
+
+        let mapped = |a: SocketAddr| SocketAddr::new("::ffff:127.0.0.1".parse().unwrap(), a.port());
+        let d = Datagram::new_with_tos(sender_addr, mapped(receiver_addr), IpTos::ECT0, vec![1]);
+        send_datagram(&sender, &d).unwrap();
+        let mut buf = [0; 16];
+        let incoming = recv_datagram(&receiver, receiver_addr, &mut buf).unwrap();
+        assert_eq!(incoming.source(), mapped(sender_addr));
+        assert_eq!(incoming.tos(), IpTos::ECT0);
+    }
+
+    #[test]
+    fn not_enabled() {
+        let (sender, sender_addr) = socket("127.0.0.1:0");
+        let (receiver, receiver_addr) = socket("127.0.0.1:0");
+
+        let d = Datagram::new_with_tos(sender_addr, receiver_addr, IpTos::ECT0, vec![1]);
+        send_datagram(&sender, &d).unwrap();
+        let mut buf = [0; 16];
+        let incoming = recv_datagram(&receiver, receiver_addr, &mut buf).unwrap();
+        assert_eq!(&incoming[..], &[1]);
+        assert_eq!(incoming.tos(), IpTos::default());
+    }
+}
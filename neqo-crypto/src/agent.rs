@@ -7,24 +7,27 @@
 pub use crate::agentio::{as_c_void, Record, RecordList};
 use crate::agentio::{AgentIo, METHODS};
 use crate::assert_initialized;
-use crate::auth::AuthenticationStatus;
+use crate::auth::{AuthenticationStatus, CertificateVerifier};
 pub use crate::cert::CertificateInfo;
 use crate::constants::{
-    Alert, Cipher, Epoch, Extension, Group, SignatureScheme, Version, TLS_VERSION_1_3,
+    Alert, Cipher, Epoch, Extension, Group, SignatureScheme, Version, TLS_CT_HANDSHAKE,
+    TLS_EPOCH_APPLICATION_DATA, TLS_EPOCH_HANDSHAKE, TLS_EPOCH_INITIAL, TLS_EPOCH_ZERO_RTT,
+    TLS_HS_CLIENT_HELLO, TLS_VERSION_1_3,
 };
-use crate::err::{is_blocked, secstatus_to_res, Error, PRErrorCode, Res};
+use crate::err::{is_blocked, secstatus_to_res, Error, PRErrorCode, PR_SetError, Res};
 use crate::ext::{ExtensionHandler, ExtensionTracker};
 use crate::p11;
 use crate::prio;
 use crate::replay::AntiReplay;
-use crate::secrets::SecretHolder;
+use crate::secrets::{SecretDirection, SecretHolder};
 use crate::ssl::{self, PRBool};
 use crate::time::TimeHolder;
 
-use neqo_common::{hex_snip_middle, qdebug, qinfo, qtrace, qwarn};
+use neqo_common::{hex, hex_snip_middle, qdebug, qinfo, qtrace, qwarn};
 use std::cell::RefCell;
 use std::convert::TryFrom;
 use std::ffi::CString;
+use std::fmt;
 use std::mem::{self, MaybeUninit};
 use std::ops::{Deref, DerefMut};
 use std::os::raw::{c_uint, c_void};
@@ -206,6 +209,67 @@ impl SecretAgentInfo {
     }
 }
 
+/// The label that the key log format uses for a secret.
+fn keylog_label(epoch: Epoch, client: bool) -> Option<&'static str> {
+    match (epoch, client) {
+        (TLS_EPOCH_ZERO_RTT, true) => Some("CLIENT_EARLY_TRAFFIC_SECRET"),
+        (TLS_EPOCH_HANDSHAKE, true) => Some("CLIENT_HANDSHAKE_TRAFFIC_SECRET"),
+        (TLS_EPOCH_HANDSHAKE, false) => Some("SERVER_HANDSHAKE_TRAFFIC_SECRET"),
+        (TLS_EPOCH_APPLICATION_DATA, true) => Some("CLIENT_TRAFFIC_SECRET_0"),
+        (TLS_EPOCH_APPLICATION_DATA, false) => Some("SERVER_TRAFFIC_SECRET_0"),
+        _ => None,
+    }
+}
+
+/// Produces output in the format of `SSLKEYLOGFILE`.
+struct KeyLog {
+    callback: Box<dyn FnMut(&str)>,
+    /// The random value from the `ClientHello`, which identifies the connection.
+    client_random: Option<Vec<u8>>,
+}
+
+impl KeyLog {
+    /// Look for a `ClientHello` in a handshake message and save its random value.
+    fn observe(&mut self, msg: &[u8]) {
+        // The random follows the type (1), length (3), and version (2).
+        const RANDOM: std::ops::Range<usize> = 6..38;
+        if self.client_random.is_none() && msg.len() >= RANDOM.end && msg[0] == TLS_HS_CLIENT_HELLO
+        {
+            self.client_random = Some(msg[RANDOM].to_vec());
+        }
+    }
+
+    /// Look for a `ClientHello` in a raw record.
+    fn observe_raw(&mut self, record: &Record) {
+        if record.epoch == TLS_EPOCH_INITIAL && record.ct == TLS_CT_HANDSHAKE {
+            self.observe(&record.data);
+        }
+    }
+
+    /// Look for a `ClientHello` in a TLS record.
+    fn observe_record(&mut self, record: &[u8]) {
+        const HEADER_LEN: usize = 5;
+        if record.len() > HEADER_LEN && record[0] == TLS_CT_HANDSHAKE {
+            self.observe(&record[HEADER_LEN..]);
+        }
+    }
+}
+
+impl fmt::Debug for KeyLog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "KeyLog")
+    }
+}
+
+/// The state of certificate authentication, which is shared with NSS.
+#[derive(Debug, Default)]
+struct Authentication {
+    /// Whether authentication of certificates is required.
+    required: bool,
+    /// Used to check certificates instead of waiting for the application.
+    verifier: Option<Box<dyn CertificateVerifier>>,
+}
+
 /// `SecretAgent` holds the common parts of client and server.
 #[derive(Debug)]
 #[allow(clippy::module_name_repetitions)]
@@ -217,7 +281,7 @@ pub struct SecretAgent {
     state: HandshakeState,
 
     /// Records whether authentication of certificates is required.
-    auth_required: Pin<Box<bool>>,
+    auth: Pin<Box<Authentication>>,
     /// Records any fatal alert that is sent by the stack.
     alert: Pin<Box<Option<Alert>>>,
     /// The current time.
@@ -225,6 +289,10 @@ pub struct SecretAgent {
 
     extension_handlers: Vec<ExtensionTracker>,
     inf: Option<SecretAgentInfo>,
+    /// Whether this is a server.
+    server: bool,
+    /// Where secrets are logged, if that is enabled.
+    keylog: Option<KeyLog>,
 }
 
 impl SecretAgent {
@@ -238,12 +306,14 @@ impl SecretAgent {
             io,
             state: HandshakeState::New,
 
-            auth_required: Box::pin(false),
+            auth: Box::pin(Authentication::default()),
             alert: Box::pin(None),
             now: TimeHolder::default(),
 
             extension_handlers: Vec::new(),
             inf: None,
+            server: false,
+            keylog: None,
         })
     }
 
@@ -276,12 +346,26 @@ impl SecretAgent {
 
     unsafe extern "C" fn auth_complete_hook(
         arg: *mut c_void,
-        _fd: *mut ssl::PRFileDesc,
+        fd: *mut ssl::PRFileDesc,
         _check_sig: ssl::PRBool,
         _is_server: ssl::PRBool,
     ) -> ssl::SECStatus {
-        let auth_required_ptr = arg as *mut bool;
-        *auth_required_ptr = true;
+        let auth = (arg as *mut Authentication).as_mut().unwrap();
+        if let Some(verifier) = &mut auth.verifier {
+            let status = if let Some(certs) = CertificateInfo::new(fd) {
+                verifier.verify(certs)
+            } else {
+                AuthenticationStatus::Unknown
+            };
+            qdebug!([format!("{:p}", fd)], "verified certificate: {:?}", status);
+            return if status == AuthenticationStatus::Ok {
+                ssl::SECSuccess
+            } else {
+                PR_SetError(status.into(), 0);
+                ssl::SECFailure
+            };
+        }
+        auth.required = true;
         // NSS insists on getting SECWouldBlock here rather than accepting
         // the usual combination of PR_WOULD_BLOCK_ERROR and SECFailure.
         ssl::_SECStatus_SECWouldBlock
@@ -311,11 +395,12 @@ impl SecretAgent {
 
     // Ready this for connecting.
     fn ready(&mut self, is_server: bool) -> Res<()> {
+        self.server = is_server;
         secstatus_to_res(unsafe {
             ssl::SSL_AuthCertificateHook(
                 self.fd,
                 Some(Self::auth_complete_hook),
-                as_c_void(&mut self.auth_required),
+                as_c_void(&mut self.auth),
             )
         })?;
 
@@ -540,10 +625,56 @@ impl SecretAgent {
     /// `HandshakeState::AuthenticationPending`, or it will panic.
     pub fn authenticated(&mut self, status: AuthenticationStatus) {
         assert_eq!(self.state, HandshakeState::AuthenticationPending);
-        *self.auth_required = false;
+        self.auth.required = false;
         self.state = HandshakeState::Authenticated(status.into());
     }
 
+    /// Use `verifier` to check the peer's certificates.  The handshake then
+    /// continues, or fails, without waiting for `authenticated` to be called.
+    pub fn set_certificate_verifier(&mut self, verifier: Box<dyn CertificateVerifier>) {
+        self.auth.verifier = Some(verifier);
+    }
+
+    /// Log secrets in the format of `SSLKEYLOGFILE`, as used by Wireshark.
+    /// `callback` is given a line of output for each secret as it becomes
+    /// available.  This exposes secrets, so only use it for debugging.
+    pub fn set_keylog(&mut self, callback: Box<dyn FnMut(&str)>) {
+        self.secrets.enable_keylog();
+        self.keylog = Some(KeyLog {
+            callback,
+            client_random: None,
+        });
+    }
+
+    /// Move the certificate verifier and the key log callback to `other`, which
+    /// is going to be used in place of this agent.
+    pub fn move_callbacks(&mut self, other: &mut Self) {
+        if let Some(verifier) = self.auth.verifier.take() {
+            other.set_certificate_verifier(verifier);
+        }
+        if let Some(keylog) = self.keylog.take() {
+            other.set_keylog(keylog.callback);
+        }
+    }
+
+    /// Pass any new secrets to the key log.  This waits until the `ClientHello`
+    /// has been seen, as the lines identify the connection with its random value.
+    fn write_keylog(&mut self) {
+        if let Some(keylog) = &mut self.keylog {
+            if let Some(random) = &keylog.client_random {
+                let random = hex(random);
+                for (dir, epoch, key) in self.secrets.take_keylog() {
+                    let client = (dir == SecretDirection::Write) != self.server;
+                    if let (Some(label), Ok(secret)) = (keylog_label(epoch, client), key.as_bytes())
+                    {
+                        let line = format!("{} {} {}", label, random, hex(secret));
+                        (keylog.callback)(&line);
+                    }
+                }
+            }
+        }
+    }
+
     fn capture_error<T>(&mut self, res: Res<T>) -> Res<T> {
         if let Err(e) = &res {
             qwarn!([self], "error: {:?}", e);
@@ -554,7 +685,7 @@ impl SecretAgent {
 
     fn update_state(&mut self, res: Res<()>) -> Res<()> {
         self.state = if is_blocked(&res) {
-            if *self.auth_required {
+            if self.auth.required {
                 HandshakeState::AuthenticationPending
             } else {
                 HandshakeState::InProgress
@@ -582,6 +713,9 @@ impl SecretAgent {
         self.now.set(now)?;
         self.set_raw(false)?;
 
+        if let Some(keylog) = &mut self.keylog {
+            keylog.observe_record(input);
+        }
         let rv = {
             // Within this scope, _h maintains a mutable reference to self.io.
             let _h = self.io.wrap(input);
@@ -595,7 +729,11 @@ impl SecretAgent {
         // Take before updating state so that we leave the output buffer empty
         // even if there is an error.
         let output = self.io.take_output();
+        if let Some(keylog) = &mut self.keylog {
+            keylog.observe_record(&output);
+        }
         self.update_state(secstatus_to_res(rv))?;
+        self.write_keylog();
         Ok(output)
     }
 
@@ -629,12 +767,21 @@ impl SecretAgent {
 
         // Feed in any records.
         if let Some(rec) = input {
+            if let Some(keylog) = &mut self.keylog {
+                keylog.observe_raw(&rec);
+            }
             self.capture_error(rec.write(self.fd))?;
         }
 
         // Drive the handshake once more.
         let rv = secstatus_to_res(unsafe { ssl::SSL_ForceHandshake(self.fd) });
+        if let Some(keylog) = &mut self.keylog {
+            for rec in records.iter() {
+                keylog.observe_raw(rec);
+            }
+        }
         self.update_state(rv)?;
+        self.write_keylog();
 
         Ok(*Pin::into_inner(records))
     }
@@ -686,14 +833,23 @@ impl ::std::fmt::Display for SecretAgent {
     }
 }
 
+/// The resumption tokens that a client has received.
+#[derive(Debug, Default)]
+struct ResumptionTokens {
+    /// Tokens that can be taken, oldest first.
+    tokens: Vec<Vec<u8>>,
+    /// Tokens that have arrived since `Client::new_resumption_tokens` was last called.
+    fresh: Vec<Vec<u8>>,
+}
+
 /// A TLS Client.
 #[derive(Debug)]
-#[allow(clippy::box_vec)] // We need the Box.
 pub struct Client {
     agent: SecretAgent,
+    server_name: String,
 
     /// Records the resumption tokens we've received.
-    resumption: Pin<Box<Vec<Vec<u8>>>>,
+    resumption: Pin<Box<ResumptionTokens>>,
 }
 
 impl Client {
@@ -708,7 +864,8 @@ impl Client {
         agent.ready(false)?;
         let mut client = Self {
             agent,
-            resumption: Box::pin(Vec::new()),
+            server_name: server_name.to_owned(),
+            resumption: Box::pin(ResumptionTokens::default()),
         };
         client.ready()?;
         Ok(client)
@@ -720,7 +877,7 @@ impl Client {
         len: c_uint,
         arg: *mut c_void,
     ) -> ssl::SECStatus {
-        let resumption_ptr = arg as *mut Vec<Vec<u8>>;
+        let resumption_ptr = arg as *mut ResumptionTokens;
         let resumption = resumption_ptr.as_mut().unwrap();
         let len = usize::try_from(len).unwrap();
         let mut v = Vec::with_capacity(len);
@@ -730,10 +887,14 @@ impl Client {
             "Got resumption token {}",
             hex_snip_middle(&v)
         );
-        if resumption.len() >= MAX_TICKETS {
-            resumption.remove(0);
+        if resumption.tokens.len() >= MAX_TICKETS {
+            resumption.tokens.remove(0);
+        }
+        if resumption.fresh.len() >= MAX_TICKETS {
+            resumption.fresh.remove(0);
         }
-        resumption.push(v);
+        resumption.tokens.push(v.clone());
+        resumption.fresh.push(v);
         ssl::SECSuccess
     }
 
@@ -748,10 +909,23 @@ impl Client {
         }
     }
 
+    /// The name of the server that this client connects to.
+    #[must_use]
+    pub fn server_name(&self) -> &str {
+        &self.server_name
+    }
+
     /// Take a resumption token.
     #[must_use]
     pub fn resumption_token(&mut self) -> Option<Vec<u8>> {
-        (*self.resumption).pop()
+        self.resumption.tokens.pop()
+    }
+
+    /// Get copies of the resumption tokens that have arrived since this was last called.
+    /// This doesn't affect the tokens that `resumption_token` provides.
+    #[must_use]
+    pub fn new_resumption_tokens(&mut self) -> Vec<Vec<u8>> {
+        mem::take(&mut self.resumption.fresh)
     }
 
     /// Enable resumption, using a token previously provided.
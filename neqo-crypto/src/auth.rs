@@ -4,6 +4,7 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use crate::cert::CertificateInfo;
 use crate::err::{mozpkix, sec, ssl, PRErrorCode};
 
 /// The outcome of authentication.
@@ -98,3 +99,12 @@ impl From<PRErrorCode> for AuthenticationStatus {
         }
     }
 }
+
+/// A `CertificateVerifier` decides whether to accept the certificate chain that
+/// the peer presents.  When an agent has one of these, it doesn't wait for
+/// `authenticated` to be called.
+pub trait CertificateVerifier: std::fmt::Debug {
+    /// Check the certificates, which start with the end-entity certificate.
+    /// Anything other than `AuthenticationStatus::Ok` fails the handshake.
+    fn verify(&mut self, certs: CertificateInfo) -> AuthenticationStatus;
+}
@@ -39,11 +39,13 @@ pub use self::agent::{
     Agent, AllowZeroRtt, Client, HandshakeState, Record, RecordList, SecretAgent, SecretAgentInfo,
     SecretAgentPreInfo, Server, ZeroRttCheckResult, ZeroRttChecker,
 };
-pub use self::auth::AuthenticationStatus;
+pub use self::auth::{AuthenticationStatus, CertificateVerifier};
 pub use self::constants::*;
 pub use self::err::{Error, PRErrorCode, Res};
 pub use self::ext::{ExtensionHandler, ExtensionHandlerResult, ExtensionWriterResult};
 pub use self::p11::{random, SymKey};
+#[cfg(feature = "deterministic-random")]
+pub use self::p11::{set_rng, RngCore, SeededRng};
 pub use self::replay::AntiReplay;
 pub use self::secrets::SecretDirection;
 pub use self::ssl::Opt;
@@ -13,6 +13,8 @@ use crate::err::{secstatus_to_res, Error, Res};
 
 use neqo_common::hex_with_len;
 
+#[cfg(feature = "deterministic-random")]
+use std::cell::RefCell;
 use std::convert::TryInto;
 use std::ops::{Deref, DerefMut};
 use std::ptr::NonNull;
@@ -105,6 +107,17 @@ impl std::fmt::Debug for SymKey {
 #[must_use]
 pub fn random(size: usize) -> Vec<u8> {
     let mut buf = vec![0; size];
+    #[cfg(feature = "deterministic-random")]
+    {
+        if RNG.with(|rng| {
+            rng.borrow_mut()
+                .as_mut()
+                .map(|rng| rng.fill_bytes(&mut buf))
+                .is_some()
+        }) {
+            return buf;
+        }
+    }
     secstatus_to_res(unsafe {
         PK11_GenerateRandom(buf.as_mut_ptr(), buf.len().try_into().unwrap())
     })
@@ -112,6 +125,61 @@ pub fn random(size: usize) -> Vec<u8> {
     buf
 }
 
+/// A source of random bytes that can stand in for the NSS generator.
+#[cfg(feature = "deterministic-random")]
+pub trait RngCore {
+    fn fill_bytes(&mut self, dest: &mut [u8]);
+}
+
+#[cfg(feature = "deterministic-random")]
+thread_local!(static RNG: RefCell<Option<Box<dyn RngCore>>> = RefCell::new(None));
+
+/// Replace the generator that `random` uses on the current thread, or restore
+/// the NSS generator by passing `None`.  This only exists with the
+/// `deterministic-random` feature, which is for reproducible tests and must
+/// never be enabled in production.  Randomness that NSS uses internally, such
+/// as for TLS key exchange, is not affected.
+#[cfg(feature = "deterministic-random")]
+pub fn set_rng(rng: Option<Box<dyn RngCore>>) {
+    RNG.with(|r| *r.borrow_mut() = rng);
+}
+
+/// A small, seeded pseudorandom generator (xorshift64*).  This is predictable
+/// by design, so it is only suitable for tests.
+#[cfg(feature = "deterministic-random")]
+#[derive(Debug, Clone)]
+pub struct SeededRng {
+    state: u64,
+}
+
+#[cfg(feature = "deterministic-random")]
+impl SeededRng {
+    #[must_use]
+    pub fn new(seed: u64) -> Self {
+        // The state must never be zero, or the output would be all zeros.
+        Self {
+            state: (seed ^ 0x9e37_79b9_7f4a_7c15).max(1),
+        }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+}
+
+#[cfg(feature = "deterministic-random")]
+impl RngCore for SeededRng {
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            let v = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&v[..chunk.len()]);
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::random;
@@ -123,4 +191,21 @@ mod test {
         // If this ever fails, there is either a bug, or it's time to buy a lottery ticket.
         assert_ne!(random(16), random(16));
     }
+
+    #[cfg(feature = "deterministic-random")]
+    #[test]
+    fn seeded() {
+        use super::{set_rng, SeededRng};
+
+        fixture_init();
+        set_rng(Some(Box::new(SeededRng::new(7))));
+        let first = random(20);
+        set_rng(Some(Box::new(SeededRng::new(7))));
+        assert_eq!(first, random(20));
+        set_rng(Some(Box::new(SeededRng::new(8))));
+        assert_ne!(first, random(20));
+
+        set_rng(None);
+        assert_ne!(random(16), random(16));
+    }
 }
@@ -21,7 +21,7 @@ experimental_api!(SSL_SecretCallback(
     arg: *mut c_void,
 ));
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum SecretDirection {
     Read,
     Write,
@@ -66,6 +66,8 @@ impl DirectionalSecrets {
 pub struct Secrets {
     r: DirectionalSecrets,
     w: DirectionalSecrets,
+    /// Copies of secrets that are waiting to be logged, if logging is enabled.
+    keylog: Option<Vec<(SecretDirection, Epoch, SymKey)>>,
 }
 
 impl Secrets {
@@ -93,6 +95,9 @@ impl Secrets {
 
     fn put(&mut self, dir: SecretDirection, epoch: Epoch, key: SymKey) {
         qdebug!("{:?} secret available for {:?}", dir, epoch);
+        if let Some(keylog) = &mut self.keylog {
+            keylog.push((dir, epoch, key.clone()));
+        }
         let keys = match dir {
             SecretDirection::Read => &mut self.r,
             SecretDirection::Write => &mut self.w,
@@ -121,6 +126,21 @@ impl SecretHolder {
     pub fn take_write(&mut self, epoch: Epoch) -> Option<SymKey> {
         self.secrets.w.take(epoch)
     }
+
+    /// Keep copies of secrets as they become available, for `take_keylog`.
+    pub fn enable_keylog(&mut self) {
+        if self.secrets.keylog.is_none() {
+            self.secrets.keylog = Some(Vec::new());
+        }
+    }
+
+    /// Take the secrets that have become available since the last call.
+    pub fn take_keylog(&mut self) -> Vec<(SecretDirection, Epoch, SymKey)> {
+        self.secrets
+            .keylog
+            .as_mut()
+            .map_or_else(Vec::new, std::mem::take)
+    }
 }
 
 impl Default for SecretHolder {
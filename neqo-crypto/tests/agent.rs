@@ -1,10 +1,11 @@
 #![cfg_attr(feature = "deny-warnings", deny(warnings))]
 #![warn(clippy::pedantic)]
 
+use neqo_crypto::agent::CertificateInfo;
 use neqo_crypto::{
-    AuthenticationStatus, Client, HandshakeState, SecretAgentPreInfo, Server, ZeroRttCheckResult,
-    ZeroRttChecker, TLS_AES_128_GCM_SHA256, TLS_CHACHA20_POLY1305_SHA256, TLS_GRP_EC_SECP256R1,
-    TLS_VERSION_1_3,
+    AuthenticationStatus, CertificateVerifier, Client, HandshakeState, SecretAgentPreInfo, Server,
+    ZeroRttCheckResult, ZeroRttChecker, TLS_AES_128_GCM_SHA256, TLS_CHACHA20_POLY1305_SHA256,
+    TLS_GRP_EC_SECP256R1, TLS_VERSION_1_3,
 };
 
 use std::boxed::Box;
@@ -135,6 +136,48 @@ fn raw() {
     assert!(server.peer_certificate().is_none());
 }
 
+/// A verifier that checks that there is a certificate, then returns a fixed answer.
+#[derive(Debug)]
+struct FixedVerifier(AuthenticationStatus);
+
+impl CertificateVerifier for FixedVerifier {
+    fn verify(&mut self, mut certs: CertificateInfo) -> AuthenticationStatus {
+        assert!((&mut certs).next().is_some());
+        self.0
+    }
+}
+
+#[test]
+fn certificate_verifier_accept() {
+    fixture_init();
+    let mut client = Client::new("server.example").expect("should create client");
+    client.set_certificate_verifier(Box::new(FixedVerifier(AuthenticationStatus::Ok)));
+    let mut server = Server::new(&["key"]).expect("should create server");
+
+    let client_records = client.handshake_raw(now(), None).expect("send CH");
+    let server_records = forward_records(now(), &mut server, client_records).expect("read CH");
+    // There is no need to wait for authentication.
+    let client_records = forward_records(now(), &mut client, server_records).expect("read SH");
+    assert!(client.state().is_connected());
+
+    let server_records = forward_records(now(), &mut server, client_records).expect("finish");
+    assert!(server_records.is_empty());
+    assert!(server.state().is_connected());
+}
+
+#[test]
+fn certificate_verifier_reject() {
+    fixture_init();
+    let mut client = Client::new("server.example").expect("should create client");
+    client.set_certificate_verifier(Box::new(FixedVerifier(AuthenticationStatus::CertUntrusted)));
+    let mut server = Server::new(&["key"]).expect("should create server");
+
+    let client_records = client.handshake_raw(now(), None).expect("send CH");
+    let server_records = forward_records(now(), &mut server, client_records).expect("read CH");
+    assert!(forward_records(now(), &mut client, server_records).is_err());
+    assert!(matches!(client.state(), HandshakeState::Failed(_)));
+}
+
 #[test]
 fn chacha_client() {
     fixture_init();
@@ -25,6 +25,19 @@ pub enum Http3ClientEvent {
         headers: Option<Vec<Header>>,
         fin: bool,
     },
+    /// Some of the response headers are decoded.  These are only reported if incremental
+    /// headers are enabled with `Http3Parameters::set_incremental_headers`, in which case the
+    /// `HeaderReady` event that follows the last of them has no headers.
+    HeaderFieldsReady {
+        stream_id: u64,
+        headers: Vec<Header>,
+    },
+    /// Response trailers are received, after the response body.
+    TrailersReady {
+        stream_id: u64,
+        headers: Vec<Header>,
+        fin: bool,
+    },
     /// A stream can accept new data.
     DataWritable { stream_id: u64 },
     /// New bytes available for reading.
@@ -76,6 +89,20 @@ impl RecvMessageEvents for Http3ClientEvents {
         });
     }
 
+    /// Add a new `HeaderFieldsReady` event.
+    fn header_fields_ready(&self, stream_id: u64, headers: Vec<Header>) {
+        self.insert(Http3ClientEvent::HeaderFieldsReady { stream_id, headers });
+    }
+
+    /// Add a new `TrailersReady` event.
+    fn trailers_ready(&self, stream_id: u64, headers: Vec<Header>, fin: bool) {
+        self.insert(Http3ClientEvent::TrailersReady {
+            stream_id,
+            headers,
+            fin,
+        });
+    }
+
     /// Add a new `DataReadable` event
     fn data_readable(&self, stream_id: u64) {
         self.insert(Http3ClientEvent::DataReadable { stream_id });
@@ -86,6 +113,8 @@ impl RecvMessageEvents for Http3ClientEvents {
         self.remove(|evt| {
             matches!(evt,
                 Http3ClientEvent::HeaderReady { stream_id: x, .. }
+                | Http3ClientEvent::HeaderFieldsReady { stream_id: x, .. }
+                | Http3ClientEvent::TrailersReady { stream_id: x, .. }
                 | Http3ClientEvent::DataReadable { stream_id: x }
                 | Http3ClientEvent::PushPromise { request_stream_id: x, .. }
                 | Http3ClientEvent::Reset { stream_id: x, .. } if *x == stream_id)
@@ -199,6 +228,7 @@ impl Http3ClientEvents {
         self.remove(|evt| {
             matches!(evt,
                 Http3ClientEvent::HeaderReady { stream_id: x, .. }
+                | Http3ClientEvent::HeaderFieldsReady { stream_id: x, .. }
                 | Http3ClientEvent::DataWritable { stream_id: x }
                 | Http3ClientEvent::DataReadable { stream_id: x }
                 | Http3ClientEvent::PushPromise { request_stream_id: x, .. }
@@ -14,18 +14,28 @@ use crate::settings::{HSetting, HSettingType, HSettings, HttpZeroRttChecker};
 use crate::stream_type_reader::NewStreamTypeReader;
 use crate::RecvStream;
 use neqo_common::{qdebug, qerror, qinfo, qtrace, qwarn};
+use neqo_crypto::random;
 use neqo_qpack::decoder::{QPackDecoder, QPACK_UNI_STREAM_TYPE_DECODER};
 use neqo_qpack::encoder::{QPackEncoder, QPACK_UNI_STREAM_TYPE_ENCODER};
 use neqo_qpack::QpackSettings;
 use neqo_transport::{AppError, CloseError, Connection, State, StreamType};
 use std::collections::{BTreeSet, HashMap};
+use std::convert::TryFrom;
 use std::fmt::Debug;
 use std::mem;
 
-use crate::{Error, Res};
+use crate::{Error, Header, Res};
 
-const HTTP3_UNI_STREAM_TYPE_PUSH: u64 = 0x1;
-const QPACK_TABLE_SIZE_LIMIT: u64 = 1 << 30;
+pub(crate) const HTTP3_UNI_STREAM_TYPE_PUSH: u64 = 0x1;
+pub(crate) const QPACK_TABLE_SIZE_LIMIT: u64 = 1 << 30;
+
+/// Pick a reserved identifier, of the form `0x1f * N + 0x21`, for a GREASE setting or frame.
+fn grease_type() -> u64 {
+    let n = u64::from(u32::from_be_bytes(
+        <[u8; 4]>::try_from(&random(4)[..]).unwrap(),
+    ));
+    0x1f * n + 0x21
+}
 
 pub(crate) enum HandleReadableOutput {
     StreamNotFound,
@@ -62,6 +72,9 @@ impl Http3State {
 pub(crate) struct Http3Connection {
     pub state: Http3State,
     local_qpack_settings: QpackSettings,
+    enable_connect: bool,
+    grease: bool,
+    max_header_list_size: Option<u64>,
     control_stream_local: ControlStreamLocal,
     control_stream_remote: ControlStreamRemote,
     new_streams: HashMap<u64, NewStreamTypeReader>,
@@ -80,8 +93,9 @@ impl ::std::fmt::Display for Http3Connection {
 }
 
 impl Http3Connection {
-    /// Create a new connection.
-    pub fn new(local_qpack_settings: QpackSettings) -> Self {
+    /// Create a new connection.  `enable_connect` controls whether Extended CONNECT is
+    /// advertised with `SETTINGS_ENABLE_CONNECT_PROTOCOL`.
+    pub fn new(local_qpack_settings: QpackSettings, enable_connect: bool) -> Self {
         if (local_qpack_settings.max_table_size_encoder >= QPACK_TABLE_SIZE_LIMIT)
             || (local_qpack_settings.max_table_size_decoder >= QPACK_TABLE_SIZE_LIMIT)
         {
@@ -90,6 +104,9 @@ impl Http3Connection {
         Self {
             state: Http3State::Initializing,
             local_qpack_settings,
+            enable_connect,
+            grease: false,
+            max_header_list_size: None,
             control_stream_local: ControlStreamLocal::default(),
             control_stream_remote: ControlStreamRemote::new(),
             new_streams: HashMap::new(),
@@ -113,18 +130,86 @@ impl Http3Connection {
 
     fn send_settings(&mut self) {
         qdebug!([self], "Send settings.");
+        let mut settings = vec![
+            HSetting {
+                setting_type: HSettingType::MaxTableCapacity,
+                value: self.qpack_decoder.get_max_table_size(),
+            },
+            HSetting {
+                setting_type: HSettingType::BlockedStreams,
+                value: self.qpack_decoder.get_blocked_streams().into(),
+            },
+        ];
+        if self.enable_connect {
+            settings.push(HSetting::new(HSettingType::EnableConnectProtocol, 1));
+        }
+        if let Some(size) = self.max_header_list_size {
+            settings.push(HSetting::new(HSettingType::MaxHeaderListSize, size));
+        }
+        if self.grease {
+            settings.push(HSetting::new(
+                HSettingType::Grease(grease_type()),
+                u64::from(random(1)[0]),
+            ));
+        }
         self.control_stream_local.queue_frame(&HFrame::Settings {
-            settings: HSettings::new(&[
-                HSetting {
-                    setting_type: HSettingType::MaxTableCapacity,
-                    value: self.qpack_decoder.get_max_table_size(),
-                },
-                HSetting {
-                    setting_type: HSettingType::BlockedStreams,
-                    value: self.qpack_decoder.get_blocked_streams().into(),
-                },
-            ]),
+            settings: HSettings::new(&settings),
         });
+        if self.grease {
+            self.control_stream_local.queue_frame(&HFrame::Grease {
+                frame_type: grease_type(),
+                payload: random(usize::from(random(1)[0] & 0x7)),
+            });
+        }
+    }
+
+    /// Send reserved settings and frame types that the peer must ignore.  This needs to be
+    /// set before the connection is established.
+    pub fn set_grease(&mut self, grease: bool) {
+        self.grease = grease;
+    }
+
+    /// Limit the size of header lists that are accepted from the peer.  This is advertised
+    /// with `SETTINGS_MAX_FIELD_SECTION_SIZE`, so it needs to be set before the connection is
+    /// established.  `None` means that there is no limit.
+    pub fn set_max_header_list_size(&mut self, size: Option<u64>) {
+        self.max_header_list_size = size;
+        self.qpack_decoder.set_max_header_list_size(size);
+    }
+
+    pub fn max_header_list_size(&self) -> Option<u64> {
+        self.max_header_list_size
+    }
+
+    /// Reset a stream whose header list was larger than `max_header_list_size` and tell the
+    /// application.  The connection remains usable.
+    pub fn reset_excessive_load(&mut self, conn: &mut Connection, stream_id: u64) {
+        let error = Error::HttpExcessiveLoad.code();
+        qinfo!([self], "Header list too large on stream {}.", stream_id);
+        self.send_streams.remove(&stream_id);
+        if let Some(s) = self.recv_streams.remove(&stream_id) {
+            s.stream_reset_recv(error, &mut self.qpack_decoder);
+        }
+        // The stream might already be closed, in which case these fail, which is fine.
+        let _ = conn.stream_reset_send(stream_id, error);
+        let _ = conn.stream_stop_sending(stream_id, error);
+    }
+
+    /// Whether Extended CONNECT is enabled locally.
+    pub fn enable_connect(&self) -> bool {
+        self.enable_connect
+    }
+
+    /// Whether the peer has enabled Extended CONNECT.  Before the peer's SETTINGS frame
+    /// arrives, this uses the settings remembered for 0-RTT.
+    pub fn peer_enable_connect(&self) -> bool {
+        match &self.settings_state {
+            Http3RemoteSettingsState::Received(settings)
+            | Http3RemoteSettingsState::ZeroRtt(settings) => {
+                settings.get(HSettingType::EnableConnectProtocol) == 1
+            }
+            Http3RemoteSettingsState::NotReceived => false,
+        }
     }
 
     /// Save settings for adding to the session ticket.
@@ -457,11 +542,16 @@ impl Http3Connection {
             "Request/response stream {} is readable.",
             stream_id
         );
-        if header_unblocked {
-            recv_stream.header_unblocked(conn, &mut self.qpack_decoder)?;
+        let res = if header_unblocked {
+            recv_stream.header_unblocked(conn, &mut self.qpack_decoder)
         } else {
-            recv_stream.receive(conn, &mut self.qpack_decoder)?;
+            recv_stream.receive(conn, &mut self.qpack_decoder)
+        };
+        if let Err(Error::HttpExcessiveLoad) = res {
+            self.reset_excessive_load(conn, stream_id);
+            return Ok(true);
         }
+        res?;
         if recv_stream.done() {
             self.recv_streams.remove(&stream_id);
         }
@@ -510,7 +600,9 @@ impl Http3Connection {
     pub fn close(&mut self, error: AppError) {
         qinfo!([self], "Close connection error {:?}.", error);
         self.state = Http3State::Closing(CloseError::Application(error));
-        if (!self.send_streams.is_empty() || !self.recv_streams.is_empty()) && (error == 0) {
+        if (!self.send_streams.is_empty() || !self.recv_streams.is_empty())
+            && (error == AppError(0))
+        {
             qwarn!("close(0) called when streams still active");
         }
         self.send_streams.clear();
@@ -561,6 +653,21 @@ impl Http3Connection {
         Ok(())
     }
 
+    /// Queue trailers on a stream.  They are sent after the message body, and end the message.
+    /// # Errors
+    /// `InvalidStreamId` if the stream does not exist, `InvalidState` if no headers have been
+    /// set yet, or `AlreadyClosed` if the message has been ended already.
+    pub fn send_trailers(&mut self, stream_id: u64, headers: &[Header]) -> Res<()> {
+        qinfo!([self], "Send trailers on stream {}.", stream_id);
+        debug_assert!(self.state.active());
+        self.send_streams
+            .get_mut(&stream_id)
+            .ok_or(Error::InvalidStreamId)?
+            .send_trailers(headers)?;
+        self.insert_streams_have_data_to_send(stream_id);
+        Ok(())
+    }
+
     // If the control stream has received frames MaxPushId or Goaway which handling is specific to
     // the client and server, we must give them to the specific client/server handler.
     fn handle_control_frame(&mut self, f: HFrame) -> Res<Option<HFrame>> {
@@ -575,9 +682,11 @@ impl Http3Connection {
                 self.handle_settings(settings)?;
                 Ok(None)
             }
-            HFrame::Goaway { .. } | HFrame::MaxPushId { .. } | HFrame::CancelPush { .. } => {
-                Ok(Some(f))
-            }
+            HFrame::Goaway { .. }
+            | HFrame::MaxPushId { .. }
+            | HFrame::CancelPush { .. }
+            | HFrame::PriorityUpdateRequest { .. }
+            | HFrame::PriorityUpdatePush { .. } => Ok(Some(f)),
             _ => Err(Error::HttpFrameUnexpected),
         }
     }
@@ -590,7 +699,9 @@ impl Http3Connection {
                 HSettingType::BlockedStreams => {
                     self.qpack_encoder.set_max_blocked_streams(s.value)?
                 }
-                HSettingType::MaxHeaderListSize => (),
+                HSettingType::MaxHeaderListSize
+                | HSettingType::EnableConnectProtocol
+                | HSettingType::Grease(_) => (),
             }
         }
         Ok(())
@@ -598,6 +709,9 @@ impl Http3Connection {
 
     fn handle_settings(&mut self, new_settings: HSettings) -> Res<()> {
         qinfo!([self], "Handle SETTINGS frame.");
+        if new_settings.get(HSettingType::EnableConnectProtocol) > 1 {
+            return Err(Error::HttpSettings);
+        }
         match &self.settings_state {
             Http3RemoteSettingsState::NotReceived => {
                 self.set_qpack_settings(&new_settings)?;
@@ -610,6 +724,7 @@ impl Http3Connection {
                     HSettingType::MaxHeaderListSize,
                     HSettingType::MaxTableCapacity,
                     HSettingType::BlockedStreams,
+                    HSettingType::EnableConnectProtocol,
                 ] {
                     let zero_rtt_value = settings.get(*st);
                     let new_value = new_settings.get(*st);
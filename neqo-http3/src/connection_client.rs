@@ -5,7 +5,9 @@
 // except according to those terms.
 
 use crate::client_events::{Http3ClientEvent, Http3ClientEvents};
-use crate::connection::{HandleReadableOutput, Http3Connection, Http3State};
+use crate::connection::{
+    HandleReadableOutput, Http3Connection, Http3State, QPACK_TABLE_SIZE_LIMIT,
+};
 use crate::hframe::HFrame;
 use crate::push_controller::PushController;
 use crate::push_stream::PushStream;
@@ -13,6 +15,7 @@ use crate::recv_message::RecvMessage;
 use crate::send_message::{SendMessage, SendMessageEvents};
 use crate::settings::HSettings;
 use crate::Header;
+use crate::Priority;
 use crate::RecvMessageEvents;
 use neqo_common::{
     hex, hex_with_len, qdebug, qinfo, qlog::NeqoQlog, qtrace, Datagram, Decoder, Encoder, Role,
@@ -59,6 +62,51 @@ where
 pub struct Http3Parameters {
     pub qpack_settings: QpackSettings,
     pub max_concurrent_push_streams: u64,
+    pub grease: bool,
+    pub max_header_list_size: Option<u64>,
+    pub incremental_headers: bool,
+}
+
+impl Http3Parameters {
+    /// Set the capacity of the QPACK dynamic table.  This is advertised to the peer as the size
+    /// of the table our decoder will keep, and it also limits how much of the table the peer
+    /// allows our encoder to use.  A larger table lets repeated headers be sent as references.
+    /// # Errors
+    /// `InvalidInput` if `capacity` is not less than 2^30.
+    pub fn set_qpack_max_table_capacity(&mut self, capacity: u64) -> Res<()> {
+        if capacity >= QPACK_TABLE_SIZE_LIMIT {
+            return Err(Error::InvalidInput);
+        }
+        self.qpack_settings.max_table_size_decoder = capacity;
+        self.qpack_settings.max_table_size_encoder = capacity;
+        Ok(())
+    }
+
+    /// Set the number of streams that can be blocked waiting for QPACK encoder instructions.
+    /// This is advertised to the peer.
+    pub fn set_qpack_max_blocked_streams(&mut self, blocked_streams: u16) {
+        self.qpack_settings.max_blocked_streams = blocked_streams;
+    }
+
+    /// Send a reserved GREASE setting and frame on the control stream, which the peer must
+    /// ignore.  This checks that peers tolerate settings and frame types they do not know.
+    pub fn set_grease(&mut self, grease: bool) {
+        self.grease = grease;
+    }
+
+    /// Limit the size of the header lists that the peer can send, which is advertised with
+    /// `SETTINGS_MAX_FIELD_SECTION_SIZE`.  The size of a list is the length of all names and
+    /// values plus 32 for each field.  A stream that carries a larger header list is reset
+    /// with `H3_EXCESSIVE_LOAD`.
+    pub fn set_max_header_list_size(&mut self, size: u64) {
+        self.max_header_list_size = Some(size);
+    }
+
+    /// Report response headers with `HeaderFieldsReady` events as they are decoded, rather than
+    /// waiting for the complete header list.  This does not apply to trailers or pushes.
+    pub fn set_incremental_headers(&mut self, incremental: bool) {
+        self.incremental_headers = incremental;
+    }
 }
 
 pub struct Http3Client {
@@ -66,6 +114,7 @@ pub struct Http3Client {
     base_handler: Http3Connection,
     events: Http3ClientEvents,
     push_handler: Rc<RefCell<PushController>>,
+    incremental_headers: bool,
 }
 
 impl Display for Http3Client {
@@ -101,16 +150,24 @@ impl Http3Client {
     }
 
     #[must_use]
-    pub fn new_with_conn(c: Connection, http3_parameters: &Http3Parameters) -> Self {
+    pub fn new_with_conn(mut c: Connection, http3_parameters: &Http3Parameters) -> Self {
+        // After 0-RTT is rejected, everything is set up again from the start,
+        // including the control and QPACK streams, so the transport can't keep
+        // the old streams.
+        c.set_0rtt_resend(false);
         let events = Http3ClientEvents::default();
+        let mut base_handler = Http3Connection::new(http3_parameters.qpack_settings, false);
+        base_handler.set_grease(http3_parameters.grease);
+        base_handler.set_max_header_list_size(http3_parameters.max_header_list_size);
         Self {
             conn: c,
-            base_handler: Http3Connection::new(http3_parameters.qpack_settings),
+            base_handler,
             events: events.clone(),
             push_handler: Rc::new(RefCell::new(PushController::new(
                 http3_parameters.max_concurrent_push_streams,
                 events,
             ))),
+            incremental_headers: http3_parameters.incremental_headers,
         }
     }
 
@@ -250,6 +307,74 @@ impl Http3Client {
             host,
             path
         );
+        // Transform pseudo-header fields
+        let mut final_headers = Vec::new();
+        final_headers.push((":method".into(), method.to_owned()));
+        final_headers.push((":scheme".into(), scheme.to_owned()));
+        final_headers.push((":authority".into(), host.to_owned()));
+        final_headers.push((":path".into(), path.to_owned()));
+        final_headers.extend_from_slice(headers);
+        self.request(now, final_headers)
+    }
+
+    /// Open a TCP tunnel to `authority`, a host and port, with a CONNECT request.  The request
+    /// has only the `:method` and `:authority` pseudo-headers.  Once the server responds with a
+    /// 2xx status, the stream carries bytes in both directions: `send_request_body` writes to it
+    /// and `read_response_data` reads from it.
+    /// # Errors
+    /// Any error that `fetch` can return.
+    pub fn connect(&mut self, now: Instant, authority: &str, headers: &[Header]) -> Res<u64> {
+        qinfo!([self], "CONNECT authority={}", authority);
+        let mut final_headers = Vec::new();
+        final_headers.push((":method".into(), "CONNECT".into()));
+        final_headers.push((":authority".into(), authority.to_owned()));
+        final_headers.extend_from_slice(headers);
+        self.request(now, final_headers)
+    }
+
+    /// Whether the server allows Extended CONNECT (RFC 9220), which `extended_connect` needs.
+    #[must_use]
+    pub fn extended_connect_enabled(&self) -> bool {
+        self.base_handler.peer_enable_connect()
+    }
+
+    /// Open a tunnel to `host` with an Extended CONNECT request for `protocol`, for example
+    /// "websocket".  Once the server responds with a 2xx status, the stream carries bytes in both
+    /// directions: `send_request_body` writes to it and `read_response_data` reads from it.
+    /// # Errors
+    /// `Unavailable` if the server has not enabled Extended CONNECT, or any error that `fetch`
+    /// can return.
+    pub fn extended_connect(
+        &mut self,
+        now: Instant,
+        protocol: &str,
+        scheme: &str,
+        host: &str,
+        path: &str,
+        headers: &[Header],
+    ) -> Res<u64> {
+        qinfo!(
+            [self],
+            "Extended CONNECT protocol={}, scheme={}, host={}, path={}",
+            protocol,
+            scheme,
+            host,
+            path
+        );
+        if !self.base_handler.peer_enable_connect() {
+            return Err(Error::Unavailable);
+        }
+        let mut final_headers = Vec::new();
+        final_headers.push((":method".into(), "CONNECT".into()));
+        final_headers.push((":protocol".into(), protocol.to_owned()));
+        final_headers.push((":scheme".into(), scheme.to_owned()));
+        final_headers.push((":authority".into(), host.to_owned()));
+        final_headers.push((":path".into(), path.to_owned()));
+        final_headers.extend_from_slice(headers);
+        self.request(now, final_headers)
+    }
+
+    fn request(&mut self, now: Instant, final_headers: Vec<Header>) -> Res<u64> {
         // Requests cannot be created when a connection is in states: Initializing, GoingAway, Closing and Closed.
         match self.base_handler.state() {
             Http3State::GoingAway(..) | Http3State::Closing(..) | Http3State::Closed(..) => {
@@ -264,14 +389,6 @@ impl Http3Client {
             .stream_create(StreamType::BiDi)
             .map_err(|e| Error::map_stream_create_errors(&e))?;
 
-        // Transform pseudo-header fields
-        let mut final_headers = Vec::new();
-        final_headers.push((":method".into(), method.to_owned()));
-        final_headers.push((":scheme".into(), scheme.to_owned()));
-        final_headers.push((":authority".into(), host.to_owned()));
-        final_headers.push((":path".into(), path.to_owned()));
-        final_headers.extend_from_slice(headers);
-
         self.base_handler.add_streams(
             id,
             SendMessage::new_with_headers(id, final_headers, Box::new(self.events.clone())),
@@ -279,6 +396,8 @@ impl Http3Client {
                 id,
                 Box::new(self.events.clone()),
                 Some(self.push_handler.clone()),
+                self.base_handler.max_header_list_size(),
+                self.incremental_headers,
             )),
         );
 
@@ -312,6 +431,31 @@ impl Http3Client {
         Ok(())
     }
 
+    /// Ask the server to change the priority of the response on `stream_id`, by
+    /// sending a `PRIORITY_UPDATE` frame.
+    /// # Errors
+    /// `InvalidStreamId` if the stream does not exist or the response has been
+    /// received completely.
+    pub fn priority_update(&mut self, stream_id: u64, priority: Priority) -> Res<()> {
+        qinfo!(
+            [self],
+            "Priority update for stream {}: {:?}.",
+            stream_id,
+            priority
+        );
+        if !StreamId::new(stream_id).is_bidi()
+            || !self.base_handler.recv_streams.contains_key(&stream_id)
+        {
+            return Err(Error::InvalidStreamId);
+        }
+        self.base_handler
+            .queue_control_frame(&HFrame::PriorityUpdateRequest {
+                element_id: stream_id,
+                priority,
+            });
+        Ok(())
+    }
+
     /// This is call when application is done sending a request.
     /// # Errors
     /// An error will be return if stream does not exist.
@@ -321,6 +465,15 @@ impl Http3Client {
             .stream_close_send(&mut self.conn, stream_id)
     }
 
+    /// Send `headers` as trailers after the request body.  This ends the request, like
+    /// `stream_close_send`.
+    /// # Errors
+    /// `InvalidStreamId` if the stream does not exist, or `AlreadyClosed` if the request has
+    /// been ended already.
+    pub fn send_trailers(&mut self, stream_id: u64, headers: &[Header]) -> Res<()> {
+        self.base_handler.send_trailers(stream_id, headers)
+    }
+
     /// To supply a request body this function is called (headers are supplied through the `fetch` function.)
     /// # Errors
     /// `InvalidStreamId` if thee stream does not exist,
@@ -367,6 +520,11 @@ impl Http3Client {
                 }
                 Ok((amount, fin))
             }
+            Err(Error::HttpExcessiveLoad) => {
+                self.base_handler
+                    .reset_excessive_load(&mut self.conn, stream_id);
+                Err(Error::HttpExcessiveLoad)
+            }
             Err(e) => {
                 if e.connection_error() {
                     self.close(now, e.code(), "");
@@ -547,7 +705,19 @@ impl Http3Client {
                 } => self
                     .base_handler
                     .handle_stream_stop_sending(stream_id, app_error)?,
-                ConnectionEvent::SendStreamComplete { .. } => {}
+                ConnectionEvent::SendStreamComplete { .. }
+                | ConnectionEvent::HandshakeCompleted
+                | ConnectionEvent::KeyUpdated { .. }
+                | ConnectionEvent::Datagram { .. }
+                | ConnectionEvent::DatagramOutcome { .. }
+                | ConnectionEvent::StreamDataLost { .. }
+                | ConnectionEvent::RecvStreamFin { .. }
+                | ConnectionEvent::RecvStreamGap { .. }
+                | ConnectionEvent::SendStreamBlocked { .. }
+                | ConnectionEvent::PathMigrated { .. }
+                | ConnectionEvent::ConnectionClosed { .. }
+                | ConnectionEvent::ResumptionToken { .. }
+                | ConnectionEvent::NewToken { .. } => {}
                 ConnectionEvent::SendStreamCreatable { stream_type } => {
                     self.events.new_requests_creatable(stream_type)
                 }
@@ -584,11 +754,13 @@ impl Http3Client {
                             .push_handler
                             .borrow_mut()
                             .handle_cancel_push(push_id, &mut self.conn, &mut self.base_handler),
-                        HFrame::MaxPushId { .. } => Err(Error::HttpFrameUnexpected),
+                        HFrame::MaxPushId { .. }
+                        | HFrame::PriorityUpdateRequest { .. }
+                        | HFrame::PriorityUpdatePush { .. } => Err(Error::HttpFrameUnexpected),
                         HFrame::Goaway { stream_id } => self.handle_goaway(stream_id),
                         _ => {
                             unreachable!(
-                                "we should only put MaxPushId and Goaway into control_frames."
+                                "only MaxPushId, Goaway and PriorityUpdate go into control_frames."
                             );
                         }
                     }?;
@@ -607,6 +779,7 @@ impl Http3Client {
                     stream_id,
                     self.push_handler.clone(),
                     self.events.clone(),
+                    self.base_handler.max_header_list_size(),
                 )),
             );
             if force_read {
@@ -692,12 +865,12 @@ impl Http3Client {
 #[cfg(test)]
 mod tests {
     use super::{
-        AuthenticationStatus, Connection, Error, HSettings, Header, Http3Client, Http3ClientEvent,
-        Http3Parameters, Http3State, QpackSettings, Rc, RefCell, StreamType,
+        AppError, AuthenticationStatus, Connection, Error, HSettings, Header, Http3Client,
+        Http3ClientEvent, Http3Parameters, Http3State, QpackSettings, Rc, RefCell, StreamType,
     };
     use crate::hframe::HFrame;
     use crate::settings::{HSetting, HSettingType};
-    use neqo_common::{Datagram, Encoder};
+    use neqo_common::{Datagram, Decoder, Encoder};
     use neqo_crypto::{AllowZeroRtt, AntiReplay};
     use neqo_qpack::encoder::QPackEncoder;
     use neqo_transport::{
@@ -718,8 +891,22 @@ mod tests {
         };
     }
 
-    /// Create a http3 client with default configuration.
-    pub fn default_http3_client() -> Http3Client {
+    fn default_http3_parameters() -> Http3Parameters {
+        Http3Parameters {
+            qpack_settings: QpackSettings {
+                max_table_size_encoder: 100,
+                max_table_size_decoder: 100,
+                max_blocked_streams: 100,
+            },
+            max_concurrent_push_streams: 5,
+            grease: false,
+            max_header_list_size: None,
+            incremental_headers: false,
+        }
+    }
+
+    /// Create a http3 client with the given parameters.
+    fn http3_client_with_params(params: &Http3Parameters) -> Http3Client {
         fixture_init();
         Http3Client::new(
             DEFAULT_SERVER_NAME,
@@ -728,16 +915,14 @@ mod tests {
             loopback(),
             loopback(),
             QuicVersion::default(),
-            &Http3Parameters {
-                qpack_settings: QpackSettings {
-                    max_table_size_encoder: 100,
-                    max_table_size_decoder: 100,
-                    max_blocked_streams: 100,
-                },
-                max_concurrent_push_streams: 5,
-            },
+            params,
         )
-        .expect("create a default client")
+        .expect("create a client")
+    }
+
+    /// Create a http3 client with default configuration.
+    pub fn default_http3_client() -> Http3Client {
+        http3_client_with_params(&default_http3_parameters())
     }
 
     // default_http3_client use following setting:
@@ -939,7 +1124,7 @@ mod tests {
                         }
                     }
                     ConnectionEvent::StateChange(State::Connected) => connected = true,
-                    ConnectionEvent::StateChange(_) => {}
+                    ConnectionEvent::StateChange(_) | ConnectionEvent::HandshakeCompleted => {}
                     _ => panic!("unexpected event"),
                 }
             }
@@ -1369,6 +1554,248 @@ mod tests {
         let _ = connect();
     }
 
+    fn is_grease(t: u64) -> bool {
+        t >= 0x21 && (t - 0x21) % 0x1f == 0
+    }
+
+    // With GREASE enabled, the client sends a reserved setting and then a reserved frame.
+    #[test]
+    fn test_client_grease() {
+        let mut params = default_http3_parameters();
+        params.set_grease(true);
+        let mut client = http3_client_with_params(&params);
+        let mut server = TestServer::new();
+        connect_only_transport_with(&mut client, &mut server);
+        let out = client.process(None, now());
+        let _ = server.conn.process(out.dgram(), now());
+
+        let mut buf = [0_u8; 100];
+        let (amount, fin) = server
+            .conn
+            .stream_recv(CLIENT_SIDE_CONTROL_STREAM_ID, &mut buf)
+            .unwrap();
+        assert_eq!(fin, false);
+        let mut dec = Decoder::from(&buf[..amount]);
+        assert_eq!(dec.decode_varint(), Some(0x0)); // control stream type
+        assert_eq!(dec.decode_varint(), Some(0x4)); // SETTINGS
+        let mut settings = Decoder::from(dec.decode_vvec().unwrap());
+        let mut grease_setting = false;
+        while settings.remaining() > 0 {
+            let setting_type = settings.decode_varint().unwrap();
+            assert!(settings.decode_varint().is_some());
+            grease_setting |= is_grease(setting_type);
+        }
+        assert!(grease_setting);
+
+        assert!(is_grease(dec.decode_varint().unwrap()));
+        assert!(dec.decode_vvec().is_some());
+        // MAX_PUSH_ID follows, as usual.
+        assert_eq!(dec.decode_remainder(), &[0xd, 0x1, 0x5]);
+    }
+
+    // A response with a header list that is larger than the advertised limit resets the
+    // stream with H3_EXCESSIVE_LOAD, but the connection remains open.
+    #[test]
+    fn test_max_header_list_size() {
+        let mut params = default_http3_parameters();
+        params.set_max_header_list_size(100);
+        let mut client = http3_client_with_params(&params);
+        let mut server = TestServer::new();
+        connect_only_transport_with(&mut client, &mut server);
+        let out = client.process(None, now());
+        let _ = server.conn.process(out.dgram(), now());
+
+        let mut buf = [0_u8; 100];
+        let (amount, _) = server
+            .conn
+            .stream_recv(CLIENT_SIDE_CONTROL_STREAM_ID, &mut buf)
+            .unwrap();
+        let mut dec = Decoder::from(&buf[..amount]);
+        assert_eq!(dec.decode_varint(), Some(0x0)); // control stream type
+        assert_eq!(dec.decode_varint(), Some(0x4)); // SETTINGS
+        let mut settings = Decoder::from(dec.decode_vvec().unwrap());
+        let mut limit = None;
+        while settings.remaining() > 0 {
+            let setting_type = settings.decode_varint().unwrap();
+            let value = settings.decode_varint().unwrap();
+            if setting_type == 0x6 {
+                limit = Some(value);
+            }
+        }
+        assert_eq!(limit, Some(100));
+        while server.conn.next_event().is_some() {}
+
+        server.create_control_stream();
+        server.create_qpack_streams();
+        let out = server.conn.process(None, now());
+        client.process(out.dgram(), now());
+        assert_eq!(client.state(), Http3State::Connected);
+
+        let request_stream_id = make_request_and_exchange_pkts(&mut client, &mut server, true);
+        // The header block is short enough, but decoding stops at the second field:
+        // 7 + 3 + 32 for the status, and 9 + 20 + 32 for the other field.
+        let headers = vec![
+            (String::from(":status"), String::from("200")),
+            (String::from("my-header"), "a".repeat(20)),
+        ];
+        let encoded_headers = server
+            .encoder
+            .encode_header_block(&mut server.conn, &headers, request_stream_id)
+            .unwrap();
+        let mut d = Encoder::default();
+        HFrame::Headers {
+            header_block: encoded_headers.to_vec(),
+        }
+        .encode(&mut d);
+        server_send_response_and_exchange_packet(
+            &mut client,
+            &mut server,
+            request_stream_id,
+            &d,
+            false,
+        );
+
+        let events = client.events().collect::<Vec<_>>();
+        assert!(!events
+            .iter()
+            .any(|e| matches!(e, Http3ClientEvent::HeaderReady { .. })));
+        assert!(events.iter().any(|e| {
+            matches!(e, Http3ClientEvent::Reset { stream_id, error }
+                if *stream_id == request_stream_id && *error == Error::HttpExcessiveLoad.code())
+        }));
+        assert_eq!(client.state(), Http3State::Connected);
+
+        // The server is asked to stop sending.
+        let out = client.process(None, now());
+        let _ = server.conn.process(out.dgram(), now());
+        let stop_sending_event = |e| {
+            matches!(e, ConnectionEvent::SendStreamStopSending { stream_id, app_error }
+                if stream_id == request_stream_id && app_error == Error::HttpExcessiveLoad.code())
+        };
+        assert!(server.conn.events().any(stop_sending_event));
+    }
+
+    // A HEADERS frame that is too long to carry a header list within the limit resets the
+    // stream as soon as its length is known.
+    #[test]
+    fn test_max_header_list_size_frame_length() {
+        let mut params = default_http3_parameters();
+        params.set_max_header_list_size(100);
+        let mut client = http3_client_with_params(&params);
+        let mut server = TestServer::new();
+        connect_only_transport_with(&mut client, &mut server);
+        let out = client.process(None, now());
+        let _ = server.conn.process(out.dgram(), now());
+        while server.conn.next_event().is_some() {}
+        server.create_control_stream();
+        server.create_qpack_streams();
+        let out = server.conn.process(None, now());
+        client.process(out.dgram(), now());
+        assert_eq!(client.state(), Http3State::Connected);
+
+        let request_stream_id = make_request_and_exchange_pkts(&mut client, &mut server, true);
+        // Only the start of a HEADERS frame with a 423 byte payload is sent.  At most 4 bytes
+        // are needed for each byte of a header list of 100, plus 22 for the prefix.
+        server_send_response_and_exchange_packet(
+            &mut client,
+            &mut server,
+            request_stream_id,
+            &[0x1, 0x41, 0xa7, 0x0, 0x0],
+            false,
+        );
+
+        let reset = |e| {
+            matches!(e, Http3ClientEvent::Reset { stream_id, error }
+                if stream_id == request_stream_id && error == Error::HttpExcessiveLoad.code())
+        };
+        assert!(client.events().any(reset));
+        assert_eq!(client.state(), Http3State::Connected);
+    }
+
+    // With incremental headers, each part of the response headers is reported as soon as it is
+    // decoded, before the rest of the HEADERS frame has arrived.
+    #[test]
+    fn test_incremental_headers() {
+        let mut params = default_http3_parameters();
+        params.set_incremental_headers(true);
+        let mut client = http3_client_with_params(&params);
+        let mut server = TestServer::new();
+        connect_with(&mut client, &mut server);
+        let request_stream_id = make_request_and_exchange_pkts(&mut client, &mut server, true);
+
+        // A HEADERS frame with ":status: 200" and "content-length: 3" from the static table,
+        // cut off in the second field.
+        server_send_response_and_exchange_packet(
+            &mut client,
+            &mut server,
+            request_stream_id,
+            &[0x1, 0x6, 0x0, 0x0, 0xd9, 0x54],
+            false,
+        );
+        let events = client.events().collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            vec![Http3ClientEvent::HeaderFieldsReady {
+                stream_id: request_stream_id,
+                headers: vec![(String::from(":status"), String::from("200"))],
+            }]
+        );
+
+        // The rest of the HEADERS frame and a DATA frame.
+        server_send_response_and_exchange_packet(
+            &mut client,
+            &mut server,
+            request_stream_id,
+            &[0x1, 0x33, 0x0, 0x3, 0x61, 0x62, 0x63],
+            true,
+        );
+        let events = client.events().collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            vec![
+                Http3ClientEvent::HeaderFieldsReady {
+                    stream_id: request_stream_id,
+                    headers: vec![(String::from("content-length"), String::from("3"))],
+                },
+                Http3ClientEvent::HeaderReady {
+                    stream_id: request_stream_id,
+                    headers: None,
+                    fin: false,
+                },
+                Http3ClientEvent::DataReadable {
+                    stream_id: request_stream_id
+                },
+            ]
+        );
+
+        let mut buf = [0_u8; 10];
+        let (amount, fin) = client
+            .read_response_data(now(), request_stream_id, &mut buf)
+            .unwrap();
+        assert_eq!(&buf[..amount], b"abc");
+        assert!(fin);
+    }
+
+    // A stream that ends in the middle of a streamed header block is an error.
+    #[test]
+    fn test_incremental_headers_truncated() {
+        let mut params = default_http3_parameters();
+        params.set_incremental_headers(true);
+        let mut client = http3_client_with_params(&params);
+        let mut server = TestServer::new();
+        connect_with(&mut client, &mut server);
+        let request_stream_id = make_request_and_exchange_pkts(&mut client, &mut server, true);
+
+        server_send_response_and_exchange_packet(
+            &mut client,
+            &mut server,
+            request_stream_id,
+            &[0x1, 0x6, 0x0, 0x0, 0xd9, 0x54],
+            true,
+        );
+        assert_closed(&client, &Error::HttpFrame);
+    }
+
     // Client: Test that the connection will be closed if control stream
     // has been closed.
     #[test]
@@ -1786,7 +2213,7 @@ mod tests {
         let res = client.read_response_data(now(), request_stream_id, &mut buf);
         assert_eq!(res.unwrap_err(), Error::InvalidStreamId);
 
-        client.close(now(), 0, "");
+        client.close(now(), AppError(0), "");
     }
 
     // Helper function: read response when a server sends HTTP_RESPONSE_2.
@@ -1826,7 +2253,7 @@ mod tests {
         assert!(res.is_err());
         assert_eq!(res.unwrap_err(), Error::InvalidStreamId);
 
-        client.close(now(), 0, "");
+        client.close(now(), AppError(0), "");
     }
 
     // Data sent with a request:
@@ -2181,7 +2608,7 @@ mod tests {
         assert!(res.is_err());
         assert_eq!(res.unwrap_err(), Error::InvalidStreamId);
 
-        client.close(now(), 0, "");
+        client.close(now(), AppError(0), "");
     }
 
     // Server sends stop sending and reset.
@@ -2239,7 +2666,7 @@ mod tests {
         assert!(res.is_err());
         assert_eq!(res.unwrap_err(), Error::InvalidStreamId);
 
-        client.close(now(), 0, "");
+        client.close(now(), AppError(0), "");
     }
 
     // Server sends stop sending with RequestRejected, but it does not send reset.
@@ -2285,7 +2712,7 @@ mod tests {
         let res = client.read_response_data(now(), request_stream_id, &mut buf);
         assert!(res.is_ok());
 
-        client.close(now(), 0, "");
+        client.close(now(), AppError(0), "");
     }
 
     // Server sends stop sending and reset. We have some events for that stream already
@@ -2352,7 +2779,7 @@ mod tests {
         assert!(res.is_err());
         assert_eq!(res.unwrap_err(), Error::InvalidStreamId);
 
-        client.close(now(), 0, "");
+        client.close(now(), AppError(0), "");
     }
 
     // Server sends stop sending with code that is not HttpNoError.
@@ -2417,7 +2844,7 @@ mod tests {
         assert_eq!(amount, EXPECTED_RESPONSE_DATA_2_FRAME_1.len());
         assert_eq!(&buf[..amount], EXPECTED_RESPONSE_DATA_2_FRAME_1);
 
-        client.close(now(), 0, "");
+        client.close(now(), AppError(0), "");
     }
 
     // Server sends a reset. We will close sending side as well.
@@ -2465,7 +2892,7 @@ mod tests {
         assert!(res.is_err());
         assert_eq!(res.unwrap_err(), Error::InvalidStreamId);
 
-        client.close(now(), 0, "");
+        client.close(now(), AppError(0), "");
     }
 
     fn test_incomplet_frame(buf: &[u8], error: &Error) {
@@ -2580,7 +3007,7 @@ mod tests {
             Err(Error::AlreadyClosed)
         );
 
-        client.close(now(), 0, "");
+        client.close(now(), AppError(0), "");
     }
 
     #[test]
@@ -3174,6 +3601,72 @@ mod tests {
         assert!(recv_header && recv_data);
     }
 
+    // A streamed header block that is blocked on encoder instructions is decoded once they arrive.
+    #[test]
+    fn test_incremental_headers_blocked() {
+        let mut params = default_http3_parameters();
+        params.set_incremental_headers(true);
+        let mut client = http3_client_with_params(&params);
+        let mut server = TestServer::new();
+        connect_with(&mut client, &mut server);
+        let request_stream_id = make_request_and_exchange_pkts(&mut client, &mut server, true);
+
+        setup_server_side_encoder(&mut client, &mut server);
+
+        let headers = vec![
+            (String::from(":status"), String::from("200")),
+            (String::from("my-header"), String::from("my-header")),
+            (String::from("content-length"), String::from("3")),
+        ];
+        let encoded_headers = server
+            .encoder
+            .encode_header_block(&mut server.conn, &headers, request_stream_id)
+            .unwrap();
+        let hframe = HFrame::Headers {
+            header_block: encoded_headers.to_vec(),
+        };
+
+        // Delay the encoder instructions, so that decoding the headers is blocked.
+        let encoder_inst_pkt = server.conn.process(None, now());
+
+        let mut d = Encoder::default();
+        hframe.encode(&mut d);
+        let d_frame = HFrame::Data { len: 3 };
+        d_frame.encode(&mut d);
+        d.encode(&[0x61, 0x62, 0x63]);
+        server_send_response_and_exchange_packet(
+            &mut client,
+            &mut server,
+            request_stream_id,
+            &d,
+            true,
+        );
+        assert!(!client.events().any(|e| matches!(
+            e,
+            Http3ClientEvent::HeaderFieldsReady { .. } | Http3ClientEvent::HeaderReady { .. }
+        )));
+
+        let _ = client.process(encoder_inst_pkt.dgram(), now());
+        let events = client.events().collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            vec![
+                Http3ClientEvent::HeaderFieldsReady {
+                    stream_id: request_stream_id,
+                    headers,
+                },
+                Http3ClientEvent::HeaderReady {
+                    stream_id: request_stream_id,
+                    headers: None,
+                    fin: false,
+                },
+                Http3ClientEvent::DataReadable {
+                    stream_id: request_stream_id
+                },
+            ]
+        );
+    }
+
     #[test]
     fn test_read_frames_header_blocked_with_fin_after_headers() {
         let (mut hconn, mut server, request_stream_id) = connect_and_send_request(true);
@@ -3497,7 +3990,7 @@ mod tests {
                 HSetting::new(HSettingType::BlockedStreams, 100),
                 HSetting::new(HSettingType::MaxHeaderListSize, 10000),
             ],
-            &Http3State::Closing(CloseError::Application(265)),
+            &Http3State::Closing(CloseError::Application(AppError(265))),
             ENCODER_STREAM_DATA_WITH_CAP_INSTRUCTION,
         );
     }
@@ -3515,7 +4008,7 @@ mod tests {
                 HSetting::new(HSettingType::MaxTableCapacity, 100),
                 HSetting::new(HSettingType::MaxHeaderListSize, 10000),
             ],
-            &Http3State::Closing(CloseError::Application(265)),
+            &Http3State::Closing(CloseError::Application(AppError(265))),
             ENCODER_STREAM_DATA_WITH_CAP_INSTRUCTION,
         );
     }
@@ -3552,7 +4045,7 @@ mod tests {
                 HSetting::new(HSettingType::BlockedStreams, 100),
                 HSetting::new(HSettingType::MaxHeaderListSize, 10000),
             ],
-            &Http3State::Closing(CloseError::Application(514)),
+            &Http3State::Closing(CloseError::Application(AppError(514))),
             ENCODER_STREAM_DATA_WITH_CAP_INSTRUCTION,
         );
     }
@@ -3571,7 +4064,7 @@ mod tests {
                 HSetting::new(HSettingType::BlockedStreams, 100),
                 HSetting::new(HSettingType::MaxHeaderListSize, 10000),
             ],
-            &Http3State::Closing(CloseError::Application(265)),
+            &Http3State::Closing(CloseError::Application(AppError(265))),
             ENCODER_STREAM_DATA_WITH_CAP_INSTRUCTION,
         );
     }
@@ -3609,7 +4102,7 @@ mod tests {
                 HSetting::new(HSettingType::BlockedStreams, 50),
                 HSetting::new(HSettingType::MaxHeaderListSize, 10000),
             ],
-            &Http3State::Closing(CloseError::Application(265)),
+            &Http3State::Closing(CloseError::Application(AppError(265))),
             ENCODER_STREAM_DATA_WITH_CAP_INSTRUCTION,
         );
     }
@@ -3647,7 +4140,7 @@ mod tests {
                 HSetting::new(HSettingType::BlockedStreams, 100),
                 HSetting::new(HSettingType::MaxHeaderListSize, 5000),
             ],
-            &Http3State::Closing(CloseError::Application(265)),
+            &Http3State::Closing(CloseError::Application(AppError(265))),
             ENCODER_STREAM_DATA_WITH_CAP_INSTRUCTION,
         );
     }
@@ -3704,7 +4197,7 @@ mod tests {
                 HSetting::new(HSettingType::BlockedStreams, 100),
                 HSetting::new(HSettingType::MaxHeaderListSize, 10000),
             ],
-            &Http3State::Closing(CloseError::Application(265)),
+            &Http3State::Closing(CloseError::Application(AppError(265))),
             ENCODER_STREAM_DATA_WITH_CAP_INSTRUCTION,
         );
     }
@@ -3756,16 +4249,25 @@ mod tests {
             |e| matches!(*e, Http3ClientEvent::HeaderReady { .. });
         assert!(!events.iter().any(header_ready));
 
-        // Check that we have a DataReady event. Reading from the stream will return fin=true.
+        // The trailers carry the fin, so there is no DataReadable event.
         let data_readable: fn(&Http3ClientEvent) -> _ =
             |e| matches!(*e, Http3ClientEvent::DataReadable { .. });
-        assert!(events.iter().any(data_readable));
-        let mut buf = [0_u8; 100];
-        let (len, fin) = client
-            .read_response_data(now(), request_stream_id, &mut buf)
-            .unwrap();
-        assert_eq!(0, len);
-        assert_eq!(fin, true)
+        assert!(!events.iter().any(data_readable));
+        let mut trailers = false;
+        for e in events {
+            if let Http3ClientEvent::TrailersReady {
+                stream_id,
+                headers,
+                fin,
+            } = e
+            {
+                assert_eq!(stream_id, request_stream_id);
+                check_response_header_0(&headers);
+                assert_eq!(fin, true);
+                trailers = true;
+            }
+        }
+        assert!(trailers);
     }
 
     #[test]
@@ -4649,7 +5151,7 @@ mod tests {
     fn assert_stop_sending_event(
         server: &mut TestServer,
         push_stream_id: u64,
-        expected_error: u64,
+        expected_error: AppError,
     ) {
         assert!(server.conn.events().any(|e| matches!(
             e,
@@ -4975,6 +5477,11 @@ mod tests {
         );
 
         assert!(check_data_readable(&mut client));
+        // Readable events are level-triggered, so read the data before sending more.
+        let mut buf = [0_u8; 100];
+        let _ = client
+            .read_response_data(now(), request_stream_id, &mut buf)
+            .unwrap();
 
         // Let client receive the encoder instructions.
         let _out = client.process(encoder_inst_pkt, now());
@@ -4,15 +4,18 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use crate::connection::{HandleReadableOutput, Http3Connection, Http3State};
+use crate::connection::{
+    HandleReadableOutput, Http3Connection, Http3State, HTTP3_UNI_STREAM_TYPE_PUSH,
+};
 use crate::hframe::HFrame;
 use crate::recv_message::RecvMessage;
 use crate::send_message::SendMessage;
 use crate::server_connection_events::{Http3ServerConnEvent, Http3ServerConnEvents};
-use crate::{Error, Header, Res};
-use neqo_common::{qdebug, qinfo, qtrace};
+use crate::{Error, Header, Priority, Res};
+use neqo_common::{qdebug, qinfo, qtrace, Encoder};
 use neqo_qpack::QpackSettings;
-use neqo_transport::{AppError, Connection, ConnectionEvent, StreamType};
+use neqo_transport::{AppError, Connection, ConnectionEvent, StreamId, StreamType};
+use std::collections::HashMap;
 use std::time::Instant;
 
 #[derive(Debug)]
@@ -20,6 +23,13 @@ pub struct Http3ServerHandler {
     base_handler: Http3Connection,
     events: Http3ServerConnEvents,
     needs_processing: bool,
+    // The largest push ID that the client allows, if it has sent MAX_PUSH_ID.
+    max_push_id: Option<u64>,
+    next_push_id: u64,
+    // Maps a push ID to the stream that carries the pushed response.
+    push_streams: HashMap<u64, u64>,
+    // The stream ID in the last GOAWAY that was sent.
+    goaway: Option<u64>,
 }
 
 impl ::std::fmt::Display for Http3ServerHandler {
@@ -29,14 +39,22 @@ impl ::std::fmt::Display for Http3ServerHandler {
 }
 
 impl Http3ServerHandler {
-    pub(crate) fn new(qpack_settings: QpackSettings) -> Self {
+    pub(crate) fn new(qpack_settings: QpackSettings, enable_connect: bool) -> Self {
         Self {
-            base_handler: Http3Connection::new(qpack_settings),
+            base_handler: Http3Connection::new(qpack_settings, enable_connect),
             events: Http3ServerConnEvents::default(),
             needs_processing: false,
+            max_push_id: None,
+            next_push_id: 0,
+            push_streams: HashMap::new(),
+            goaway: None,
         }
     }
 
+    pub(crate) fn set_max_header_list_size(&mut self, size: Option<u64>) {
+        self.base_handler.set_max_header_list_size(size);
+    }
+
     /// Supply a response for a request.
     pub(crate) fn set_response(
         &mut self,
@@ -54,6 +72,186 @@ impl Http3ServerHandler {
         Ok(())
     }
 
+    /// Send trailers after the response body.
+    pub(crate) fn send_trailers(&mut self, stream_id: u64, headers: &[Header]) -> Res<()> {
+        self.base_handler.send_trailers(stream_id, headers)?;
+        self.needs_processing = true;
+        Ok(())
+    }
+
+    /// Check a CONNECT request, or a request that uses the `:protocol` pseudo-header.
+    /// `:protocol` is only allowed in an Extended CONNECT request when Extended CONNECT is
+    /// enabled.  A CONNECT request without `:protocol` needs `:authority`, must not have
+    /// `:scheme` or `:path`, and can't have a body, so it must not have `content-length`.
+    /// Anything else is a malformed request and the stream is reset.  Returns false if the
+    /// request was rejected.
+    pub(crate) fn check_request_headers(
+        &mut self,
+        conn: &mut Connection,
+        stream_id: u64,
+        headers: &[Header],
+    ) -> bool {
+        let find = |name: &str| headers.iter().find(|(n, _)| n == name).map(|(_, v)| v);
+        let connect = matches!(find(":method"), Some(m) if m == "CONNECT");
+        let valid = if find(":protocol").is_some() {
+            self.base_handler.enable_connect()
+                && connect
+                && find(":scheme").is_some()
+                && find(":path").is_some()
+                && find(":authority").is_some()
+        } else if connect {
+            find(":authority").is_some()
+                && find(":scheme").is_none()
+                && find(":path").is_none()
+                && find("content-length").is_none()
+        } else {
+            return true;
+        };
+        if !valid {
+            qinfo!([self], "Malformed CONNECT on stream {}.", stream_id);
+            let _ = self.stream_reset(conn, stream_id, Error::HttpMessageError.code());
+        }
+        valid
+    }
+
+    /// Accept a CONNECT or Extended CONNECT request by sending `headers`, which should have a 2xx
+    /// status.
+    /// The stream then carries bytes in both directions.
+    pub(crate) fn accept_connect(
+        &mut self,
+        conn: &mut Connection,
+        stream_id: u64,
+        headers: &[Header],
+    ) -> Res<()> {
+        let send_stream = self
+            .base_handler
+            .send_streams
+            .get_mut(&stream_id)
+            .ok_or(Error::InvalidStreamId)?;
+        send_stream.set_headers(headers)?;
+        // Send the headers now so that data can follow immediately.
+        send_stream.send(conn, &mut self.base_handler.qpack_encoder)?;
+        if send_stream.has_data_to_send() {
+            self.base_handler
+                .insert_streams_have_data_to_send(stream_id);
+        }
+        self.needs_processing = true;
+        Ok(())
+    }
+
+    /// Send data on a stream whose response headers have been sent.
+    pub(crate) fn send_data(
+        &mut self,
+        conn: &mut Connection,
+        stream_id: u64,
+        buf: &[u8],
+    ) -> Res<usize> {
+        let sent = self
+            .base_handler
+            .send_streams
+            .get_mut(&stream_id)
+            .ok_or(Error::InvalidStreamId)?
+            .send_body(conn, buf)?;
+        self.needs_processing = true;
+        Ok(sent)
+    }
+
+    /// Close the sending side of a stream.
+    pub(crate) fn stream_close_send(&mut self, conn: &mut Connection, stream_id: u64) -> Res<()> {
+        self.base_handler.stream_close_send(conn, stream_id)?;
+        self.needs_processing = true;
+        Ok(())
+    }
+
+    /// Promise a push of `response_headers` and `data` in response to the request `headers`.
+    /// A `PUSH_PROMISE` frame is sent on `stream_id`, and the response on a new push stream.
+    /// # Errors
+    /// `InvalidStreamId` if the request stream does not exist or its response has been sent,
+    /// `Unavailable` if the client does not allow another push, and `StreamLimitError` if
+    /// a push stream cannot be created.
+    pub(crate) fn push_promise(
+        &mut self,
+        conn: &mut Connection,
+        stream_id: u64,
+        headers: &[Header],
+        response_headers: &[Header],
+        data: &[u8],
+    ) -> Res<u64> {
+        let request = self.base_handler.send_streams.get(&stream_id);
+        if !matches!(request, Some(r) if r.push_promise_allowed()) {
+            return Err(Error::InvalidStreamId);
+        }
+        let push_id = self.next_push_id;
+        if !self.push_id_allowed(push_id) {
+            qdebug!([self], "Push {} is not allowed by the client.", push_id);
+            return Err(Error::Unavailable);
+        }
+
+        // Encode the promise before creating the push stream, so that a failure doesn't
+        // leave a push stream behind that nothing is sent on.
+        let header_block = self
+            .base_handler
+            .qpack_encoder
+            .encode_header_block(conn, headers, stream_id)?;
+
+        let push_stream_id = conn.stream_create(StreamType::UniDi)?;
+        let mut enc = Encoder::default();
+        enc.encode_varint(HTTP3_UNI_STREAM_TYPE_PUSH);
+        enc.encode_varint(push_id);
+        // A new stream can always buffer this much.
+        let sent = conn.stream_send(push_stream_id, &enc)?;
+        debug_assert_eq!(sent, enc.len());
+
+        self.base_handler
+            .send_streams
+            .get_mut(&stream_id)
+            .ok_or(Error::InvalidStreamId)?
+            .push_promise(push_id, &header_block)
+            .map_err(|_| Error::InvalidStreamId)?;
+        self.base_handler
+            .insert_streams_have_data_to_send(stream_id);
+
+        let mut push_stream = SendMessage::new(push_stream_id, Box::new(self.events.clone()));
+        push_stream.set_message(response_headers, Some(data))?;
+        self.base_handler
+            .send_streams
+            .insert(push_stream_id, push_stream);
+        self.base_handler
+            .insert_streams_have_data_to_send(push_stream_id);
+
+        qinfo!(
+            [self],
+            "Push {} promised on stream {}, push stream {}.",
+            push_id,
+            stream_id,
+            push_stream_id
+        );
+        self.next_push_id += 1;
+        self.push_streams.insert(push_id, push_stream_id);
+        Ok(push_id)
+    }
+
+    /// Send a `GOAWAY` frame.  Requests on `stream_id` and later streams are rejected; requests
+    /// on earlier streams can still complete.  A later `GOAWAY` can only lower the limit.
+    /// # Errors
+    /// `InvalidStreamId` if `stream_id` is not a client-initiated bidirectional stream, and
+    /// `InvalidInput` if it is more than the stream ID in a previous `GOAWAY`.
+    pub(crate) fn goaway(&mut self, stream_id: u64) -> Res<()> {
+        let id = StreamId::new(stream_id);
+        if !id.is_client_initiated() || !id.is_bidi() {
+            return Err(Error::InvalidStreamId);
+        }
+        if matches!(self.goaway, Some(limit) if stream_id > limit) {
+            return Err(Error::InvalidInput);
+        }
+        qinfo!([self], "Send GOAWAY {}.", stream_id);
+        self.base_handler
+            .queue_control_frame(&HFrame::Goaway { stream_id });
+        self.goaway = Some(stream_id);
+        self.needs_processing = true;
+        Ok(())
+    }
+
     /// Reset a request.
     pub fn stream_reset(
         &mut self,
@@ -62,6 +260,7 @@ impl Http3ServerHandler {
         app_error: AppError,
     ) -> Res<()> {
         self.base_handler.stream_reset(conn, stream_id, app_error)?;
+        self.push_stream_closed(stream_id);
         self.events.remove_events_for_stream_id(stream_id);
         self.needs_processing = true;
         Ok(())
@@ -122,6 +321,14 @@ impl Http3ServerHandler {
             qdebug!([self], "check_connection_events - event {:?}.", e);
             match e {
                 ConnectionEvent::NewStream { stream_id } => match stream_id.stream_type() {
+                    StreamType::BiDi
+                        if matches!(self.goaway, Some(limit) if stream_id.as_u64() >= limit) =>
+                    {
+                        qinfo!([self], "Reject request {} after GOAWAY.", stream_id);
+                        let error = Error::HttpRequestRejected.code();
+                        let _ = conn.stream_reset_send(stream_id.as_u64(), error);
+                        let _ = conn.stream_stop_sending(stream_id.as_u64(), error);
+                    }
                     StreamType::BiDi => self.base_handler.add_streams(
                         stream_id.as_u64(),
                         SendMessage::new(stream_id.as_u64(), Box::new(self.events.clone())),
@@ -129,6 +336,8 @@ impl Http3ServerHandler {
                             stream_id.as_u64(),
                             Box::new(self.events.clone()),
                             None,
+                            self.base_handler.max_header_list_size(),
+                            false,
                         )),
                     ),
                     StreamType::UniDi => {
@@ -153,9 +362,14 @@ impl Http3ServerHandler {
                 ConnectionEvent::SendStreamStopSending {
                     stream_id,
                     app_error,
-                } => self
-                    .base_handler
-                    .handle_stream_stop_sending(stream_id, app_error)?,
+                } => {
+                    self.push_stream_closed(stream_id);
+                    self.base_handler
+                        .handle_stream_stop_sending(stream_id, app_error)?;
+                }
+                ConnectionEvent::SendStreamComplete { stream_id } => {
+                    self.push_stream_closed(stream_id);
+                }
                 ConnectionEvent::StateChange(state) => {
                     if self.base_handler.handle_state_change(conn, &state)? {
                         if self.base_handler.state() == Http3State::Connected {
@@ -170,8 +384,19 @@ impl Http3ServerHandler {
                     return Err(Error::HttpInternal)
                 }
                 ConnectionEvent::SendStreamWritable { .. }
-                | ConnectionEvent::SendStreamComplete { .. }
-                | ConnectionEvent::SendStreamCreatable { .. } => {}
+                | ConnectionEvent::SendStreamCreatable { .. }
+                | ConnectionEvent::HandshakeCompleted
+                | ConnectionEvent::KeyUpdated { .. }
+                | ConnectionEvent::Datagram { .. }
+                | ConnectionEvent::DatagramOutcome { .. }
+                | ConnectionEvent::StreamDataLost { .. }
+                | ConnectionEvent::RecvStreamFin { .. }
+                | ConnectionEvent::RecvStreamGap { .. }
+                | ConnectionEvent::SendStreamBlocked { .. }
+                | ConnectionEvent::PathMigrated { .. }
+                | ConnectionEvent::ConnectionClosed { .. }
+                | ConnectionEvent::ResumptionToken { .. }
+                | ConnectionEvent::NewToken { .. } => {}
             }
         }
         Ok(())
@@ -183,15 +408,19 @@ impl Http3ServerHandler {
             HandleReadableOutput::ControlFrames(control_frames) => {
                 for f in control_frames {
                     match f {
-                        HFrame::MaxPushId { .. } => {
-                            // TODO implement push
-                            Ok(())
-                        }
-                        HFrame::Goaway { .. } | HFrame::CancelPush { .. } => {
-                            Err(Error::HttpFrameUnexpected)
-                        }
+                        HFrame::MaxPushId { push_id } => self.handle_max_push_id(push_id),
+                        HFrame::CancelPush { push_id } => self.handle_cancel_push(conn, push_id),
+                        HFrame::Goaway { .. } => Err(Error::HttpFrameUnexpected),
+                        HFrame::PriorityUpdateRequest {
+                            element_id,
+                            priority,
+                        } => self.handle_priority_update_request(conn, element_id, priority),
+                        HFrame::PriorityUpdatePush {
+                            element_id,
+                            priority,
+                        } => self.handle_priority_update_push(conn, element_id, priority),
                         _ => unreachable!(
-                            "we should only put MaxPushId and Goaway into control_frames."
+                            "only MaxPushId, Goaway and PriorityUpdate go into control_frames."
                         ),
                     }?;
                 }
@@ -201,6 +430,85 @@ impl Http3ServerHandler {
         }
     }
 
+    /// Forget the push that `stream_id` carries, if any, once nothing more is sent on it.
+    fn push_stream_closed(&mut self, stream_id: u64) {
+        self.push_streams.retain(|_, s| *s != stream_id);
+    }
+
+    #[cfg(test)]
+    pub(crate) fn push_stream_count(&self) -> usize {
+        self.push_streams.len()
+    }
+
+    fn push_id_allowed(&self, push_id: u64) -> bool {
+        matches!(self.max_push_id, Some(max) if push_id <= max)
+    }
+
+    fn handle_max_push_id(&mut self, push_id: u64) -> Res<()> {
+        qdebug!([self], "MAX_PUSH_ID {}.", push_id);
+        if matches!(self.max_push_id, Some(max) if push_id < max) {
+            return Err(Error::HttpId);
+        }
+        self.max_push_id = Some(push_id);
+        Ok(())
+    }
+
+    fn handle_cancel_push(&mut self, conn: &mut Connection, push_id: u64) -> Res<()> {
+        qdebug!([self], "CANCEL_PUSH {}.", push_id);
+        if !self.push_id_allowed(push_id) {
+            return Err(Error::HttpId);
+        }
+        if let Some(stream_id) = self.push_streams.remove(&push_id) {
+            // The response may have been sent already, so ignore an error.
+            let _ =
+                self.base_handler
+                    .stream_reset(conn, stream_id, Error::HttpRequestCancelled.code());
+        }
+        Ok(())
+    }
+
+    fn handle_priority_update_request(
+        &mut self,
+        conn: &mut Connection,
+        stream_id: u64,
+        priority: Priority,
+    ) -> Res<()> {
+        qdebug!(
+            [self],
+            "PRIORITY_UPDATE for stream {}: {:?}.",
+            stream_id,
+            priority
+        );
+        let id = StreamId::new(stream_id);
+        if !id.is_client_initiated() || !id.is_bidi() {
+            return Err(Error::HttpId);
+        }
+        // The response might be complete or not started yet, so ignore an error.
+        let _ = conn.stream_priority(stream_id, priority.urgency(), priority.incremental());
+        Ok(())
+    }
+
+    fn handle_priority_update_push(
+        &mut self,
+        conn: &mut Connection,
+        push_id: u64,
+        priority: Priority,
+    ) -> Res<()> {
+        qdebug!(
+            [self],
+            "PRIORITY_UPDATE for push {}: {:?}.",
+            push_id,
+            priority
+        );
+        if !self.push_id_allowed(push_id) {
+            return Err(Error::HttpId);
+        }
+        if let Some(stream_id) = self.push_streams.get(&push_id) {
+            let _ = conn.stream_priority(*stream_id, priority.urgency(), priority.incremental());
+        }
+        Ok(())
+    }
+
     /// Response data are read directly into a buffer supplied as a parameter of this function to avoid copying
     /// data.
     /// # Errors
@@ -227,6 +535,10 @@ impl Http3ServerHandler {
                         }
                         Ok((amount, fin))
                     }
+                    Err(Error::HttpExcessiveLoad) => {
+                        self.base_handler.reset_excessive_load(conn, stream_id);
+                        Err(Error::HttpExcessiveLoad)
+                    }
                     Err(e) => {
                         self.close(conn, now, &e);
                         Err(e)
@@ -4,6 +4,7 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use crate::priority::Priority;
 use crate::settings::HSettings;
 use neqo_common::{
     hex_with_len, qtrace, Decoder, Encoder, IncrementalDecoderBuffer, IncrementalDecoderIgnore,
@@ -24,8 +25,18 @@ const H3_FRAME_TYPE_SETTINGS: HFrameType = 0x4;
 const H3_FRAME_TYPE_PUSH_PROMISE: HFrameType = 0x5;
 const H3_FRAME_TYPE_GOAWAY: HFrameType = 0x7;
 const H3_FRAME_TYPE_MAX_PUSH_ID: HFrameType = 0xd;
-
-const MAX_READ_SIZE: usize = 4096;
+const H3_FRAME_TYPE_PRIORITY_UPDATE_REQUEST: HFrameType = 0xf0700;
+const H3_FRAME_TYPE_PRIORITY_UPDATE_PUSH: HFrameType = 0xf0701;
+
+pub(crate) const MAX_READ_SIZE: usize = 4096;
+// Huffman coding takes at most 30 bits for an octet, so a field line takes at most four times the
+// bytes that it counts for in the header list size.  The 32 bytes of overhead that are counted
+// for each field cover its prefix and length integers.
+const MAX_ENCODED_LEN_PER_LIST_SIZE: u64 = 4;
+// The header block prefix is two integers of at most 11 bytes each.
+const MAX_HEADER_BLOCK_PREFIX_LEN: u64 = 22;
+// A PUSH_PROMISE frame carries a push ID as well.
+const MAX_PUSH_ID_LEN: u64 = 8;
 // data for DATA frame is not read into HFrame::Data.
 #[derive(PartialEq, Debug)]
 pub(crate) enum HFrame {
@@ -35,6 +46,11 @@ pub(crate) enum HFrame {
     Headers {
         header_block: Vec<u8>,
     },
+    /// The start of a `HEADERS` frame whose header block is left on the stream, like the data of
+    /// a DATA frame.  These are only read, by a `HFrameReader` made with `streaming_headers`.
+    HeadersStart {
+        len: u64, // length of the header block
+    },
     CancelPush {
         push_id: u64,
     },
@@ -51,18 +67,36 @@ pub(crate) enum HFrame {
     MaxPushId {
         push_id: u64,
     },
+    /// Change the priority of the request stream `element_id`.
+    PriorityUpdateRequest {
+        element_id: u64,
+        priority: Priority,
+    },
+    /// Change the priority of the push with ID `element_id`.
+    PriorityUpdatePush {
+        element_id: u64,
+        priority: Priority,
+    },
+    /// A frame of a reserved type, which the peer must ignore.  This is only sent.
+    Grease {
+        frame_type: HFrameType,
+        payload: Vec<u8>,
+    },
 }
 
 impl HFrame {
     fn get_type(&self) -> HFrameType {
         match self {
             Self::Data { .. } => H3_FRAME_TYPE_DATA,
-            Self::Headers { .. } => H3_FRAME_TYPE_HEADERS,
+            Self::Headers { .. } | Self::HeadersStart { .. } => H3_FRAME_TYPE_HEADERS,
             Self::CancelPush { .. } => H3_FRAME_TYPE_CANCEL_PUSH,
             Self::Settings { .. } => H3_FRAME_TYPE_SETTINGS,
             Self::PushPromise { .. } => H3_FRAME_TYPE_PUSH_PROMISE,
             Self::Goaway { .. } => H3_FRAME_TYPE_GOAWAY,
             Self::MaxPushId { .. } => H3_FRAME_TYPE_MAX_PUSH_ID,
+            Self::PriorityUpdateRequest { .. } => H3_FRAME_TYPE_PRIORITY_UPDATE_REQUEST,
+            Self::PriorityUpdatePush { .. } => H3_FRAME_TYPE_PRIORITY_UPDATE_PUSH,
+            Self::Grease { frame_type, .. } => *frame_type,
         }
     }
 
@@ -70,7 +104,7 @@ impl HFrame {
         enc.encode_varint(self.get_type());
 
         match self {
-            Self::Data { len } => {
+            Self::Data { len } | Self::HeadersStart { len } => {
                 // DATA frame only encode the length here.
                 enc.encode_varint(*len);
             }
@@ -103,6 +137,22 @@ impl HFrame {
                     enc_inner.encode_varint(*push_id);
                 });
             }
+            Self::PriorityUpdateRequest {
+                element_id,
+                priority,
+            }
+            | Self::PriorityUpdatePush {
+                element_id,
+                priority,
+            } => {
+                enc.encode_vvec_with(|enc_inner| {
+                    enc_inner.encode_varint(*element_id);
+                    enc_inner.encode(priority.to_string().as_bytes());
+                });
+            }
+            Self::Grease { payload, .. } => {
+                enc.encode_vvec(payload);
+            }
         }
     }
 }
@@ -121,6 +171,8 @@ pub(crate) struct HFrameReader {
     hframe_type: u64,
     hframe_len: u64,
     payload: Vec<u8>,
+    max_header_list_size: Option<u64>,
+    streaming_headers: bool,
 }
 
 impl Default for HFrameReader {
@@ -139,6 +191,30 @@ impl HFrameReader {
             hframe_type: 0,
             hframe_len: 0,
             payload: Vec::new(),
+            max_header_list_size: None,
+            streaming_headers: false,
+        }
+    }
+
+    /// A reader that rejects `HEADERS` and `PUSH_PROMISE` frames that are too long to carry a
+    /// header list of at most `max_header_list_size`, before their payload is read.  The QPACK
+    /// decoder checks the size of the decoded list as well.
+    #[must_use]
+    pub fn with_max_header_list_size(max_header_list_size: Option<u64>) -> Self {
+        Self {
+            max_header_list_size,
+            ..Self::new()
+        }
+    }
+
+    /// Like `with_max_header_list_size`, but the header block of a `HEADERS` frame is left on
+    /// the stream, so that it can be decoded as it arrives.  The frame is returned as
+    /// `HFrame::HeadersStart`.
+    #[must_use]
+    pub fn streaming_headers(max_header_list_size: Option<u64>) -> Self {
+        Self {
+            streaming_headers: true,
+            ..Self::with_max_header_list_size(max_header_list_size)
         }
     }
 
@@ -236,17 +312,26 @@ impl HFrameReader {
                         len
                     );
                     self.hframe_len = len;
+                    self.check_header_block_len()?;
                     self.state = match self.hframe_type {
                         // DATA payload are left on the quic stream and picked up separately
                         H3_FRAME_TYPE_DATA => {
                             return Ok(Some(self.get_frame()?));
                         }
 
+                        // So is the header block if headers are streamed.
+                        H3_FRAME_TYPE_HEADERS if self.streaming_headers && len > 0 => {
+                            self.reset();
+                            return Ok(Some(HFrame::HeadersStart { len }));
+                        }
+
                         // for other frames get all data before decoding.
                         H3_FRAME_TYPE_CANCEL_PUSH
                         | H3_FRAME_TYPE_SETTINGS
                         | H3_FRAME_TYPE_GOAWAY
                         | H3_FRAME_TYPE_MAX_PUSH_ID
+                        | H3_FRAME_TYPE_PRIORITY_UPDATE_REQUEST
+                        | H3_FRAME_TYPE_PRIORITY_UPDATE_PUSH
                         | H3_FRAME_TYPE_PUSH_PROMISE
                         | H3_FRAME_TYPE_HEADERS => {
                             if len == 0 {
@@ -295,6 +380,29 @@ impl HFrameReader {
         Ok(None)
     }
 
+    /// # Errors
+    /// Returns `HttpExcessiveLoad` if a `HEADERS` or `PUSH_PROMISE` frame is longer than allowed.
+    fn check_header_block_len(&self) -> Res<()> {
+        let (limit, extra) = match (self.max_header_list_size, self.hframe_type) {
+            (Some(limit), H3_FRAME_TYPE_HEADERS) => (limit, 0),
+            (Some(limit), H3_FRAME_TYPE_PUSH_PROMISE) => (limit, MAX_PUSH_ID_LEN),
+            _ => return Ok(()),
+        };
+        let limit = limit
+            .saturating_mul(MAX_ENCODED_LEN_PER_LIST_SIZE)
+            .saturating_add(MAX_HEADER_BLOCK_PREFIX_LEN + extra);
+        if self.hframe_len > limit {
+            qtrace!(
+                "HFrameReader::receive: frame type {} length {} exceeds {}",
+                self.hframe_type,
+                self.hframe_len,
+                limit
+            );
+            return Err(Error::HttpExcessiveLoad);
+        }
+        Ok(())
+    }
+
     /// # Errors
     /// May return `HttpFrame` if a frame cannot be decoded.
     fn get_frame(&mut self) -> Res<HFrame> {
@@ -327,6 +435,23 @@ impl HFrameReader {
             H3_FRAME_TYPE_MAX_PUSH_ID => HFrame::MaxPushId {
                 push_id: dec.decode_varint().ok_or(Error::HttpFrame)?,
             },
+            H3_FRAME_TYPE_PRIORITY_UPDATE_REQUEST | H3_FRAME_TYPE_PRIORITY_UPDATE_PUSH => {
+                let element_id = dec.decode_varint().ok_or(Error::HttpFrame)?;
+                let value =
+                    std::str::from_utf8(dec.decode_remainder()).map_err(|_| Error::HttpFrame)?;
+                let priority = Priority::from_field_value(value);
+                if self.hframe_type == H3_FRAME_TYPE_PRIORITY_UPDATE_REQUEST {
+                    HFrame::PriorityUpdateRequest {
+                        element_id,
+                        priority,
+                    }
+                } else {
+                    HFrame::PriorityUpdatePush {
+                        element_id,
+                        priority,
+                    }
+                }
+            }
             _ => panic!("We should not be calling this function with unknown frame type!"),
         };
         self.reset();
@@ -336,7 +461,7 @@ impl HFrameReader {
 
 #[cfg(test)]
 mod tests {
-    use super::{Encoder, Error, HFrame, HFrameReader, HSettings};
+    use super::{Encoder, Error, HFrame, HFrameReader, HSettings, Priority};
     use crate::settings::{HSetting, HSettingType};
     use neqo_crypto::AuthenticationStatus;
     use neqo_transport::{Connection, StreamType};
@@ -442,6 +567,32 @@ mod tests {
         enc_dec(&f, "0d0105", 0);
     }
 
+    #[test]
+    fn test_priority_update_frame() {
+        let f = HFrame::PriorityUpdateRequest {
+            element_id: 4,
+            priority: Priority::new(1, false).unwrap(),
+        };
+        enc_dec(&f, "800f07000404753d31", 0);
+        let f = HFrame::PriorityUpdatePush {
+            element_id: 5,
+            priority: Priority::default(),
+        };
+        enc_dec(&f, "800f07010105", 0);
+    }
+
+    #[test]
+    fn test_grease_frame() {
+        // A GREASE frame is only sent; the reader discards it like any unknown frame.
+        let f = HFrame::Grease {
+            frame_type: 0x21,
+            payload: vec![0x1, 0x2],
+        };
+        let mut d = Encoder::default();
+        f.encode(&mut d);
+        assert_eq!(&d[..], &Encoder::from_hex("21020102")[..]);
+    }
+
     struct HFrameReaderTest {
         pub fr: HFrameReader,
         pub conn_c: Connection,
@@ -476,12 +627,12 @@ mod tests {
     fn test_frame_reading_with_stream_settings1() {
         let mut fr = HFrameReaderTest::new();
 
-        // Send and read settings frame 040406040804
+        // Send and read settings frame 040406040a04
         assert!(fr.process(&[0x4]).is_none());
         assert!(fr.process(&[0x4]).is_none());
         assert!(fr.process(&[0x6]).is_none());
         assert!(fr.process(&[0x4]).is_none());
-        assert!(fr.process(&[0x8]).is_none());
+        assert!(fr.process(&[0xa]).is_none());
         let frame = fr.process(&[0x4]);
 
         assert!(frame.is_some());
@@ -498,8 +649,8 @@ mod tests {
     fn test_frame_reading_with_stream_settings2() {
         let mut fr = HFrameReaderTest::new();
 
-        // Read settings frame 400406064004084100
-        for i in &[0x40, 0x04, 0x06, 0x06, 0x40, 0x04, 0x08, 0x41] {
+        // Read settings frame 4004060640040a4100
+        for i in &[0x40, 0x04, 0x06, 0x06, 0x40, 0x04, 0x0a, 0x41] {
             assert!(fr.process(&[*i]).is_none());
         }
         let frame = fr.process(&[0x0]);
@@ -553,6 +704,21 @@ mod tests {
         assert_eq!(amount, 3);
     }
 
+    #[test]
+    fn test_frame_reading_streaming_headers() {
+        let mut fr = HFrameReaderTest::new();
+        fr.fr = HFrameReader::streaming_headers(None);
+
+        // Read headers frame 01030000d9
+        let frame = fr.process(&[0x1, 0x3, 0x0, 0x0, 0xd9]).unwrap();
+        assert!(matches!(frame, HFrame::HeadersStart { len } if len == 3));
+
+        // The header block is still on the stream.
+        let mut buf = [0_u8; 100];
+        let (amount, _) = fr.conn_c.stream_recv(fr.stream_id, &mut buf).unwrap();
+        assert_eq!(&buf[..amount], &[0x0, 0x0, 0xd9]);
+    }
+
     // Test an unknown frame
     #[test]
     fn test_unknown_frame() {
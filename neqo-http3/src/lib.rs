@@ -15,6 +15,7 @@ mod connection_server;
 mod control_stream_local;
 mod control_stream_remote;
 pub mod hframe;
+mod priority;
 mod push_controller;
 mod push_stream;
 mod qlog;
@@ -37,6 +38,7 @@ pub use connection::Http3State;
 pub use connection_client::Http3Client;
 pub use connection_client::Http3Parameters;
 pub use neqo_qpack::Header;
+pub use priority::Priority;
 pub use server::Http3Server;
 pub use server_events::Http3ServerEvent;
 
@@ -58,6 +60,7 @@ pub enum Error {
     HttpRequestRejected,
     HttpRequestCancelled,
     HttpRequestIncomplete,
+    HttpMessageError,
     HttpConnect,
     HttpVersionFallback,
     QpackError(neqo_qpack::Error),
@@ -85,7 +88,7 @@ pub enum Error {
 impl Error {
     #[must_use]
     pub fn code(&self) -> AppError {
-        match self {
+        AppError(match self {
             Self::HttpNoError => 0x100,
             Self::HttpGeneralProtocol => 0x101,
             Self::HttpInternal => 0x102,
@@ -100,12 +103,13 @@ impl Error {
             Self::HttpRequestRejected => 0x10b,
             Self::HttpRequestCancelled => 0x10c,
             Self::HttpRequestIncomplete => 0x10d,
+            Self::HttpMessageError => 0x10e,
             Self::HttpConnect => 0x10f,
             Self::HttpVersionFallback => 0x110,
-            Self::QpackError(e) => e.code(),
+            Self::QpackError(e) => e.code().0,
             // These are all internal errors.
             _ => 3,
-        }
+        })
     }
 
     #[must_use]
@@ -117,7 +121,6 @@ impl Error {
             | Self::HttpClosedCriticalStream
             | Self::HttpFrameUnexpected
             | Self::HttpFrame
-            | Self::HttpExcessiveLoad
             | Self::HttpId
             | Self::HttpSettings
             | Self::HttpMissingSettings
@@ -196,6 +199,7 @@ impl From<QpackError> for Error {
         match err {
             QpackError::ClosedCriticalStream => Error::HttpClosedCriticalStream,
             QpackError::InternalError => Error::HttpInternal,
+            QpackError::HeaderListTooLarge => Error::HttpExcessiveLoad,
             e => Self::QpackError(e),
         }
     }
@@ -203,7 +207,7 @@ impl From<QpackError> for Error {
 
 impl From<AppError> for Error {
     fn from(error: AppError) -> Self {
-        match error {
+        match error.0 {
             0x100 => Self::HttpNoError,
             0x101 => Self::HttpGeneralProtocol,
             0x103 => Self::HttpStreamCreation,
@@ -217,6 +221,7 @@ impl From<AppError> for Error {
             0x10b => Self::HttpRequestRejected,
             0x10c => Self::HttpRequestCancelled,
             0x10d => Self::HttpRequestIncomplete,
+            0x10e => Self::HttpMessageError,
             0x10f => Self::HttpConnect,
             0x110 => Self::HttpVersionFallback,
             0x200 => Self::QpackError(QpackError::DecompressionFailed),
@@ -265,6 +270,8 @@ pub trait RecvStream: Debug {
 
 pub(crate) trait RecvMessageEvents: Debug {
     fn header_ready(&self, stream_id: u64, headers: Option<Vec<Header>>, fin: bool);
+    fn header_fields_ready(&self, stream_id: u64, headers: Vec<Header>);
+    fn trailers_ready(&self, stream_id: u64, headers: Vec<Header>, fin: bool);
     fn data_readable(&self, stream_id: u64);
     fn reset(&self, stream_id: u64, error: AppError);
 }
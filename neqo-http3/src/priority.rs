@@ -0,0 +1,118 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// The priority parameters of RFC 9218.
+
+use crate::{Error, Res};
+use std::fmt::{self, Display};
+
+const MAX_URGENCY: u8 = 7;
+const DEFAULT_URGENCY: u8 = 3;
+
+/// The priority of a request, as defined in RFC 9218.  Responses with lower
+/// `urgency` are sent first.  Responses of the same urgency that are
+/// `incremental` share the available capacity, all others are sent in turn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Priority {
+    urgency: u8,
+    incremental: bool,
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Self {
+            urgency: DEFAULT_URGENCY,
+            incremental: false,
+        }
+    }
+}
+
+impl Priority {
+    /// # Errors
+    /// `InvalidInput` if `urgency` is more than 7.
+    pub fn new(urgency: u8, incremental: bool) -> Res<Self> {
+        if urgency > MAX_URGENCY {
+            return Err(Error::InvalidInput);
+        }
+        Ok(Self {
+            urgency,
+            incremental,
+        })
+    }
+
+    #[must_use]
+    pub fn urgency(self) -> u8 {
+        self.urgency
+    }
+
+    #[must_use]
+    pub fn incremental(self) -> bool {
+        self.incremental
+    }
+
+    /// Parse a Priority Field Value, a structured field dictionary such as
+    /// "u=1, i".  Members that are unknown or that have invalid values are
+    /// ignored, leaving the default value in place.
+    #[must_use]
+    pub fn from_field_value(value: &str) -> Self {
+        let mut priority = Self::default();
+        for member in value.split(',') {
+            let mut kv = member.trim().splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some("u"), Some(u)) => {
+                    if let Ok(u) = u.trim().parse::<u8>() {
+                        if u <= MAX_URGENCY {
+                            priority.urgency = u;
+                        }
+                    }
+                }
+                (Some("i"), None | Some("?1")) => priority.incremental = true,
+                (Some("i"), Some("?0")) => priority.incremental = false,
+                _ => {}
+            }
+        }
+        priority
+    }
+}
+
+/// Formats as a Priority Field Value, leaving out the parameters that have default values.
+impl Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.urgency == DEFAULT_URGENCY, self.incremental) {
+            (true, false) => Ok(()),
+            (true, true) => write!(f, "i"),
+            (false, false) => write!(f, "u={}", self.urgency),
+            (false, true) => write!(f, "u={}, i", self.urgency),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Priority;
+    use crate::Error;
+
+    #[test]
+    fn field_value() {
+        let p = Priority::from_field_value("u=1, i");
+        assert_eq!(p, Priority::new(1, true).unwrap());
+        assert_eq!(p.to_string(), "u=1, i");
+        assert_eq!(Priority::from_field_value(""), Priority::default());
+        assert_eq!(Priority::default().to_string(), "");
+        assert_eq!(Priority::new(3, true).unwrap().to_string(), "i");
+        assert_eq!(Priority::new(0, false).unwrap().to_string(), "u=0");
+    }
+
+    #[test]
+    fn field_value_invalid() {
+        // Unknown members and invalid values are ignored.
+        let p = Priority::from_field_value("u=9, x=y, i=?0,u=x");
+        assert_eq!(p, Priority::default());
+        let p = Priority::from_field_value("i=?1,u=6");
+        assert_eq!(p, Priority::new(6, true).unwrap());
+        assert_eq!(Priority::new(8, false), Err(Error::InvalidInput));
+    }
+}
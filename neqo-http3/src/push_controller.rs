@@ -468,6 +468,12 @@ impl RecvMessageEvents for RecvPushEvents {
         );
     }
 
+    // Headers of pushed responses are not decoded incrementally.
+    fn header_fields_ready(&self, _stream_id: u64, _headers: Vec<Header>) {}
+
+    // Trailers of pushed responses are not reported.
+    fn trailers_ready(&self, _stream_id: u64, _headers: Vec<Header>, _fin: bool) {}
+
     fn data_readable(&self, _stream_id: u64) {
         self.push_handler.borrow_mut().new_stream_event(
             self.push_id,
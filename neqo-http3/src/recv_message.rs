@@ -4,7 +4,7 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use crate::hframe::{HFrame, HFrameReader};
+use crate::hframe::{HFrame, HFrameReader, MAX_READ_SIZE};
 use crate::push_controller::PushController;
 use crate::qlog;
 use crate::RecvMessageEvents;
@@ -12,7 +12,7 @@ use crate::RecvStream;
 use crate::{Error, Header, Res};
 
 use neqo_common::{qdebug, qinfo, qtrace};
-use neqo_qpack::decoder::QPackDecoder;
+use neqo_qpack::decoder::{PartialHeaderBlock, QPackDecoder};
 use neqo_transport::{AppError, Connection};
 use std::cell::RefCell;
 use std::cmp::min;
@@ -27,12 +27,19 @@ use std::rc::Rc;
  *                                also get a PUSH_PROMISE frame.
  *    DecodingHeaders : In this step the headers will be decoded. The stream
  *                      may be blocked in this state on encoder instructions.
+ *    ReadingHeaders : Instead of DecodingHeaders if headers are streamed. The
+ *                     header block is decoded as it is read from the stream
+ *                     and each part of the headers is reported right away.
  *    WaitingForData : we got HEADERS, we are waiting for one or more data
  *                     frames. In this state we can receive one or more
  *                     PUSH_PROMIS frames or a HEADERS frame carrying trailers.
  *    ReadingData : we got a DATA frame, now we letting the app read payload.
  *                  From here we will go back to WaitingForData state to wait
  *                  for more data frames or to CLosed state
+ *    DecodingTrailers : In this step the trailers will be decoded. Like
+ *                       DecodingHeaders, this may be blocked.
+ *    WaitingForFinAfterTrailers : we got trailers, only the end of the stream
+ *                                 may follow.
  *    ClosePending : waiting for app to pick up data, after that we can delete
  * the TransactionClient.
  *    Closed
@@ -41,8 +48,10 @@ use std::rc::Rc;
 enum RecvMessageState {
     WaitingForResponseHeaders { frame_reader: HFrameReader },
     DecodingHeaders { header_block: Vec<u8>, fin: bool },
+    ReadingHeaders { header_block: PartialHeaderBlock },
     WaitingForData { frame_reader: HFrameReader },
     ReadingData { remaining_data_len: usize },
+    DecodingTrailers { header_block: Vec<u8>, fin: bool },
     WaitingForFinAfterTrailers { frame_reader: HFrameReader },
     ClosePending, // Close must first be read by application
     Closed,
@@ -61,6 +70,7 @@ pub(crate) struct RecvMessage {
     push_handler: Option<Rc<RefCell<PushController>>>,
     stream_id: u64,
     blocked_push_promise: VecDeque<PushInfo>,
+    max_header_list_size: Option<u64>,
 }
 
 impl ::std::fmt::Display for RecvMessage {
@@ -74,15 +84,21 @@ impl RecvMessage {
         stream_id: u64,
         conn_events: Box<dyn RecvMessageEvents>,
         push_handler: Option<Rc<RefCell<PushController>>>,
+        max_header_list_size: Option<u64>,
+        streaming_headers: bool,
     ) -> Self {
+        let frame_reader = if streaming_headers {
+            HFrameReader::streaming_headers(max_header_list_size)
+        } else {
+            HFrameReader::with_max_header_list_size(max_header_list_size)
+        };
         Self {
-            state: RecvMessageState::WaitingForResponseHeaders {
-                frame_reader: HFrameReader::new(),
-            },
+            state: RecvMessageState::WaitingForResponseHeaders { frame_reader },
             conn_events,
             push_handler,
             stream_id,
             blocked_push_promise: VecDeque::new(),
+            max_header_list_size,
         }
     }
 
@@ -101,8 +117,11 @@ impl RecvMessage {
                 }
              }
             RecvMessageState::WaitingForData { ..} => {
-                // TODO implement trailers, for now just ignore them.
-                self.state = RecvMessageState::WaitingForFinAfterTrailers{frame_reader: HFrameReader::new()};
+                if header_block.is_empty() {
+                    self.add_trailers(Vec::new(), fin, decoder);
+                } else {
+                    self.state = RecvMessageState::DecodingTrailers { header_block, fin };
+                }
             }
             RecvMessageState::WaitingForFinAfterTrailers {..} => {
                 return Err(Error::HttpFrameUnexpected);
@@ -112,6 +131,20 @@ impl RecvMessage {
         Ok(())
     }
 
+    fn handle_headers_start(&mut self, len: u64, fin: bool) -> Res<()> {
+        debug_assert!(matches!(
+            self.state,
+            RecvMessageState::WaitingForResponseHeaders { .. }
+        ));
+        if fin {
+            return Err(Error::HttpFrame);
+        }
+        self.state = RecvMessageState::ReadingHeaders {
+            header_block: PartialHeaderBlock::new(usize::try_from(len).or(Err(Error::HttpFrame))?),
+        };
+        Ok(())
+    }
+
     fn handle_data_frame(&mut self, len: u64, fin: bool) -> Res<()> {
         match self.state {
             RecvMessageState::WaitingForResponseHeaders {..} | RecvMessageState::WaitingForFinAfterTrailers {..} => {
@@ -140,7 +173,19 @@ impl RecvMessage {
             self.conn_events
                 .header_ready(self.stream_id, headers, false);
             self.state = RecvMessageState::WaitingForData {
-                frame_reader: HFrameReader::new(),
+                frame_reader: HFrameReader::with_max_header_list_size(self.max_header_list_size),
+            };
+        }
+    }
+
+    fn add_trailers(&mut self, headers: Vec<Header>, fin: bool, decoder: &mut QPackDecoder) {
+        self.conn_events
+            .trailers_ready(self.stream_id, headers, fin);
+        if fin {
+            self.set_closed(decoder);
+        } else {
+            self.state = RecvMessageState::WaitingForFinAfterTrailers {
+                frame_reader: HFrameReader::with_max_header_list_size(self.max_header_list_size),
             };
         }
     }
@@ -232,6 +277,9 @@ impl RecvMessage {
                                 HFrame::Headers { header_block } => {
                                     self.handle_headers_frame(header_block, fin, decoder)?
                                 }
+                                HFrame::HeadersStart { len } => {
+                                    self.handle_headers_start(len, fin)?;
+                                }
                                 HFrame::Data { len } => self.handle_data_frame(len, fin)?,
                                 HFrame::PushPromise {
                                     push_id,
@@ -242,7 +290,13 @@ impl RecvMessage {
                             if matches!(self.state, RecvMessageState::Closed) {
                                 break Ok(());
                             }
-                            if fin && !matches!(self.state, RecvMessageState::DecodingHeaders{..}) {
+                            if fin
+                                && !matches!(
+                                    self.state,
+                                    RecvMessageState::DecodingHeaders { .. }
+                                        | RecvMessageState::DecodingTrailers { .. }
+                                )
+                            {
                                 self.set_state_to_close_pending(decoder);
                                 break Ok(());
                             }
@@ -252,6 +306,10 @@ impl RecvMessage {
                 RecvMessageState::DecodingHeaders {
                     ref header_block,
                     fin,
+                }
+                | RecvMessageState::DecodingTrailers {
+                    ref header_block,
+                    fin,
                 } => {
                     if decoder.refers_dynamic_table(header_block)?
                         && !self.blocked_push_promise.is_empty()
@@ -266,7 +324,11 @@ impl RecvMessage {
                     if let Some(headers) =
                         decoder.decode_header_block(header_block, self.stream_id)?
                     {
-                        self.add_headers(Some(headers), done, decoder);
+                        if matches!(self.state, RecvMessageState::DecodingTrailers { .. }) {
+                            self.add_trailers(headers, done, decoder);
+                        } else {
+                            self.add_headers(Some(headers), done, decoder);
+                        }
                         if done {
                             break Ok(());
                         }
@@ -275,6 +337,11 @@ impl RecvMessage {
                         break Ok(());
                     }
                 }
+                RecvMessageState::ReadingHeaders { .. } => {
+                    if !self.read_headers(conn, decoder)? {
+                        break Ok(());
+                    }
+                }
                 RecvMessageState::ReadingData { .. } => {
                     if post_readable_event {
                         self.conn_events.data_readable(self.stream_id);
@@ -288,6 +355,53 @@ impl RecvMessage {
         }
     }
 
+    /// Read and decode more of a streamed header block.  Returns true if there might be more to
+    /// do, either because some of the header block was read or because all of it is decoded.
+    fn read_headers(&mut self, conn: &mut Connection, decoder: &mut QPackDecoder) -> Res<bool> {
+        if let RecvMessageState::ReadingHeaders { header_block } = &mut self.state {
+            // If decoding is blocked, the end of the stream is found when reading the next frame.
+            let mut fin = false;
+            let mut read = false;
+            if header_block.remaining() > 0 {
+                let mut buf = vec![0; min(header_block.remaining(), MAX_READ_SIZE)];
+                let (amount, f) = conn
+                    .stream_recv(self.stream_id, &mut buf)
+                    .map_err(|e| Error::map_stream_recv_errors(&e))?;
+                header_block.add(&buf[..amount]);
+                if f && header_block.remaining() > 0 {
+                    return Err(Error::HttpFrame);
+                }
+                fin = f;
+                read = amount > 0;
+            }
+            // Keep reading the header block while decoding it is blocked.
+            if !self.blocked_push_promise.is_empty()
+                && header_block.refers_dynamic_table() != Some(false)
+            {
+                qinfo!(
+                    [self],
+                    "decoding header is blocked waiting for a push_promise header block."
+                );
+            } else if let Some(headers) =
+                decoder.decode_header_fields(header_block, self.stream_id)?
+            {
+                if !headers.is_empty() {
+                    self.conn_events
+                        .header_fields_ready(self.stream_id, headers);
+                }
+                if header_block.done() {
+                    self.add_headers(None, fin, decoder);
+                    return Ok(!fin);
+                }
+            } else {
+                qinfo!([self], "decoding header is blocked.");
+            }
+            Ok(read)
+        } else {
+            unreachable!("This function is only called in ReadingHeaders state.")
+        }
+    }
+
     fn set_closed(&mut self, decoder: &mut QPackDecoder) {
         if !self.blocked_push_promise.is_empty() {
             decoder.cancel_stream(self.stream_id);
@@ -373,7 +487,9 @@ impl RecvStream for RecvMessage {
                         break Ok((written, fin));
                     } else if *remaining_data_len == 0 {
                         self.state = RecvMessageState::WaitingForData {
-                            frame_reader: HFrameReader::new(),
+                            frame_reader: HFrameReader::with_max_header_list_size(
+                                self.max_header_list_size,
+                            ),
                         };
                         self.receive_internal(conn, decoder, false)?;
                     } else {
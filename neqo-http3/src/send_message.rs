@@ -40,6 +40,9 @@ pub(crate) trait SendMessageEvents: Debug {
  *                     has already closed the send stream).
  *    SendingData : We are sending request data until the app closes the stream.
  *    Closed
+ *
+ *  Trailers set with send_trailers() are encoded once everything before them has been sent, and
+ *  are then sent like the initial message, in the SendingInitialMessage state with fin set.
  */
 
 #[derive(PartialEq, Debug)]
@@ -80,6 +83,10 @@ impl SendMessageState {
 pub(crate) struct SendMessage {
     state: SendMessageState,
     stream_id: u64,
+    // Encoded PUSH_PROMISE frames that are sent before the message.
+    push_promises: Vec<u8>,
+    // Trailers that are sent after the message body, which end the message.
+    trailers: Option<Vec<Header>>,
     conn_events: Box<dyn SendMessageEvents>,
 }
 
@@ -89,6 +96,8 @@ impl SendMessage {
         Self {
             state: SendMessageState::Uninitialized,
             stream_id,
+            push_promises: Vec::new(),
+            trailers: None,
             conn_events,
         }
     }
@@ -106,6 +115,8 @@ impl SendMessage {
                 fin: false,
             },
             stream_id,
+            push_promises: Vec::new(),
+            trailers: None,
             conn_events,
         }
     }
@@ -127,6 +138,74 @@ impl SendMessage {
         Ok(())
     }
 
+    /// Set headers without ending the message.  The body is sent afterwards with `send_body`.
+    /// # Errors
+    /// `AlreadyInitialized` if a message has been set already.
+    pub fn set_headers(&mut self, headers: &[Header]) -> Res<()> {
+        if !matches!(self.state, SendMessageState::Uninitialized) {
+            return Err(Error::AlreadyInitialized);
+        }
+
+        self.state = SendMessageState::Initialized {
+            headers: headers.to_vec(),
+            data: None,
+            fin: false,
+        };
+        Ok(())
+    }
+
+    /// Whether a `PUSH_PROMISE` frame can still be sent before the end of the message headers.
+    pub fn push_promise_allowed(&self) -> bool {
+        !matches!(
+            self.state,
+            SendMessageState::SendingData | SendMessageState::Closed
+        )
+    }
+
+    /// Queue a `PUSH_PROMISE` frame.  It is sent ahead of the message if the message has not
+    /// been encoded yet, otherwise after the part of the message that is already queued.
+    /// # Errors
+    /// `AlreadyClosed` if the message has been sent already.
+    pub fn push_promise(&mut self, push_id: u64, header_block: &[u8]) -> Res<()> {
+        let mut enc = Encoder::default();
+        HFrame::PushPromise {
+            push_id,
+            header_block: header_block.to_vec(),
+        }
+        .encode(&mut enc);
+        match &mut self.state {
+            SendMessageState::Uninitialized | SendMessageState::Initialized { .. } => {
+                self.push_promises.append(&mut enc.into());
+            }
+            SendMessageState::SendingInitialMessage { buf, .. } => buf.append(&mut enc.into()),
+            SendMessageState::SendingData | SendMessageState::Closed => {
+                return Err(Error::AlreadyClosed)
+            }
+        }
+        Ok(())
+    }
+
+    /// Set trailers that are sent after the message body.  This ends the message, so no more
+    /// body can be sent afterwards.
+    /// # Errors
+    /// `InvalidState` if no headers have been set yet, or `AlreadyClosed` if the message has
+    /// been ended already.
+    pub fn send_trailers(&mut self, headers: &[Header]) -> Res<()> {
+        if self.trailers.is_some() {
+            return Err(Error::AlreadyClosed);
+        }
+        match &mut self.state {
+            SendMessageState::Uninitialized => return Err(Error::InvalidState),
+            SendMessageState::Initialized { fin, .. }
+            | SendMessageState::SendingInitialMessage { fin, .. } => *fin = true,
+            SendMessageState::SendingData => {}
+            SendMessageState::Closed => return Err(Error::AlreadyClosed),
+        }
+        self.trailers = Some(headers.to_vec());
+        self.conn_events.remove_send_side_event(self.stream_id);
+        Ok(())
+    }
+
     pub fn send_body(&mut self, conn: &mut Connection, buf: &[u8]) -> Res<usize> {
         qtrace!(
             [self],
@@ -134,6 +213,9 @@ impl SendMessage {
             self.state,
             buf.len()
         );
+        if self.trailers.is_some() {
+            return Err(Error::AlreadyClosed);
+        }
         match self.state {
             SendMessageState::Uninitialized
             | SendMessageState::Initialized { .. }
@@ -225,6 +307,34 @@ impl SendMessage {
         Ok(())
     }
 
+    /// Encode the trailers once everything before them has been sent.
+    /// # Errors
+    /// `ClosedCriticalStream` if the encoder stream is closed.
+    /// `InternalError` if an unexpected error occurred.
+    fn ensure_trailers_encoded(
+        &mut self,
+        conn: &mut Connection,
+        encoder: &mut QPackEncoder,
+    ) -> Res<()> {
+        if !matches!(self.state, SendMessageState::SendingData) {
+            return Ok(());
+        }
+        if let Some(trailers) = self.trailers.take() {
+            qdebug!([self], "Encoding trailers");
+            let header_block = encoder.encode_header_block(conn, &trailers, self.stream_id)?;
+            let mut d = Encoder::default();
+            HFrame::Headers {
+                header_block: header_block.to_vec(),
+            }
+            .encode(&mut d);
+            self.state = SendMessageState::SendingInitialMessage {
+                buf: d.into(),
+                fin: true,
+            };
+        }
+        Ok(())
+    }
+
     /// # Errors
     /// `ClosedCriticalStream` if the encoder stream is closed.
     /// `InternalError` if an unexpected error occurred.
@@ -233,7 +343,18 @@ impl SendMessage {
     /// `TransportStreamDoesNotExist` if the transport stream does not exist (this may happen if `process_output`
     /// has not been called when needed, and HTTP3 layer has not picked up the info that the stream has been closed.)
     pub fn send(&mut self, conn: &mut Connection, encoder: &mut QPackEncoder) -> Res<()> {
+        if !self.push_promises.is_empty() {
+            let sent = conn
+                .stream_send(self.stream_id, &self.push_promises)
+                .map_err(|_| Error::map_send_errors())?;
+            qlog::h3_data_moved_down(&mut conn.qlog_mut(), self.stream_id, sent);
+            self.push_promises.drain(..sent);
+            if !self.push_promises.is_empty() {
+                return Ok(());
+            }
+        }
         self.ensure_encoded(conn, encoder)?;
+        self.ensure_trailers_encoded(conn, encoder)?;
 
         let label = if ::log::log_enabled!(::log::Level::Debug) {
             format!("{}", self)
@@ -250,7 +371,10 @@ impl SendMessage {
             qtrace!([label], "{} bytes sent", sent);
 
             if sent == buf.len() {
-                if fin {
+                if fin && self.trailers.is_some() {
+                    self.state = SendMessageState::SendingData;
+                    return self.send(conn, encoder);
+                } else if fin {
                     conn.stream_close_send(self.stream_id)
                         .map_err(|_| Error::map_send_errors())?;
                     self.state = SendMessageState::Closed;
@@ -272,7 +396,9 @@ impl SendMessage {
     // This method returns if they're still being sent. Request body (if any) is sent by
     // http client afterwards using `send_request_body` after receiving DataWritable event.
     pub fn has_data_to_send(&self) -> bool {
-        matches!(self.state, SendMessageState::Initialized {..} | SendMessageState::SendingInitialMessage { .. } )
+        !self.push_promises.is_empty()
+            || self.trailers.is_some()
+            || matches!(self.state, SendMessageState::Initialized {..} | SendMessageState::SendingInitialMessage { .. } )
     }
 
     pub fn close(&mut self, conn: &mut Connection) -> Res<()> {
@@ -281,6 +407,8 @@ impl SendMessage {
             | SendMessageState::Initialized { ref mut fin, .. } => {
                 *fin = true;
             }
+            // The stream is closed after the trailers have been sent.
+            SendMessageState::SendingData if self.trailers.is_some() => {}
             _ => {
                 self.state = SendMessageState::Closed;
                 conn.stream_close_send(self.stream_id)?;
@@ -292,7 +420,7 @@ impl SendMessage {
     }
 
     pub fn stop_sending(&mut self, app_err: AppError) {
-        if !self.state.is_sending_closed() {
+        if !self.state.is_sending_closed() && self.trailers.is_none() {
             self.conn_events.remove_send_side_event(self.stream_id);
             self.conn_events.stop_sending(self.stream_id, app_err);
         }
@@ -11,7 +11,7 @@ use crate::connection_server::Http3ServerHandler;
 use crate::server_connection_events::Http3ServerConnEvent;
 use crate::server_events::{ClientRequestStream, Http3ServerEvent, Http3ServerEvents};
 use crate::settings::HttpZeroRttChecker;
-use crate::Res;
+use crate::{Error, Res};
 use neqo_common::{qtrace, Datagram};
 use neqo_crypto::AntiReplay;
 use neqo_qpack::QpackSettings;
@@ -31,6 +31,8 @@ const MAX_EVENT_DATA_SIZE: usize = 1024;
 pub struct Http3Server {
     server: Server,
     qpack_settings: QpackSettings,
+    enable_connect: bool,
+    max_header_list_size: Option<u64>,
     http3_handlers: HashMap<ActiveConnectionRef, HandlerRef>,
     events: Http3ServerEvents,
 }
@@ -63,6 +65,8 @@ impl Http3Server {
                 cid_manager,
             )?,
             qpack_settings,
+            enable_connect: false,
+            max_header_list_size: None,
             http3_handlers: HashMap::new(),
             events: Http3ServerEvents::default(),
         })
@@ -72,6 +76,34 @@ impl Http3Server {
         self.server.set_qlog_dir(dir)
     }
 
+    /// Enable Extended CONNECT (RFC 9220) for new connections.  Clients can then send CONNECT
+    /// requests with a `:protocol` pseudo-header, which `ClientRequestStream::accept_connect`
+    /// turns into a tunnel.
+    pub fn set_enable_connect(&mut self, enable: bool) {
+        self.enable_connect = enable;
+    }
+
+    /// Limit the size of the header lists that clients can send on new connections.  Requests
+    /// with larger header lists are reset with `H3_EXCESSIVE_LOAD`.
+    pub fn set_max_header_list_size(&mut self, size: u64) {
+        self.max_header_list_size = Some(size);
+    }
+
+    /// Send a `GOAWAY` on `conn`, so that the client doesn't make new requests.  Requests on
+    /// `stream_id` and later streams are rejected; requests on earlier streams can still
+    /// complete, so passing the ID after the last request received lets all of those finish.
+    /// # Errors
+    /// `InvalidState` if `conn` is not an HTTP/3 connection of this server, `InvalidStreamId`
+    /// if `stream_id` is not a client-initiated bidirectional stream, and `InvalidInput` if it
+    /// is more than the stream ID in a previous `GOAWAY`.
+    pub fn goaway(&mut self, conn: &ActiveConnectionRef, stream_id: u64) -> Res<()> {
+        self.http3_handlers
+            .get(conn)
+            .ok_or(Error::InvalidState)?
+            .borrow_mut()
+            .goaway(stream_id)
+    }
+
     pub fn process(&mut self, dgram: Option<Datagram>, now: Instant) -> Output {
         qtrace!([self], "Process.");
         let out = self.server.process(dgram, now);
@@ -112,11 +144,14 @@ impl Http3Server {
             .iter()
             .for_each(|conn| self.server.add_to_waiting(conn.clone()));
         let qpack_settings = self.qpack_settings;
+        let enable_connect = self.enable_connect;
+        let max_header_list_size = self.max_header_list_size;
         for mut conn in active_conns {
-            let handler = self
-                .http3_handlers
-                .entry(conn.clone())
-                .or_insert_with(|| Rc::new(RefCell::new(Http3ServerHandler::new(qpack_settings))));
+            let handler = self.http3_handlers.entry(conn.clone()).or_insert_with(|| {
+                let mut handler = Http3ServerHandler::new(qpack_settings, enable_connect);
+                handler.set_max_header_list_size(max_header_list_size);
+                Rc::new(RefCell::new(handler))
+            });
 
             handler
                 .borrow_mut()
@@ -130,11 +165,33 @@ impl Http3Server {
                             stream_id,
                             headers,
                             fin,
-                        } => self.events.headers(
-                            ClientRequestStream::new(conn.clone(), handler.clone(), stream_id),
+                        } => {
+                            if let Some(h) = &headers {
+                                if !handler_borrowed.check_request_headers(
+                                    &mut conn.borrow_mut(),
+                                    stream_id,
+                                    h,
+                                ) {
+                                    continue;
+                                }
+                            }
+                            self.events.headers(
+                                ClientRequestStream::new(conn.clone(), handler.clone(), stream_id),
+                                headers,
+                                fin,
+                            );
+                        }
+                        Http3ServerConnEvent::Trailers {
+                            stream_id,
                             headers,
                             fin,
-                        ),
+                        } => {
+                            self.events.trailers(
+                                ClientRequestStream::new(conn.clone(), handler.clone(), stream_id),
+                                headers,
+                                fin,
+                            );
+                        }
                         Http3ServerConnEvent::DataReadable { stream_id } => {
                             prepare_data(
                                 stream_id,
@@ -216,8 +273,10 @@ fn prepare_data(
 
 #[cfg(test)]
 mod tests {
-    use super::{Http3Server, Http3ServerEvent, Http3State, Rc, RefCell};
+    use super::{ClientRequestStream, Http3Server, Http3ServerEvent, Http3State, Rc, RefCell, Res};
+    use crate::hframe::HFrame;
     use crate::{Error, Header};
+    use neqo_common::Encoder;
     use neqo_crypto::AuthenticationStatus;
     use neqo_qpack::encoder::QPackEncoder;
     use neqo_qpack::QpackSettings;
@@ -226,6 +285,8 @@ mod tests {
         ZeroRttState,
     };
     use std::ops::{Deref, DerefMut};
+    use std::slice;
+    use std::time::Duration;
     use test_fixture::{
         anti_replay, default_client, fixture_init, now, DEFAULT_ALPN, DEFAULT_KEYS,
     };
@@ -375,7 +436,7 @@ mod tests {
                     );
                 }
                 ConnectionEvent::StateChange(State::Connected) => connected = true,
-                ConnectionEvent::StateChange(_) => (),
+                ConnectionEvent::StateChange(_) | ConnectionEvent::HandshakeCompleted => (),
                 _ => panic!("unexpected event"),
             }
         }
@@ -486,6 +547,18 @@ mod tests {
         assert_closed(&mut hconn, &Error::HttpMissingSettings);
     }
 
+    // Server: SETTINGS_ENABLE_CONNECT_PROTOCOL can only be 0 or 1.
+    #[test]
+    fn test_server_enable_connect_setting_invalid() {
+        let (mut hconn, mut neqo_trans_conn) = connect_and_receive_settings();
+        let control_stream = neqo_trans_conn.stream_create(StreamType::UniDi).unwrap();
+        let sent = neqo_trans_conn.stream_send(control_stream, &[0x0, 0x4, 0x2, 0x8, 0x2]);
+        assert_eq!(sent, Ok(5));
+        let out = neqo_trans_conn.process(None, now());
+        hconn.process(out.dgram(), now());
+        assert_closed(&mut hconn, &Error::HttpSettings);
+    }
+
     // Server: receiving SETTINGS frame twice causes connection close
     // with error HTTP_UNEXPECTED_FRAME.
     #[test]
@@ -890,6 +963,245 @@ mod tests {
         assert_eq!(stop_sending, 1);
     }
 
+    // Send the headers of a request and return the request on the server.
+    fn push_request(
+        hconn: &mut Http3Server,
+        peer_conn: &mut PeerConnection,
+    ) -> ClientRequestStream {
+        let stream_id = peer_conn.stream_create(StreamType::BiDi).unwrap();
+        peer_conn
+            .stream_send(stream_id, &REQUEST_WITH_BODY[..20])
+            .unwrap();
+        let out = peer_conn.process(None, now());
+        hconn.process(out.dgram(), now());
+        loop {
+            match hconn.next_event() {
+                Some(Http3ServerEvent::Headers { request, .. }) => return request,
+                Some(_) => {}
+                None => panic!("expected a request"),
+            }
+        }
+    }
+
+    fn push_promise(request: &mut ClientRequestStream) -> Res<u64> {
+        request.push_promise(
+            &[(String::from(":path"), String::from("/pushed"))],
+            &[(String::from(":status"), String::from("200"))],
+            RESPONSE_BODY,
+        )
+    }
+
+    // Server: a push cannot be promised until the client sends MAX_PUSH_ID.
+    #[test]
+    fn test_server_push_without_max_push_id() {
+        let (mut hconn, mut peer_conn) = connect();
+        let mut request = push_request(&mut hconn, &mut peer_conn);
+        assert_eq!(push_promise(&mut request), Err(Error::Unavailable));
+    }
+
+    // Server: reducing MAX_PUSH_ID closes the connection with HTTP_ID_ERROR.
+    #[test]
+    fn test_server_max_push_id_reduced() {
+        let (mut hconn, mut peer_conn) = connect();
+        peer_conn.control_send(&[0xd, 0x1, 0x5]);
+        peer_conn.control_send(&[0xd, 0x1, 0x3]);
+        let out = peer_conn.process(None, now());
+        hconn.process(out.dgram(), now());
+        assert_closed(&mut hconn, &Error::HttpId);
+    }
+
+    // Server: PRIORITY_UPDATE must reference a request stream, but that stream
+    // doesn't need to exist.
+    #[test]
+    fn test_server_priority_update() {
+        let (mut hconn, mut peer_conn) = connect();
+        peer_conn.control_send(&[0x80, 0x0f, 0x07, 0x00, 0x04, 0x08, 0x75, 0x3d, 0x31]);
+        let out = peer_conn.process(None, now());
+        hconn.process(out.dgram(), now());
+        assert_not_closed(&mut hconn);
+
+        peer_conn.control_send(&[0x80, 0x0f, 0x07, 0x00, 0x01, 0x02]);
+        let out = peer_conn.process(None, now());
+        hconn.process(out.dgram(), now());
+        assert_closed(&mut hconn, &Error::HttpId);
+    }
+
+    // Server: a CANCEL_PUSH from the client resets the push stream.
+    #[test]
+    fn test_server_push_canceled() {
+        const PUSH_STREAM_ID: u64 = 15;
+
+        let (mut hconn, mut peer_conn) = connect();
+        peer_conn.control_send(&[0xd, 0x1, 0x0]);
+        let mut request = push_request(&mut hconn, &mut peer_conn);
+        assert_eq!(push_promise(&mut request), Ok(0));
+        assert_eq!(push_promise(&mut request), Err(Error::Unavailable));
+
+        // Cancel the push before the server has sent the response.
+        peer_conn.control_send(&[0x3, 0x1, 0x0]);
+        let out = peer_conn.process(None, now());
+        let out = hconn.process(out.dgram(), now());
+        let _ = peer_conn.process(out.dgram(), now());
+        let out = hconn.process(None, now());
+        let _ = peer_conn.process(out.dgram(), now());
+
+        let reset = |e| {
+            matches!(e, ConnectionEvent::RecvStreamReset { stream_id, app_error }
+                if stream_id == PUSH_STREAM_ID && app_error == Error::HttpRequestCancelled.code())
+        };
+        assert!(peer_conn.events().any(reset));
+        assert_not_closed(&mut hconn);
+    }
+
+    // Server: a push that can't be promised doesn't leave a push stream behind.
+    #[test]
+    fn test_server_push_after_response() {
+        let (mut hconn, mut peer_conn) = connect();
+        peer_conn.control_send(&[0xd, 0x1, 0x0]);
+        let mut request = push_request(&mut hconn, &mut peer_conn);
+        request
+            .set_response(&[(String::from(":status"), String::from("200"))], &[])
+            .unwrap();
+        let _ = hconn.process(None, now());
+        assert_eq!(push_promise(&mut request), Err(Error::InvalidStreamId));
+
+        let out = hconn.process(None, now());
+        let _ = peer_conn.process(out.dgram(), now());
+        let push_stream = |e| {
+            matches!(e, ConnectionEvent::NewStream { stream_id }
+                if stream_id.stream_type() == StreamType::UniDi && stream_id.as_u64() == 15)
+        };
+        assert!(!peer_conn.events().any(push_stream));
+        assert_not_closed(&mut hconn);
+    }
+
+    // Server: a push is forgotten once its stream is done.
+    #[test]
+    fn test_server_push_complete() {
+        let (mut hconn, mut peer_conn) = connect();
+        peer_conn.control_send(&[0xd, 0x1, 0x0]);
+        let mut request = push_request(&mut hconn, &mut peer_conn);
+        assert_eq!(push_promise(&mut request), Ok(0));
+        let handler = Rc::clone(hconn.http3_handlers.values().next().unwrap());
+        assert_eq!(handler.borrow().push_stream_count(), 1);
+
+        let mut out = hconn.process(None, now()).dgram();
+        while out.is_some() {
+            out = peer_conn.process(out, now()).dgram();
+            out = hconn.process(out, now()).dgram();
+        }
+        // The push stream is done once the client acknowledges all of it.
+        let out = peer_conn.process(None, now() + Duration::from_millis(100));
+        let _ = hconn.process(out.dgram(), now());
+        assert_eq!(handler.borrow().push_stream_count(), 0);
+        assert_not_closed(&mut hconn);
+    }
+
+    // Server: after a GOAWAY, requests on streams at or above its limit are rejected.
+    #[test]
+    fn test_server_goaway() {
+        let (mut hconn, mut peer_conn) = connect();
+        let conn = hconn.http3_handlers.keys().next().unwrap().clone();
+        assert_eq!(hconn.goaway(&conn, 2), Err(Error::InvalidStreamId));
+        assert_eq!(hconn.goaway(&conn, 4), Ok(()));
+        assert_eq!(hconn.goaway(&conn, 8), Err(Error::InvalidInput));
+        assert_eq!(hconn.goaway(&conn, 0), Ok(()));
+
+        let stream_id = peer_conn.stream_create(StreamType::BiDi).unwrap();
+        peer_conn.stream_send(stream_id, &[0x1, 0x0]).unwrap();
+        let out = peer_conn.process(None, now());
+        hconn.process(out.dgram(), now());
+        let request = |e| matches!(e, Http3ServerEvent::Headers { .. });
+        assert!(!hconn.events().any(request));
+
+        let out = hconn.process(None, now());
+        let _ = peer_conn.process(out.dgram(), now());
+        let reset = |e| {
+            matches!(e, ConnectionEvent::RecvStreamReset { stream_id: id, app_error }
+                if id == stream_id && app_error == Error::HttpRequestRejected.code())
+        };
+        assert!(peer_conn.events().any(reset));
+        assert_not_closed(&mut hconn);
+    }
+
+    /// Send a request with `headers` and check that the server rejects it as malformed by
+    /// resetting the stream, without telling the application about it.
+    fn assert_request_rejected(headers: &[Header]) {
+        let (mut hconn, mut peer_conn) = connect();
+
+        let stream_id = peer_conn.stream_create(StreamType::BiDi).unwrap();
+        let mut encoder = QPackEncoder::new(
+            QpackSettings {
+                max_table_size_encoder: 0,
+                max_table_size_decoder: 0,
+                max_blocked_streams: 0,
+            },
+            true,
+        );
+        let header_block = encoder
+            .encode_header_block(&mut peer_conn, headers, stream_id)
+            .unwrap();
+        let mut enc = Encoder::default();
+        HFrame::Headers {
+            header_block: header_block.to_vec(),
+        }
+        .encode(&mut enc);
+        peer_conn.stream_send(stream_id, &enc).unwrap();
+        let out = peer_conn.process(None, now());
+        hconn.process(out.dgram(), now());
+        let request = |e| matches!(e, Http3ServerEvent::Headers { .. });
+        assert!(!hconn.events().any(request));
+
+        let out = hconn.process(None, now());
+        let _ = peer_conn.process(out.dgram(), now());
+        let reset = |e| {
+            matches!(e, ConnectionEvent::RecvStreamReset { stream_id: id, app_error }
+                if id == stream_id && app_error == Error::HttpMessageError.code())
+        };
+        assert!(peer_conn.events().any(reset));
+    }
+
+    // Server: a request with a `:protocol` pseudo-header is malformed unless Extended CONNECT
+    // is enabled, so the stream is reset.
+    #[test]
+    fn test_server_protocol_without_extended_connect() {
+        assert_request_rejected(&[
+            (String::from(":method"), String::from("CONNECT")),
+            (String::from(":protocol"), String::from("websocket")),
+            (String::from(":scheme"), String::from("https")),
+            (String::from(":authority"), String::from("something.com")),
+            (String::from(":path"), String::from("/chat")),
+        ]);
+    }
+
+    // Server: a CONNECT request must have `:authority` and no `:scheme` or `:path`.
+    #[test]
+    fn test_server_connect_malformed() {
+        let method = (String::from(":method"), String::from("CONNECT"));
+        let authority = (String::from(":authority"), String::from("localhost:443"));
+        assert_request_rejected(slice::from_ref(&method));
+        assert_request_rejected(&[
+            method.clone(),
+            authority.clone(),
+            (String::from(":path"), String::from("/")),
+        ]);
+        assert_request_rejected(&[
+            method,
+            (String::from(":scheme"), String::from("https")),
+            authority,
+        ]);
+    }
+
+    // Server: a CONNECT request can't have a body.
+    #[test]
+    fn test_server_connect_with_body() {
+        assert_request_rejected(&[
+            (String::from(":method"), String::from("CONNECT")),
+            (String::from(":authority"), String::from("localhost:443")),
+            (String::from("content-length"), String::from("3")),
+        ]);
+    }
+
     // Server: Test that the connection will be closed if the local control stream
     // has been reset.
     #[test]
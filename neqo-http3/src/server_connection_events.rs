@@ -23,6 +23,12 @@ pub(crate) enum Http3ServerConnEvent {
         headers: Option<Vec<Header>>,
         fin: bool,
     },
+    /// Trailers are ready.
+    Trailers {
+        stream_id: u64,
+        headers: Vec<Header>,
+        fin: bool,
+    },
     /// Request data is ready.
     DataReadable { stream_id: u64 },
     //TODO: This is never used. Do we need it?
@@ -47,6 +53,18 @@ impl RecvMessageEvents for Http3ServerConnEvents {
         });
     }
 
+    // Request headers are not decoded incrementally.
+    fn header_fields_ready(&self, _stream_id: u64, _headers: Vec<Header>) {}
+
+    /// Add a new `Trailers` event.
+    fn trailers_ready(&self, stream_id: u64, headers: Vec<Header>, fin: bool) {
+        self.insert(Http3ServerConnEvent::Trailers {
+            stream_id,
+            headers,
+            fin,
+        });
+    }
+
     /// Add a new `DataReadable` event
     fn data_readable(&self, stream_id: u64) {
         self.insert(Http3ServerConnEvent::DataReadable { stream_id });
@@ -92,7 +110,9 @@ impl Http3ServerConnEvents {
     pub fn remove_events_for_stream_id(&self, stream_id: u64) {
         self.remove(|evt| {
             matches!(evt,
-                Http3ServerConnEvent::Headers { stream_id: x, .. } | Http3ServerConnEvent::DataReadable { stream_id: x, .. } if *x == stream_id)
+                Http3ServerConnEvent::Headers { stream_id: x, .. }
+                | Http3ServerConnEvent::Trailers { stream_id: x, .. }
+                | Http3ServerConnEvent::DataReadable { stream_id: x, .. } if *x == stream_id)
         });
     }
 }
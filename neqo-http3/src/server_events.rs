@@ -56,6 +56,76 @@ impl ClientRequestStream {
             .set_response(self.stream_id, headers, data)
     }
 
+    /// Accept a CONNECT or Extended CONNECT request by sending the response `headers` without
+    /// ending the stream.  After this the stream is a tunnel: `send_data` writes to it, and `Data`
+    /// events deliver what the client sends.
+    /// # Errors
+    /// `InvalidStreamId` if the stream does not exist, or `AlreadyInitialized` if a response has
+    /// been set already.
+    pub fn accept_connect(&mut self, headers: &[Header]) -> Res<()> {
+        qinfo!([self], "Accept CONNECT.");
+        self.handler.borrow_mut().accept_connect(
+            &mut self.conn.borrow_mut(),
+            self.stream_id,
+            headers,
+        )
+    }
+
+    /// Send data on a tunnel that was established with `accept_connect`.  This returns the
+    /// number of bytes that were sent, which is 0 if the stream cannot take more data yet.
+    /// # Errors
+    /// `InvalidStreamId` if the stream does not exist, or `AlreadyClosed` if the sending side has
+    /// been closed.
+    pub fn send_data(&mut self, buf: &[u8]) -> Res<usize> {
+        self.handler
+            .borrow_mut()
+            .send_data(&mut self.conn.borrow_mut(), self.stream_id, buf)
+    }
+
+    /// Send `headers` as trailers after the response body.  This ends the response.
+    /// # Errors
+    /// `InvalidStreamId` if the stream does not exist, `InvalidState` if no response headers
+    /// have been set, or `AlreadyClosed` if the response has been ended already.
+    pub fn send_trailers(&mut self, headers: &[Header]) -> Res<()> {
+        qinfo!([self], "Send trailers.");
+        self.handler
+            .borrow_mut()
+            .send_trailers(self.stream_id, headers)
+    }
+
+    /// Close the sending side of a tunnel.
+    /// # Errors
+    /// `InvalidStreamId` if the stream does not exist.
+    pub fn stream_close_send(&mut self) -> Res<()> {
+        qdebug!([self], "close send.");
+        self.handler
+            .borrow_mut()
+            .stream_close_send(&mut self.conn.borrow_mut(), self.stream_id)
+    }
+
+    /// Promise a push of a response to the request `headers`, for example a resource that the
+    /// client will need.  The response, `response_headers` and `data`, is sent on a push stream.
+    /// This must be called before the response to this request has been sent.  The push ID is
+    /// returned.
+    /// # Errors
+    /// `InvalidStreamId` if the response has been sent already, `Unavailable` if the client does
+    /// not allow another push, or `StreamLimitError` if a push stream cannot be created.
+    pub fn push_promise(
+        &mut self,
+        headers: &[Header],
+        response_headers: &[Header],
+        data: &[u8],
+    ) -> Res<u64> {
+        qinfo!([self], "Push promise.");
+        self.handler.borrow_mut().push_promise(
+            &mut self.conn.borrow_mut(),
+            self.stream_id,
+            headers,
+            response_headers,
+            data,
+        )
+    }
+
     /// Request a peer to stop sending a request.
     pub fn stream_stop_sending(&mut self, app_error: AppError) -> Res<()> {
         qdebug!(
@@ -89,6 +159,12 @@ pub enum Http3ServerEvent {
         headers: Option<Vec<Header>>,
         fin: bool,
     },
+    /// Trailers are ready, after the request body.
+    Trailers {
+        request: ClientRequestStream,
+        headers: Vec<Header>,
+        fin: bool,
+    },
     /// Request data is ready.
     Data {
         request: ClientRequestStream,
@@ -141,6 +217,15 @@ impl Http3ServerEvents {
         });
     }
 
+    /// Insert a `Trailers` event.
+    pub(crate) fn trailers(&self, request: ClientRequestStream, headers: Vec<Header>, fin: bool) {
+        self.insert(Http3ServerEvent::Trailers {
+            request,
+            headers,
+            fin,
+        });
+    }
+
     /// Insert a `StateChange` event.
     pub(crate) fn connection_state_change(&self, conn: ActiveConnectionRef, state: Http3State) {
         self.insert(Http3ServerEvent::StateChange { conn, state });
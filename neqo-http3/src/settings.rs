@@ -19,18 +19,25 @@ const SETTINGS_ZERO_RTT_VERSION: u64 = 1;
 const SETTINGS_MAX_HEADER_LIST_SIZE: SettingsType = 0x6;
 const SETTINGS_QPACK_MAX_TABLE_CAPACITY: SettingsType = 0x1;
 const SETTINGS_QPACK_BLOCKED_STREAMS: SettingsType = 0x7;
+const SETTINGS_ENABLE_CONNECT_PROTOCOL: SettingsType = 0x8;
 
 #[derive(Clone, PartialEq, Debug, Copy)]
 pub(crate) enum HSettingType {
     MaxHeaderListSize,
     MaxTableCapacity,
     BlockedStreams,
+    EnableConnectProtocol,
+    /// A reserved setting with the given identifier, which the peer must ignore.
+    Grease(SettingsType),
 }
 
 fn hsetting_default(setting_type: HSettingType) -> u64 {
     match setting_type {
         HSettingType::MaxHeaderListSize => 1 << 62,
-        HSettingType::MaxTableCapacity | HSettingType::BlockedStreams => 0,
+        HSettingType::MaxTableCapacity
+        | HSettingType::BlockedStreams
+        | HSettingType::EnableConnectProtocol
+        | HSettingType::Grease(_) => 0,
     }
 }
 
@@ -84,6 +91,14 @@ impl HSettings {
                         enc_inner.encode_varint(SETTINGS_QPACK_BLOCKED_STREAMS as u64);
                         enc_inner.encode_varint(iter.value);
                     }
+                    HSettingType::EnableConnectProtocol => {
+                        enc_inner.encode_varint(SETTINGS_ENABLE_CONNECT_PROTOCOL as u64);
+                        enc_inner.encode_varint(iter.value);
+                    }
+                    HSettingType::Grease(setting_type) => {
+                        enc_inner.encode_varint(setting_type);
+                        enc_inner.encode_varint(iter.value);
+                    }
                 }
             }
         });
@@ -104,6 +119,9 @@ impl HSettings {
                 (Some(SETTINGS_QPACK_BLOCKED_STREAMS), Some(value)) => self
                     .settings
                     .push(HSetting::new(HSettingType::BlockedStreams, value)),
+                (Some(SETTINGS_ENABLE_CONNECT_PROTOCOL), Some(value)) => self
+                    .settings
+                    .push(HSetting::new(HSettingType::EnableConnectProtocol, value)),
                 // other supported settings here
                 (Some(_), Some(_)) => {} // ignore unknown setting, it is fine.
                 _ => return Err(Error::NotEnoughData),
@@ -166,7 +184,9 @@ impl ZeroRttChecker for HttpZeroRttChecker {
                 u64::from(self.settings.max_blocked_streams) >= setting.value
             }
             HSettingType::MaxTableCapacity => self.settings.max_table_size_decoder >= setting.value,
-            HSettingType::MaxHeaderListSize => true,
+            HSettingType::MaxHeaderListSize
+            | HSettingType::EnableConnectProtocol
+            | HSettingType::Grease(_) => true,
         }) {
             ZeroRttCheckResult::Accept
         } else {
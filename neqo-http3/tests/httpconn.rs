@@ -8,7 +8,10 @@
 
 use neqo_common::Datagram;
 use neqo_crypto::AuthenticationStatus;
-use neqo_http3::{Http3Client, Http3ClientEvent, Http3Server, Http3ServerEvent, Http3State};
+use neqo_http3::{
+    Error, Http3Client, Http3ClientEvent, Http3Server, Http3ServerEvent, Http3State, Priority,
+};
+use neqo_qpack::QpackSettings;
 use test_fixture::*;
 
 const RESPONSE_DATA: &[u8] = &[0x61, 0x62, 0x63];
@@ -79,9 +82,13 @@ fn process_client_events(conn: &mut Http3Client) {
 }
 
 fn connect() -> (Http3Client, Http3Server, Option<Datagram>) {
-    let mut hconn_c = default_http3_client();
-    let mut hconn_s = default_http3_server();
+    connect_with(default_http3_client(), default_http3_server())
+}
 
+fn connect_with(
+    mut hconn_c: Http3Client,
+    mut hconn_s: Http3Server,
+) -> (Http3Client, Http3Server, Option<Datagram>) {
     assert_eq!(hconn_c.state(), Http3State::Initializing);
     let out = hconn_c.process(None, now()); // Initial
     let out = hconn_s.process(out.dgram(), now()); // Initial + Handshake
@@ -107,10 +114,7 @@ fn test_connect() {
     let (_hconn_c, _hconn_s, _d) = connect();
 }
 
-#[test]
-fn test_fetch() {
-    let (mut hconn_c, mut hconn_s, dgram) = connect();
-
+fn fetch(mut hconn_c: Http3Client, mut hconn_s: Http3Server, dgram: Option<Datagram>) {
     eprintln!("-----client");
     let req = hconn_c
         .fetch(now(), "GET", "https", "something.com", "/", &[])
@@ -130,3 +134,606 @@ fn test_fetch() {
     let _ = hconn_c.process(out.dgram(), now());
     process_client_events(&mut hconn_c);
 }
+
+#[test]
+fn test_fetch() {
+    let (hconn_c, hconn_s, dgram) = connect();
+    fetch(hconn_c, hconn_s, dgram);
+}
+
+#[test]
+fn test_fetch_with_grease() {
+    let mut params = default_http3_parameters();
+    params.set_grease(true);
+    let (hconn_c, hconn_s, dgram) =
+        connect_with(http3_client_with_params(&params), default_http3_server());
+    fetch(hconn_c, hconn_s, dgram);
+}
+
+const PUSH_DATA: &[u8] = &[0x64, 0x65, 0x66, 0x67];
+
+fn push_request_headers() -> Vec<(String, String)> {
+    vec![
+        (String::from(":method"), String::from("GET")),
+        (String::from(":scheme"), String::from("https")),
+        (String::from(":authority"), String::from("something.com")),
+        (String::from(":path"), String::from("/style.css")),
+    ]
+}
+
+fn push_response_headers() -> Vec<(String, String)> {
+    vec![
+        (String::from(":status"), String::from("200")),
+        (String::from("content-length"), String::from("4")),
+    ]
+}
+
+fn exchange_packets(client: &mut Http3Client, server: &mut Http3Server) {
+    let mut out = client.process(None, now()).dgram();
+    loop {
+        out = server.process(out, now()).dgram();
+        out = client.process(out, now()).dgram();
+        if out.is_none() {
+            break;
+        }
+    }
+}
+
+/// Send a request and deliver it to the server.
+fn push_setup(client: &mut Http3Client, server: &mut Http3Server, dgram: Option<Datagram>) {
+    let req = client
+        .fetch(now(), "GET", "https", "something.com", "/", &[])
+        .unwrap();
+    client.stream_close_send(req).unwrap();
+    let out = client.process(dgram, now());
+    let _ = server.process(out.dgram(), now());
+}
+
+#[test]
+fn test_server_push() {
+    let (mut hconn_c, mut hconn_s, dgram) = connect();
+    push_setup(&mut hconn_c, &mut hconn_s, dgram);
+
+    let mut pushed = false;
+    while let Some(event) = hconn_s.next_event() {
+        if let Http3ServerEvent::Headers { mut request, .. } = event {
+            let push_id = request
+                .push_promise(&push_request_headers(), &push_response_headers(), PUSH_DATA)
+                .unwrap();
+            assert_eq!(push_id, 0);
+            request
+                .set_response(
+                    &[
+                        (String::from(":status"), String::from("200")),
+                        (String::from("content-length"), String::from("3")),
+                    ],
+                    RESPONSE_DATA,
+                )
+                .unwrap();
+            pushed = true;
+        }
+    }
+    assert!(pushed);
+    exchange_packets(&mut hconn_c, &mut hconn_s);
+
+    let mut promise_found = false;
+    let mut push_headers_found = false;
+    let mut push_data_found = false;
+    while let Some(event) = hconn_c.next_event() {
+        match event {
+            Http3ClientEvent::PushPromise {
+                push_id,
+                request_stream_id,
+                headers,
+            } => {
+                assert_eq!(push_id, 0);
+                assert_eq!(request_stream_id, 0);
+                assert_eq!(headers, push_request_headers());
+                promise_found = true;
+            }
+            Http3ClientEvent::PushHeaderReady {
+                push_id,
+                headers,
+                fin,
+            } => {
+                assert_eq!(push_id, 0);
+                assert_eq!(headers, Some(push_response_headers()));
+                assert!(!fin);
+                push_headers_found = true;
+            }
+            Http3ClientEvent::PushDataReadable { push_id } => {
+                let mut buf = [0u8; 100];
+                let (amount, fin) = hconn_c.push_read_data(now(), push_id, &mut buf).unwrap();
+                assert!(fin);
+                assert_eq!(&buf[..amount], PUSH_DATA);
+                push_data_found = true;
+            }
+            _ => {}
+        }
+    }
+    assert!(promise_found);
+    assert!(push_headers_found);
+    assert!(push_data_found);
+}
+
+#[test]
+fn test_server_push_limit() {
+    let (mut hconn_c, mut hconn_s, dgram) = connect();
+    push_setup(&mut hconn_c, &mut hconn_s, dgram);
+
+    let mut request = loop {
+        match hconn_s.next_event() {
+            Some(Http3ServerEvent::Headers { request, .. }) => break request,
+            Some(_) => {}
+            None => panic!("expected a request"),
+        }
+    };
+    // The client allows push IDs up to 10.
+    for i in 0..=10 {
+        assert_eq!(
+            request.push_promise(&push_request_headers(), &push_response_headers(), PUSH_DATA),
+            Ok(i)
+        );
+    }
+    assert_eq!(
+        request.push_promise(&push_request_headers(), &push_response_headers(), PUSH_DATA),
+        Err(Error::Unavailable)
+    );
+}
+
+const TUNNEL_DATA: &[u8] = &[0x01, 0x02, 0x03, 0x04, 0x05];
+
+#[test]
+fn test_extended_connect() {
+    let mut server = default_http3_server();
+    server.set_enable_connect(true);
+    let (mut hconn_c, mut hconn_s, dgram) = connect_with(default_http3_client(), server);
+    let _ = hconn_s.process(dgram, now());
+    assert!(hconn_c.extended_connect_enabled());
+
+    let stream_id = hconn_c
+        .extended_connect(now(), "websocket", "https", "something.com", "/chat", &[])
+        .unwrap();
+    exchange_packets(&mut hconn_c, &mut hconn_s);
+
+    let mut request = loop {
+        match hconn_s.next_event() {
+            Some(Http3ServerEvent::Headers {
+                request,
+                headers,
+                fin,
+            }) => {
+                assert_eq!(
+                    headers,
+                    Some(vec![
+                        (String::from(":method"), String::from("CONNECT")),
+                        (String::from(":protocol"), String::from("websocket")),
+                        (String::from(":scheme"), String::from("https")),
+                        (String::from(":authority"), String::from("something.com")),
+                        (String::from(":path"), String::from("/chat")),
+                    ])
+                );
+                assert!(!fin);
+                break request;
+            }
+            Some(_) => {}
+            None => panic!("expected a CONNECT request"),
+        }
+    };
+    request
+        .accept_connect(&[(String::from(":status"), String::from("200"))])
+        .unwrap();
+    assert_eq!(request.send_data(TUNNEL_DATA), Ok(TUNNEL_DATA.len()));
+    exchange_packets(&mut hconn_c, &mut hconn_s);
+
+    // The client gets the response headers and then the tunneled bytes.
+    let mut headers_found = false;
+    let mut data_found = false;
+    while let Some(event) = hconn_c.next_event() {
+        match event {
+            Http3ClientEvent::HeaderReady { headers, fin, .. } => {
+                assert_eq!(
+                    headers,
+                    Some(vec![(String::from(":status"), String::from("200"))])
+                );
+                assert!(!fin);
+                headers_found = true;
+            }
+            Http3ClientEvent::DataReadable { stream_id: id } => {
+                assert_eq!(id, stream_id);
+                let mut buf = [0u8; 100];
+                let (amount, fin) = hconn_c.read_response_data(now(), id, &mut buf).unwrap();
+                assert!(!fin);
+                assert_eq!(&buf[..amount], TUNNEL_DATA);
+                data_found = true;
+            }
+            _ => {}
+        }
+    }
+    assert!(headers_found);
+    assert!(data_found);
+
+    // And bytes flow the other way.
+    assert_eq!(
+        hconn_c.send_request_body(stream_id, TUNNEL_DATA),
+        Ok(TUNNEL_DATA.len())
+    );
+    hconn_c.stream_close_send(stream_id).unwrap();
+    exchange_packets(&mut hconn_c, &mut hconn_s);
+    let received = |e| {
+        matches!(e, Http3ServerEvent::Data { data, fin: true, .. } if data == TUNNEL_DATA)
+    };
+    assert!(hconn_s.events().any(received));
+}
+
+#[test]
+fn test_connect_tunnel() {
+    let (mut hconn_c, mut hconn_s, dgram) = connect();
+    let _ = hconn_s.process(dgram, now());
+
+    let stream_id = hconn_c.connect(now(), "localhost:443", &[]).unwrap();
+    exchange_packets(&mut hconn_c, &mut hconn_s);
+
+    let mut request = loop {
+        match hconn_s.next_event() {
+            Some(Http3ServerEvent::Headers {
+                request,
+                headers,
+                fin,
+            }) => {
+                assert_eq!(
+                    headers,
+                    Some(vec![
+                        (String::from(":method"), String::from("CONNECT")),
+                        (String::from(":authority"), String::from("localhost:443")),
+                    ])
+                );
+                assert!(!fin);
+                break request;
+            }
+            Some(_) => {}
+            None => panic!("expected a CONNECT request"),
+        }
+    };
+    request
+        .accept_connect(&[(String::from(":status"), String::from("200"))])
+        .unwrap();
+    assert_eq!(request.send_data(TUNNEL_DATA), Ok(TUNNEL_DATA.len()));
+    exchange_packets(&mut hconn_c, &mut hconn_s);
+
+    let readable =
+        |e| matches!(e, Http3ClientEvent::DataReadable { stream_id: id } if id == stream_id);
+    assert!(hconn_c.events().any(readable));
+    let mut buf = [0u8; 100];
+    let (amount, fin) = hconn_c
+        .read_response_data(now(), stream_id, &mut buf)
+        .unwrap();
+    assert!(!fin);
+    assert_eq!(&buf[..amount], TUNNEL_DATA);
+
+    assert_eq!(
+        hconn_c.send_request_body(stream_id, TUNNEL_DATA),
+        Ok(TUNNEL_DATA.len())
+    );
+    exchange_packets(&mut hconn_c, &mut hconn_s);
+    let received = |e| {
+        matches!(e, Http3ServerEvent::Data { data, fin: false, .. } if data == TUNNEL_DATA)
+    };
+    assert!(hconn_s.events().any(received));
+}
+
+#[test]
+fn test_extended_connect_not_enabled() {
+    let (mut hconn_c, _hconn_s, _dgram) = connect();
+    assert!(!hconn_c.extended_connect_enabled());
+    assert_eq!(
+        hconn_c.extended_connect(now(), "websocket", "https", "something.com", "/chat", &[]),
+        Err(Error::Unavailable)
+    );
+}
+
+/// Send the same request headers a few times and return the number of bytes the client sent
+/// and the number of its header blocks that referenced the dynamic table.
+fn send_repeated_headers(table_capacity: u64) -> (usize, usize) {
+    let mut params = default_http3_parameters();
+    params.set_qpack_max_table_capacity(table_capacity).unwrap();
+    params.set_qpack_max_blocked_streams(20);
+    let server = http3_server_with_settings(QpackSettings {
+        max_table_size_encoder: 4096,
+        max_table_size_decoder: 4096,
+        max_blocked_streams: 100,
+    });
+    let (mut hconn_c, mut hconn_s, dgram) = connect_with(http3_client_with_params(&params), server);
+    let out = hconn_s.process(dgram, now());
+    let _ = hconn_c.process(out.dgram(), now());
+
+    let headers = vec![
+        (
+            String::from("user-agent"),
+            String::from("a-rather-long-user-agent/1.0 (with some detail)"),
+        ),
+        (
+            String::from("x-session"),
+            String::from("0123456789abcdef0123456789abcdef"),
+        ),
+    ];
+    let before = hconn_c.conn().stats().bytes_tx;
+    for _ in 0..5 {
+        let req = hconn_c
+            .fetch(now(), "GET", "https", "something.com", "/", &headers)
+            .unwrap();
+        hconn_c.stream_close_send(req).unwrap();
+        exchange_packets(&mut hconn_c, &mut hconn_s);
+    }
+    (
+        hconn_c.conn().stats().bytes_tx - before,
+        hconn_c.qpack_encoder_stats().dynamic_table_references,
+    )
+}
+
+#[test]
+fn test_qpack_table_capacity() {
+    let (small_bytes, small_refs) = send_repeated_headers(0);
+    let (large_bytes, large_refs) = send_repeated_headers(1000);
+    assert_eq!(small_refs, 0);
+    assert!(large_refs > 0);
+    assert!(large_bytes < small_bytes);
+
+    let mut params = default_http3_parameters();
+    assert_eq!(
+        params.set_qpack_max_table_capacity(1 << 30),
+        Err(Error::InvalidInput)
+    );
+}
+
+/// Deliver `dgrams` and everything else the server sends, then read response data.
+/// Returns the bytes received on each of the `streams` and the client's acknowledgments,
+/// which are held back so that the server can't send more.
+fn receive_response_data(
+    client: &mut Http3Client,
+    server: &mut Http3Server,
+    streams: &[u64],
+    mut dgrams: Vec<Datagram>,
+) -> (Vec<usize>, Vec<Datagram>) {
+    let mut acks = Vec::new();
+    while let Some(d) = server.process(None, now()).dgram() {
+        dgrams.push(d);
+    }
+    for d in dgrams {
+        acks.extend(client.process(Some(d), now()).dgram());
+    }
+    let mut received = vec![0; streams.len()];
+    let mut buf = [0; 4096];
+    while let Some(event) = client.next_event() {
+        if let Http3ClientEvent::DataReadable { stream_id } = event {
+            let i = streams.iter().position(|s| *s == stream_id).unwrap();
+            loop {
+                let (amount, fin) = client
+                    .read_response_data(now(), stream_id, &mut buf)
+                    .unwrap();
+                received[i] += amount;
+                if fin || amount == 0 {
+                    break;
+                }
+            }
+        }
+    }
+    (received, acks)
+}
+
+#[test]
+fn test_priority_update() {
+    const BODY: &[u8] = &[0; 30_000];
+    let (mut hconn_c, mut hconn_s, dgram) = connect();
+    let out = hconn_s.process(dgram, now());
+    let _ = hconn_c.process(out.dgram(), now());
+
+    let mut streams = Vec::new();
+    for _ in 0..2 {
+        let stream_id = hconn_c
+            .fetch(now(), "GET", "https", "something.com", "/", &[])
+            .unwrap();
+        hconn_c.stream_close_send(stream_id).unwrap();
+        streams.push(stream_id);
+    }
+    exchange_packets(&mut hconn_c, &mut hconn_s);
+    while let Some(event) = hconn_s.next_event() {
+        if let Http3ServerEvent::Headers { mut request, .. } = event {
+            request
+                .set_response(&[(String::from(":status"), String::from("200"))], BODY)
+                .unwrap();
+        }
+    }
+
+    // Both responses have the default priority, so the first is sent first.
+    let (received, acks) = receive_response_data(&mut hconn_c, &mut hconn_s, &streams, Vec::new());
+    assert!(received[0] > 0);
+    assert!(received[0] < BODY.len());
+    assert_eq!(received[1], 0);
+
+    // Once the client raises the priority of the second, that is sent instead.
+    hconn_c
+        .priority_update(streams[1], Priority::new(0, false).unwrap())
+        .unwrap();
+    let out = hconn_c.process(None, now());
+    let _ = hconn_s.process(out.dgram(), now());
+    let dgrams = acks
+        .into_iter()
+        .filter_map(|ack| hconn_s.process(Some(ack), now()).dgram())
+        .collect();
+    let (received, _) = receive_response_data(&mut hconn_c, &mut hconn_s, &streams, dgrams);
+    assert_eq!(received[0], 0);
+    assert!(received[1] > 0);
+}
+
+fn trailers() -> Vec<(String, String)> {
+    vec![
+        (String::from("grpc-status"), String::from("0")),
+        (String::from("grpc-message"), String::from("ok")),
+    ]
+}
+
+#[test]
+fn test_response_trailers() {
+    let (mut hconn_c, mut hconn_s, dgram) = connect();
+    let out = hconn_s.process(dgram, now());
+    let _ = hconn_c.process(out.dgram(), now());
+
+    let stream_id = hconn_c
+        .fetch(now(), "GET", "https", "something.com", "/", &[])
+        .unwrap();
+    hconn_c.stream_close_send(stream_id).unwrap();
+    exchange_packets(&mut hconn_c, &mut hconn_s);
+
+    let mut request = loop {
+        if let Some(Http3ServerEvent::Headers { request, .. }) = hconn_s.next_event() {
+            break request;
+        }
+    };
+    request
+        .set_response(
+            &[
+                (String::from(":status"), String::from("200")),
+                (String::from("content-length"), String::from("3")),
+            ],
+            RESPONSE_DATA,
+        )
+        .unwrap();
+    request.send_trailers(&trailers()).unwrap();
+    assert_eq!(
+        request.send_trailers(&trailers()),
+        Err(Error::AlreadyClosed)
+    );
+    exchange_packets(&mut hconn_c, &mut hconn_s);
+
+    let mut headers_found = false;
+    let mut trailers_found = false;
+    while let Some(event) = hconn_c.next_event() {
+        match event {
+            Http3ClientEvent::HeaderReady { headers, fin, .. } => {
+                assert_eq!(
+                    headers,
+                    Some(vec![
+                        (String::from(":status"), String::from("200")),
+                        (String::from("content-length"), String::from("3")),
+                    ])
+                );
+                assert!(!fin);
+                headers_found = true;
+            }
+            Http3ClientEvent::DataReadable { stream_id } => {
+                let mut buf = [0u8; 100];
+                let (amount, _) = hconn_c
+                    .read_response_data(now(), stream_id, &mut buf)
+                    .unwrap();
+                assert_eq!(&buf[..amount], RESPONSE_DATA);
+            }
+            Http3ClientEvent::TrailersReady { headers, fin, .. } => {
+                assert!(headers_found);
+                assert_eq!(headers, trailers());
+                assert!(fin);
+                trailers_found = true;
+            }
+            _ => {}
+        }
+    }
+    assert!(trailers_found);
+}
+
+#[test]
+fn test_request_trailers() {
+    let (mut hconn_c, mut hconn_s, dgram) = connect();
+    let out = hconn_s.process(dgram, now());
+    let _ = hconn_c.process(out.dgram(), now());
+
+    let stream_id = hconn_c
+        .fetch(now(), "POST", "https", "something.com", "/", &[])
+        .unwrap();
+    exchange_packets(&mut hconn_c, &mut hconn_s);
+    assert_eq!(
+        hconn_c.send_request_body(stream_id, RESPONSE_DATA),
+        Ok(RESPONSE_DATA.len())
+    );
+    hconn_c.send_trailers(stream_id, &trailers()).unwrap();
+    assert_eq!(
+        hconn_c.send_request_body(stream_id, RESPONSE_DATA),
+        Err(Error::AlreadyClosed)
+    );
+    exchange_packets(&mut hconn_c, &mut hconn_s);
+
+    let mut data = Vec::new();
+    let mut trailers_found = false;
+    while let Some(event) = hconn_s.next_event() {
+        match event {
+            Http3ServerEvent::Headers { headers, fin, .. } => {
+                assert!(matches!(headers, Some(h) if !h.contains(&trailers()[0])));
+                assert!(!fin);
+            }
+            Http3ServerEvent::Data { data: d, .. } => data.extend_from_slice(&d),
+            Http3ServerEvent::Trailers { headers, fin, .. } => {
+                assert_eq!(headers, trailers());
+                assert!(fin);
+                trailers_found = true;
+            }
+            Http3ServerEvent::StateChange { .. } => {}
+        }
+    }
+    assert_eq!(data, RESPONSE_DATA);
+    assert!(trailers_found);
+}
+
+#[test]
+fn test_goaway() {
+    let (mut hconn_c, mut hconn_s, dgram) = connect();
+    let out = hconn_s.process(dgram, now());
+    let _ = hconn_c.process(out.dgram(), now());
+    let conn = hconn_s
+        .events()
+        .find_map(|e| match e {
+            Http3ServerEvent::StateChange { conn, .. } => Some(conn),
+            _ => None,
+        })
+        .unwrap();
+
+    let mut streams = Vec::new();
+    for _ in 0..2 {
+        let stream_id = hconn_c
+            .fetch(now(), "GET", "https", "something.com", "/", &[])
+            .unwrap();
+        hconn_c.stream_close_send(stream_id).unwrap();
+        streams.push(stream_id);
+    }
+    exchange_packets(&mut hconn_c, &mut hconn_s);
+    let mut requests = hconn_s
+        .events()
+        .filter_map(|e| match e {
+            Http3ServerEvent::Headers { request, .. } => Some(request),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(requests.len(), 2);
+
+    // Requests after the two that were received are refused.
+    hconn_s.goaway(&conn, streams[1] + 4).unwrap();
+    exchange_packets(&mut hconn_c, &mut hconn_s);
+    assert!(hconn_c
+        .events()
+        .any(|e| matches!(e, Http3ClientEvent::GoawayReceived)));
+    assert_eq!(hconn_c.state(), Http3State::GoingAway(streams[1] + 4));
+    assert_eq!(
+        hconn_c.fetch(now(), "GET", "https", "something.com", "/", &[]),
+        Err(Error::AlreadyClosed)
+    );
+
+    // The requests that were received still complete.
+    for request in &mut requests {
+        request
+            .set_response(
+                &[(String::from(":status"), String::from("200"))],
+                RESPONSE_DATA,
+            )
+            .unwrap();
+    }
+    let (received, _) = receive_response_data(&mut hconn_c, &mut hconn_s, &streams, Vec::new());
+    assert_eq!(received, vec![RESPONSE_DATA.len(); 2]);
+}
@@ -12,8 +12,8 @@ use neqo_crypto::{init, AuthenticationStatus};
 use neqo_http3::{Header, Http3Client, Http3ClientEvent, Http3Parameters, Http3State};
 use neqo_qpack::QpackSettings;
 use neqo_transport::{
-    Connection, ConnectionError, ConnectionEvent, Error, FixedConnectionIdManager, Output,
-    QuicVersion, State, StreamType,
+    AppError, Connection, ConnectionError, ConnectionEvent, Error, FixedConnectionIdManager,
+    Output, QuicVersion, State, StreamType,
 };
 
 use std::cell::RefCell;
@@ -146,8 +146,9 @@ fn process_loop(
             continue;
         }
         if sz > 0 {
-            let received = Datagram::new(nctx.remote_addr, nctx.local_addr, &buf[..sz]);
-            client.process_input(received, Instant::now());
+            let now = Instant::now();
+            let received = Datagram::new_at(nctx.remote_addr, nctx.local_addr, &buf[..sz], now);
+            client.process_input(received, now);
         }
     }
 }
@@ -196,7 +197,7 @@ impl Handler for H9Handler {
                     self.rbytes += sz;
                     if fin {
                         eprintln!("<FIN[{}]>", stream_id);
-                        client.close(Instant::now(), 0, "kthxbye!");
+                        client.close(Instant::now(), AppError(0), "kthxbye!");
                         self.rsfin = true;
                         return false;
                     }
@@ -311,8 +312,9 @@ fn process_loop_h3(
             continue;
         }
         if sz > 0 {
-            let received = Datagram::new(nctx.remote_addr, nctx.local_addr, &buf[..sz]);
-            handler.h3.process_input(received, Instant::now());
+            let now = Instant::now();
+            let received = Datagram::new_at(nctx.remote_addr, nctx.local_addr, &buf[..sz], now);
+            handler.h3.process_input(received, now);
         }
     }
 }
@@ -353,7 +355,7 @@ impl H3Handler {
                     if fin {
                         eprintln!("<FIN[{}]>", stream_id);
                         if close {
-                            self.h3.close(Instant::now(), 0, "kthxbye!");
+                            self.h3.close(Instant::now(), AppError(0), "kthxbye!");
                         }
                         return false;
                     }
@@ -513,6 +515,9 @@ fn connect_h3(nctx: &NetworkCtx, peer: &Peer, client: Connection) -> Result<H3Ha
                     max_blocked_streams: 10,
                 },
                 max_concurrent_push_streams: 10,
+                grease: false,
+                max_header_list_size: None,
+                incremental_headers: false,
             },
         ),
         host: String::from(peer.host),
@@ -610,6 +615,9 @@ fn test_h3_rz(
                 max_blocked_streams: 10,
             },
             max_concurrent_push_streams: 0,
+            grease: false,
+            max_header_list_size: None,
+            incremental_headers: false,
         },
     );
     if handler.is_err() {
@@ -673,7 +681,12 @@ impl Handler for VnHandler {
     fn rewrite_out(&mut self, d: &Datagram) -> Option<Datagram> {
         let mut payload = d[..].to_vec();
         payload[1] = 0x1a;
-        Some(Datagram::new(d.source(), d.destination(), payload))
+        Some(Datagram::new_with_tos(
+            d.source(),
+            d.destination(),
+            d.tos(),
+            payload,
+        ))
     }
 }
 
@@ -12,7 +12,7 @@ use crate::reader::ReceiverConnWrapper;
 use crate::stats::Stats;
 use crate::table::HeaderTable;
 use crate::{Error, Header, QpackSettings, Res};
-use neqo_common::qdebug;
+use neqo_common::{qdebug, qtrace};
 use neqo_transport::Connection;
 use std::convert::TryInto;
 
@@ -30,6 +30,7 @@ pub struct QPackDecoder {
     max_table_size: u64,
     max_blocked_streams: usize,
     blocked_streams: Vec<(u64, u64)>, //stream_id and requested inserts count.
+    max_header_list_size: Option<u64>,
     stats: Stats,
 }
 
@@ -48,6 +49,7 @@ impl QPackDecoder {
             max_table_size: qpack_settings.max_table_size_decoder,
             max_blocked_streams: qpack_settings.max_blocked_streams.try_into().unwrap(),
             blocked_streams: Vec::new(),
+            max_header_list_size: None,
             stats: Stats::default(),
         }
     }
@@ -182,32 +184,31 @@ impl QPackDecoder {
         HeaderDecoder::new(buf).refers_dynamic_table(self.max_entries, self.table.base())
     }
 
+    /// Limit the size of decoded header lists, counted as the length of all names and values
+    /// plus 32 for each field.  `None` means that there is no limit.
+    pub fn set_max_header_list_size(&mut self, size: Option<u64>) {
+        self.max_header_list_size = size;
+    }
+
     /// This function returns None if the stream is blocked waiting for table insertions.
     /// 'buf' must contain the complete header block.
     /// # Errors
-    /// May return `DecompressionFailed` if header block is incorrect or incomplete.
+    /// May return `DecompressionFailed` if header block is incorrect or incomplete and
+    /// `HeaderListTooLarge` if the decoded list exceeds the limit set with
+    /// `set_max_header_list_size`.  Decoding stops as soon as the limit is exceeded.
     pub fn decode_header_block(&mut self, buf: &[u8], stream_id: u64) -> Res<Option<Vec<Header>>> {
         qdebug!([self], "decode header block.");
         let mut decoder = HeaderDecoder::new(buf);
 
-        match decoder.decode_header_block(&self.table, self.max_entries, self.table.base()) {
+        match decoder.decode_header_block(
+            &self.table,
+            self.max_entries,
+            self.table.base(),
+            self.max_header_list_size,
+        ) {
             Ok(HeaderDecoderResult::Blocked(req_insert_cnt)) => {
-                if self.blocked_streams.len() > self.max_blocked_streams {
-                    Err(Error::DecompressionFailed)
-                } else {
-                    let r = self
-                        .blocked_streams
-                        .iter()
-                        .filter_map(|(id, req)| if *id == stream_id { Some(*req) } else { None })
-                        .collect::<Vec<_>>();
-                    if !r.is_empty() {
-                        debug_assert!(r.len() == 1);
-                        debug_assert!(r[0] == req_insert_cnt);
-                        return Ok(None);
-                    }
-                    self.blocked_streams.push((stream_id, req_insert_cnt));
-                    Ok(None)
-                }
+                self.block_stream(stream_id, req_insert_cnt)?;
+                Ok(None)
             }
             Ok(HeaderDecoderResult::Headers(h)) => {
                 if decoder.get_req_insert_cnt() != 0 {
@@ -216,10 +217,83 @@ impl QPackDecoder {
                 }
                 Ok(Some(h))
             }
+            Err(Error::HeaderListTooLarge) => Err(Error::HeaderListTooLarge),
             Err(_) => Err(Error::DecompressionFailed),
         }
     }
 
+    /// Decode the field lines of `block` that have been added so far.  This returns `None` if
+    /// the stream is blocked waiting for table insertions, otherwise the fields that this call
+    /// decoded, which may be none.  The header block is acknowledged when it is complete.
+    /// # Errors
+    /// May return `DecompressionFailed` if header block is incorrect, or incomplete once all of
+    /// it has been added, and `HeaderListTooLarge` if the decoded list exceeds the limit set with
+    /// `set_max_header_list_size`.
+    pub fn decode_header_fields(
+        &mut self,
+        block: &mut PartialHeaderBlock,
+        stream_id: u64,
+    ) -> Res<Option<Vec<Header>>> {
+        qdebug!([self], "decode header fields.");
+        let mut decoder = if let Some((base, req_insert_cnt)) = block.prefix {
+            HeaderDecoder::with_base(&block.buf, base, req_insert_cnt)
+        } else {
+            let mut decoder = HeaderDecoder::new(&block.buf);
+            match decoder.read_base(self.max_entries, self.table.base()) {
+                Ok(()) => {}
+                Err(Error::NeedMoreData) if block.remaining > 0 => return Ok(Some(Vec::new())),
+                Err(_) => return Err(Error::DecompressionFailed),
+            }
+            block.prefix = Some((decoder.get_base(), decoder.get_req_insert_cnt()));
+            decoder
+        };
+
+        let req_insert_cnt = decoder.get_req_insert_cnt();
+        let headers = if self.table.base() < req_insert_cnt {
+            None
+        } else {
+            Some(decoder.decode_field_lines(
+                &self.table,
+                self.max_header_list_size,
+                &mut block.list_size,
+            )?)
+        };
+        let consumed = decoder.consumed();
+        block.buf.drain(..consumed);
+
+        if headers.is_none() {
+            qtrace!([self], "decoding header fields is blocked.");
+            self.block_stream(stream_id, req_insert_cnt)?;
+        } else if block.remaining == 0 {
+            if !block.buf.is_empty() {
+                return Err(Error::DecompressionFailed);
+            }
+            if req_insert_cnt != 0 {
+                self.header_ack(stream_id, req_insert_cnt);
+                self.stats.dynamic_table_references += 1;
+            }
+        }
+        Ok(headers)
+    }
+
+    fn block_stream(&mut self, stream_id: u64, req_insert_cnt: u64) -> Res<()> {
+        if self.blocked_streams.len() > self.max_blocked_streams {
+            return Err(Error::DecompressionFailed);
+        }
+        let r = self
+            .blocked_streams
+            .iter()
+            .filter_map(|(id, req)| if *id == stream_id { Some(*req) } else { None })
+            .collect::<Vec<_>>();
+        if r.is_empty() {
+            self.blocked_streams.push((stream_id, req_insert_cnt));
+        } else {
+            debug_assert!(r.len() == 1);
+            debug_assert!(r[0] == req_insert_cnt);
+        }
+        Ok(())
+    }
+
     #[must_use]
     pub fn is_recv_stream(&self, stream_id: u64) -> bool {
         match self.remote_stream_id {
@@ -263,6 +337,58 @@ impl QPackDecoder {
     }
 }
 
+/// A header block that is decoded while it is received, with `QPackDecoder::decode_header_fields`.
+#[derive(Debug)]
+pub struct PartialHeaderBlock {
+    buf: Vec<u8>,
+    remaining: usize,
+    // The base and the required insert count, once the prefix has been decoded.
+    prefix: Option<(u64, u64)>,
+    list_size: u64,
+}
+
+impl PartialHeaderBlock {
+    /// `len` is the length of the complete header block.
+    #[must_use]
+    pub fn new(len: usize) -> Self {
+        Self {
+            buf: Vec::new(),
+            remaining: len,
+            prefix: None,
+            list_size: 0,
+        }
+    }
+
+    /// Add the next part of the header block.
+    pub fn add(&mut self, data: &[u8]) {
+        debug_assert!(data.len() <= self.remaining);
+        self.remaining -= data.len();
+        self.buf.extend_from_slice(data);
+    }
+
+    /// The number of bytes of the header block that have not been added yet.
+    #[must_use]
+    pub fn remaining(&self) -> usize {
+        self.remaining
+    }
+
+    /// Whether all of the header block has been added and decoded.
+    #[must_use]
+    pub fn done(&self) -> bool {
+        self.remaining == 0 && self.buf.is_empty() && self.prefix.is_some()
+    }
+
+    /// Whether the header block refers to the dynamic table, or `None` if not enough of it has
+    /// been added to tell.  The Required Insert Count is zero only if the first byte is.
+    #[must_use]
+    pub fn refers_dynamic_table(&self) -> Option<bool> {
+        match self.prefix {
+            Some((_, req_insert_cnt)) => Some(req_insert_cnt != 0),
+            None => self.buf.first().map(|b| *b != 0),
+        }
+    }
+}
+
 impl ::std::fmt::Display for QPackDecoder {
     fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
         write!(f, "QPackDecoder {}", self.capacity())
@@ -279,7 +405,7 @@ fn map_error(err: &Error) -> Error {
 
 #[cfg(test)]
 mod tests {
-    use super::{Connection, Error, Header, QPackDecoder, Res};
+    use super::{Connection, Error, Header, PartialHeaderBlock, QPackDecoder, Res};
     use crate::QpackSettings;
     use neqo_transport::StreamType;
     use std::convert::TryInto;
@@ -805,4 +931,101 @@ mod tests {
 
         decode_headers(&mut decoder, HEADER_BLOCK, &headers, 0);
     }
+
+    #[test]
+    fn test_header_fields_in_parts() {
+        let headers = vec![
+            (String::from(":method"), String::from("GET")),
+            (String::from(":path"), String::from("/somewhere")),
+            (String::from(":authority"), String::from("example.com")),
+            (String::from(":scheme"), String::from("https")),
+        ];
+        let header_block = &[
+            0x00, 0x01, 0xd1, 0x51, 0x0a, 0x2f, 0x73, 0x6f, 0x6d, 0x65, 0x77, 0x68, 0x65, 0x72,
+            0x65, 0x50, 0x0b, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x2e, 0x63, 0x6f, 0x6d,
+            0xd7,
+        ];
+
+        let mut decoder = connect();
+        let mut block = PartialHeaderBlock::new(header_block.len());
+        let mut fields_so_far = Vec::new();
+        for (i, b) in header_block.iter().enumerate() {
+            block.add(&[*b]);
+            let fields = decoder
+                .decoder
+                .decode_header_fields(&mut block, 0)
+                .unwrap()
+                .unwrap();
+            // The first field is available as soon as its only byte is.
+            if i == 2 {
+                assert_eq!(fields, &headers[..1]);
+            }
+            fields_so_far.extend(fields);
+        }
+        assert!(block.done());
+        assert_eq!(fields_so_far, headers);
+    }
+
+    #[test]
+    fn test_header_fields_blocked() {
+        let headers = [
+            (String::from("my-headera"), String::from("my-valuea")),
+            (String::from("my-headerb"), String::from("my-valueb")),
+        ];
+        let header_block = &[0x03, 0x81, 0x10, 0x11];
+        let encoder_inst = &[
+            0x4a, 0x6d, 0x79, 0x2d, 0x68, 0x65, 0x61, 0x64, 0x65, 0x72, 0x61, 0x09, 0x6d, 0x79,
+            0x2d, 0x76, 0x61, 0x6c, 0x75, 0x65, 0x61, 0x4a, 0x6d, 0x79, 0x2d, 0x68, 0x65, 0x61,
+            0x64, 0x65, 0x72, 0x62, 0x09, 0x6d, 0x79, 0x2d, 0x76, 0x61, 0x6c, 0x75, 0x65, 0x62,
+        ];
+
+        let mut decoder = connect();
+        assert!(decoder.decoder.set_capacity(200).is_ok());
+
+        let mut block = PartialHeaderBlock::new(header_block.len());
+        block.add(&header_block[..1]);
+        assert_eq!(block.refers_dynamic_table(), Some(true));
+        assert_eq!(
+            decoder.decoder.decode_header_fields(&mut block, 0),
+            Ok(Some(Vec::new()))
+        );
+        block.add(&header_block[1..3]);
+        assert_eq!(
+            decoder.decoder.decode_header_fields(&mut block, 0),
+            Ok(None)
+        );
+
+        recv_instruction(&mut decoder, encoder_inst, &Ok(()));
+        assert_eq!(
+            decoder.decoder.decode_header_fields(&mut block, 0),
+            Ok(Some(headers[..1].to_vec()))
+        );
+        // The header block is only acknowledged when all of it has been decoded.
+        block.add(&header_block[3..]);
+        assert_eq!(
+            decoder.decoder.decode_header_fields(&mut block, 0),
+            Ok(Some(headers[1..].to_vec()))
+        );
+        assert!(block.done());
+        send_instructions_and_check(&mut decoder, &[0x03, 0x80]);
+    }
+
+    #[test]
+    fn test_header_fields_truncated() {
+        // The literal value is cut off at the end of the header block.
+        let header_block = &[0x00, 0x00, 0xd1, 0x51, 0x0a, 0x2f];
+
+        let mut decoder = connect();
+        let mut block = PartialHeaderBlock::new(header_block.len());
+        block.add(&header_block[..4]);
+        assert_eq!(
+            decoder.decoder.decode_header_fields(&mut block, 0),
+            Ok(Some(vec![(String::from(":method"), String::from("GET"))]))
+        );
+        block.add(&header_block[4..]);
+        assert_eq!(
+            decoder.decoder.decode_header_fields(&mut block, 0),
+            Err(Error::DecompressionFailed)
+        );
+    }
 }
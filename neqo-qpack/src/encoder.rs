@@ -1612,6 +1612,13 @@ mod tests {
         // Ensure that we have sent only one instruction for (String::from("something"), String::from("1234"))
         send_instructions(&mut encoder, ONE_INSTRUCTION);
 
+        // The peer reads the stream data, which gives the encoder more flow control credit.
+        let mut buf = [0_u8; STREAM_DATA_LEN];
+        let (amount, _) = encoder.peer_conn.stream_recv(stream_id, &mut buf).unwrap();
+        assert_eq!(amount, STREAM_DATA_LEN);
+        let out = encoder.peer_conn.process(None, now());
+        let _ = encoder.conn.process(out.dgram(), now());
+
         // Try writing a new header block. Now, headers will be added to the dynamic table again, because
         // instructions can be sent.
         let buf3 = encoder
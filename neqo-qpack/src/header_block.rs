@@ -16,6 +16,7 @@ use crate::table::HeaderTable;
 use crate::Header;
 use crate::{Error, Res};
 use neqo_common::qtrace;
+use std::convert::TryFrom;
 use std::mem;
 use std::ops::{Deref, Div};
 
@@ -193,6 +194,15 @@ impl<'a> HeaderDecoder<'a> {
         }
     }
 
+    /// A decoder for the rest of a header block, after its prefix has been read.
+    pub fn with_base(buf: &'a [u8], base: u64, req_insert_cnt: u64) -> Self {
+        Self {
+            buf: ReceiverBufferWrapper::new(buf),
+            base,
+            req_insert_cnt,
+        }
+    }
+
     pub fn refers_dynamic_table(
         &mut self,
         max_entries: u64,
@@ -208,6 +218,7 @@ impl<'a> HeaderDecoder<'a> {
         table: &HeaderTable,
         max_entries: u64,
         total_num_of_inserts: u64,
+        max_list_size: Option<u64>,
     ) -> Res<HeaderDecoderResult> {
         self.read_base(max_entries, total_num_of_inserts)
             .map_err(|_| Error::DecompressionFailed)?;
@@ -221,58 +232,100 @@ impl<'a> HeaderDecoder<'a> {
             return Ok(HeaderDecoderResult::Blocked(self.req_insert_cnt));
         }
         let mut h: Vec<Header> = Vec::new();
+        let mut list_size = 0;
 
         while !self.buf.done() {
-            let b = self.buf.peek().map_err(|_| Error::DecompressionFailed)?;
-            if HEADER_FIELD_INDEX_STATIC.cmp_prefix(b) {
-                h.push(
-                    self.read_indexed_static()
-                        .map_err(|_| Error::DecompressionFailed)?,
-                );
-            } else if HEADER_FIELD_INDEX_DYNAMIC.cmp_prefix(b) {
-                h.push(
-                    self.read_indexed_dynamic(table)
-                        .map_err(|_| Error::DecompressionFailed)?,
-                );
-            } else if HEADER_FIELD_INDEX_DYNAMIC_POST.cmp_prefix(b) {
-                h.push(
-                    self.read_indexed_dynamic_post(table)
-                        .map_err(|_| Error::DecompressionFailed)?,
-                );
-            } else if HEADER_FIELD_LITERAL_NAME_REF_STATIC.cmp_prefix(b) {
-                h.push(
-                    self.read_literal_with_name_ref_static()
-                        .map_err(|_| Error::DecompressionFailed)?,
-                );
-            } else if HEADER_FIELD_LITERAL_NAME_REF_DYNAMIC.cmp_prefix(b) {
-                h.push(
-                    self.read_literal_with_name_ref_dynamic(table)
-                        .map_err(|_| Error::DecompressionFailed)?,
-                );
-            } else if HEADER_FIELD_LITERAL_NAME_LITERAL.cmp_prefix(b) {
-                h.push(
-                    self.read_literal_with_name_literal()
-                        .map_err(|_| Error::DecompressionFailed)?,
-                );
-            } else if HEADER_FIELD_LITERAL_NAME_REF_DYNAMIC_POST.cmp_prefix(b) {
-                h.push(
-                    self.read_literal_with_name_ref_dynamic_post(table)
-                        .map_err(|_| Error::DecompressionFailed)?,
-                );
-            } else {
-                unreachable!("All prefixes are covered");
-            }
+            let header = self
+                .read_field_line(table)
+                .map_err(|_| Error::DecompressionFailed)?;
+            Self::add_to_list_size(&header, max_list_size, &mut list_size)?;
+            h.push(header);
         }
 
         qtrace!([self], "done decoding header block.");
         Ok(HeaderDecoderResult::Headers(h))
     }
 
+    /// Decode the field lines that are complete, leaving a field line that is cut off at the end
+    /// of the buffer for later.  The prefix has to be read first with `read_base`, or set with
+    /// `with_base` if it was read from an earlier part of the header block.
+    pub fn decode_field_lines(
+        &mut self,
+        table: &HeaderTable,
+        max_list_size: Option<u64>,
+        list_size: &mut u64,
+    ) -> Res<Vec<Header>> {
+        let mut h: Vec<Header> = Vec::new();
+        while !self.buf.done() {
+            let start = self.buf.offset();
+            match self.read_field_line(table) {
+                Ok(header) => {
+                    Self::add_to_list_size(&header, max_list_size, list_size)?;
+                    h.push(header);
+                }
+                Err(Error::NeedMoreData) => {
+                    qtrace!([self], "field line is not complete.");
+                    self.buf.rewind(start);
+                    break;
+                }
+                Err(_) => return Err(Error::DecompressionFailed),
+            }
+        }
+        Ok(h)
+    }
+
+    fn add_to_list_size(
+        header: &Header,
+        max_list_size: Option<u64>,
+        list_size: &mut u64,
+    ) -> Res<()> {
+        if let Some(limit) = max_list_size {
+            let (name, value) = header;
+            *list_size += u64::try_from(name.len() + value.len() + 32).unwrap();
+            if *list_size > limit {
+                qtrace!("header list is larger than {}.", limit);
+                return Err(Error::HeaderListTooLarge);
+            }
+        }
+        Ok(())
+    }
+
+    fn read_field_line(&mut self, table: &HeaderTable) -> Res<Header> {
+        let b = self.buf.peek()?;
+        if HEADER_FIELD_INDEX_STATIC.cmp_prefix(b) {
+            self.read_indexed_static()
+        } else if HEADER_FIELD_INDEX_DYNAMIC.cmp_prefix(b) {
+            self.read_indexed_dynamic(table)
+        } else if HEADER_FIELD_INDEX_DYNAMIC_POST.cmp_prefix(b) {
+            self.read_indexed_dynamic_post(table)
+        } else if HEADER_FIELD_LITERAL_NAME_REF_STATIC.cmp_prefix(b) {
+            self.read_literal_with_name_ref_static()
+        } else if HEADER_FIELD_LITERAL_NAME_REF_DYNAMIC.cmp_prefix(b) {
+            self.read_literal_with_name_ref_dynamic(table)
+        } else if HEADER_FIELD_LITERAL_NAME_LITERAL.cmp_prefix(b) {
+            self.read_literal_with_name_literal()
+        } else if HEADER_FIELD_LITERAL_NAME_REF_DYNAMIC_POST.cmp_prefix(b) {
+            self.read_literal_with_name_ref_dynamic_post(table)
+        } else {
+            unreachable!("All prefixes are covered");
+        }
+    }
+
     pub fn get_req_insert_cnt(&self) -> u64 {
         self.req_insert_cnt
     }
 
-    fn read_base(&mut self, max_entries: u64, total_num_of_inserts: u64) -> Res<()> {
+    pub fn get_base(&self) -> u64 {
+        self.base
+    }
+
+    /// The number of bytes that have been decoded.
+    pub fn consumed(&self) -> usize {
+        self.buf.offset()
+    }
+
+    /// Returns `NeedMoreData` if the buffer ends before the prefix does.
+    pub fn read_base(&mut self, max_entries: u64, total_num_of_inserts: u64) -> Res<()> {
         let insert_cnt = self.buf.read_prefixed_int(0)?;
         self.req_insert_cnt =
             HeaderDecoder::calc_req_insert_cnt(insert_cnt, max_entries, total_num_of_inserts)?;
@@ -406,7 +459,7 @@ impl<'a> HeaderDecoder<'a> {
 #[cfg(test)]
 mod tests {
 
-    use super::{HeaderDecoder, HeaderDecoderResult, HeaderEncoder, HeaderTable};
+    use super::{Error, HeaderDecoder, HeaderDecoderResult, HeaderEncoder, HeaderTable};
 
     const INDEX_STATIC_TEST: &[(u64, &[u8], &str, &str)] = &[
         (0, &[0x0, 0x0, 0xc0], ":authority", ""),
@@ -677,8 +730,9 @@ mod tests {
         for (_, encoded, decoded1, decoded2) in INDEX_STATIC_TEST {
             let table = HeaderTable::new(false);
             let mut decoder_h = HeaderDecoder::new(encoded);
-            if let HeaderDecoderResult::Headers(result) =
-                decoder_h.decode_header_block(&table, 1000, 0).unwrap()
+            if let HeaderDecoderResult::Headers(result) = decoder_h
+                .decode_header_block(&table, 1000, 0, None)
+                .unwrap()
             {
                 assert_eq!(result.len(), 1);
                 assert_eq!(result[0].0, *decoded1);
@@ -705,8 +759,9 @@ mod tests {
             let mut table = HeaderTable::new(false);
             fill_table(&mut table);
             let mut decoder_h = HeaderDecoder::new(encoded);
-            if let HeaderDecoderResult::Headers(result) =
-                decoder_h.decode_header_block(&table, 1000, 0).unwrap()
+            if let HeaderDecoderResult::Headers(result) = decoder_h
+                .decode_header_block(&table, 1000, 0, None)
+                .unwrap()
             {
                 assert_eq!(result.len(), 1);
                 assert_eq!(result[0].0, *decoded1);
@@ -723,8 +778,9 @@ mod tests {
             let mut table = HeaderTable::new(false);
             fill_table(&mut table);
             let mut decoder_h = HeaderDecoder::new(encoded);
-            if let HeaderDecoderResult::Headers(result) =
-                decoder_h.decode_header_block(&table, 1000, 0).unwrap()
+            if let HeaderDecoderResult::Headers(result) = decoder_h
+                .decode_header_block(&table, 1000, 0, None)
+                .unwrap()
             {
                 assert_eq!(result.len(), 1);
                 assert_eq!(result[0].0, *decoded1);
@@ -740,8 +796,9 @@ mod tests {
         for (_, encoded, decoded1, decoded2) in NAME_REF_STATIC {
             let table = HeaderTable::new(false);
             let mut decoder_h = HeaderDecoder::new(encoded);
-            if let HeaderDecoderResult::Headers(result) =
-                decoder_h.decode_header_block(&table, 1000, 0).unwrap()
+            if let HeaderDecoderResult::Headers(result) = decoder_h
+                .decode_header_block(&table, 1000, 0, None)
+                .unwrap()
             {
                 assert_eq!(result.len(), 1);
                 assert_eq!(result[0].0, *decoded1);
@@ -758,8 +815,9 @@ mod tests {
             let mut table = HeaderTable::new(false);
             fill_table(&mut table);
             let mut decoder_h = HeaderDecoder::new(encoded);
-            if let HeaderDecoderResult::Headers(result) =
-                decoder_h.decode_header_block(&table, 1000, 0).unwrap()
+            if let HeaderDecoderResult::Headers(result) = decoder_h
+                .decode_header_block(&table, 1000, 0, None)
+                .unwrap()
             {
                 assert_eq!(result.len(), 1);
                 assert_eq!(result[0].0, *decoded1);
@@ -776,8 +834,9 @@ mod tests {
             let mut table = HeaderTable::new(false);
             fill_table(&mut table);
             let mut decoder_h = HeaderDecoder::new(encoded);
-            if let HeaderDecoderResult::Headers(result) =
-                decoder_h.decode_header_block(&table, 1000, 0).unwrap()
+            if let HeaderDecoderResult::Headers(result) = decoder_h
+                .decode_header_block(&table, 1000, 0, None)
+                .unwrap()
             {
                 assert_eq!(result.len(), 1);
                 assert_eq!(result[0].0, *decoded1);
@@ -794,8 +853,9 @@ mod tests {
             let mut table = HeaderTable::new(false);
             fill_table(&mut table);
             let mut decoder_h = HeaderDecoder::new(encoded);
-            if let HeaderDecoderResult::Headers(result) =
-                decoder_h.decode_header_block(&table, 1000, 0).unwrap()
+            if let HeaderDecoderResult::Headers(result) = decoder_h
+                .decode_header_block(&table, 1000, 0, None)
+                .unwrap()
             {
                 assert_eq!(result.len(), 1);
                 assert_eq!(result[0].0, *decoded1);
@@ -811,8 +871,9 @@ mod tests {
         let mut table = HeaderTable::new(false);
         fill_table(&mut table);
         let mut decoder_h = HeaderDecoder::new(LITERAL_LITERAL);
-        if let HeaderDecoderResult::Headers(result) =
-            decoder_h.decode_header_block(&table, 1000, 0).unwrap()
+        if let HeaderDecoderResult::Headers(result) = decoder_h
+            .decode_header_block(&table, 1000, 0, None)
+            .unwrap()
         {
             assert_eq!(result.len(), 1);
             assert_eq!(result[0].0, *LITERAL_VALUE);
@@ -822,8 +883,9 @@ mod tests {
         }
 
         let mut decoder_h = HeaderDecoder::new(LITERAL_LITERAL_HUFFMAN);
-        if let HeaderDecoderResult::Headers(result) =
-            decoder_h.decode_header_block(&table, 1000, 0).unwrap()
+        if let HeaderDecoderResult::Headers(result) = decoder_h
+            .decode_header_block(&table, 1000, 0, None)
+            .unwrap()
         {
             assert_eq!(result.len(), 1);
             assert_eq!(result[0].0, *LITERAL_VALUE);
@@ -833,6 +895,23 @@ mod tests {
         }
     }
 
+    // The list has one field, "custom-key: custom-key", which counts as 52 bytes.
+    #[test]
+    fn decode_max_list_size() {
+        let table = HeaderTable::new(false);
+        let mut decoder_h = HeaderDecoder::new(LITERAL_LITERAL);
+        assert!(matches!(
+            decoder_h.decode_header_block(&table, 1000, 0, Some(52)),
+            Ok(HeaderDecoderResult::Headers(_))
+        ));
+
+        let mut decoder_h = HeaderDecoder::new(LITERAL_LITERAL);
+        assert_eq!(
+            decoder_h.decode_header_block(&table, 1000, 0, Some(51)),
+            Err(Error::HeaderListTooLarge)
+        );
+    }
+
     // Test that we are ignoring N-bit.
     #[test]
     fn decode_ignore_n_bit() {
@@ -875,8 +954,9 @@ mod tests {
             let mut table = HeaderTable::new(false);
             fill_table(&mut table);
             let mut decoder_h = HeaderDecoder::new(encoded);
-            if let HeaderDecoderResult::Headers(result) =
-                decoder_h.decode_header_block(&table, 1000, 0).unwrap()
+            if let HeaderDecoderResult::Headers(result) = decoder_h
+                .decode_header_block(&table, 1000, 0, None)
+                .unwrap()
             {
                 assert_eq!(result.len(), 1);
                 assert_eq!(result[0].0, *decoded1);
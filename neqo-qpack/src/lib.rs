@@ -44,6 +44,7 @@ pub enum Error {
     DecoderStream,
     ClosedCriticalStream,
     InternalError,
+    HeaderListTooLarge, // A header block decoded to more than the limit on the list size.
 
     // These are internal errors, they will be transformed into one of the above.
     NeedMoreData, // Return when an input stream does not have more data that a decoder needs.(It does not mean that a stream is closed.)
@@ -66,14 +67,14 @@ pub enum Error {
 impl Error {
     #[must_use]
     pub fn code(&self) -> neqo_transport::AppError {
-        match self {
+        neqo_transport::AppError(match self {
             Self::DecompressionFailed => 0x200,
             Self::EncoderStream => 0x201,
             Self::DecoderStream => 0x202,
             Self::ClosedCriticalStream => 0x104,
             // These are all internal errors.
             _ => 3,
-        }
+        })
     }
 }
 
@@ -58,9 +58,9 @@ impl<'a> ReceiverConnWrapper<'a> {
     }
 }
 
-/// This is only used by header decoder therefore all errors are `DecompressionFailed`.
-/// A header block is read entirely before decoding it, therefore if there is not enough
-/// data in the buffer an error `DecompressionFailed` will be return.
+/// This is only used by header decoder.  A header block can be decoded before all of it is
+/// received, therefore if there is not enough data in the buffer an error `NeedMoreData` will be
+/// returned.  The header decoder turns that into `DecompressionFailed` when the block is complete.
 pub(crate) struct ReceiverBufferWrapper<'a> {
     buf: &'a [u8],
     offset: usize,
@@ -69,7 +69,7 @@ pub(crate) struct ReceiverBufferWrapper<'a> {
 impl<'a> ReadByte for ReceiverBufferWrapper<'a> {
     fn read_byte(&mut self) -> Res<u8> {
         if self.offset == self.buf.len() {
-            Err(Error::DecompressionFailed)
+            Err(Error::NeedMoreData)
         } else {
             let b = self.buf[self.offset];
             self.offset += 1;
@@ -85,7 +85,7 @@ impl<'a> ReceiverBufferWrapper<'a> {
 
     pub fn peek(&self) -> Res<u8> {
         if self.offset == self.buf.len() {
-            Err(Error::DecompressionFailed)
+            Err(Error::NeedMoreData)
         } else {
             Ok(self.buf[self.offset])
         }
@@ -95,11 +95,20 @@ impl<'a> ReceiverBufferWrapper<'a> {
         self.offset == self.buf.len()
     }
 
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Go back to `offset`, to read a field line that was cut off again when there is more data.
+    pub fn rewind(&mut self, offset: usize) {
+        debug_assert!(offset <= self.offset);
+        self.offset = offset;
+    }
+
     /// The function decodes varint with a prefixed, i.e. ignores `prefix_len` bits of the first
     /// byte.
-    /// `ReceiverBufferWrapper` is only used for decoding header blocks. The header blocks are read
-    /// entirely before a decoding starts, therefore any incomplete varint because of reaching the
-    /// end of a buffer will be treated as the `DecompressionFailed` error.
+    /// `ReceiverBufferWrapper` is only used for decoding header blocks. Any incomplete varint
+    /// because of reaching the end of a buffer will be treated as the `NeedMoreData` error.
     pub fn read_prefixed_int(&mut self, prefix_len: u8) -> Res<u64> {
         debug_assert!(prefix_len < 8);
 
@@ -116,9 +125,8 @@ impl<'a> ReceiverBufferWrapper<'a> {
     ///   4) reads the literal
     ///   5) performs huffman decoding if needed.
     ///
-    /// `ReceiverBufferWrapper` is only used for decoding header blocks. The header blocks are read
-    /// entirely before a decoding starts, therefore any incomplete varint or literal because of
-    /// reaching the end of a buffer will be treated as the `DecompressionFailed` error.
+    /// `ReceiverBufferWrapper` is only used for decoding header blocks. Any incomplete varint or
+    /// literal because of reaching the end of a buffer will be treated as the `NeedMoreData` error.
     pub fn read_literal_from_buffer(&mut self, prefix_len: u8) -> Res<String> {
         debug_assert!(prefix_len < 7);
 
@@ -138,7 +146,7 @@ impl<'a> ReceiverBufferWrapper<'a> {
 
     fn slice(&mut self, len: usize) -> Res<&[u8]> {
         if self.offset + len > self.buf.len() {
-            Err(Error::DecompressionFailed)
+            Err(Error::NeedMoreData)
         } else {
             let start = self.offset;
             self.offset += len;
@@ -572,7 +580,7 @@ mod tests {
         let (buf, prefix_len, _) = &TEST_CASES_NUMBERS[4];
         let mut buffer = ReceiverBufferWrapper::new(&buf[..1]);
         let mut reader = IntReader::new(buffer.read_byte().unwrap(), *prefix_len);
-        assert_eq!(reader.read(&mut buffer), Err(Error::DecompressionFailed));
+        assert_eq!(reader.read(&mut buffer), Err(Error::NeedMoreData));
     }
 
     #[test]
@@ -581,7 +589,7 @@ mod tests {
         let mut buffer = ReceiverBufferWrapper::new(&buf[..6]);
         assert_eq!(
             buffer.read_literal_from_buffer(*prefix_len),
-            Err(Error::DecompressionFailed)
+            Err(Error::NeedMoreData)
         );
     }
 }
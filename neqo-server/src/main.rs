@@ -27,7 +27,7 @@ use mio::{Events, Poll, PollOpt, Ready, Token};
 use mio_extras::timer::{Builder, Timeout, Timer};
 use structopt::StructOpt;
 
-use neqo_common::{qdebug, qinfo, Datagram};
+use neqo_common::{qdebug, qinfo, udp, Datagram};
 use neqo_crypto::{init_db, AntiReplay};
 use neqo_http3::{Error, Http3Server, Http3ServerEvent};
 use neqo_qpack::QpackSettings;
@@ -107,9 +107,7 @@ impl Args {
 }
 
 fn emit_packet(socket: &mut UdpSocket, out_dgram: Datagram) {
-    let sent = socket
-        .send_to(&out_dgram, &out_dgram.destination())
-        .expect("Error sending datagram");
+    let sent = udp::send_datagram(socket, &out_dgram).expect("Error sending datagram");
     if sent != out_dgram.len() {
         eprintln!("Unable to send all {} bytes of datagram", out_dgram.len());
     }
@@ -239,7 +237,7 @@ fn read_dgram(
     local_address: &SocketAddr,
 ) -> Result<Option<Datagram>, io::Error> {
     let buf = &mut [0u8; 2048];
-    let (sz, remote_addr) = match socket.recv_from(&mut buf[..]) {
+    let dgram = match udp::recv_datagram(socket, *local_address, &mut buf[..]) {
         Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(None),
         Err(err) => {
             eprintln!("UDP recv error: {:?}", err);
@@ -248,15 +246,15 @@ fn read_dgram(
         Ok(res) => res,
     };
 
-    if sz == buf.len() {
+    if dgram.len() == buf.len() {
         eprintln!("Might have received more than {} bytes", buf.len());
     }
 
-    if sz == 0 {
+    if dgram.is_empty() {
         eprintln!("zero length datagram received?");
         Ok(None)
     } else {
-        Ok(Some(Datagram::new(remote_addr, *local_address, &buf[..sz])))
+        Ok(Some(dgram.received(Instant::now())))
     }
 }
 
@@ -311,6 +309,10 @@ impl ServersRunner {
                 Ok(s) => s,
             };
 
+            if let Err(err) = udp::enable_recv_tos(&socket, local_addr) {
+                eprintln!("Unable to read the TOS byte of received datagrams: {}", err);
+            }
+
             let res = socket.only_v6();
             let also_v4 = if res.is_ok() && !res.unwrap() {
                 " as well as V4"
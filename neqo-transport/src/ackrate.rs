@@ -0,0 +1,148 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Requesting a change in the rate of acknowledgments from a peer
+// (draft-ietf-quic-ack-frequency).
+#![deny(clippy::pedantic)]
+
+use neqo_common::qdebug;
+
+use std::cmp::max;
+use std::convert::TryFrom;
+use std::time::Duration;
+
+use crate::frame::Frame;
+use crate::recovery::RecoveryToken;
+
+/// The largest encoded size of an `ACK_FREQUENCY` frame.
+const MAX_FRAME_SIZE: usize = 2 + 8 + 8 + 8 + 1;
+
+/// The ACK rate that has been requested from the peer.
+#[derive(Debug, Default)]
+pub struct AckRate {
+    /// The sequence number of the most recent request.
+    seqno: u64,
+    /// The number of packets and the delay that were requested.
+    rate: Option<(u64, Duration)>,
+    /// Whether the most recent request needs to be sent.
+    needed: bool,
+}
+
+impl AckRate {
+    /// Ask the peer to acknowledge after `packets` ack-eliciting packets, or
+    /// after `delay`.  This replaces any previous request.
+    pub fn set(&mut self, packets: u64, delay: Duration) {
+        if self.rate.is_some() {
+            self.seqno += 1;
+        }
+        self.rate = Some((packets, delay));
+        self.needed = true;
+    }
+
+    /// Write an `ACK_FREQUENCY` frame, if one is needed.  The requested delay is
+    /// raised to `min_delay`, which is the minimum that the peer supports.
+    pub fn get_frame(
+        &mut self,
+        remaining: usize,
+        min_delay: Duration,
+    ) -> Option<(Frame, Option<RecoveryToken>)> {
+        if !self.needed || remaining < MAX_FRAME_SIZE {
+            return None;
+        }
+        let (packets, delay) = self.rate?;
+        let delay = max(delay, min_delay);
+        qdebug!(
+            "ACK_FREQUENCY seqno {} packets {} delay {:?}",
+            self.seqno,
+            packets,
+            delay
+        );
+        self.needed = false;
+        Some((
+            Frame::AckFrequency {
+                seqno: self.seqno,
+                tolerance: packets,
+                delay: u64::try_from(delay.as_micros()).unwrap_or(u64::MAX),
+                ignore_order: false,
+            },
+            Some(RecoveryToken::AckFrequency(self.seqno, delay)),
+        ))
+    }
+
+    /// An `ACK_FREQUENCY` frame was lost, so send it again if it is still current.
+    pub fn lost(&mut self, seqno: u64) {
+        if seqno == self.seqno {
+            self.needed = true;
+        }
+    }
+
+    /// An `ACK_FREQUENCY` frame was acknowledged.  If it is still current,
+    /// return the delay the peer will now use.
+    pub fn acked(&self, seqno: u64, delay: Duration) -> Option<Duration> {
+        if seqno == self.seqno && !self.needed {
+            Some(delay)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AckRate, Duration, Frame, RecoveryToken};
+
+    const DELAY: Duration = Duration::from_millis(50);
+    const MIN_DELAY: Duration = Duration::from_millis(1);
+
+    fn seqno(rate: &mut AckRate) -> u64 {
+        match rate.get_frame(100, MIN_DELAY) {
+            Some((Frame::AckFrequency { seqno, .. }, Some(RecoveryToken::AckFrequency(s, _)))) => {
+                assert_eq!(seqno, s);
+                seqno
+            }
+            _ => panic!("expected an ACK_FREQUENCY frame"),
+        }
+    }
+
+    #[test]
+    fn nothing_requested() {
+        let mut rate = AckRate::default();
+        assert!(rate.get_frame(100, MIN_DELAY).is_none());
+    }
+
+    #[test]
+    fn resend_current() {
+        let mut rate = AckRate::default();
+        rate.set(10, DELAY);
+        let first = seqno(&mut rate);
+        assert!(rate.get_frame(100, MIN_DELAY).is_none());
+
+        // A lost frame is sent again, until it is replaced.
+        rate.lost(first);
+        assert_eq!(seqno(&mut rate), first);
+        rate.set(20, DELAY);
+        rate.lost(first);
+        let second = seqno(&mut rate);
+        assert!(second > first);
+        rate.lost(first);
+        assert!(rate.get_frame(100, MIN_DELAY).is_none());
+
+        // Only the current frame changes the delay.
+        assert_eq!(rate.acked(first, DELAY), None);
+        assert_eq!(rate.acked(second, DELAY), Some(DELAY));
+    }
+
+    #[test]
+    fn min_delay() {
+        let mut rate = AckRate::default();
+        rate.set(10, Duration::from_micros(10));
+        assert!(rate.get_frame(10, MIN_DELAY).is_none());
+        match rate.get_frame(100, MIN_DELAY) {
+            Some((Frame::AckFrequency { delay, .. }, _)) => assert_eq!(delay, 1000),
+            _ => panic!("expected an ACK_FREQUENCY frame"),
+        }
+    }
+}
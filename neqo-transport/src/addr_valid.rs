@@ -202,7 +202,9 @@ impl AddressValidation {
             self.validation
         );
 
-        if token.is_empty() {
+        // A token that is too short to be one of ours is treated as though
+        // there was no token at all.
+        if token.len() <= TOKEN_IDENTIFIER_RETRY.len() {
             if self.validation == ValidateAddress::Never {
                 qinfo!("AddressValidation: no token; accepting");
                 return AddressValidationResult::Pass;
@@ -211,11 +213,6 @@ impl AddressValidation {
                 return AddressValidationResult::Validate;
             }
         }
-        if token.len() <= TOKEN_IDENTIFIER_RETRY.len() {
-            // Treat bad tokens strictly.
-            qinfo!("AddressValidation: too short token");
-            return AddressValidationResult::Invalid;
-        }
         let retry = Self::is_likely_retry(token);
         let enc = &token[TOKEN_IDENTIFIER_RETRY.len()..];
         // Note that this allows the token identifier part to be corrupted.
@@ -0,0 +1,575 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// BBR congestion control
+
+use std::cmp::{max, min};
+use std::collections::{BTreeMap, VecDeque};
+use std::convert::TryFrom;
+use std::fmt::{self, Display};
+use std::time::{Duration, Instant};
+
+use super::{CongestionControl, CWND_INITIAL, MAX_DATAGRAM_SIZE};
+use crate::packet::PacketNumber;
+use crate::qlog::{self, QlogMetric};
+use crate::tracking::{PNSpace, SentPacket};
+use neqo_common::{qdebug, qinfo, qlog::NeqoQlog, qtrace};
+
+/// Gains are expressed in percent.  This is the gain used in startup, 2/ln(2).
+const HIGH_GAIN: usize = 289;
+/// The gain applied to the bandwidth-delay product to get the congestion window.
+const CWND_GAIN: usize = 200;
+/// The gains used in `ProbeBw`, which move through this cycle once per round trip.
+const GAIN_CYCLE: [usize; 8] = [125, 75, 100, 100, 100, 100, 100, 100];
+/// The number of round trips over which the maximum bandwidth is tracked.
+const BTL_BW_FILTER_ROUNDS: u64 = 10;
+/// How long a minimum RTT sample is valid for.
+const MIN_RTT_FILTER_LEN: Duration = Duration::from_secs(10);
+/// How long to spend in `ProbeRtt`.
+const PROBE_RTT_DURATION: Duration = Duration::from_millis(200);
+/// The smallest congestion window, which is also used in `ProbeRtt`.
+const MIN_PIPE_CWND: usize = 4 * MAX_DATAGRAM_SIZE;
+/// Startup ends when bandwidth grows by less than this (in percent)...
+const FULL_BW_GROWTH: u64 = 125;
+/// ...for this many round trips.
+const FULL_BW_ROUNDS: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BbrState {
+    /// Grow quickly to find the available bandwidth.
+    Startup,
+    /// Drain the queue that was created in startup.
+    Drain,
+    /// Cycle through gains to probe for more bandwidth.
+    ProbeBw,
+    /// Reduce the amount in flight to measure the minimum RTT.
+    ProbeRtt,
+}
+
+/// The state of delivery at the time that a packet was sent.
+#[derive(Debug)]
+struct DeliveryState {
+    delivered: usize,
+    delivered_time: Instant,
+    first_sent_time: Instant,
+}
+
+/// A BBR (version 1) congestion controller.
+///
+/// This models the path using estimates of the bottleneck bandwidth and the
+/// minimum RTT rather than reacting to loss.  Bandwidth is estimated by
+/// sampling the delivery rate of acknowledged packets.
+///
+/// The pacer doesn't use a pacing rate, so the gains that BBR would apply
+/// to pacing are instead applied to the congestion window.
+#[derive(Debug)]
+pub struct Bbr {
+    state: BbrState,
+    /// The window that is used until the path has been measured.
+    initial_cwnd: usize,
+    congestion_window: usize,
+    bytes_in_flight: usize,
+
+    /// Bandwidth samples, in bytes per second, with the round in which they
+    /// were taken.  This only holds samples with decreasing bandwidth, so
+    /// the first entry is the maximum.
+    btl_bw_samples: VecDeque<(u64, u64)>,
+    /// The minimum RTT and when it was measured.
+    min_rtt: Option<(Duration, Instant)>,
+
+    /// The total number of bytes that have been acknowledged.
+    delivered: usize,
+    /// When `delivered` was last updated.
+    delivered_time: Option<Instant>,
+    /// The time the most recently acknowledged packet was sent.
+    first_sent_time: Option<Instant>,
+    /// Delivery state for packets that are in flight.
+    in_flight: BTreeMap<(PNSpace, PacketNumber), DeliveryState>,
+
+    /// The number of round trips counted so far.
+    round_count: u64,
+    /// The value of `delivered` that marks the end of the current round trip.
+    next_round_delivered: usize,
+
+    /// The bandwidth that startup last saw substantial growth at.
+    full_bw: u64,
+    /// The number of rounds without substantial growth.
+    full_bw_count: usize,
+    filled_pipe: bool,
+
+    /// The position in `GAIN_CYCLE` and when that position started.
+    cycle_index: usize,
+    cycle_start: Option<Instant>,
+
+    /// When `ProbeRtt` can end; set once the amount in flight is low enough.
+    probe_rtt_done: Option<Instant>,
+    probe_rtt_round_done: bool,
+    /// The congestion window before entering `ProbeRtt`.
+    prior_cwnd: usize,
+
+    qlog: NeqoQlog,
+}
+
+impl Default for Bbr {
+    fn default() -> Self {
+        Self {
+            state: BbrState::Startup,
+            initial_cwnd: CWND_INITIAL,
+            congestion_window: CWND_INITIAL,
+            bytes_in_flight: 0,
+            btl_bw_samples: VecDeque::new(),
+            min_rtt: None,
+            delivered: 0,
+            delivered_time: None,
+            first_sent_time: None,
+            in_flight: BTreeMap::new(),
+            round_count: 0,
+            next_round_delivered: 0,
+            full_bw: 0,
+            full_bw_count: 0,
+            filled_pipe: false,
+            cycle_index: 0,
+            cycle_start: None,
+            probe_rtt_done: None,
+            probe_rtt_round_done: false,
+            prior_cwnd: 0,
+            qlog: NeqoQlog::disabled(),
+        }
+    }
+}
+
+impl Display for Bbr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Bbr {:?} {}/{} bw {}",
+            self.state,
+            self.bytes_in_flight,
+            self.congestion_window,
+            self.btl_bw()
+        )?;
+        if let Some((rtt, _)) = self.min_rtt {
+            write!(f, " min_rtt {:?}", rtt)?;
+        }
+        Ok(())
+    }
+}
+
+impl CongestionControl for Bbr {
+    fn set_qlog(&mut self, qlog: NeqoQlog) {
+        self.qlog = qlog;
+    }
+
+    fn cwnd(&self) -> usize {
+        self.congestion_window
+    }
+
+    fn set_initial_cwnd(&mut self, cwnd: usize) {
+        debug_assert_eq!(self.delivered, 0);
+        self.initial_cwnd = cwnd;
+        self.congestion_window = cwnd;
+    }
+
+    fn bytes_in_flight(&self) -> usize {
+        self.bytes_in_flight
+    }
+
+    fn on_packet_sent(&mut self, pkt: &SentPacket) {
+        if !pkt.ack_eliciting() {
+            return;
+        }
+        if self.bytes_in_flight == 0 {
+            // Don't count idle time in delivery rate samples.
+            self.first_sent_time = Some(pkt.time_sent);
+            self.delivered_time = Some(pkt.time_sent);
+        }
+        self.in_flight.insert(
+            (PNSpace::from(pkt.pt), pkt.pn),
+            DeliveryState {
+                delivered: self.delivered,
+                delivered_time: self.delivered_time.unwrap_or(pkt.time_sent),
+                first_sent_time: self.first_sent_time.unwrap_or(pkt.time_sent),
+            },
+        );
+        self.bytes_in_flight += pkt.size;
+        qtrace!([self], "Pkt Sent len {}", pkt.size);
+    }
+
+    fn on_packets_acked(&mut self, acked_pkts: &[SentPacket], now: Instant) {
+        let mut acked_bytes = 0;
+        let mut newest: Option<(DeliveryState, Instant)> = None;
+        for pkt in acked_pkts {
+            if let Some(state) = self.in_flight.remove(&(PNSpace::from(pkt.pt), pkt.pn)) {
+                self.bytes_in_flight -= pkt.size;
+                self.delivered += pkt.size;
+                acked_bytes += pkt.size;
+                if !matches!(&newest, Some((_, t)) if pkt.time_sent < *t) {
+                    newest = Some((state, pkt.time_sent));
+                }
+            }
+        }
+        if let Some((state, time_sent)) = newest {
+            self.on_delivered(&state, time_sent, acked_bytes, now);
+        }
+    }
+
+    /// BBR doesn't use loss as a signal, so this only updates the number of
+    /// bytes in flight.
+    fn on_packets_lost(
+        &mut self,
+        _now: Instant,
+        _first_rtt_sample_time: Option<Instant>,
+        _prev_largest_acked_sent: Option<Instant>,
+        _pto: Duration,
+        lost_packets: &[SentPacket],
+    ) {
+        for pkt in lost_packets {
+            self.discard(pkt);
+        }
+        qdebug!([self], "Pkts lost {}", lost_packets.len());
+    }
+
+    fn on_congestion_event(&mut self, _now: Instant, _sent_time: Instant) {}
+
+    fn discard(&mut self, pkt: &SentPacket) {
+        if self
+            .in_flight
+            .remove(&(PNSpace::from(pkt.pt), pkt.pn))
+            .is_some()
+        {
+            self.bytes_in_flight -= pkt.size;
+        }
+    }
+}
+
+impl Bbr {
+    /// Update the model using the delivery state of the most recently sent
+    /// packet that was acknowledged.
+    fn on_delivered(
+        &mut self,
+        state: &DeliveryState,
+        time_sent: Instant,
+        acked_bytes: usize,
+        now: Instant,
+    ) {
+        self.delivered_time = Some(now);
+        self.first_sent_time = Some(time_sent);
+
+        let round_start = state.delivered >= self.next_round_delivered;
+        if round_start {
+            self.next_round_delivered = self.delivered;
+            self.round_count += 1;
+        }
+
+        let min_rtt_expired = self.update_min_rtt(now - time_sent, now);
+        self.sample_bandwidth(state, time_sent, now);
+
+        self.check_full_pipe(round_start);
+        self.check_drain(now);
+        self.update_gain_cycle(now);
+        self.check_probe_rtt(now, round_start, min_rtt_expired);
+        self.set_cwnd(acked_bytes);
+
+        qlog::metrics_updated(
+            &mut self.qlog,
+            &[
+                QlogMetric::CongestionWindow(self.congestion_window),
+                QlogMetric::BytesInFlight(self.bytes_in_flight),
+            ],
+        );
+    }
+
+    /// The current estimate of the bottleneck bandwidth, in bytes per second.
+    fn btl_bw(&self) -> u64 {
+        self.btl_bw_samples.front().map_or(0, |(_, bw)| *bw)
+    }
+
+    /// The estimated bandwidth-delay product, scaled by `gain`.
+    fn bdp(&self, gain: usize) -> Option<usize> {
+        let (rtt, _) = self.min_rtt?;
+        let bw = self.btl_bw();
+        if bw == 0 {
+            return None;
+        }
+        let bdp = u128::from(bw) * rtt.as_micros() / 1_000_000;
+        let bdp = usize::try_from(bdp).unwrap_or(usize::MAX);
+        Some(bdp.saturating_mul(gain) / 100)
+    }
+
+    /// Update the minimum RTT.  Returns true if the previous estimate expired.
+    fn update_min_rtt(&mut self, sample: Duration, now: Instant) -> bool {
+        let expired = matches!(self.min_rtt, Some((_, t)) if now >= t + MIN_RTT_FILTER_LEN);
+        if expired || !matches!(self.min_rtt, Some((rtt, _)) if sample > rtt) {
+            self.min_rtt = Some((sample, now));
+        }
+        expired
+    }
+
+    fn sample_bandwidth(&mut self, state: &DeliveryState, time_sent: Instant, now: Instant) {
+        let send_elapsed = time_sent.saturating_duration_since(state.first_sent_time);
+        let ack_elapsed = now.saturating_duration_since(state.delivered_time);
+        let interval = max(send_elapsed, ack_elapsed);
+        // Intervals shorter than the minimum RTT produce unreliable samples.
+        if interval.as_micros() == 0 || matches!(self.min_rtt, Some((rtt, _)) if interval < rtt) {
+            return;
+        }
+        let delivered = u128::try_from(self.delivered - state.delivered).unwrap();
+        let bw = u64::try_from(delivered * 1_000_000 / interval.as_micros()).unwrap_or(u64::MAX);
+
+        // Maintain a windowed maximum.
+        while matches!(self.btl_bw_samples.back(), Some((_, b)) if *b <= bw) {
+            self.btl_bw_samples.pop_back();
+        }
+        self.btl_bw_samples.push_back((self.round_count, bw));
+        while matches!(self.btl_bw_samples.front(),
+                       Some((r, _)) if r + BTL_BW_FILTER_ROUNDS <= self.round_count)
+        {
+            self.btl_bw_samples.pop_front();
+        }
+        qtrace!([self], "Bandwidth sample {}", bw);
+    }
+
+    /// Whether there are more bytes in flight than the estimated path capacity.
+    fn queue_exists(&self) -> bool {
+        matches!(self.bdp(100), Some(bdp) if self.bytes_in_flight > bdp)
+    }
+
+    fn check_full_pipe(&mut self, round_start: bool) {
+        if self.filled_pipe || !round_start {
+            return;
+        }
+        let bw = self.btl_bw();
+        if bw * 100 >= self.full_bw * FULL_BW_GROWTH {
+            self.full_bw = bw;
+            self.full_bw_count = 0;
+            return;
+        }
+        self.full_bw_count += 1;
+        if self.full_bw_count >= FULL_BW_ROUNDS {
+            qinfo!([self], "Startup complete, bandwidth {}", bw);
+            self.filled_pipe = true;
+        }
+    }
+
+    fn check_drain(&mut self, now: Instant) {
+        if self.state == BbrState::Startup && self.filled_pipe {
+            qdebug!([self], "Entering Drain");
+            self.state = BbrState::Drain;
+        }
+        if self.state == BbrState::Drain && !self.queue_exists() {
+            self.enter_probe_bw(now);
+        }
+    }
+
+    fn enter_probe_bw(&mut self, now: Instant) {
+        qdebug!([self], "Entering ProbeBw");
+        self.state = BbrState::ProbeBw;
+        // Start at the end of the cycle so that the first step probes for bandwidth.
+        self.cycle_index = GAIN_CYCLE.len() - 1;
+        self.cycle_start = Some(now);
+    }
+
+    fn update_gain_cycle(&mut self, now: Instant) {
+        if self.state != BbrState::ProbeBw {
+            return;
+        }
+        let elapsed = self
+            .cycle_start
+            .map_or(Duration::from_secs(0), |t| now.saturating_duration_since(t));
+        let full_length = !matches!(self.min_rtt, Some((rtt, _)) if elapsed <= rtt);
+        let gain = GAIN_CYCLE[self.cycle_index];
+        // When draining, move on as soon as the queue is gone.
+        let drained = gain < 100 && !self.queue_exists();
+        if full_length || drained {
+            self.cycle_index = (self.cycle_index + 1) % GAIN_CYCLE.len();
+            self.cycle_start = Some(now);
+        }
+    }
+
+    fn check_probe_rtt(&mut self, now: Instant, round_start: bool, min_rtt_expired: bool) {
+        if self.state != BbrState::ProbeRtt && min_rtt_expired {
+            qdebug!([self], "Entering ProbeRtt");
+            self.state = BbrState::ProbeRtt;
+            self.prior_cwnd = self.congestion_window;
+            self.probe_rtt_done = None;
+        }
+        if self.state != BbrState::ProbeRtt {
+            return;
+        }
+
+        if let Some(done) = self.probe_rtt_done {
+            if round_start {
+                self.probe_rtt_round_done = true;
+            }
+            if self.probe_rtt_round_done && now >= done {
+                if let Some((rtt, _)) = self.min_rtt {
+                    self.min_rtt = Some((rtt, now));
+                }
+                self.congestion_window = max(self.congestion_window, self.prior_cwnd);
+                if self.filled_pipe {
+                    self.enter_probe_bw(now);
+                } else {
+                    qdebug!([self], "Entering Startup");
+                    self.state = BbrState::Startup;
+                }
+            }
+        } else if self.bytes_in_flight <= MIN_PIPE_CWND {
+            self.probe_rtt_done = Some(now + PROBE_RTT_DURATION);
+            self.probe_rtt_round_done = false;
+            self.next_round_delivered = self.delivered;
+        }
+    }
+
+    /// The gain applied to the congestion window in the current state.
+    fn cwnd_gain(&self) -> usize {
+        match self.state {
+            BbrState::Startup => HIGH_GAIN,
+            BbrState::Drain | BbrState::ProbeRtt => 100,
+            BbrState::ProbeBw => CWND_GAIN * GAIN_CYCLE[self.cycle_index] / 100,
+        }
+    }
+
+    fn set_cwnd(&mut self, acked_bytes: usize) {
+        if self.state == BbrState::ProbeRtt {
+            self.congestion_window = MIN_PIPE_CWND;
+            return;
+        }
+        let target = self.bdp(self.cwnd_gain()).map_or(self.initial_cwnd, |bdp| {
+            // Allow for a few packets to be held up by delayed acknowledgments.
+            bdp + 3 * MAX_DATAGRAM_SIZE
+        });
+        if self.filled_pipe {
+            self.congestion_window = min(self.congestion_window + acked_bytes, target);
+        } else if self.congestion_window < target || self.delivered < self.initial_cwnd {
+            self.congestion_window += acked_bytes;
+        }
+        self.congestion_window = max(self.congestion_window, MIN_PIPE_CWND);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Bbr, BbrState, MIN_PIPE_CWND};
+    use crate::cc::{CongestionControl, NewReno, MAX_DATAGRAM_SIZE};
+    use crate::packet::PacketType;
+    use crate::tracking::SentPacket;
+    use std::cmp::max;
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+    use std::time::Duration;
+    use test_fixture::now;
+
+    /// The round trip time of the simulated path.
+    const RTT: Duration = Duration::from_millis(100);
+    /// The time it takes to send a packet at the bottleneck rate, about 100Mbps.
+    const PACKET_TIME: Duration = Duration::from_micros(107);
+    /// The bandwidth-delay product of the path, in packets.
+    const BDP_PACKETS: usize = 934;
+    /// One in this many packets is lost.
+    const LOSS_INTERVAL: u64 = 200;
+    /// How long to run simulations for.
+    const SIMULATION_TIME: Duration = Duration::from_secs(5);
+
+    /// Drive a congestion controller over a path with high bandwidth and
+    /// high latency, and a small amount of random loss.  The sender sends
+    /// whenever the congestion window allows; packets queue at the bottleneck
+    /// and are acknowledged a round trip after they leave it.
+    fn simulate(cc: &mut dyn CongestionControl, duration: Duration) {
+        let start = now();
+        let mut t = start;
+        let mut link_free = start;
+        let mut pn = 0;
+        let mut in_flight = VecDeque::new();
+        let deliver = |cc: &mut dyn CongestionControl, pkt: SentPacket, ack_time| {
+            if pkt.pn % LOSS_INTERVAL == LOSS_INTERVAL - 1 {
+                cc.on_packets_lost(ack_time, Some(start), None, RTT * 2, &[pkt]);
+            } else {
+                cc.on_packets_acked(&[pkt], ack_time);
+            }
+        };
+
+        while t < start + duration {
+            while cc.cwnd_avail() >= MAX_DATAGRAM_SIZE {
+                let pkt = SentPacket::new(
+                    PacketType::Short,
+                    pn,
+                    t,
+                    true,
+                    Rc::default(),
+                    MAX_DATAGRAM_SIZE,
+                );
+                cc.on_packet_sent(&pkt);
+                link_free = max(t, link_free) + PACKET_TIME;
+                in_flight.push_back((pkt, link_free + RTT));
+                pn += 1;
+            }
+
+            let (pkt, ack_time) = in_flight.pop_front().unwrap();
+            t = ack_time;
+            deliver(cc, pkt, ack_time);
+        }
+
+        // Acknowledge anything that is left.
+        for (pkt, ack_time) in in_flight {
+            deliver(cc, pkt, ack_time);
+        }
+        assert_eq!(cc.bytes_in_flight(), 0);
+    }
+
+    #[test]
+    fn startup_to_probe_bw() {
+        let mut bbr = Bbr::default();
+        simulate(&mut bbr, SIMULATION_TIME);
+        assert!(bbr.filled_pipe);
+        assert_eq!(bbr.state, BbrState::ProbeBw);
+
+        // The estimates should be close to the real values.
+        let (min_rtt, _) = bbr.min_rtt.unwrap();
+        assert_eq!(min_rtt, RTT + PACKET_TIME);
+        let bdp = bbr.bdp(100).unwrap();
+        let expected = BDP_PACKETS * MAX_DATAGRAM_SIZE;
+        assert!(bdp > expected * 9 / 10 && bdp < expected * 11 / 10);
+    }
+
+    #[test]
+    fn bbr_beats_new_reno() {
+        let mut bbr = Bbr::default();
+        simulate(&mut bbr, SIMULATION_TIME);
+        let mut new_reno = NewReno::default();
+        simulate(&mut new_reno, SIMULATION_TIME);
+        assert!(bbr.cwnd() > BDP_PACKETS * MAX_DATAGRAM_SIZE);
+        assert!(bbr.cwnd() > new_reno.cwnd() * 2);
+    }
+
+    #[test]
+    fn probe_rtt() {
+        let mut bbr = Bbr::default();
+        simulate(&mut bbr, SIMULATION_TIME);
+        let cwnd = bbr.cwnd();
+
+        // After a long time without a better RTT sample, BBR probes.
+        let mut t = now() + Duration::from_secs(20);
+        let pkt = |pn, t| SentPacket::new(PacketType::Short, pn, t, true, Rc::default(), 100);
+        let p1 = pkt(1_000_000, t);
+        bbr.on_packet_sent(&p1);
+        t += RTT;
+        bbr.on_packets_acked(&[p1], t);
+        assert_eq!(bbr.state, BbrState::ProbeRtt);
+        assert_eq!(bbr.cwnd(), MIN_PIPE_CWND);
+
+        // ProbeRtt lasts for at least one round trip and 200ms.
+        let mut pn = 1_000_001;
+        while bbr.state == BbrState::ProbeRtt {
+            let p = pkt(pn, t);
+            bbr.on_packet_sent(&p);
+            t += RTT;
+            bbr.on_packets_acked(&[p], t);
+            pn += 1;
+        }
+        assert_eq!(bbr.state, BbrState::ProbeBw);
+        assert!(bbr.cwnd() >= cwnd);
+    }
+}
@@ -0,0 +1,94 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Congestion control
+#![deny(clippy::pedantic)]
+
+use std::fmt::{Debug, Display};
+use std::time::{Duration, Instant};
+
+use crate::path::PATH_MTU_V6;
+use crate::tracking::SentPacket;
+use neqo_common::{const_max, const_min, qlog::NeqoQlog};
+
+mod bbr;
+mod new_reno;
+
+pub use bbr::Bbr;
+pub use new_reno::NewReno;
+
+pub const MAX_DATAGRAM_SIZE: usize = PATH_MTU_V6;
+pub const CWND_INITIAL_PKTS: usize = 10;
+const CWND_INITIAL: usize = const_min(
+    CWND_INITIAL_PKTS * MAX_DATAGRAM_SIZE,
+    const_max(2 * MAX_DATAGRAM_SIZE, 14720),
+);
+pub const CWND_MIN: usize = MAX_DATAGRAM_SIZE * 2;
+/// The largest initial congestion window that can be set, in packets.
+pub const CWND_INITIAL_MAX_PKTS: usize = 100;
+/// The number of packets we allow to burst from the pacer.
+pub(crate) const PACING_BURST_SIZE: usize = 2;
+
+/// A congestion controller.  Loss recovery tells the controller about each
+/// packet as it is sent, acknowledged, lost, or discarded; in return, the
+/// controller determines how many bytes can be in flight.
+pub trait CongestionControl: Display + Debug {
+    /// Set the qlog that the controller records its state to.
+    fn set_qlog(&mut self, _qlog: NeqoQlog) {}
+
+    /// The congestion window, in bytes.
+    #[must_use]
+    fn cwnd(&self) -> usize;
+
+    /// Start with a congestion window of `cwnd` bytes instead of the default.
+    /// This is only used before any packets are sent.  Controllers that don't
+    /// have an initial window can ignore it.
+    fn set_initial_cwnd(&mut self, _cwnd: usize) {}
+
+    /// The number of bytes that are counted against the congestion window.
+    #[must_use]
+    fn bytes_in_flight(&self) -> usize;
+
+    /// The slow start threshold, for those controllers that have one.
+    #[must_use]
+    fn ssthresh(&self) -> usize {
+        usize::MAX
+    }
+
+    /// The number of bytes that can be sent before the window is full.
+    #[must_use]
+    fn cwnd_avail(&self) -> usize {
+        // BIF can be higher than cwnd due to PTO packets, which are sent even
+        // if avail is 0, but still count towards BIF.
+        self.cwnd().saturating_sub(self.bytes_in_flight())
+    }
+
+    /// A packet was sent.
+    fn on_packet_sent(&mut self, pkt: &SentPacket);
+
+    /// Packets were acknowledged.  `now` is when the acknowledgment arrived.
+    fn on_packets_acked(&mut self, acked_pkts: &[SentPacket], now: Instant);
+
+    /// Packets were declared lost.  The other arguments are the time of the
+    /// first RTT sample, the time that the largest acknowledged packet was
+    /// sent before this loss was detected, and the PTO period, which can be
+    /// used to detect persistent congestion.
+    fn on_packets_lost(
+        &mut self,
+        now: Instant,
+        first_rtt_sample_time: Option<Instant>,
+        prev_largest_acked_sent: Option<Instant>,
+        pto: Duration,
+        lost_packets: &[SentPacket],
+    );
+
+    /// Respond to a signal of congestion for a packet that was sent at `sent_time`.
+    fn on_congestion_event(&mut self, now: Instant, sent_time: Instant);
+
+    /// A packet is no longer tracked, without being either acknowledged or
+    /// lost. This happens when keys are discarded, for instance.
+    fn discard(&mut self, pkt: &SentPacket);
+}
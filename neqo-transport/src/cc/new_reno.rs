@@ -4,45 +4,36 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-// Congestion control
-#![deny(clippy::pedantic)]
+// NewReno congestion control
 
 use std::cmp::{max, min};
 use std::fmt::{self, Display};
 use std::time::{Duration, Instant};
 
-use crate::pace::Pacer;
-use crate::path::PATH_MTU_V6;
+use super::{CongestionControl, CWND_INITIAL, CWND_MIN, MAX_DATAGRAM_SIZE};
 use crate::qlog::{self, CongestionState, QlogMetric};
 use crate::tracking::SentPacket;
-use neqo_common::{const_max, const_min, qdebug, qinfo, qlog::NeqoQlog, qtrace};
-
-pub const MAX_DATAGRAM_SIZE: usize = PATH_MTU_V6;
-pub const CWND_INITIAL_PKTS: usize = 10;
-const CWND_INITIAL: usize = const_min(
-    CWND_INITIAL_PKTS * MAX_DATAGRAM_SIZE,
-    const_max(2 * MAX_DATAGRAM_SIZE, 14720),
-);
-pub const CWND_MIN: usize = MAX_DATAGRAM_SIZE * 2;
-/// The number of packets we allow to burst from the pacer.
-pub(crate) const PACING_BURST_SIZE: usize = 2;
+use neqo_common::{qdebug, qinfo, qlog::NeqoQlog, qtrace};
+
 const PERSISTENT_CONG_THRESH: u32 = 3;
 
+/// The `NewReno` congestion controller, as described in RFC 6582 and in the
+/// QUIC recovery specification.  This is the default.
 #[derive(Debug)]
-pub struct CongestionControl {
+#[allow(clippy::module_name_repetitions)]
+pub struct NewReno {
     congestion_window: usize, // = kInitialWindow
     bytes_in_flight: usize,
     acked_bytes: usize,
     congestion_recovery_start_time: Option<Instant>,
     ssthresh: usize,
-    pacer: Option<Pacer>,
     in_recovery: bool,
 
     qlog: NeqoQlog,
     qlog_curr_cong_state: CongestionState,
 }
 
-impl Default for CongestionControl {
+impl Default for NewReno {
     fn default() -> Self {
         Self {
             congestion_window: CWND_INITIAL,
@@ -50,7 +41,6 @@ impl Default for CongestionControl {
             acked_bytes: 0,
             congestion_recovery_start_time: None,
             ssthresh: usize::MAX,
-            pacer: None,
             in_recovery: false,
             qlog: NeqoQlog::disabled(),
             qlog_curr_cong_state: CongestionState::SlowStart,
@@ -58,52 +48,40 @@ impl Default for CongestionControl {
     }
 }
 
-impl Display for CongestionControl {
+impl Display for NewReno {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "CongCtrl {}/{} ssthresh {}",
+            "NewReno {}/{} ssthresh {}",
             self.bytes_in_flight, self.congestion_window, self.ssthresh,
-        )?;
-        if let Some(p) = &self.pacer {
-            write!(f, " {}", p)?;
-        }
-        Ok(())
+        )
     }
 }
 
-impl CongestionControl {
-    pub fn set_qlog(&mut self, qlog: NeqoQlog) {
+impl CongestionControl for NewReno {
+    fn set_qlog(&mut self, qlog: NeqoQlog) {
         self.qlog = qlog;
     }
 
-    #[cfg(test)]
-    #[must_use]
-    pub fn cwnd(&self) -> usize {
+    fn cwnd(&self) -> usize {
         self.congestion_window
     }
 
-    #[cfg(test)]
-    #[must_use]
-    pub fn ssthresh(&self) -> usize {
-        self.ssthresh
+    fn set_initial_cwnd(&mut self, cwnd: usize) {
+        debug_assert_eq!(self.bytes_in_flight, 0);
+        self.congestion_window = cwnd;
     }
 
-    #[cfg(test)]
-    #[must_use]
-    pub fn bif(&self) -> usize {
+    fn bytes_in_flight(&self) -> usize {
         self.bytes_in_flight
     }
 
-    #[must_use]
-    pub fn cwnd_avail(&self) -> usize {
-        // BIF can be higher than cwnd due to PTO packets, which are sent even
-        // if avail is 0, but still count towards BIF.
-        self.congestion_window.saturating_sub(self.bytes_in_flight)
+    fn ssthresh(&self) -> usize {
+        self.ssthresh
     }
 
     // Multi-packet version of OnPacketAckedCC
-    pub fn on_packets_acked(&mut self, acked_pkts: &[SentPacket]) {
+    fn on_packets_acked(&mut self, acked_pkts: &[SentPacket], _now: Instant) {
         for pkt in acked_pkts.iter().filter(|pkt| pkt.cc_outstanding()) {
             assert!(self.bytes_in_flight >= pkt.size);
             self.bytes_in_flight -= pkt.size;
@@ -165,59 +143,7 @@ impl CongestionControl {
         );
     }
 
-    fn detect_persistent_congestion(
-        &mut self,
-        first_rtt_sample_time: Option<Instant>,
-        prev_largest_acked_sent: Option<Instant>,
-        pto: Duration,
-        lost_packets: &[SentPacket],
-    ) {
-        if first_rtt_sample_time.is_none() {
-            return;
-        }
-
-        let pc_period = pto * PERSISTENT_CONG_THRESH;
-
-        let mut last_pn = 1 << 62; // Impossibly large, but not enough to overflow.
-        let mut start = None;
-
-        // Look for the first lost packet after the previous largest acknowledged.
-        // Ignore packets that weren't ack-eliciting for the start of this range.
-        // Also, make sure to ignore any packets sent before we got an RTT estimate
-        // as we might not have sent PTO packets soon enough after those.
-        let cutoff = max(first_rtt_sample_time, prev_largest_acked_sent);
-        for p in lost_packets
-            .iter()
-            .skip_while(|p| Some(p.time_sent) < cutoff)
-        {
-            if p.pn != last_pn + 1 {
-                // Not a contiguous range of lost packets, start over.
-                start = None;
-            }
-            last_pn = p.pn;
-            if !p.ack_eliciting() {
-                // Not interesting, keep looking.
-                continue;
-            }
-            if let Some(t) = start {
-                if p.time_sent.duration_since(t) > pc_period {
-                    // In persistent congestion.  Stop.
-                    self.congestion_window = CWND_MIN;
-                    self.acked_bytes = 0;
-                    qlog::metrics_updated(
-                        &mut self.qlog,
-                        &[QlogMetric::CongestionWindow(self.congestion_window)],
-                    );
-                    qinfo!([self], "persistent congestion");
-                    return;
-                }
-            } else {
-                start = Some(p.time_sent);
-            }
-        }
-    }
-
-    pub fn on_packets_lost(
+    fn on_packets_lost(
         &mut self,
         now: Instant,
         first_rtt_sample_time: Option<Instant>,
@@ -240,17 +166,19 @@ impl CongestionControl {
 
         qdebug!([self], "Pkts lost {}", lost_packets.len());
 
-        let last_lost_pkt = lost_packets.last().unwrap();
-        self.on_congestion_event(now, last_lost_pkt.time_sent);
-        self.detect_persistent_congestion(
-            first_rtt_sample_time,
-            prev_largest_acked_sent,
-            pto,
-            lost_packets,
-        );
+        // The loss of a PMTUD probe is not a sign of congestion.
+        if let Some(last_lost_pkt) = lost_packets.iter().rev().find(|p| !p.is_pmtud_probe()) {
+            self.on_congestion_event(now, last_lost_pkt.time_sent);
+            self.detect_persistent_congestion(
+                first_rtt_sample_time,
+                prev_largest_acked_sent,
+                pto,
+                lost_packets,
+            );
+        }
     }
 
-    pub fn discard(&mut self, pkt: &SentPacket) {
+    fn discard(&mut self, pkt: &SentPacket) {
         if pkt.cc_outstanding() {
             assert!(self.bytes_in_flight >= pkt.size);
             self.bytes_in_flight -= pkt.size;
@@ -262,12 +190,7 @@ impl CongestionControl {
         }
     }
 
-    pub fn on_packet_sent(&mut self, pkt: &SentPacket, rtt: Duration) {
-        self.pacer
-            .as_mut()
-            .unwrap()
-            .spend(pkt.time_sent, rtt, self.congestion_window, pkt.size);
-
+    fn on_packet_sent(&mut self, pkt: &SentPacket) {
         if !pkt.ack_eliciting() {
             return;
         }
@@ -286,14 +209,6 @@ impl CongestionControl {
         );
     }
 
-    #[must_use]
-    fn after_recovery_start(&mut self, sent_time: Instant) -> bool {
-        match self.congestion_recovery_start_time {
-            Some(crst) => sent_time > crst,
-            None => true,
-        }
-    }
-
     fn on_congestion_event(&mut self, now: Instant, sent_time: Instant) {
         // Start a new congestion event if lost packet was sent after the start
         // of the previous congestion recovery period.
@@ -325,40 +240,80 @@ impl CongestionControl {
             );
         }
     }
+}
+
+impl NewReno {
+    fn detect_persistent_congestion(
+        &mut self,
+        first_rtt_sample_time: Option<Instant>,
+        prev_largest_acked_sent: Option<Instant>,
+        pto: Duration,
+        lost_packets: &[SentPacket],
+    ) {
+        if first_rtt_sample_time.is_none() {
+            return;
+        }
+
+        let pc_period = pto * PERSISTENT_CONG_THRESH;
+
+        let mut last_pn = 1 << 62; // Impossibly large, but not enough to overflow.
+        let mut start = None;
+
+        // Look for the first lost packet after the previous largest acknowledged.
+        // Ignore packets that weren't ack-eliciting for the start of this range.
+        // Also, make sure to ignore any packets sent before we got an RTT estimate
+        // as we might not have sent PTO packets soon enough after those.
+        let cutoff = max(first_rtt_sample_time, prev_largest_acked_sent);
+        for p in lost_packets
+            .iter()
+            .skip_while(|p| Some(p.time_sent) < cutoff)
+        {
+            if p.pn != last_pn + 1 {
+                // Not a contiguous range of lost packets, start over.
+                start = None;
+            }
+            last_pn = p.pn;
+            if !p.ack_eliciting() {
+                // Not interesting, keep looking.
+                continue;
+            }
+            if let Some(t) = start {
+                if p.time_sent.duration_since(t) > pc_period {
+                    // In persistent congestion.  Stop.
+                    self.congestion_window = CWND_MIN;
+                    self.acked_bytes = 0;
+                    qlog::metrics_updated(
+                        &mut self.qlog,
+                        &[QlogMetric::CongestionWindow(self.congestion_window)],
+                    );
+                    qinfo!([self], "persistent congestion");
+                    return;
+                }
+            } else {
+                start = Some(p.time_sent);
+            }
+        }
+    }
+
+    #[must_use]
+    fn after_recovery_start(&mut self, sent_time: Instant) -> bool {
+        match self.congestion_recovery_start_time {
+            Some(crst) => sent_time > crst,
+            None => true,
+        }
+    }
 
     #[allow(clippy::unused_self)]
     fn app_limited(&self) -> bool {
         //TODO(agrover): how do we get this info??
         false
     }
-
-    pub fn start_pacer(&mut self, now: Instant) {
-        // Start the pacer with a small burst size.
-        self.pacer = Some(Pacer::new(
-            now,
-            MAX_DATAGRAM_SIZE * PACING_BURST_SIZE,
-            MAX_DATAGRAM_SIZE,
-        ));
-    }
-
-    pub fn next_paced(&self, rtt: Duration) -> Option<Instant> {
-        // Only pace if there are bytes in flight.
-        if self.bytes_in_flight > 0 {
-            Some(
-                self.pacer
-                    .as_ref()
-                    .unwrap()
-                    .next(rtt, self.congestion_window),
-            )
-        } else {
-            None
-        }
-    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::cc::{CongestionControl, CWND_INITIAL, CWND_MIN, PERSISTENT_CONG_THRESH};
+    use super::{NewReno, PERSISTENT_CONG_THRESH};
+    use crate::cc::{CongestionControl, CWND_INITIAL, CWND_MIN};
     use crate::packet::{PacketNumber, PacketType};
     use crate::tracking::SentPacket;
     use std::convert::TryFrom;
@@ -379,15 +334,13 @@ mod tests {
 
     #[test]
     fn issue_876() {
-        let mut cc = CongestionControl::default();
+        let mut cc = NewReno::default();
         let time_now = now();
         let time_before = time_now - Duration::from_millis(100);
         let time_after1 = time_now + Duration::from_millis(100);
         let time_after2 = time_now + Duration::from_millis(150);
         let time_after3 = time_now + Duration::from_millis(175);
 
-        cc.start_pacer(time_now);
-
         let sent_packets = vec![
             SentPacket::new(
                 PacketType::Short,
@@ -415,17 +368,17 @@ mod tests {
             ),
         ];
 
-        cc.on_packet_sent(&sent_packets[0], RTT);
+        cc.on_packet_sent(&sent_packets[0]);
         assert_eq!(cc.acked_bytes, 0);
         assert_eq!(cc.cwnd(), CWND_INITIAL);
         assert_eq!(cc.ssthresh(), usize::MAX);
-        assert_eq!(cc.bif(), 103);
+        assert_eq!(cc.bytes_in_flight(), 103);
 
-        cc.on_packet_sent(&sent_packets[1], RTT);
+        cc.on_packet_sent(&sent_packets[1]);
         assert_eq!(cc.acked_bytes, 0);
         assert_eq!(cc.cwnd(), CWND_INITIAL);
         assert_eq!(cc.ssthresh(), usize::MAX);
-        assert_eq!(cc.bif(), 208);
+        assert_eq!(cc.bytes_in_flight(), 208);
 
         cc.on_packets_lost(time_after1, Some(time_now), None, PTO, &sent_packets[0..1]);
 
@@ -433,28 +386,28 @@ mod tests {
         assert_eq!(cc.acked_bytes, 0);
         assert_eq!(cc.cwnd(), CWND_INITIAL / 2);
         assert_eq!(cc.ssthresh(), CWND_INITIAL / 2);
-        assert_eq!(cc.bif(), 105);
+        assert_eq!(cc.bytes_in_flight(), 105);
 
         // Send a packet after recovery starts
-        cc.on_packet_sent(&sent_packets[2], RTT);
+        cc.on_packet_sent(&sent_packets[2]);
         assert_eq!(cc.acked_bytes, 0);
         assert_eq!(cc.cwnd(), CWND_INITIAL / 2);
         assert_eq!(cc.ssthresh(), CWND_INITIAL / 2);
-        assert_eq!(cc.bif(), 212);
+        assert_eq!(cc.bytes_in_flight(), 212);
 
         // and ack it. cwnd increases slightly
-        cc.on_packets_acked(&sent_packets[2..3]);
+        cc.on_packets_acked(&sent_packets[2..3], time_now);
         assert_eq!(cc.acked_bytes, sent_packets[2].size);
         assert_eq!(cc.cwnd(), CWND_INITIAL / 2);
         assert_eq!(cc.ssthresh(), CWND_INITIAL / 2);
-        assert_eq!(cc.bif(), 105);
+        assert_eq!(cc.bytes_in_flight(), 105);
 
         // Packet from before is lost. Should not hurt cwnd.
         cc.on_packets_lost(time_after3, Some(time_now), None, PTO, &sent_packets[1..2]);
         assert_eq!(cc.acked_bytes, sent_packets[2].size);
         assert_eq!(cc.cwnd(), CWND_INITIAL / 2);
         assert_eq!(cc.ssthresh(), CWND_INITIAL / 2);
-        assert_eq!(cc.bif(), 0);
+        assert_eq!(cc.bytes_in_flight(), 0);
     }
 
     fn lost(pn: PacketNumber, ack_eliciting: bool, t: Duration) -> SentPacket {
@@ -469,10 +422,9 @@ mod tests {
     }
 
     fn persistent_congestion(lost_packets: &[SentPacket]) -> bool {
-        let mut cc = CongestionControl::default();
-        cc.start_pacer(now());
+        let mut cc = NewReno::default();
         for p in lost_packets {
-            cc.on_packet_sent(p, RTT);
+            cc.on_packet_sent(p);
         }
 
         cc.on_packets_lost(now(), Some(now()), None, PTO, lost_packets);
@@ -646,7 +598,7 @@ mod tests {
     /// `last_ack` and `rtt_time` are times in multiples of `PTO`, relative to `now()`,
     /// for the time of the largest acknowledged and the first RTT sample, respectively.
     fn persistent_congestion_by_pto(last_ack: u32, rtt_time: u32, lost: &[SentPacket]) -> bool {
-        let mut cc = CongestionControl::default();
+        let mut cc = NewReno::default();
         assert_eq!(cc.cwnd(), CWND_INITIAL);
 
         let last_ack = Some(by_pto(last_ack));
@@ -717,7 +669,7 @@ mod tests {
     #[test]
     fn persistent_congestion_no_prev_ack() {
         let lost = make_lost(&[1, PERSISTENT_CONG_THRESH + 2]);
-        let mut cc = CongestionControl::default();
+        let mut cc = NewReno::default();
         cc.detect_persistent_congestion(Some(by_pto(0)), None, PTO, &lost);
         assert_eq!(cc.cwnd(), CWND_MIN);
     }
@@ -7,11 +7,18 @@
 // Encoding and decoding packets off the wire.
 
 use neqo_common::{hex, hex_with_len, Decoder};
-use neqo_crypto::random;
+use neqo_crypto::{
+    constants::{TLS_AES_128_GCM_SHA256, TLS_VERSION_1_3},
+    hkdf, random, SymKey,
+};
 
 use std::borrow::Borrow;
 use std::cmp::max;
-use std::convert::AsRef;
+use std::convert::{AsRef, TryFrom};
+
+use crate::frame::Frame;
+use crate::recovery::RecoveryToken;
+use crate::{Error, Res};
 
 pub const MAX_CONNECTION_ID_LEN: usize = 20;
 
@@ -137,6 +144,310 @@ pub trait ConnectionIdDecoder {
 pub trait ConnectionIdManager: ConnectionIdDecoder {
     fn generate_cid(&mut self) -> ConnectionId;
     fn as_decoder(&self) -> &dyn ConnectionIdDecoder;
+    /// The peer retired `cid`, so packets that use it no longer belong to the
+    /// connection that it was generated for.
+    fn retire_cid(&mut self, _cid: &ConnectionIdRef) {}
+}
+
+/// The size of a stateless reset token.
+pub const STATELESS_RESET_TOKEN_LEN: usize = 16;
+
+/// Generates stateless reset tokens for connection IDs.  Each token is the
+/// output of HKDF-Expand, keyed by a static secret, over the connection ID, so
+/// a server can produce the token for a connection after it has lost all other
+/// state for that connection.  Servers that share a key produce the same token
+/// for the same connection ID.
+pub struct StatelessResetTokens {
+    secret: SymKey,
+}
+
+impl StatelessResetTokens {
+    /// Create a generator with a random key.
+    /// # Errors
+    /// When NSS is unable to generate a key.
+    pub fn new() -> Res<Self> {
+        let key = hkdf::generate_key(TLS_VERSION_1_3, TLS_AES_128_GCM_SHA256)?;
+        Self::with_key(&key)
+    }
+
+    /// Create a generator from the given key material.
+    /// # Errors
+    /// When NSS is unable to import the key.
+    pub fn from_bytes(key: &[u8]) -> Res<Self> {
+        let key = hkdf::import_key(TLS_VERSION_1_3, TLS_AES_128_GCM_SHA256, key)?;
+        Self::with_key(&key)
+    }
+
+    fn with_key(key: &SymKey) -> Res<Self> {
+        let secret = hkdf::extract(TLS_VERSION_1_3, TLS_AES_128_GCM_SHA256, None, key)?;
+        Ok(Self { secret })
+    }
+
+    /// Generate the stateless reset token for `cid`.
+    pub fn token(&self, cid: &[u8]) -> [u8; STATELESS_RESET_TOKEN_LEN] {
+        // The connection ID is the context for HKDF-Expand-Label.
+        let key = hkdf::expand_label(
+            TLS_VERSION_1_3,
+            TLS_AES_128_GCM_SHA256,
+            &self.secret,
+            cid,
+            "neqo reset",
+        )
+        .expect("able to generate a reset token");
+        let bytes = key.as_bytes().expect("able to read a reset token");
+        <[u8; STATELESS_RESET_TOKEN_LEN]>::try_from(&bytes[..STATELESS_RESET_TOKEN_LEN]).unwrap()
+    }
+}
+
+impl ::std::fmt::Debug for StatelessResetTokens {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "StatelessResetTokens")
+    }
+}
+
+/// A connection ID, with its sequence number and stateless reset token.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConnectionIdEntry {
+    seqno: u64,
+    cid: ConnectionId,
+    srt: [u8; STATELESS_RESET_TOKEN_LEN],
+}
+
+impl ConnectionIdEntry {
+    pub fn new(seqno: u64, cid: ConnectionId, srt: [u8; STATELESS_RESET_TOKEN_LEN]) -> Self {
+        Self { seqno, cid, srt }
+    }
+
+    pub fn seqno(&self) -> u64 {
+        self.seqno
+    }
+
+    pub fn connection_id(&self) -> &ConnectionId {
+        &self.cid
+    }
+
+    pub fn reset_token(&self) -> &[u8; STATELESS_RESET_TOKEN_LEN] {
+        &self.srt
+    }
+
+    /// Whether `other` reuses the sequence number or connection ID of this
+    /// entry with different values, which a peer isn't allowed to do.
+    pub fn conflicts(&self, other: &Self) -> bool {
+        (self.seqno == other.seqno || self.cid == other.cid) && self != other
+    }
+}
+
+/// The connection IDs that this endpoint has issued to its peer and that the
+/// peer hasn't retired.  The first is the one used during the handshake.
+#[derive(Debug)]
+pub struct LocalConnectionIds {
+    cids: Vec<ConnectionIdEntry>,
+    next_seqno: u64,
+    /// The sequence numbers of connection IDs that need a NEW_CONNECTION_ID frame.
+    to_send: Vec<u64>,
+}
+
+impl LocalConnectionIds {
+    pub fn new(initial: ConnectionId) -> Self {
+        Self {
+            // The token for the handshake connection ID is never sent in a frame.
+            cids: vec![ConnectionIdEntry::new(
+                0,
+                initial,
+                [0; STATELESS_RESET_TOKEN_LEN],
+            )],
+            next_seqno: 1,
+            to_send: Vec::new(),
+        }
+    }
+
+    /// The number of connection IDs that the peer can use.
+    pub fn len(&self) -> usize {
+        self.cids.len()
+    }
+
+    /// Determine whether `cid` is one that the peer can use.
+    pub fn contains(&self, cid: &ConnectionIdRef) -> bool {
+        self.cids.iter().any(|e| e.cid == *cid)
+    }
+
+    /// Issue a new connection ID.
+    pub fn add(&mut self, cid: ConnectionId, srt: [u8; STATELESS_RESET_TOKEN_LEN]) {
+        let seqno = self.next_seqno;
+        self.next_seqno += 1;
+        self.cids.push(ConnectionIdEntry::new(seqno, cid, srt));
+        self.to_send.push(seqno);
+    }
+
+    /// The peer retired a connection ID.  This returns the connection ID, unless
+    /// it was retired already.
+    /// # Errors
+    /// `ProtocolViolation` if the connection ID was never issued.
+    pub fn retire(&mut self, seqno: u64) -> Res<Option<ConnectionId>> {
+        if seqno >= self.next_seqno {
+            return Err(Error::ProtocolViolation);
+        }
+        self.to_send.retain(|&s| s != seqno);
+        let i = self.cids.iter().position(|e| e.seqno == seqno);
+        Ok(i.map(|i| self.cids.remove(i).cid))
+    }
+
+    /// Write a NEW_CONNECTION_ID frame, if one is needed.
+    pub fn get_frame(&mut self, remaining: usize) -> Option<(Frame, Option<RecoveryToken>)> {
+        let seqno = *self.to_send.first()?;
+        let entry = self.cids.iter().find(|e| e.seqno == seqno).unwrap();
+        // A one byte type, a sequence number, a zero Retire Prior To,
+        // the connection ID, and the token.
+        let len = 1 + 8 + 1 + 1 + entry.cid.len() + STATELESS_RESET_TOKEN_LEN;
+        if remaining < len {
+            return None;
+        }
+        self.to_send.remove(0);
+        Some((
+            Frame::NewConnectionId {
+                sequence_number: seqno,
+                retire_prior: 0,
+                connection_id: entry.cid.to_vec(),
+                stateless_reset_token: entry.srt,
+            },
+            Some(RecoveryToken::NewConnectionId(seqno)),
+        ))
+    }
+
+    /// A NEW_CONNECTION_ID frame was lost, so send it again if the
+    /// connection ID is still active.
+    pub fn lost(&mut self, seqno: u64) {
+        if self.cids.iter().any(|e| e.seqno == seqno) && !self.to_send.contains(&seqno) {
+            self.to_send.push(seqno);
+        }
+    }
+}
+
+/// The smallest number of retirements that can be outstanding at once.  This is
+/// twice the default value of the active_connection_id_limit transport parameter.
+const MIN_RETIRING: usize = 4;
+
+/// Connection IDs that the peer has provided, but that aren't in use yet.
+/// This also tracks connection IDs that need to be retired.
+#[derive(Debug, Default)]
+pub struct RemoteConnectionIds {
+    spare: Vec<ConnectionIdEntry>,
+    /// The largest Retire Prior To value that the peer has sent.
+    retire_prior: u64,
+    /// The sequence numbers that need a RETIRE_CONNECTION_ID frame.
+    to_retire: Vec<u64>,
+    /// The sequence numbers in RETIRE_CONNECTION_ID frames that aren't acknowledged yet.
+    retiring: Vec<u64>,
+    /// The active_connection_id_limit transport parameter that this endpoint sent.
+    active_limit: usize,
+}
+
+impl RemoteConnectionIds {
+    /// Add a connection ID from a NEW_CONNECTION_ID frame.  Any that have
+    /// a sequence number less than `retire_prior` are retired.
+    /// # Errors
+    /// `ProtocolViolation` if the frame reuses the sequence number or
+    /// connection ID of one that is available, with different values.
+    pub fn add(&mut self, entry: ConnectionIdEntry, retire_prior: u64) -> Res<()> {
+        if self.spare.iter().any(|e| e.conflicts(&entry)) {
+            return Err(Error::ProtocolViolation);
+        }
+        if entry.seqno < self.retire_prior {
+            // This was already retired, but the frame was late.
+            self.retire(entry.seqno)?;
+        } else if self.spare.iter().all(|e| e.seqno != entry.seqno) {
+            self.spare.push(entry);
+        }
+        self.retire_prior_to(retire_prior)
+    }
+
+    /// Retire any connection IDs that have a sequence number less than `retire_prior`.
+    /// # Errors
+    /// `ConnectionIdLimitError` if too many retirements are outstanding.
+    pub fn retire_prior_to(&mut self, retire_prior: u64) -> Res<()> {
+        if retire_prior > self.retire_prior {
+            self.retire_prior = retire_prior;
+            let (retired, spare) = self.spare.drain(..).partition(|e| e.seqno < retire_prior);
+            self.spare = spare;
+            for e in retired {
+                self.retire(e.seqno)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Set the active_connection_id_limit transport parameter that this endpoint
+    /// sent.  Twice this many retirements can be outstanding.
+    pub fn set_active_limit(&mut self, limit: usize) {
+        self.active_limit = limit;
+    }
+
+    /// Connection IDs with sequence numbers lower than this must not be used.
+    pub fn retire_prior(&self) -> u64 {
+        self.retire_prior
+    }
+
+    /// The number of connection IDs that are available.
+    pub fn len(&self) -> usize {
+        self.spare.len()
+    }
+
+    /// Take the oldest available connection ID for use.
+    pub fn take(&mut self) -> Option<ConnectionIdEntry> {
+        let (i, _) = self.spare.iter().enumerate().min_by_key(|(_, e)| e.seqno)?;
+        Some(self.spare.remove(i))
+    }
+
+    /// The stateless reset tokens for the connection IDs that are available.
+    pub fn reset_tokens(&self) -> impl Iterator<Item = &[u8; STATELESS_RESET_TOKEN_LEN]> {
+        self.spare.iter().map(|e| &e.srt)
+    }
+
+    /// Tell the peer that a connection ID won't be used any more.  A peer that
+    /// makes this endpoint retire connection IDs faster than it acknowledges the
+    /// RETIRE_CONNECTION_ID frames could make it keep any number of them, so
+    /// this is limited.
+    /// # Errors
+    /// `ConnectionIdLimitError` if too many retirements are outstanding.
+    pub fn retire(&mut self, seqno: u64) -> Res<()> {
+        if self.to_retire.contains(&seqno) || self.retiring.contains(&seqno) {
+            return Ok(());
+        }
+        if self.to_retire.len() + self.retiring.len() >= max(MIN_RETIRING, 2 * self.active_limit) {
+            return Err(Error::ConnectionIdLimitError);
+        }
+        self.to_retire.push(seqno);
+        Ok(())
+    }
+
+    /// Write a RETIRE_CONNECTION_ID frame, if one is needed.
+    pub fn get_frame(&mut self, remaining: usize) -> Option<(Frame, Option<RecoveryToken>)> {
+        // A one byte type and a sequence number.
+        if self.to_retire.is_empty() || remaining < 9 {
+            return None;
+        }
+        let seqno = self.to_retire.remove(0);
+        self.retiring.push(seqno);
+        Some((
+            Frame::RetireConnectionId {
+                sequence_number: seqno,
+            },
+            Some(RecoveryToken::RetireConnectionId(seqno)),
+        ))
+    }
+
+    /// A RETIRE_CONNECTION_ID frame was acknowledged.
+    pub fn acked(&mut self, seqno: u64) {
+        self.retiring.retain(|&s| s != seqno);
+    }
+
+    /// A RETIRE_CONNECTION_ID frame was lost, so send it again.
+    pub fn lost(&mut self, seqno: u64) {
+        if let Some(i) = self.retiring.iter().position(|&s| s == seqno) {
+            self.retiring.remove(i);
+            self.to_retire.push(seqno);
+        }
+    }
 }
 
 #[cfg(test)]
@@ -154,4 +465,119 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn reset_tokens() {
+        fixture_init();
+        let key = [7; 16];
+        let a = StatelessResetTokens::from_bytes(&key).unwrap();
+        let b = StatelessResetTokens::from_bytes(&key).unwrap();
+        assert_eq!(a.token(&[1, 2, 3]), b.token(&[1, 2, 3]));
+        assert_ne!(a.token(&[1, 2, 3]), a.token(&[1, 2, 4]));
+        let c = StatelessResetTokens::new().unwrap();
+        assert_ne!(a.token(&[1, 2, 3]), c.token(&[1, 2, 3]));
+    }
+
+    fn entry(seqno: u64) -> ConnectionIdEntry {
+        ConnectionIdEntry::new(seqno, ConnectionId::generate(8), [seqno as u8; 16])
+    }
+
+    fn retired(remote: &mut RemoteConnectionIds) -> Vec<u64> {
+        let mut seqnos = Vec::new();
+        while let Some((Frame::RetireConnectionId { sequence_number }, _)) = remote.get_frame(100) {
+            seqnos.push(sequence_number);
+        }
+        seqnos
+    }
+
+    #[test]
+    fn local_issue_and_retire() {
+        fixture_init();
+        let initial = ConnectionId::generate(8);
+        let mut local = LocalConnectionIds::new(initial.clone());
+        let cid = ConnectionId::generate(8);
+        local.add(cid.clone(), [1; 16]);
+        assert_eq!(local.len(), 2);
+        assert!(local.contains(&cid.as_cid_ref()));
+
+        // The frame is sent again if it is lost, but only once.
+        assert!(local.get_frame(10).is_none());
+        assert!(local.get_frame(100).is_some());
+        local.lost(1);
+        local.lost(1);
+        assert!(local.get_frame(100).is_some());
+        assert!(local.get_frame(100).is_none());
+
+        assert_eq!(local.retire(0), Ok(Some(initial.clone())));
+        assert!(!local.contains(&initial.as_cid_ref()));
+        assert_eq!(local.retire(0), Ok(None));
+        assert_eq!(local.retire(2), Err(Error::ProtocolViolation));
+        // A retired connection ID isn't sent again.
+        assert_eq!(local.retire(1), Ok(Some(cid)));
+        local.lost(1);
+        assert!(local.get_frame(100).is_none());
+        assert_eq!(local.len(), 0);
+    }
+
+    #[test]
+    fn remote_retire_prior() {
+        fixture_init();
+        let mut remote = RemoteConnectionIds::default();
+        remote.add(entry(1), 0).unwrap();
+        let two = entry(2);
+        remote.add(two.clone(), 0).unwrap();
+        remote.add(two, 0).unwrap();
+        assert_eq!(remote.len(), 2);
+        assert!(retired(&mut remote).is_empty());
+
+        remote.add(entry(3), 2).unwrap();
+        assert_eq!(remote.retire_prior(), 2);
+        assert_eq!(retired(&mut remote), vec![1]);
+        // A late frame for a retired connection ID is retired immediately.
+        remote.add(entry(0), 0).unwrap();
+        assert_eq!(retired(&mut remote), vec![0]);
+
+        assert_eq!(remote.take().unwrap().seqno(), 2);
+        assert_eq!(remote.take().unwrap().seqno(), 3);
+        assert!(remote.take().is_none());
+    }
+
+    #[test]
+    fn remote_retire_limit() {
+        fixture_init();
+        let mut remote = RemoteConnectionIds::default();
+        remote.set_active_limit(2);
+        for seqno in 0..4 {
+            remote.retire(seqno).unwrap();
+        }
+        // Retiring the same connection ID again doesn't count.
+        remote.retire(3).unwrap();
+        assert_eq!(remote.retire(4), Err(Error::ConnectionIdLimitError));
+
+        // Sending the frames isn't enough; they have to be acknowledged.
+        assert_eq!(retired(&mut remote), vec![0, 1, 2, 3]);
+        assert_eq!(remote.retire(4), Err(Error::ConnectionIdLimitError));
+        remote.lost(0);
+        remote.acked(1);
+        remote.retire(4).unwrap();
+        assert_eq!(retired(&mut remote), vec![0, 4]);
+        // A frame that was acknowledged isn't sent again.
+        remote.lost(1);
+        assert!(retired(&mut remote).is_empty());
+    }
+
+    #[test]
+    fn remote_reuse() {
+        fixture_init();
+        let mut remote = RemoteConnectionIds::default();
+        let one = entry(1);
+        remote.add(one.clone(), 0).unwrap();
+
+        // The same sequence number with a different connection ID.
+        assert_eq!(remote.add(entry(1), 0), Err(Error::ProtocolViolation));
+        // The same connection ID with a different sequence number.
+        let moved = ConnectionIdEntry::new(2, one.connection_id().clone(), [2; 16]);
+        assert_eq!(remote.add(moved, 0), Err(Error::ProtocolViolation));
+        assert_eq!(remote.len(), 1);
+    }
 }
@@ -5,6 +5,7 @@
 // except according to those terms.
 
 use std::cmp::{max, min};
+use std::mem;
 use std::time::{Duration, Instant};
 
 use crate::LOCAL_IDLE_TIMEOUT;
@@ -25,28 +26,91 @@ enum IdleTimeoutState {
 pub struct IdleTimeout {
     timeout: Duration,
     state: IdleTimeoutState,
+    /// How long to wait before sending a PING to keep the connection open.
+    keep_alive: Option<Duration>,
+    /// How long to wait before sending a PING to refresh the RTT estimate.
+    rtt_probe: Option<Duration>,
+    /// Whether an RTT probe has been sent since the connection became idle.
+    rtt_probe_sent: bool,
+    /// Whether the packet being sent only has a PING that `on_ping` was told about.
+    ping_only: bool,
 }
 
-#[cfg(test)]
 impl IdleTimeout {
     pub fn new(timeout: Duration) -> Self {
         Self {
             timeout,
             state: IdleTimeoutState::Init,
+            keep_alive: None,
+            rtt_probe: None,
+            rtt_probe_sent: false,
+            ping_only: false,
         }
     }
 }
 
 impl Default for IdleTimeout {
     fn default() -> Self {
-        Self {
-            timeout: LOCAL_IDLE_TIMEOUT,
-            state: IdleTimeoutState::Init,
-        }
+        Self::new(LOCAL_IDLE_TIMEOUT)
     }
 }
 
 impl IdleTimeout {
+    pub fn set_local_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    pub fn set_keep_alive(&mut self, keep_alive: Option<Duration>) {
+        self.keep_alive = keep_alive;
+    }
+
+    /// When a keep-alive PING needs to be sent, if one is needed.  This is
+    /// only needed if nothing ack-eliciting has been sent since the last packet
+    /// was received; if something was, the peer will acknowledge it.
+    pub fn keep_alive_time(&self) -> Option<Instant> {
+        match self.state {
+            IdleTimeoutState::New(t) | IdleTimeoutState::PacketReceived(t) => {
+                self.keep_alive.map(|k| t + k)
+            }
+            IdleTimeoutState::Init | IdleTimeoutState::AckElicitingPacketSent(_) => None,
+        }
+    }
+
+    pub fn keep_alive_due(&self, now: Instant) -> bool {
+        matches!(self.keep_alive_time(), Some(t) if now >= t)
+    }
+
+    pub fn set_rtt_probe(&mut self, rtt_probe: Option<Duration>) {
+        self.rtt_probe = rtt_probe;
+    }
+
+    /// When a PING needs to be sent to refresh the RTT estimate.  Like a
+    /// keep-alive, this is only needed if nothing ack-eliciting is outstanding,
+    /// but only one probe is sent until the connection is used again.
+    pub fn rtt_probe_time(&self) -> Option<Instant> {
+        if self.rtt_probe_sent {
+            return None;
+        }
+        match self.state {
+            IdleTimeoutState::New(t) | IdleTimeoutState::PacketReceived(t) => {
+                self.rtt_probe.map(|p| t + p)
+            }
+            IdleTimeoutState::Init | IdleTimeoutState::AckElicitingPacketSent(_) => None,
+        }
+    }
+
+    pub fn rtt_probe_due(&self, now: Instant) -> bool {
+        matches!(self.rtt_probe_time(), Some(t) if now >= t)
+    }
+
+    /// Note that the next packet only carries a PING that was added as a
+    /// keep-alive or, if `rtt_probe` is true, as an RTT probe.  Sending
+    /// these doesn't count as using the connection.
+    pub fn on_ping(&mut self, rtt_probe: bool) {
+        self.ping_only = true;
+        self.rtt_probe_sent |= rtt_probe;
+    }
+
     pub fn set_peer_timeout(&mut self, peer_timeout: Duration) {
         self.timeout = min(self.timeout, peer_timeout);
     }
@@ -65,6 +129,9 @@ impl IdleTimeout {
     }
 
     pub fn on_packet_sent(&mut self, now: Instant) {
+        if !mem::take(&mut self.ping_only) {
+            self.rtt_probe_sent = false;
+        }
         // Only reset idle timeout if we've received a packet since the last
         // time we reset the timeout here.
         match self.state {
@@ -0,0 +1,216 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Options that are set before a connection starts.
+
+use std::convert::TryFrom;
+use std::time::Duration;
+
+use super::{Connection, LOCAL_MAX_DATA, LOCAL_STREAM_LIMIT_BIDI, LOCAL_STREAM_LIMIT_UNI};
+use crate::cc::CongestionControl;
+use crate::recv_stream::RECV_BUFFER_SIZE;
+use crate::{Error, Res, LOCAL_IDLE_TIMEOUT};
+
+/// The largest stream limit that can be advertised.
+const MAX_STREAMS_LIMIT: u64 = 1 << 60;
+
+/// Options for a connection that need to be set before the handshake starts.
+/// Each method sets one option, starting from the defaults that a connection
+/// otherwise uses.  The options are checked together when they are passed to
+/// `Connection::new_client_with_params` or `Connection::new_server_with_params`.
+#[derive(Debug)]
+pub struct ConnectionParameters {
+    idle_timeout: Duration,
+    keep_alive: Option<Duration>,
+    rtt_probe: Option<Duration>,
+    max_data: u64,
+    max_stream_data_bidi_local: u64,
+    max_stream_data_bidi_remote: u64,
+    max_stream_data_uni: u64,
+    max_streams_bidi: u64,
+    max_streams_uni: u64,
+    congestion_control: Option<Box<dyn CongestionControl>>,
+    initial_cwnd: Option<usize>,
+    ecn: bool,
+    accept_stream_gaps: bool,
+    resumption_blob: Option<Vec<u8>>,
+}
+
+impl Default for ConnectionParameters {
+    fn default() -> Self {
+        let recv_buffer = u64::try_from(RECV_BUFFER_SIZE).unwrap();
+        Self {
+            idle_timeout: LOCAL_IDLE_TIMEOUT,
+            keep_alive: None,
+            rtt_probe: None,
+            max_data: LOCAL_MAX_DATA,
+            max_stream_data_bidi_local: recv_buffer,
+            max_stream_data_bidi_remote: recv_buffer,
+            max_stream_data_uni: recv_buffer,
+            max_streams_bidi: LOCAL_STREAM_LIMIT_BIDI,
+            max_streams_uni: LOCAL_STREAM_LIMIT_UNI,
+            congestion_control: None,
+            initial_cwnd: None,
+            ecn: true,
+            accept_stream_gaps: false,
+            resumption_blob: None,
+        }
+    }
+}
+
+impl ConnectionParameters {
+    /// The idle timeout that is advertised to the peer.
+    #[must_use]
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Send a PING after the connection has been idle for `interval`.  This
+    /// needs to be less than the idle timeout.
+    #[must_use]
+    pub fn keep_alive(mut self, interval: Duration) -> Self {
+        self.keep_alive = Some(interval);
+        self
+    }
+
+    /// Send a PING to refresh the RTT estimate once the connection has been
+    /// idle for `interval`.  This needs to be less than the idle timeout and
+    /// the keep-alive interval.
+    #[must_use]
+    pub fn rtt_probe(mut self, interval: Duration) -> Self {
+        self.rtt_probe = Some(interval);
+        self
+    }
+
+    /// The connection-level flow control limit.
+    #[must_use]
+    pub fn max_data(mut self, limit: u64) -> Self {
+        self.max_data = limit;
+        self
+    }
+
+    /// The flow control limit for bidirectional streams that this endpoint opens.
+    #[must_use]
+    pub fn max_stream_data_bidi_local(mut self, limit: u64) -> Self {
+        self.max_stream_data_bidi_local = limit;
+        self
+    }
+
+    /// The flow control limit for bidirectional streams that the peer opens.
+    #[must_use]
+    pub fn max_stream_data_bidi_remote(mut self, limit: u64) -> Self {
+        self.max_stream_data_bidi_remote = limit;
+        self
+    }
+
+    /// The flow control limit for unidirectional streams that the peer opens.
+    #[must_use]
+    pub fn max_stream_data_uni(mut self, limit: u64) -> Self {
+        self.max_stream_data_uni = limit;
+        self
+    }
+
+    /// The number of bidirectional streams that the peer can open.
+    #[must_use]
+    pub fn max_streams_bidi(mut self, limit: u64) -> Self {
+        self.max_streams_bidi = limit;
+        self
+    }
+
+    /// The number of unidirectional streams that the peer can open.
+    #[must_use]
+    pub fn max_streams_uni(mut self, limit: u64) -> Self {
+        self.max_streams_uni = limit;
+        self
+    }
+
+    /// The congestion controller to use instead of `NewReno`.
+    #[must_use]
+    pub fn congestion_control(mut self, cc: Box<dyn CongestionControl>) -> Self {
+        self.congestion_control = Some(cc);
+        self
+    }
+
+    /// The initial congestion window, in packets.
+    #[must_use]
+    pub fn initial_cwnd(mut self, packets: usize) -> Self {
+        self.initial_cwnd = Some(packets);
+        self
+    }
+
+    /// Whether to mark packets with ECN, which is enabled by default.
+    #[must_use]
+    pub fn ecn(mut self, enabled: bool) -> Self {
+        self.ecn = enabled;
+        self
+    }
+
+    /// Whether the peer can skip stale stream data, which is disabled by default.
+    /// See `Connection::set_accept_stream_gaps`.
+    #[must_use]
+    pub fn accept_stream_gaps(mut self, accept: bool) -> Self {
+        self.accept_stream_gaps = accept;
+        self
+    }
+
+    /// Resume a session using a blob from `Connection::resumption_blob`, which
+    /// can come from another process.  The blob is used when the client first
+    /// produces output, so it can only be set on a client.
+    #[must_use]
+    pub fn resumption_blob(mut self, blob: Vec<u8>) -> Self {
+        self.resumption_blob = Some(blob);
+        self
+    }
+
+    /// Check that the options are consistent.
+    /// # Errors
+    /// `InvalidInput` if the idle timeout is zero, if the keep-alive interval
+    /// is not less than the idle timeout, if the RTT probe interval is not less
+    /// than both, or if a stream limit is more than 2^60.
+    pub(crate) fn check(&self) -> Res<()> {
+        if self.idle_timeout == Duration::from_secs(0) {
+            return Err(Error::InvalidInput);
+        }
+        if matches!(self.keep_alive, Some(k) if k >= self.idle_timeout) {
+            return Err(Error::InvalidInput);
+        }
+        if let Some(p) = self.rtt_probe {
+            if p >= self.idle_timeout || matches!(self.keep_alive, Some(k) if p >= k) {
+                return Err(Error::InvalidInput);
+            }
+        }
+        if self.max_streams_bidi > MAX_STREAMS_LIMIT || self.max_streams_uni > MAX_STREAMS_LIMIT {
+            return Err(Error::InvalidInput);
+        }
+        Ok(())
+    }
+
+    /// Apply the options to a connection that hasn't started.
+    pub(crate) fn apply(self, c: &mut Connection) -> Res<()> {
+        self.check()?;
+        c.set_idle_timeout(self.idle_timeout)?;
+        c.set_keep_alive(self.keep_alive);
+        c.set_rtt_probe(self.rtt_probe);
+        c.set_initial_max_data(self.max_data)?;
+        c.set_initial_max_stream_data_bidi_local(self.max_stream_data_bidi_local)?;
+        c.set_initial_max_stream_data_bidi_remote(self.max_stream_data_bidi_remote)?;
+        c.set_initial_max_stream_data_uni(self.max_stream_data_uni)?;
+        c.set_initial_max_streams_bidi(self.max_streams_bidi)?;
+        c.set_initial_max_streams_uni(self.max_streams_uni)?;
+        if let Some(cc) = self.congestion_control {
+            c.set_congestion_control(cc)?;
+        }
+        if let Some(packets) = self.initial_cwnd {
+            c.set_initial_cwnd(packets)?;
+        }
+        c.set_accept_stream_gaps(self.accept_stream_gaps)?;
+        if let Some(blob) = self.resumption_blob {
+            c.set_resumption_blob(&blob)?;
+        }
+        c.set_ecn_enabled(self.ecn)
+    }
+}
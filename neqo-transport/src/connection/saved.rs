@@ -8,7 +8,7 @@ use std::mem;
 use std::time::Instant;
 
 use crate::crypto::CryptoSpace;
-use neqo_common::{qdebug, qinfo, Datagram};
+use neqo_common::Datagram;
 
 /// The number of datagrams that are saved during the handshake when
 /// keys to decrypt them are not yet available.
@@ -40,14 +40,15 @@ impl SavedDatagrams {
         }
     }
 
-    pub fn save(&mut self, cspace: CryptoSpace, d: Datagram, t: Instant) {
+    /// Save a datagram, returning false if too many are already saved.
+    pub fn save(&mut self, cspace: CryptoSpace, d: Datagram, t: Instant) -> bool {
         let store = self.store(cspace);
 
         if store.len() < MAX_SAVED_DATAGRAMS {
-            qdebug!("saving datagram of {} bytes", d.len());
             store.push(SavedDatagram { d, t });
+            true
         } else {
-            qinfo!("not saving datagram of {} bytes", d.len());
+            false
         }
     }
 
@@ -8,7 +8,7 @@ use std::cmp::Ordering;
 use std::mem;
 use std::time::Instant;
 
-use crate::frame::{Frame, FrameType};
+use crate::frame::Frame;
 use crate::recovery::RecoveryToken;
 use crate::{CloseError, ConnectionError};
 
@@ -111,7 +111,7 @@ impl StateSignaling {
 
     fn make_close_frame(
         error: ConnectionError,
-        frame_type: FrameType,
+        frame_type: u64,
         message: impl AsRef<str>,
     ) -> Frame {
         let reason_phrase = message.as_ref().as_bytes().to_owned();
@@ -122,23 +122,13 @@ impl StateSignaling {
         }
     }
 
-    pub fn close(
-        &mut self,
-        error: ConnectionError,
-        frame_type: FrameType,
-        message: impl AsRef<str>,
-    ) {
+    pub fn close(&mut self, error: ConnectionError, frame_type: u64, message: impl AsRef<str>) {
         if *self != Self::Reset {
             *self = Self::Closing(Self::make_close_frame(error, frame_type, message));
         }
     }
 
-    pub fn drain(
-        &mut self,
-        error: ConnectionError,
-        frame_type: FrameType,
-        message: impl AsRef<str>,
-    ) {
+    pub fn drain(&mut self, error: ConnectionError, frame_type: u64, message: impl AsRef<str>) {
         if *self != Self::Reset {
             *self = Self::Draining(Self::make_close_frame(error, frame_type, message));
         }
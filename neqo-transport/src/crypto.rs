@@ -13,7 +13,7 @@ use std::time::Instant;
 
 use neqo_common::{hex, qdebug, qinfo, qtrace, Role};
 use neqo_crypto::{
-    aead::Aead, hkdf, hp::HpKey, Agent, AntiReplay, Cipher, Epoch, HandshakeState, Record,
+    aead::Aead, hkdf, hp::HpKey, Agent, AntiReplay, Cipher, Client, Epoch, HandshakeState, Record,
     RecordList, SymKey, ZeroRttChecker, TLS_AES_128_GCM_SHA256, TLS_AES_256_GCM_SHA384,
     TLS_CHACHA20_POLY1305_SHA256, TLS_CT_HANDSHAKE, TLS_EPOCH_APPLICATION_DATA,
     TLS_EPOCH_HANDSHAKE, TLS_EPOCH_INITIAL, TLS_EPOCH_ZERO_RTT, TLS_VERSION_1_3,
@@ -29,12 +29,18 @@ use crate::tracking::PNSpace;
 use crate::{Error, Res};
 
 const MAX_AUTH_TAG: usize = 32;
+pub(crate) const CLIENT_INITIAL_LABEL: &str = "client in";
+pub(crate) const SERVER_INITIAL_LABEL: &str = "server in";
 
 #[derive(Debug)]
 pub struct Crypto {
     pub(crate) tls: Agent,
     pub(crate) streams: CryptoStreams,
     pub(crate) states: CryptoStates,
+    /// The settings that a client needs to make its TLS agent again.
+    protocols: Vec<String>,
+    ciphers: Option<Vec<Cipher>>,
+    resumption_token: Option<Vec<u8>>,
 }
 
 type TpHandler = Rc<RefCell<TransportParametersHandler>>;
@@ -59,9 +65,58 @@ impl Crypto {
             tls: agent,
             streams: Default::default(),
             states: Default::default(),
+            protocols: protocols.iter().map(|p| p.as_ref().to_owned()).collect(),
+            ciphers: None,
+            resumption_token: None,
         })
     }
 
+    pub fn set_alpn(&mut self, protocols: &[impl AsRef<str>]) -> Res<()> {
+        self.tls.set_alpn(protocols)?;
+        self.protocols = protocols.iter().map(|p| p.as_ref().to_owned()).collect();
+        Ok(())
+    }
+
+    pub fn set_ciphers(&mut self, ciphers: &[Cipher]) -> Res<()> {
+        self.tls.set_ciphers(ciphers)?;
+        self.ciphers = Some(ciphers.to_vec());
+        Ok(())
+    }
+
+    /// Give a client a resumption token from a previous connection.
+    pub fn enable_resumption(&mut self, token: &[u8]) -> Res<()> {
+        if let Agent::Client(c) = &mut self.tls {
+            c.enable_resumption(token)?;
+            self.resumption_token = Some(token.to_vec());
+            Ok(())
+        } else {
+            Err(Error::WrongRole)
+        }
+    }
+
+    /// Replace the TLS client with a new one that has the same settings.
+    /// This is used after Version Negotiation, where the client has to send a
+    /// new `ClientHello` with different transport parameters.  Any keys and
+    /// handshake data are dropped.
+    pub fn restart_client(&mut self, tphandler: TpHandler) -> Res<()> {
+        let server_name = if let Agent::Client(c) = &self.tls {
+            c.server_name().to_owned()
+        } else {
+            return Err(Error::WrongRole);
+        };
+        let agent = Client::new(&server_name)?.into();
+        let mut crypto = Self::new(agent, &self.protocols, tphandler)?;
+        if let Some(ciphers) = &self.ciphers {
+            crypto.set_ciphers(ciphers)?;
+        }
+        if let Some(token) = &self.resumption_token {
+            crypto.enable_resumption(token)?;
+        }
+        self.tls.move_callbacks(&mut crypto.tls);
+        *self = crypto;
+        Ok(())
+    }
+
     pub fn server_enable_0rtt(
         &mut self,
         tphandler: TpHandler,
@@ -600,6 +655,25 @@ impl CryptoStates {
         }
     }
 
+    /// The packet number of the next packet sent in the given `PNSpace`,
+    /// using the keys that `select_tx` would select.
+    pub fn next_pn(&self, space: PNSpace) -> Option<PacketNumber> {
+        match space {
+            PNSpace::Initial => self.initial.as_ref().map(|s| s.tx.next_pn()),
+            PNSpace::Handshake => self.handshake.as_ref().map(|s| s.tx.next_pn()),
+            PNSpace::ApplicationData => {
+                if let Some(app) = &self.app_write {
+                    Some(app.dx.next_pn())
+                } else {
+                    self.zero_rtt
+                        .as_ref()
+                        .filter(|z| z.direction == CryptoDxDirection::Write)
+                        .map(CryptoDxState::next_pn)
+                }
+            }
+        }
+    }
+
     pub fn tx<'a>(&'a mut self, cspace: CryptoSpace) -> Option<&'a mut CryptoDxState> {
         let tx = |k: Option<&'a mut CryptoState>| k.map(|dx| &mut dx.tx);
         match cspace {
@@ -664,9 +738,6 @@ impl CryptoStates {
 
     /// Create the initial crypto state.
     pub fn init(&mut self, quic_version: QuicVersion, role: Role, dcid: &[u8]) {
-        const CLIENT_INITIAL_LABEL: &str = "client in";
-        const SERVER_INITIAL_LABEL: &str = "server in";
-
         qinfo!(
             [self],
             "Creating initial cipher state role={:?} dcid={}",
@@ -840,10 +911,18 @@ impl CryptoStates {
         self.read_update_time
     }
 
+    /// The key phase of the current read keys.  Once a key update completes,
+    /// this is also the key phase used for sending.
+    #[must_use]
+    pub fn key_phase(&self) -> bool {
+        matches!(&self.app_read, Some(ar) if ar.dx.key_phase())
+    }
+
     /// Check if time has passed for updating key update parameters.
     /// If it has, then swap keys over and allow more key updates to be initiated.
     /// This is also used to discard 0-RTT read keys at the server in the same way.
-    pub fn check_key_update(&mut self, now: Instant) -> Res<()> {
+    /// Returns true if this completed a key update.
+    pub fn check_key_update(&mut self, now: Instant) -> Res<bool> {
         if let Some(expiry) = self.read_update_time {
             // If enough time has passed, then install new keys and clear the timer.
             if now >= expiry {
@@ -854,11 +933,13 @@ impl CryptoStates {
                     qtrace!([self], "Rotating read keys");
                     mem::swap(&mut self.app_read, &mut self.app_read_next);
                     self.app_read_next = Some(self.app_read.as_ref().unwrap().next()?);
+                    self.read_update_time = None;
+                    return Ok(true);
                 }
                 self.read_update_time = None;
             }
         }
-        Ok(())
+        Ok(false)
     }
 
     /// Get the current/highest epoch.  This returns (write, read) epochs.
@@ -0,0 +1,327 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Validating and using Explicit Congestion Notification (RFC 9000, Section 13.4).
+#![deny(clippy::pedantic)]
+
+use neqo_common::{qdebug, qinfo, IpTos};
+
+use std::convert::TryFrom;
+use std::ops::{Index, IndexMut};
+
+use crate::tracking::{PNSpace, SentPacket};
+
+/// The number of packets that are marked while testing a path.  If all of
+/// these are lost, the path is assumed to drop ECN-marked packets.
+const TEST_COUNT: usize = 10;
+
+/// The number of packets received with each ECN codepoint, as reported in ACK frames.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct EcnCount {
+    pub ect0: u64,
+    pub ect1: u64,
+    pub ce: u64,
+}
+
+impl EcnCount {
+    /// Count a packet that was received with the ECN codepoint from `tos`.
+    pub fn add(&mut self, tos: IpTos) {
+        match tos.ecn() {
+            IpTos::ECT0 => self.ect0 += 1,
+            IpTos::ECT1 => self.ect1 += 1,
+            IpTos::CE => self.ce += 1,
+            _ => {}
+        }
+    }
+
+    /// Whether no ECN-marked packets have been counted.
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// The counts most recently reported by the peer, for each packet number space.
+#[derive(Debug, Default)]
+struct EcnCounts([EcnCount; 3]);
+
+impl Index<PNSpace> for EcnCounts {
+    type Output = EcnCount;
+
+    fn index(&self, space: PNSpace) -> &Self::Output {
+        &self.0[space as usize]
+    }
+}
+
+impl IndexMut<PNSpace> for EcnCounts {
+    fn index_mut(&mut self, space: PNSpace) -> &mut Self::Output {
+        &mut self.0[space as usize]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EcnValidation {
+    /// Packets are being marked to test the path.
+    Testing { sent: usize, lost: usize },
+    /// All of the test packets were sent, but none have been acknowledged yet.
+    Unknown { lost: usize },
+    /// The path or the peer doesn't support ECN.
+    Failed,
+    /// ECN works on the path.
+    Capable,
+}
+
+/// The state of ECN validation for a path.
+#[derive(Debug)]
+pub struct EcnInfo {
+    state: EcnValidation,
+    baseline: EcnCounts,
+}
+
+impl EcnInfo {
+    /// Start testing the path, or don't use ECN at all if `enabled` is false.
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            state: if enabled {
+                EcnValidation::Testing { sent: 0, lost: 0 }
+            } else {
+                EcnValidation::Failed
+            },
+            baseline: EcnCounts::default(),
+        }
+    }
+
+    /// The ECN codepoint to use for the next datagram.
+    pub fn tos(&self) -> IpTos {
+        match self.state {
+            EcnValidation::Testing { .. } | EcnValidation::Capable => IpTos::ECT0,
+            EcnValidation::Unknown { .. } | EcnValidation::Failed => IpTos::NOT_ECT,
+        }
+    }
+
+    /// Whether ECN is being used, which is true until validation fails.
+    pub fn is_enabled(&self) -> bool {
+        self.state != EcnValidation::Failed
+    }
+
+    fn disable(&mut self, reason: &str) {
+        if self.state != EcnValidation::Failed {
+            qinfo!("ECN validation failed: {}", reason);
+            self.state = EcnValidation::Failed;
+        }
+    }
+
+    /// Note that a packet was sent with the codepoint from `tos()`.
+    pub fn on_packet_sent(&mut self) {
+        if let EcnValidation::Testing { sent, lost } = &mut self.state {
+            *sent += 1;
+            if *sent == TEST_COUNT {
+                qdebug!("ECN test packets sent, waiting for feedback");
+                self.state = EcnValidation::Unknown { lost: *lost };
+            }
+        }
+    }
+
+    /// Count lost test packets.  If they are all lost, disable ECN.
+    pub fn on_packets_lost(&mut self, lost_packets: &[SentPacket]) {
+        let marked = lost_packets
+            .iter()
+            .filter(|p| p.ecn_mark() == IpTos::ECT0)
+            .count();
+        if let EcnValidation::Testing { lost, .. } | EcnValidation::Unknown { lost } =
+            &mut self.state
+        {
+            *lost += marked;
+            if *lost >= TEST_COUNT {
+                self.disable("all test packets were lost");
+            }
+        }
+    }
+
+    /// Validate the ECN counts from an ACK frame that acknowledged `acked_packets`.
+    /// Returns true if the counts show a new congestion signal.
+    pub fn on_packets_acked(
+        &mut self,
+        space: PNSpace,
+        acked_packets: &[SentPacket],
+        ecn_count: Option<EcnCount>,
+    ) -> bool {
+        if self.state == EcnValidation::Failed {
+            return false;
+        }
+        let marked = acked_packets
+            .iter()
+            .filter(|p| p.ecn_mark() == IpTos::ECT0)
+            .count();
+        let counts = if let Some(counts) = ecn_count {
+            counts
+        } else {
+            if marked > 0 {
+                self.disable("marked packets were acknowledged without ECN counts");
+            }
+            return false;
+        };
+
+        let prev = self.baseline[space];
+        if counts.ect0 < prev.ect0 || counts.ect1 < prev.ect1 || counts.ce < prev.ce {
+            self.disable("ECN counts decreased");
+            return false;
+        }
+        if counts.ect1 > prev.ect1 {
+            self.disable("ECT(1) was reported, but was not sent");
+            return false;
+        }
+        let increase = (counts.ect0 - prev.ect0) + (counts.ce - prev.ce);
+        if increase < u64::try_from(marked).unwrap() {
+            self.disable("ECN markings were removed");
+            return false;
+        }
+        self.baseline[space] = counts;
+
+        if marked > 0 && self.state != EcnValidation::Capable {
+            qinfo!("ECN validation succeeded");
+            self.state = EcnValidation::Capable;
+        }
+        counts.ce > prev.ce
+    }
+}
+
+impl Default for EcnInfo {
+    fn default() -> Self {
+        Self::new(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EcnCount, EcnInfo, PNSpace, SentPacket, TEST_COUNT};
+    use crate::packet::PacketType;
+    use neqo_common::IpTos;
+    use std::rc::Rc;
+    use test_fixture::now;
+
+    fn sent(pn: u64, tos: IpTos) -> SentPacket {
+        let mut p = SentPacket::new(PacketType::Short, pn, now(), true, Rc::default(), 100);
+        p.set_ecn_mark(tos);
+        p
+    }
+
+    fn ect0(n: u64) -> EcnCount {
+        EcnCount {
+            ect0: n,
+            ..EcnCount::default()
+        }
+    }
+
+    #[test]
+    fn count() {
+        let mut count = EcnCount::default();
+        assert!(count.is_empty());
+        count.add(IpTos::NOT_ECT);
+        assert!(count.is_empty());
+        count.add(IpTos::ECT0);
+        count.add(IpTos::from(0xb9)); // DSCP bits are ignored.
+        count.add(IpTos::CE);
+        assert_eq!(
+            count,
+            EcnCount {
+                ect0: 1,
+                ect1: 1,
+                ce: 1
+            }
+        );
+    }
+
+    #[test]
+    fn validated() {
+        let mut ecn = EcnInfo::default();
+        assert_eq!(ecn.tos(), IpTos::ECT0);
+        for _ in 0..TEST_COUNT {
+            ecn.on_packet_sent();
+        }
+        // Marking stops until the test packets are acknowledged.
+        assert_eq!(ecn.tos(), IpTos::NOT_ECT);
+        assert!(ecn.is_enabled());
+        let acked = [sent(0, IpTos::ECT0), sent(1, IpTos::ECT0)];
+        assert!(!ecn.on_packets_acked(PNSpace::ApplicationData, &acked, Some(ect0(2))));
+        assert_eq!(ecn.tos(), IpTos::ECT0);
+        assert!(ecn.is_enabled());
+    }
+
+    #[test]
+    fn congestion_experienced() {
+        let mut ecn = EcnInfo::default();
+        let acked = [sent(0, IpTos::ECT0)];
+        assert!(!ecn.on_packets_acked(PNSpace::ApplicationData, &acked, Some(ect0(1))));
+        let acked = [sent(1, IpTos::ECT0)];
+        let counts = Some(EcnCount {
+            ect0: 1,
+            ect1: 0,
+            ce: 1,
+        });
+        assert!(ecn.on_packets_acked(PNSpace::ApplicationData, &acked, counts));
+        assert!(ecn.is_enabled());
+        // The same counts don't signal congestion again.
+        assert!(!ecn.on_packets_acked(PNSpace::ApplicationData, &[], counts));
+    }
+
+    #[test]
+    fn bleached() {
+        let mut ecn = EcnInfo::default();
+        let acked = [sent(0, IpTos::ECT0)];
+        assert!(!ecn.on_packets_acked(PNSpace::Initial, &acked, None));
+        assert!(!ecn.is_enabled());
+        assert_eq!(ecn.tos(), IpTos::NOT_ECT);
+    }
+
+    #[test]
+    fn remarked() {
+        let mut ecn = EcnInfo::default();
+        let acked = [sent(0, IpTos::ECT0), sent(1, IpTos::ECT0)];
+        assert!(!ecn.on_packets_acked(PNSpace::Initial, &acked, Some(ect0(1))));
+        assert!(!ecn.is_enabled());
+    }
+
+    #[test]
+    fn counts_decrease() {
+        let mut ecn = EcnInfo::default();
+        let acked = [sent(0, IpTos::ECT0), sent(1, IpTos::ECT0)];
+        assert!(!ecn.on_packets_acked(PNSpace::Handshake, &acked, Some(ect0(2))));
+        assert!(!ecn.on_packets_acked(PNSpace::Handshake, &[], Some(ect0(1))));
+        assert!(!ecn.is_enabled());
+    }
+
+    #[test]
+    fn spaces_are_separate() {
+        let mut ecn = EcnInfo::default();
+        let acked = [sent(0, IpTos::ECT0)];
+        assert!(!ecn.on_packets_acked(PNSpace::Initial, &acked, Some(ect0(1))));
+        assert!(!ecn.on_packets_acked(PNSpace::Handshake, &acked, Some(ect0(1))));
+        assert!(ecn.is_enabled());
+    }
+
+    #[test]
+    fn unmarked_packets_need_no_counts() {
+        let mut ecn = EcnInfo::default();
+        let acked = [sent(0, IpTos::NOT_ECT)];
+        assert!(!ecn.on_packets_acked(PNSpace::ApplicationData, &acked, None));
+        assert!(ecn.is_enabled());
+    }
+
+    #[test]
+    fn test_packets_lost() {
+        let mut ecn = EcnInfo::default();
+        let lost = (0..TEST_COUNT)
+            .map(|pn| sent(pn as u64, IpTos::ECT0))
+            .collect::<Vec<_>>();
+        for _ in 0..TEST_COUNT {
+            ecn.on_packet_sent();
+        }
+        ecn.on_packets_lost(&lost[1..]);
+        assert!(ecn.is_enabled());
+        ecn.on_packets_lost(&lost[..1]);
+        assert!(!ecn.is_enabled());
+    }
+}
@@ -8,13 +8,36 @@
 
 use std::cell::RefCell;
 use std::collections::VecDeque;
+use std::net::SocketAddr;
 use std::rc::Rc;
 
 use crate::connection::State;
-use crate::frame::StreamType;
+use crate::frame::{CloseError, StreamType};
 use crate::stream_id::StreamId;
 use crate::AppError;
 
+/// What caused the connection to close.
+#[derive(Debug, PartialOrd, Ord, PartialEq, Eq, Clone, Copy)]
+pub enum CloseOrigin {
+    /// This endpoint closed the connection and sent CONNECTION_CLOSE.
+    Local,
+    /// The peer closed the connection with a CONNECTION_CLOSE frame.
+    Remote,
+    /// The idle timeout expired, so this endpoint closed the connection
+    /// without sending anything.
+    IdleTimeout,
+    /// The peer sent a stateless reset.
+    StatelessReset,
+}
+
+impl CloseOrigin {
+    /// Whether this endpoint decided to close the connection.
+    #[must_use]
+    pub fn is_local(self) -> bool {
+        matches!(self, Self::Local | Self::IdleTimeout)
+    }
+}
+
 #[derive(Debug, PartialOrd, Ord, PartialEq, Eq)]
 pub enum ConnectionEvent {
     /// Cert authentication needed
@@ -23,24 +46,100 @@ pub enum ConnectionEvent {
     NewStream { stream_id: StreamId },
     /// Space available in the buffer for an application write to succeed.
     SendStreamWritable { stream_id: StreamId },
-    /// New bytes available for reading.
+    /// A write was refused because the peer's stream or connection flow
+    /// control limit has been reached.  `SendStreamWritable` follows once
+    /// the peer increases the limit.
+    SendStreamBlocked { stream_id: u64 },
+    /// New bytes available for reading.  This is not reported again for the
+    /// same stream until the application has read all the available data.
     RecvStreamReadable { stream_id: u64 },
+    /// All of the data on the stream has arrived, up to and including the end
+    /// of the stream, though it might not all have been read yet.
+    RecvStreamFin { stream_id: u64 },
+    /// The peer won't send some data again; see `Connection::stream_set_unreliable_after`.
+    /// Whatever part of the range didn't arrive reads as zeros.
+    RecvStreamGap {
+        stream_id: u64,
+        offset: u64,
+        length: u64,
+    },
     /// Peer reset the stream.
     RecvStreamReset { stream_id: u64, app_error: AppError },
     /// Peer has sent STOP_SENDING
     SendStreamStopSending { stream_id: u64, app_error: AppError },
     /// Peer has acked everything sent on the stream.
     SendStreamComplete { stream_id: u64 },
-    /// Peer increased MAX_STREAMS
+    /// Stream data that was sent was declared lost and will be sent again.
+    /// This includes data that is sent again when the probe timer fires,
+    /// which might not have been lost.  Each loss is reported, so the same
+    /// range can be reported more than once.  Data that is skipped because of
+    /// `Connection::stream_set_unreliable_after` is not sent again, so it is
+    /// not reported.
+    StreamDataLost {
+        stream_id: u64,
+        offset: u64,
+        length: usize,
+    },
+    /// Peer increased MAX_STREAMS.  `Connection::available_streams` reports
+    /// how many streams can now be created.
     SendStreamCreatable { stream_type: StreamType },
     /// Connection state change.
     StateChange(State),
+    /// The connection was closed, either by this endpoint or by the peer, as
+    /// indicated by `origin`.  `error` distinguishes transport errors from
+    /// application errors.  `frame_type` is the type of frame that caused a
+    /// transport error, which is zero if unknown or for an application close;
+    /// `FrameType::from` turns it into something more readable.
+    /// This is reported once, after the `StateChange` to a closing state.
+    ConnectionClosed {
+        origin: CloseOrigin,
+        error: CloseError,
+        frame_type: u64,
+        reason_phrase: String,
+    },
+    /// A key update completed.  `phase` is the new key phase bit, which is
+    /// now used for both sending and receiving.
+    KeyUpdated { phase: u8 },
+    /// The handshake is confirmed.  This is reported once, after the
+    /// corresponding `StateChange`.
+    HandshakeCompleted,
+    /// An unreliable datagram (a DATAGRAM frame) was received.
+    Datagram { data: Vec<u8> },
+    /// A datagram that was passed to `Connection::send_datagram` was either
+    /// sent (`accepted` is true) or dropped without being sent, because too
+    /// many datagrams were waiting.  Datagrams wait when congestion control or
+    /// pacing stop packets from being sent.  This is reported once for each
+    /// datagram, in the order that they were queued.
+    DatagramOutcome { accepted: bool },
+    /// A new path was validated and the connection now uses it.
+    PathMigrated {
+        local: SocketAddr,
+        remote: SocketAddr,
+    },
     /// The server rejected 0-RTT.
     /// This event invalidates all state in streams that has been created.
     /// Any data written to streams needs to be written again.
     ZeroRttRejected,
+    /// The client received a session ticket.  `token` can be passed to
+    /// `Connection::enable_resumption` to resume the connection.  This is
+    /// reported once for each ticket; the same tokens are also available
+    /// from `Connection::resumption_token`.
+    ResumptionToken { token: Vec<u8> },
+    /// The client received a NEW_TOKEN frame.  `token` can be passed to
+    /// `Connection::set_initial_token` on a future connection to the same
+    /// server so that the server can validate the client address without a Retry.
+    NewToken { token: Vec<u8> },
 }
 
+/// A queue of events for the application.
+///
+/// Events are delivered in the order in which they occurred.  Inserting an
+/// event that is already queued is a no-op, so a level-triggered event, such
+/// as a stream becoming readable or writable, is reported only once until it
+/// is collected.  The exceptions are events that each report a separate
+/// occurrence, such as a `Datagram`, which are always queued.  Queueing an
+/// event never reorders those that are already present; some events (a stream
+/// reset, for instance) remove earlier events that they supersede.
 #[derive(Debug, Default, Clone)]
 #[allow(clippy::module_name_repetitions)]
 pub struct ConnectionEvents {
@@ -62,6 +161,20 @@ impl ConnectionEvents {
         });
     }
 
+    pub fn recv_stream_fin(&self, stream_id: StreamId) {
+        self.insert(ConnectionEvent::RecvStreamFin {
+            stream_id: stream_id.as_u64(),
+        });
+    }
+
+    pub fn recv_stream_gap(&self, stream_id: StreamId, offset: u64, length: u64) {
+        self.insert(ConnectionEvent::RecvStreamGap {
+            stream_id: stream_id.as_u64(),
+            offset,
+            length,
+        });
+    }
+
     pub fn recv_stream_reset(&self, stream_id: StreamId, app_error: AppError) {
         // If reset, no longer readable.
         self.remove(|evt| matches!(evt, ConnectionEvent::RecvStreamReadable { stream_id: x } if *x == stream_id.as_u64()));
@@ -73,12 +186,21 @@ impl ConnectionEvents {
     }
 
     pub fn send_stream_writable(&self, stream_id: StreamId) {
+        // If writable, no longer blocked.
+        self.remove(|evt| matches!(evt, ConnectionEvent::SendStreamBlocked { stream_id: x } if *x == stream_id.as_u64()));
         self.insert(ConnectionEvent::SendStreamWritable { stream_id });
     }
 
+    pub fn send_stream_blocked(&self, stream_id: StreamId) {
+        self.insert(ConnectionEvent::SendStreamBlocked {
+            stream_id: stream_id.as_u64(),
+        });
+    }
+
     pub fn send_stream_stop_sending(&self, stream_id: StreamId, app_error: AppError) {
         // If stopped, no longer writable.
         self.remove(|evt| matches!(evt, ConnectionEvent::SendStreamWritable { stream_id: x } if *x == stream_id.as_u64()));
+        self.remove(|evt| matches!(evt, ConnectionEvent::SendStreamBlocked { stream_id: x } if *x == stream_id.as_u64()));
 
         self.insert(ConnectionEvent::SendStreamStopSending {
             stream_id: stream_id.as_u64(),
@@ -86,6 +208,14 @@ impl ConnectionEvents {
         });
     }
 
+    pub fn stream_data_lost(&self, stream_id: StreamId, offset: u64, length: usize) {
+        self.insert(ConnectionEvent::StreamDataLost {
+            stream_id: stream_id.as_u64(),
+            offset,
+            length,
+        });
+    }
+
     pub fn send_stream_complete(&self, stream_id: StreamId) {
         self.remove(|evt| matches!(evt, ConnectionEvent::SendStreamWritable { stream_id: x } if *x == stream_id));
 
@@ -116,23 +246,92 @@ impl ConnectionEvents {
         self.insert(ConnectionEvent::ZeroRttRejected);
     }
 
+    pub fn resumption_token(&self, token: Vec<u8>) {
+        self.insert(ConnectionEvent::ResumptionToken { token });
+    }
+
+    pub fn new_token(&self, token: Vec<u8>) {
+        self.insert(ConnectionEvent::NewToken { token });
+    }
+
+    pub fn connection_closed(
+        &self,
+        origin: CloseOrigin,
+        error: CloseError,
+        frame_type: u64,
+        reason_phrase: String,
+    ) {
+        self.insert(ConnectionEvent::ConnectionClosed {
+            origin,
+            error,
+            frame_type,
+            reason_phrase,
+        });
+    }
+
+    pub fn key_updated(&self, phase: u8) {
+        self.insert(ConnectionEvent::KeyUpdated { phase });
+    }
+
+    pub fn handshake_completed(&self) {
+        self.insert(ConnectionEvent::HandshakeCompleted);
+    }
+
+    pub fn datagram_received(&self, data: &[u8]) {
+        self.insert(ConnectionEvent::Datagram {
+            data: data.to_vec(),
+        });
+    }
+
+    pub fn datagram_outcome(&self, accepted: bool) {
+        self.insert(ConnectionEvent::DatagramOutcome { accepted });
+    }
+
+    pub fn path_migrated(&self, local: SocketAddr, remote: SocketAddr) {
+        self.insert(ConnectionEvent::PathMigrated { local, remote });
+    }
+
     pub fn recv_stream_complete(&self, stream_id: StreamId) {
-        // If stopped, no longer readable.
-        self.remove(|evt| matches!(evt, ConnectionEvent::RecvStreamReadable { stream_id: x } if *x == stream_id.as_u64()));
+        // If stopped or read to the end, no longer readable.
+        self.remove(|evt| {
+            matches!(evt, ConnectionEvent::RecvStreamReadable { stream_id: x }
+                | ConnectionEvent::RecvStreamFin { stream_id: x } if *x == stream_id.as_u64())
+        });
     }
 
+    /// Take all queued events, oldest first.
     pub fn events(&self) -> impl Iterator<Item = ConnectionEvent> {
         self.events.replace(VecDeque::new()).into_iter()
     }
 
+    /// Whether any events are queued.  This is cheaper than calling
+    /// `events()` when there might be nothing to do.
     pub fn has_events(&self) -> bool {
-        !self.events.borrow().is_empty()
+        !self.is_empty()
+    }
+
+    /// The number of queued events.
+    pub fn len(&self) -> usize {
+        self.events.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.borrow().is_empty()
     }
 
+    /// Take the oldest event.
     pub fn next_event(&self) -> Option<ConnectionEvent> {
         self.events.borrow_mut().pop_front()
     }
 
+    /// Take events, oldest first, as the iterator is advanced.  Unlike
+    /// `events()`, any events that are not consumed remain queued, and events
+    /// that are added while iterating are also produced.
+    pub fn drain(&self) -> impl Iterator<Item = ConnectionEvent> {
+        let events = Rc::clone(&self.events);
+        std::iter::from_fn(move || events.borrow_mut().pop_front())
+    }
+
     fn insert(&self, event: ConnectionEvent) {
         let mut q = self.events.borrow_mut();
 
@@ -147,6 +346,12 @@ impl ConnectionEvents {
                 matches!(evt, ConnectionEvent::RecvStreamReset { stream_id: x, .. }
 		                    if *x == *stream_id)
             }),
+            // Receiving the same datagram twice is legitimate, so these are
+            // never considered to be duplicates.  Each outcome is for a
+            // different datagram and each loss is for a different packet.
+            ConnectionEvent::Datagram { .. }
+            | ConnectionEvent::DatagramOutcome { .. }
+            | ConnectionEvent::StreamDataLost { .. } => false,
             _ => q.contains(&event),
         };
         if !already_present {
@@ -181,51 +386,197 @@ mod tests {
         assert_eq!(evts.events().count(), 1);
 
         evts.recv_stream_readable(6.into());
-        evts.recv_stream_reset(6.into(), 66);
-        evts.recv_stream_reset(6.into(), 65);
+        evts.recv_stream_reset(6.into(), AppError(66));
+        evts.recv_stream_reset(6.into(), AppError(65));
         assert_eq!(evts.events().count(), 1);
 
         evts.send_stream_writable(8.into());
         evts.send_stream_writable(8.into());
-        evts.send_stream_stop_sending(8.into(), 55);
-        evts.send_stream_stop_sending(8.into(), 56);
+        evts.send_stream_stop_sending(8.into(), AppError(55));
+        evts.send_stream_stop_sending(8.into(), AppError(56));
         let events = evts.events().collect::<Vec<_>>();
         assert_eq!(events.len(), 1);
         assert_eq!(
             events[0],
             ConnectionEvent::SendStreamStopSending {
                 stream_id: 8,
-                app_error: 55
+                app_error: AppError(55)
             }
         );
 
         evts.send_stream_writable(8.into());
         evts.send_stream_writable(8.into());
-        evts.send_stream_stop_sending(8.into(), 55);
-        evts.send_stream_stop_sending(8.into(), 56);
+        evts.send_stream_stop_sending(8.into(), AppError(55));
+        evts.send_stream_stop_sending(8.into(), AppError(56));
         evts.send_stream_complete(8.into());
         assert_eq!(evts.events().count(), 1);
 
         evts.send_stream_writable(8.into());
         evts.send_stream_writable(9.into());
-        evts.send_stream_stop_sending(10.into(), 55);
-        evts.send_stream_stop_sending(11.into(), 56);
+        evts.send_stream_stop_sending(10.into(), AppError(55));
+        evts.send_stream_stop_sending(11.into(), AppError(56));
         evts.send_stream_complete(12.into());
         assert_eq!(evts.events().count(), 5);
 
         evts.send_stream_writable(8.into());
         evts.send_stream_writable(9.into());
-        evts.send_stream_stop_sending(10.into(), 55);
-        evts.send_stream_stop_sending(11.into(), 56);
+        evts.send_stream_stop_sending(10.into(), AppError(55));
+        evts.send_stream_stop_sending(11.into(), AppError(56));
         evts.send_stream_complete(12.into());
         evts.client_0rtt_rejected();
         assert_eq!(evts.events().count(), 1);
 
         evts.send_stream_writable(9.into());
-        evts.send_stream_stop_sending(10.into(), 55);
+        evts.send_stream_stop_sending(10.into(), AppError(55));
         evts.connection_state_change(State::Closed(ConnectionError::Transport(
             Error::StreamStateError,
         )));
         assert_eq!(evts.events().count(), 1);
     }
+
+    #[test]
+    fn event_order() {
+        let evts = ConnectionEvents::default();
+
+        evts.new_stream(4.into());
+        evts.recv_stream_readable(4.into());
+        evts.send_stream_writable(8.into());
+        // Duplicates don't move the original event.
+        evts.recv_stream_readable(4.into());
+        evts.new_stream(0.into());
+        evts.send_stream_writable(8.into());
+        evts.recv_stream_reset(0.into(), AppError(1));
+
+        let events = evts.events().collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            vec![
+                ConnectionEvent::NewStream {
+                    stream_id: 4.into()
+                },
+                ConnectionEvent::RecvStreamReadable { stream_id: 4 },
+                ConnectionEvent::SendStreamWritable {
+                    stream_id: 8.into()
+                },
+                ConnectionEvent::NewStream {
+                    stream_id: 0.into()
+                },
+                ConnectionEvent::RecvStreamReset {
+                    stream_id: 0,
+                    app_error: AppError(1)
+                },
+            ]
+        );
+
+        // The same holds for events taken one at a time.
+        evts.send_stream_writable(8.into());
+        evts.authentication_needed();
+        evts.send_stream_writable(8.into());
+        assert_eq!(
+            evts.next_event(),
+            Some(ConnectionEvent::SendStreamWritable {
+                stream_id: 8.into()
+            })
+        );
+        assert_eq!(
+            evts.next_event(),
+            Some(ConnectionEvent::AuthenticationNeeded)
+        );
+        assert_eq!(evts.next_event(), None);
+    }
+
+    #[test]
+    fn has_events() {
+        let evts = ConnectionEvents::default();
+        assert!(!evts.has_events());
+        assert!(evts.is_empty());
+        assert_eq!(evts.len(), 0);
+
+        evts.authentication_needed();
+        assert!(evts.has_events());
+        assert_eq!(evts.len(), 1);
+        // Duplicates are not counted.
+        evts.authentication_needed();
+        evts.new_stream(4.into());
+        assert_eq!(evts.len(), 2);
+
+        assert_eq!(evts.events().count(), 2);
+        assert!(!evts.has_events());
+    }
+
+    #[test]
+    fn partial_drain() {
+        let evts = ConnectionEvents::default();
+        for i in 0..4 {
+            evts.new_stream((i * 4).into());
+        }
+
+        let mut drain = evts.drain();
+        assert_eq!(
+            drain.next(),
+            Some(ConnectionEvent::NewStream {
+                stream_id: 0.into()
+            })
+        );
+        // Events added while draining are produced by the same iterator.
+        evts.authentication_needed();
+        assert_eq!(drain.take(1).count(), 1);
+
+        // Anything that was not consumed is left in place.
+        assert_eq!(
+            evts.next_event(),
+            Some(ConnectionEvent::NewStream {
+                stream_id: 8.into()
+            })
+        );
+        let rest = evts.drain().collect::<Vec<_>>();
+        assert_eq!(
+            rest,
+            vec![
+                ConnectionEvent::NewStream {
+                    stream_id: 12.into()
+                },
+                ConnectionEvent::AuthenticationNeeded,
+            ]
+        );
+        assert_eq!(evts.drain().next(), None);
+    }
+
+    #[test]
+    fn blocked_cleared_by_writable() {
+        let evts = ConnectionEvents::default();
+
+        evts.send_stream_blocked(4.into());
+        evts.send_stream_blocked(4.into());
+        evts.send_stream_blocked(8.into());
+        evts.send_stream_writable(4.into());
+        let events = evts.events().collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            vec![
+                ConnectionEvent::SendStreamBlocked { stream_id: 8 },
+                ConnectionEvent::SendStreamWritable {
+                    stream_id: 4.into()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn datagrams_not_culled() {
+        let evts = ConnectionEvents::default();
+
+        evts.datagram_received(&[1, 2, 3]);
+        evts.datagram_received(&[1, 2, 3]);
+        let events = evts.events().collect::<Vec<_>>();
+        assert_eq!(events.len(), 2);
+        for e in events {
+            assert_eq!(
+                e,
+                ConnectionEvent::Datagram {
+                    data: vec![1, 2, 3]
+                }
+            );
+        }
+    }
 }
@@ -7,10 +7,11 @@
 // Tracks possibly-redundant flow control signals from other code and converts
 // into flow control frames needing to be sent to the remote.
 
+use std::cmp::min;
 use std::collections::HashMap;
 use std::mem;
 
-use neqo_common::{qinfo, qtrace, qwarn, Encoder};
+use neqo_common::{qdebug, qinfo, qtrace, qwarn, Encoder};
 
 use crate::frame::{Frame, StreamType};
 use crate::recovery::RecoveryToken;
@@ -18,11 +19,14 @@ use crate::recv_stream::RecvStreams;
 use crate::send_stream::SendStreams;
 use crate::stream_id::{StreamId, StreamIndex, StreamIndexes};
 use crate::tracking::PNSpace;
-use crate::AppError;
+use crate::{AppError, Error, Res};
 
 pub type FlowControlRecoveryToken = Frame;
 
-#[derive(Debug, Default)]
+/// The largest value that can be sent in a MAX_DATA frame.
+const MAX_DATA_LIMIT: u64 = (1 << 62) - 1;
+
+#[derive(Debug)]
 pub struct FlowMgr {
     // Discriminant as key ensures only 1 of every frame type will be queued.
     from_conn: HashMap<mem::Discriminant<Frame>, Frame>,
@@ -37,6 +41,40 @@ pub struct FlowMgr {
 
     used_data: u64,
     max_data: u64,
+
+    /// The connection-level limit on received data that the peer was given.
+    recv_max_data: u64,
+    /// The size of the connection-level receive window.
+    recv_window: u64,
+    /// Whether `conn_set_recv_window` has set the window.
+    recv_window_set: bool,
+    /// The limit for growing `recv_window`.
+    recv_max_window: u64,
+    /// Whether the peer used all of the credit it was given.
+    recv_window_full: bool,
+    /// The sum of the highest offsets received on each stream.
+    recv_data: u64,
+    /// The number of received bytes that were read or discarded.
+    retired_data: u64,
+}
+
+impl Default for FlowMgr {
+    fn default() -> Self {
+        Self {
+            from_conn: HashMap::default(),
+            from_streams: HashMap::default(),
+            from_stream_types: HashMap::default(),
+            used_data: 0,
+            max_data: 0,
+            recv_max_data: MAX_DATA_LIMIT,
+            recv_window: MAX_DATA_LIMIT,
+            recv_window_set: false,
+            recv_max_window: 0,
+            recv_window_full: false,
+            recv_data: 0,
+            retired_data: 0,
+        }
+    }
 }
 
 impl FlowMgr {
@@ -65,6 +103,86 @@ impl FlowMgr {
         }
     }
 
+    pub fn conn_credit_used(&self) -> u64 {
+        self.used_data
+    }
+
+    /// Replace the connection-level send limit with one from a server that
+    /// rejected 0-RTT, which can be lower than the limit that was remembered.
+    /// `used` is how much of the stream data that was written still counts.
+    pub fn conn_reset_credit(&mut self, used: u64, max: u64) {
+        debug_assert!(used <= max);
+        self.used_data = used;
+        self.max_data = max;
+
+        const DB_FRAME: Frame = Frame::DataBlocked { data_limit: 0 };
+        self.from_conn.remove(&mem::discriminant(&DB_FRAME));
+    }
+
+    /// Set the connection-level receive window, which is the limit that was
+    /// advertised in the `initial_max_data` transport parameter.  This is
+    /// called repeatedly during the handshake, but only the first call has
+    /// any effect.
+    pub fn conn_set_recv_window(&mut self, window: u64) {
+        if !self.recv_window_set {
+            self.recv_window_set = true;
+            self.recv_window = window;
+            self.recv_max_data = window;
+        }
+    }
+
+    /// Set the size that the connection-level receive window can grow to.
+    pub fn conn_set_max_recv_window(&mut self, limit: u64) {
+        self.recv_max_window = limit;
+    }
+
+    /// Count stream data that was received for the first time against the
+    /// connection-level receive limit.
+    /// # Errors
+    /// `FlowControlError` if the peer sent more than it was allowed.
+    pub fn conn_data_received(&mut self, amount: u64) -> Res<()> {
+        self.recv_data += amount;
+        if self.recv_data > self.recv_max_data {
+            qwarn!(
+                "Connection RX window {} exceeded: {}",
+                self.recv_max_data,
+                self.recv_data
+            );
+            return Err(Error::FlowControlError);
+        }
+        if self.recv_data == self.recv_max_data {
+            self.recv_window_full = true;
+        }
+        Ok(())
+    }
+
+    /// Note that received data was read by the application, or discarded.
+    /// If more than half of the window has been used, give the peer more credit.
+    /// As for stream windows, if the peer used all of its credit and the
+    /// application has read everything, the window is too small; double it.
+    pub fn conn_data_retired(&mut self, amount: u64) {
+        self.retired_data += amount;
+        let mut new_max = min(self.retired_data + self.recv_window, MAX_DATA_LIMIT);
+        if new_max > self.recv_max_data + self.recv_window / 2 {
+            if self.recv_window_full
+                && self.retired_data == self.recv_data
+                && self.recv_window < self.recv_max_window
+            {
+                self.recv_window = min(self.recv_window * 2, self.recv_max_window);
+                qdebug!("Connection RX window increased to {}", self.recv_window);
+                new_max = min(self.retired_data + self.recv_window, MAX_DATA_LIMIT);
+            }
+            self.recv_window_full = false;
+            self.recv_max_data = new_max;
+            self.max_data(new_max);
+        }
+    }
+
+    /// The connection-level limit on received data that the peer was given.
+    pub fn conn_recv_max_data(&self) -> u64 {
+        self.recv_max_data
+    }
+
     // -- frames scoped on connection --
 
     pub fn data_blocked(&mut self) {
@@ -181,7 +299,7 @@ impl FlowMgr {
     ) {
         const RESET_STREAM: &Frame = &Frame::ResetStream {
             stream_id: StreamId::new(0),
-            application_error_code: 0,
+            application_error_code: AppError(0),
             final_size: 0,
         };
 
@@ -270,6 +388,8 @@ impl FlowMgr {
                     }
                 }
             }
+            // Resend MaxData if lost (with updated value)
+            Frame::MaxData { .. } => self.max_data(self.recv_max_data),
             Frame::PathResponse { .. } => qinfo!("Path Response lost, not re-sent"),
             _ => qwarn!("Unexpected Flow frame {:?} lost, not re-sent", token),
         }
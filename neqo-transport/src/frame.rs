@@ -9,6 +9,7 @@
 use neqo_common::{qdebug, qtrace, Decoder, Encoder};
 
 use crate::cid::MAX_CONNECTION_ID_LEN;
+use crate::ecn::EcnCount;
 use crate::packet::PacketType;
 use crate::stream_id::{StreamId, StreamIndex};
 use crate::{AppError, ConnectionError, Error, Res, TransportError, ERROR_APPLICATION_CLOSE};
@@ -17,34 +18,148 @@ use std::cmp::{min, Ordering};
 use std::convert::TryFrom;
 use std::ops::RangeInclusive;
 
+const FRAME_TYPE_PADDING: u64 = 0x0;
+const FRAME_TYPE_PING: u64 = 0x1;
+const FRAME_TYPE_ACK: u64 = 0x2;
+const FRAME_TYPE_ACK_ECN: u64 = 0x3;
+const FRAME_TYPE_RST_STREAM: u64 = 0x4;
+const FRAME_TYPE_STOP_SENDING: u64 = 0x5;
+const FRAME_TYPE_CRYPTO: u64 = 0x6;
+const FRAME_TYPE_NEW_TOKEN: u64 = 0x7;
+const FRAME_TYPE_STREAM: u64 = 0x8;
+const FRAME_TYPE_STREAM_MAX: u64 = 0xf;
+const FRAME_TYPE_MAX_DATA: u64 = 0x10;
+const FRAME_TYPE_MAX_STREAM_DATA: u64 = 0x11;
+const FRAME_TYPE_MAX_STREAMS_BIDI: u64 = 0x12;
+const FRAME_TYPE_MAX_STREAMS_UNIDI: u64 = 0x13;
+const FRAME_TYPE_DATA_BLOCKED: u64 = 0x14;
+const FRAME_TYPE_STREAM_DATA_BLOCKED: u64 = 0x15;
+const FRAME_TYPE_STREAMS_BLOCKED_BIDI: u64 = 0x16;
+const FRAME_TYPE_STREAMS_BLOCKED_UNIDI: u64 = 0x17;
+const FRAME_TYPE_NEW_CONNECTION_ID: u64 = 0x18;
+const FRAME_TYPE_RETIRE_CONNECTION_ID: u64 = 0x19;
+const FRAME_TYPE_PATH_CHALLENGE: u64 = 0x1a;
+const FRAME_TYPE_PATH_RESPONSE: u64 = 0x1b;
+pub const FRAME_TYPE_CONNECTION_CLOSE_TRANSPORT: u64 = 0x1c;
+pub const FRAME_TYPE_CONNECTION_CLOSE_APPLICATION: u64 = 0x1d;
+const FRAME_TYPE_HANDSHAKE_DONE: u64 = 0x1e;
+// RFC 9221
+const FRAME_TYPE_DATAGRAM: u64 = 0x30;
+const FRAME_TYPE_DATAGRAM_WITH_LEN: u64 = 0x31;
+// draft-ietf-quic-ack-frequency
+const FRAME_TYPE_ACK_FREQUENCY: u64 = 0xaf;
+// Private; see `Connection::stream_set_unreliable_after`.
+const FRAME_TYPE_STREAM_GAP: u64 = 0x00ff_5a7e;
+
+/// The type of a frame, as reported in `ConnectionEvent::ConnectionClosed`.
+/// Types that only differ in flags, such as the variants of `STREAM`, are
+/// grouped together.  Types this doesn't know about are `Unknown`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[allow(clippy::module_name_repetitions)]
-pub type FrameType = u64;
-
-const FRAME_TYPE_PADDING: FrameType = 0x0;
-const FRAME_TYPE_PING: FrameType = 0x1;
-const FRAME_TYPE_ACK: FrameType = 0x2;
-const FRAME_TYPE_ACK_ECN: FrameType = 0x3;
-const FRAME_TYPE_RST_STREAM: FrameType = 0x4;
-const FRAME_TYPE_STOP_SENDING: FrameType = 0x5;
-const FRAME_TYPE_CRYPTO: FrameType = 0x6;
-const FRAME_TYPE_NEW_TOKEN: FrameType = 0x7;
-const FRAME_TYPE_STREAM: FrameType = 0x8;
-const FRAME_TYPE_STREAM_MAX: FrameType = 0xf;
-const FRAME_TYPE_MAX_DATA: FrameType = 0x10;
-const FRAME_TYPE_MAX_STREAM_DATA: FrameType = 0x11;
-const FRAME_TYPE_MAX_STREAMS_BIDI: FrameType = 0x12;
-const FRAME_TYPE_MAX_STREAMS_UNIDI: FrameType = 0x13;
-const FRAME_TYPE_DATA_BLOCKED: FrameType = 0x14;
-const FRAME_TYPE_STREAM_DATA_BLOCKED: FrameType = 0x15;
-const FRAME_TYPE_STREAMS_BLOCKED_BIDI: FrameType = 0x16;
-const FRAME_TYPE_STREAMS_BLOCKED_UNIDI: FrameType = 0x17;
-const FRAME_TYPE_NEW_CONNECTION_ID: FrameType = 0x18;
-const FRAME_TYPE_RETIRE_CONNECTION_ID: FrameType = 0x19;
-const FRAME_TYPE_PATH_CHALLENGE: FrameType = 0x1a;
-const FRAME_TYPE_PATH_RESPONSE: FrameType = 0x1b;
-pub const FRAME_TYPE_CONNECTION_CLOSE_TRANSPORT: FrameType = 0x1c;
-pub const FRAME_TYPE_CONNECTION_CLOSE_APPLICATION: FrameType = 0x1d;
-const FRAME_TYPE_HANDSHAKE_DONE: FrameType = 0x1e;
+pub enum FrameType {
+    Padding,
+    Ping,
+    Ack,
+    ResetStream,
+    StopSending,
+    Crypto,
+    NewToken,
+    Stream,
+    MaxData,
+    MaxStreamData,
+    MaxStreams,
+    DataBlocked,
+    StreamDataBlocked,
+    StreamsBlocked,
+    NewConnectionId,
+    RetireConnectionId,
+    PathChallenge,
+    PathResponse,
+    ConnectionClose,
+    HandshakeDone,
+    Datagram,
+    AckFrequency,
+    StreamGap,
+    Unknown(u64),
+}
+
+impl FrameType {
+    /// The name of the frame type, as it appears in the specification.
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Padding => "PADDING",
+            Self::Ping => "PING",
+            Self::Ack => "ACK",
+            Self::ResetStream => "RESET_STREAM",
+            Self::StopSending => "STOP_SENDING",
+            Self::Crypto => "CRYPTO",
+            Self::NewToken => "NEW_TOKEN",
+            Self::Stream => "STREAM",
+            Self::MaxData => "MAX_DATA",
+            Self::MaxStreamData => "MAX_STREAM_DATA",
+            Self::MaxStreams => "MAX_STREAMS",
+            Self::DataBlocked => "DATA_BLOCKED",
+            Self::StreamDataBlocked => "STREAM_DATA_BLOCKED",
+            Self::StreamsBlocked => "STREAMS_BLOCKED",
+            Self::NewConnectionId => "NEW_CONNECTION_ID",
+            Self::RetireConnectionId => "RETIRE_CONNECTION_ID",
+            Self::PathChallenge => "PATH_CHALLENGE",
+            Self::PathResponse => "PATH_RESPONSE",
+            Self::ConnectionClose => "CONNECTION_CLOSE",
+            Self::HandshakeDone => "HANDSHAKE_DONE",
+            Self::Datagram => "DATAGRAM",
+            Self::AckFrequency => "ACK_FREQUENCY",
+            Self::StreamGap => "STREAM_GAP",
+            Self::Unknown(_) => "unknown",
+        }
+    }
+}
+
+impl From<u64> for FrameType {
+    fn from(t: u64) -> Self {
+        match t {
+            FRAME_TYPE_PADDING => Self::Padding,
+            FRAME_TYPE_PING => Self::Ping,
+            FRAME_TYPE_ACK | FRAME_TYPE_ACK_ECN => Self::Ack,
+            FRAME_TYPE_RST_STREAM => Self::ResetStream,
+            FRAME_TYPE_STOP_SENDING => Self::StopSending,
+            FRAME_TYPE_CRYPTO => Self::Crypto,
+            FRAME_TYPE_NEW_TOKEN => Self::NewToken,
+            FRAME_TYPE_STREAM..=FRAME_TYPE_STREAM_MAX => Self::Stream,
+            FRAME_TYPE_MAX_DATA => Self::MaxData,
+            FRAME_TYPE_MAX_STREAM_DATA => Self::MaxStreamData,
+            FRAME_TYPE_MAX_STREAMS_BIDI | FRAME_TYPE_MAX_STREAMS_UNIDI => Self::MaxStreams,
+            FRAME_TYPE_DATA_BLOCKED => Self::DataBlocked,
+            FRAME_TYPE_STREAM_DATA_BLOCKED => Self::StreamDataBlocked,
+            FRAME_TYPE_STREAMS_BLOCKED_BIDI | FRAME_TYPE_STREAMS_BLOCKED_UNIDI => {
+                Self::StreamsBlocked
+            }
+            FRAME_TYPE_NEW_CONNECTION_ID => Self::NewConnectionId,
+            FRAME_TYPE_RETIRE_CONNECTION_ID => Self::RetireConnectionId,
+            FRAME_TYPE_PATH_CHALLENGE => Self::PathChallenge,
+            FRAME_TYPE_PATH_RESPONSE => Self::PathResponse,
+            FRAME_TYPE_CONNECTION_CLOSE_TRANSPORT | FRAME_TYPE_CONNECTION_CLOSE_APPLICATION => {
+                Self::ConnectionClose
+            }
+            FRAME_TYPE_HANDSHAKE_DONE => Self::HandshakeDone,
+            FRAME_TYPE_DATAGRAM | FRAME_TYPE_DATAGRAM_WITH_LEN => Self::Datagram,
+            FRAME_TYPE_ACK_FREQUENCY => Self::AckFrequency,
+            FRAME_TYPE_STREAM_GAP => Self::StreamGap,
+            _ => Self::Unknown(t),
+        }
+    }
+}
+
+impl ::std::fmt::Display for FrameType {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        if let Self::Unknown(t) = self {
+            write!(f, "unknown({:#x})", t)
+        } else {
+            f.write_str(self.name())
+        }
+    }
+}
 
 const STREAM_FRAME_BIT_FIN: u64 = 0x01;
 const STREAM_FRAME_BIT_LEN: u64 = 0x02;
@@ -100,13 +215,13 @@ impl CloseError {
         if (bit & 0x01) == 0 {
             Self::Transport(code)
         } else {
-            Self::Application(code)
+            Self::Application(AppError(code))
         }
     }
 
     pub fn code(&self) -> u64 {
         match self {
-            Self::Transport(c) | Self::Application(c) => *c,
+            Self::Transport(c) | Self::Application(AppError(c)) => *c,
         }
     }
 }
@@ -135,6 +250,7 @@ pub enum Frame {
         ack_delay: u64,
         first_ack_range: u64,
         ack_ranges: Vec<AckRange>,
+        ecn_count: Option<EcnCount>,
     },
     ResetStream {
         stream_id: StreamId,
@@ -202,14 +318,40 @@ pub enum Frame {
         reason_phrase: Vec<u8>,
     },
     HandshakeDone,
+    AckFrequency {
+        /// The current ACK frequency sequence number.
+        seqno: u64,
+        /// The number of ack-eliciting packets that can be received before sending an ACK.
+        tolerance: u64,
+        /// The largest delay, in microseconds, before sending an ACK.
+        delay: u64,
+        /// Whether to ignore reordering when deciding to send an ACK.
+        ignore_order: bool,
+    },
+    /// An unreliable datagram.  This is always sent with a length.
+    Datagram {
+        data: Vec<u8>,
+    },
+    /// Stream data that the sender won't retransmit.
+    StreamGap {
+        stream_id: StreamId,
+        offset: u64,
+        length: u64,
+    },
 }
 
 impl Frame {
-    pub fn get_type(&self) -> FrameType {
+    pub fn get_type(&self) -> u64 {
         match self {
             Self::Padding => FRAME_TYPE_PADDING,
             Self::Ping => FRAME_TYPE_PING,
-            Self::Ack { .. } => FRAME_TYPE_ACK, // We don't do ACK ECN.
+            Self::Ack { ecn_count, .. } => {
+                if ecn_count.is_some() {
+                    FRAME_TYPE_ACK_ECN
+                } else {
+                    FRAME_TYPE_ACK
+                }
+            }
             Self::ResetStream { .. } => FRAME_TYPE_RST_STREAM,
             Self::StopSending { .. } => FRAME_TYPE_STOP_SENDING,
             Self::Crypto { .. } => FRAME_TYPE_CRYPTO,
@@ -247,6 +389,9 @@ impl Frame {
                 FRAME_TYPE_CONNECTION_CLOSE_TRANSPORT + error_code.frame_type_bit()
             }
             Self::HandshakeDone => FRAME_TYPE_HANDSHAKE_DONE,
+            Self::AckFrequency { .. } => FRAME_TYPE_ACK_FREQUENCY,
+            Self::Datagram { .. } => FRAME_TYPE_DATAGRAM_WITH_LEN,
+            Self::StreamGap { .. } => FRAME_TYPE_STREAM_GAP,
         }
     }
 
@@ -343,6 +488,7 @@ impl Frame {
                 ack_delay,
                 first_ack_range,
                 ack_ranges,
+                ecn_count,
             } => {
                 enc.encode_varint(*largest_acknowledged);
                 enc.encode_varint(*ack_delay);
@@ -352,6 +498,11 @@ impl Frame {
                     enc.encode_varint(r.gap);
                     enc.encode_varint(r.range);
                 }
+                if let Some(counts) = ecn_count {
+                    enc.encode_varint(counts.ect0);
+                    enc.encode_varint(counts.ect1);
+                    enc.encode_varint(counts.ce);
+                }
             }
             Self::ResetStream {
                 stream_id,
@@ -452,6 +603,29 @@ impl Frame {
                 enc.encode_vvec(reason_phrase);
             }
             Self::HandshakeDone => (),
+            Self::AckFrequency {
+                seqno,
+                tolerance,
+                delay,
+                ignore_order,
+            } => {
+                enc.encode_varint(*seqno);
+                enc.encode_varint(*tolerance);
+                enc.encode_varint(*delay);
+                enc.encode_byte(u8::from(*ignore_order));
+            }
+            Self::Datagram { data } => {
+                enc.encode_vvec(data);
+            }
+            Self::StreamGap {
+                stream_id,
+                offset,
+                length,
+            } => {
+                enc.encode_varint(stream_id.as_u64());
+                enc.encode_varint(*offset);
+                enc.encode_varint(*length);
+            }
         }
     }
 
@@ -472,6 +646,18 @@ impl Frame {
         !matches!(self, Self::Ack { .. } | Self::Padding | Self::ConnectionClose { .. })
     }
 
+    /// Whether this is a probing frame (RFC 9000, Section 9.1).  A packet that
+    /// contains only probing frames doesn't cause a peer to migrate.
+    pub fn is_probing(&self) -> bool {
+        matches!(
+            self,
+            Self::PathChallenge { .. }
+                | Self::PathResponse { .. }
+                | Self::NewConnectionId { .. }
+                | Self::Padding
+        )
+    }
+
     /// Converts AckRanges as encoded in a ACK frame (see -transport
     /// 19.3.1) into ranges of acked packets (end, start), inclusive of
     /// start and end values.
@@ -576,7 +762,7 @@ impl Frame {
             FRAME_TYPE_PING => Ok(Self::Ping),
             FRAME_TYPE_RST_STREAM => Ok(Self::ResetStream {
                 stream_id: dv!(dec).into(),
-                application_error_code: d!(dec.decode_varint()),
+                application_error_code: AppError(dv!(dec)),
                 final_size: match dec.decode_varint() {
                     Some(v) => v,
                     _ => return Err(Error::NoMoreData),
@@ -597,22 +783,27 @@ impl Frame {
                 }
 
                 // Now check for the values for ACK_ECN.
-                if t == FRAME_TYPE_ACK_ECN {
-                    dv!(dec);
-                    dv!(dec);
-                    dv!(dec);
-                }
+                let ecn_count = if t == FRAME_TYPE_ACK_ECN {
+                    Some(EcnCount {
+                        ect0: dv!(dec),
+                        ect1: dv!(dec),
+                        ce: dv!(dec),
+                    })
+                } else {
+                    None
+                };
 
                 Ok(Self::Ack {
                     largest_acknowledged: la,
                     ack_delay: ad,
                     first_ack_range: fa,
                     ack_ranges: arr,
+                    ecn_count,
                 })
             }
             FRAME_TYPE_STOP_SENDING => Ok(Self::StopSending {
                 stream_id: dv!(dec).into(),
-                application_error_code: d!(dec.decode_varint()),
+                application_error_code: AppError(dv!(dec)),
             }),
             FRAME_TYPE_CRYPTO => {
                 let o = dv!(dec);
@@ -695,6 +886,9 @@ impl Frame {
                 if cid.len() > MAX_CONNECTION_ID_LEN {
                     return Err(Error::DecodingFrame);
                 }
+                if cid.is_empty() || retire_prior > s {
+                    return Err(Error::FrameEncodingError);
+                }
                 let srt = d!(dec.decode(16));
                 let mut srtv: [u8; 16] = [0; 16];
                 srtv.copy_from_slice(&srt);
@@ -729,6 +923,44 @@ impl Frame {
                 })
             }
             FRAME_TYPE_HANDSHAKE_DONE => Ok(Self::HandshakeDone),
+            FRAME_TYPE_ACK_FREQUENCY => {
+                let seqno = dv!(dec);
+                let tolerance = dv!(dec);
+                if tolerance == 0 {
+                    return Err(Error::FrameEncodingError);
+                }
+                let delay = dv!(dec);
+                let ignore_order = match d!(dec.decode_uint(1)) {
+                    0 => false,
+                    1 => true,
+                    _ => return Err(Error::FrameEncodingError),
+                };
+                Ok(Self::AckFrequency {
+                    seqno,
+                    tolerance,
+                    delay,
+                    ignore_order,
+                })
+            }
+            FRAME_TYPE_DATAGRAM => Ok(Self::Datagram {
+                data: dec.decode_remainder().to_vec(),
+            }),
+            FRAME_TYPE_DATAGRAM_WITH_LEN => Ok(Self::Datagram {
+                data: d!(dec.decode_vvec()).to_vec(),
+            }),
+            FRAME_TYPE_STREAM_GAP => {
+                let stream_id = dv!(dec).into();
+                let offset = dv!(dec);
+                let length = dv!(dec);
+                if length == 0 || offset + length > (1 << 62) - 1 {
+                    return Err(Error::FrameEncodingError);
+                }
+                Ok(Self::StreamGap {
+                    stream_id,
+                    offset,
+                    length,
+                })
+            }
             _ => Err(Error::UnknownFrameType),
         }
     }
@@ -739,6 +971,17 @@ mod tests {
     use super::*;
     use neqo_common::hex;
 
+    #[test]
+    fn frame_type_names() {
+        assert_eq!(FrameType::from(FRAME_TYPE_PADDING), FrameType::Padding);
+        assert_eq!(FrameType::from(FRAME_TYPE_ACK_ECN), FrameType::Ack);
+        assert_eq!(FrameType::from(0x0e).to_string(), "STREAM");
+        assert_eq!(FrameType::from(0x13).to_string(), "MAX_STREAMS");
+        assert_eq!(FrameType::from(0x1d).to_string(), "CONNECTION_CLOSE");
+        assert_eq!(FrameType::from(0x40), FrameType::Unknown(0x40));
+        assert_eq!(FrameType::from(0x40).to_string(), "unknown(0x40)");
+    }
+
     fn enc_dec(f: &Frame, s: &str) {
         let mut d = Encoder::default();
 
@@ -769,7 +1012,8 @@ mod tests {
             largest_acknowledged: 0x1234,
             ack_delay: 0x1235,
             first_ack_range: 0x1236,
-            ack_ranges: ar,
+            ack_ranges: ar.clone(),
+            ecn_count: None,
         };
 
         enc_dec(&f, "025234523502523601020304");
@@ -779,17 +1023,26 @@ mod tests {
         let mut dec = enc.as_decoder();
         assert_eq!(Frame::decode(&mut dec).unwrap_err(), Error::NoMoreData);
 
-        // Try to parse ACK_ECN without ECN values
-        let enc = Encoder::from_hex("035234523502523601020304010203");
-        let mut dec = enc.as_decoder();
-        assert_eq!(Frame::decode(&mut dec).unwrap(), f);
+        // Parse ACK_ECN with ECN values
+        let f = Frame::Ack {
+            largest_acknowledged: 0x1234,
+            ack_delay: 0x1235,
+            first_ack_range: 0x1236,
+            ack_ranges: ar,
+            ecn_count: Some(EcnCount {
+                ect0: 1,
+                ect1: 2,
+                ce: 3,
+            }),
+        };
+        enc_dec(&f, "035234523502523601020304010203");
     }
 
     #[test]
     fn test_reset_stream() {
         let f = Frame::ResetStream {
             stream_id: 0x1234.into(),
-            application_error_code: 0x77,
+            application_error_code: AppError(0x77),
             final_size: 0x3456,
         };
 
@@ -800,7 +1053,7 @@ mod tests {
     fn test_stop_sending() {
         let f = Frame::StopSending {
             stream_id: 63.into(),
-            application_error_code: 0x77,
+            application_error_code: AppError(0x77),
         };
 
         enc_dec(&f, "053F4077")
@@ -961,6 +1214,27 @@ mod tests {
         );
     }
 
+    #[test]
+    fn bad_new_connection_id() {
+        // An empty connection ID.
+        let mut enc = Encoder::from_hex("18523400");
+        enc.encode_vvec(&[]);
+        enc.encode(&[0x11; 16][..]);
+        assert_eq!(
+            Frame::decode(&mut enc.as_decoder()).unwrap_err(),
+            Error::FrameEncodingError
+        );
+
+        // Retire Prior To is larger than the sequence number.
+        let mut enc = Encoder::from_hex("180102");
+        enc.encode_vvec(&[0x0c; 8]);
+        enc.encode(&[0x11; 16][..]);
+        assert_eq!(
+            Frame::decode(&mut enc.as_decoder()).unwrap_err(),
+            Error::FrameEncodingError
+        );
+    }
+
     #[test]
     fn test_retire_connection_id() {
         let f = Frame::RetireConnectionId {
@@ -995,7 +1269,7 @@ mod tests {
         enc_dec(&f, "1c80005678523403010203");
 
         f = Frame::ConnectionClose {
-            error_code: CloseError::Application(0x5678),
+            error_code: CloseError::Application(AppError(0x5678)),
             frame_type: 0x1234,
             reason_phrase: vec![0x01, 0x02, 0x03],
         };
@@ -1003,6 +1277,62 @@ mod tests {
         enc_dec(&f, "1d80005678523403010203");
     }
 
+    #[test]
+    fn ack_frequency() {
+        let f = Frame::AckFrequency {
+            seqno: 1,
+            tolerance: 10,
+            delay: 25000,
+            ignore_order: true,
+        };
+        enc_dec(&f, "40af010a800061a801");
+    }
+
+    #[test]
+    fn ack_frequency_bad() {
+        // A tolerance of zero.
+        let enc = Encoder::from_hex("40af0100800061a800");
+        assert_eq!(
+            Frame::decode(&mut enc.as_decoder()).unwrap_err(),
+            Error::FrameEncodingError
+        );
+        // A bad value for Ignore Order.
+        let enc = Encoder::from_hex("40af010a800061a802");
+        assert_eq!(
+            Frame::decode(&mut enc.as_decoder()).unwrap_err(),
+            Error::FrameEncodingError
+        );
+    }
+
+    #[test]
+    fn datagram() {
+        let f = Frame::Datagram {
+            data: vec![1, 2, 3],
+        };
+        enc_dec(&f, "3103010203");
+
+        // Without a length, the datagram takes the rest of the packet.
+        let enc = Encoder::from_hex("30010203");
+        assert_eq!(Frame::decode(&mut enc.as_decoder()).unwrap(), f);
+    }
+
+    #[test]
+    fn stream_gap() {
+        let f = Frame::StreamGap {
+            stream_id: StreamId::from(4),
+            offset: 100,
+            length: 50,
+        };
+        enc_dec(&f, "80ff5a7e04406432");
+
+        // An empty gap.
+        let enc = Encoder::from_hex("80ff5a7e04406400");
+        assert_eq!(
+            Frame::decode(&mut enc.as_decoder()).unwrap_err(),
+            Error::FrameEncodingError
+        );
+    }
+
     #[test]
     fn test_compare() {
         let f1 = Frame::Padding;
@@ -1041,6 +1371,7 @@ mod tests {
                 gap: 0,   // 4
                 range: 1, // 3, 2
             }],
+            ecn_count: None,
         };
         let mut enc = Encoder::default();
         ack_frame.marshal(&mut enc);
@@ -1052,6 +1383,7 @@ mod tests {
             ack_delay,
             first_ack_range,
             ack_ranges,
+            ..
         } = f
         {
             assert_eq!(largest_acknowledged, 7);
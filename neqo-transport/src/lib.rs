@@ -9,19 +9,23 @@
 
 use neqo_common::qinfo;
 
+mod ackrate;
 mod addr_valid;
 mod cc;
 mod cid;
 mod connection;
 mod crypto;
 mod dump;
+mod ecn;
 mod events;
 mod flow_mgr;
 mod frame;
 mod pace;
 mod packet;
 mod path;
+mod pmtud;
 mod qlog;
+mod quic_datagrams;
 mod recovery;
 mod recv_stream;
 mod send_stream;
@@ -31,14 +35,22 @@ mod stream_id;
 pub mod tparams;
 mod tracking;
 
+pub use self::cc::{Bbr, CongestionControl, NewReno};
 pub use self::cid::{ConnectionId, ConnectionIdManager};
-pub use self::connection::{Connection, FixedConnectionIdManager, Output, State, ZeroRttState};
-pub use self::events::{ConnectionEvent, ConnectionEvents};
-pub use self::frame::CloseError;
+pub use self::connection::{
+    Connection, ConnectionParameters, FixedConnectionIdManager, Output, State, ZeroRttState,
+};
+pub use self::events::{CloseOrigin, ConnectionEvent, ConnectionEvents};
 pub use self::frame::StreamType;
+pub use self::frame::{CloseError, FrameType};
 pub use self::packet::QuicVersion;
+pub use self::path::{PathInfo, PathStatus};
+pub use self::recovery::RttEstimate;
+pub use self::recv_stream::RecvStreamStatus;
+pub use self::send_stream::SendStreamStatus;
 pub use self::stats::Stats;
-pub use self::stream_id::StreamId;
+pub use self::stream_id::{StreamId, StreamInfo};
+pub use self::tracking::{PacketNumberInfo, PacketNumberSpaceInfo, SentPacket};
 
 const LOCAL_IDLE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30); // 30 second
 pub use self::recv_stream::RECV_BUFFER_SIZE;
@@ -59,6 +71,7 @@ pub enum Error {
     FinalSizeError,
     FrameEncodingError,
     TransportParameterError,
+    ConnectionIdLimitError,
     ProtocolViolation,
     InvalidToken,
     ApplicationError,
@@ -69,6 +82,8 @@ pub enum Error {
     // All internal errors from here.
     AckedUnsentPacket,
     ConnectionState,
+    /// A datagram is too large to send.
+    DatagramTooLarge,
     DecodingFrame,
     DecryptError,
     HandshakeFailed,
@@ -90,6 +105,8 @@ pub enum Error {
     /// a packet sent with the current keys hasn't been acknowledged.
     KeyUpdateBlocked,
     NoMoreData,
+    /// The peer does not support the feature.
+    NotAvailable,
     NotConnected,
     PacketNumberOverlap,
     PeerApplicationError(AppError),
@@ -116,8 +133,10 @@ impl Error {
             Self::FinalSizeError => 6,
             Self::FrameEncodingError => 7,
             Self::TransportParameterError => 8,
+            Self::ConnectionIdLimitError => 9,
             Self::ProtocolViolation => 10,
             Self::InvalidToken => 11,
+            Self::VersionNegotiation => 0x11,
             Self::ApplicationError => ERROR_APPLICATION_CLOSE,
             Self::CryptoAlert(a) => 0x100 + u64::from(*a),
             // All the rest are internal errors.
@@ -160,7 +179,63 @@ impl ::std::fmt::Display for Error {
     }
 }
 
-pub type AppError = u64;
+/// An application protocol error code, as carried in `RESET_STREAM`,
+/// `STOP_SENDING`, and application `CONNECTION_CLOSE` frames.  Any value is
+/// allowed; codes defined by HTTP/3 and QPACK are displayed with their names.
+#[derive(Clone, Copy, Debug, Default, PartialEq, PartialOrd, Ord, Eq, Hash)]
+pub struct AppError(pub u64);
+
+impl AppError {
+    /// The name of the code, if it is one defined by HTTP/3 (RFC 9114) or QPACK (RFC 9204).
+    #[must_use]
+    pub fn name(self) -> Option<&'static str> {
+        Some(match self.0 {
+            0x100 => "H3_NO_ERROR",
+            0x101 => "H3_GENERAL_PROTOCOL_ERROR",
+            0x102 => "H3_INTERNAL_ERROR",
+            0x103 => "H3_STREAM_CREATION_ERROR",
+            0x104 => "H3_CLOSED_CRITICAL_STREAM",
+            0x105 => "H3_FRAME_UNEXPECTED",
+            0x106 => "H3_FRAME_ERROR",
+            0x107 => "H3_EXCESSIVE_LOAD",
+            0x108 => "H3_ID_ERROR",
+            0x109 => "H3_SETTINGS_ERROR",
+            0x10a => "H3_MISSING_SETTINGS",
+            0x10b => "H3_REQUEST_REJECTED",
+            0x10c => "H3_REQUEST_CANCELLED",
+            0x10d => "H3_REQUEST_INCOMPLETE",
+            0x10e => "H3_MESSAGE_ERROR",
+            0x10f => "H3_CONNECT_ERROR",
+            0x110 => "H3_VERSION_FALLBACK",
+            0x200 => "QPACK_DECOMPRESSION_FAILED",
+            0x201 => "QPACK_ENCODER_STREAM_ERROR",
+            0x202 => "QPACK_DECODER_STREAM_ERROR",
+            _ => return None,
+        })
+    }
+}
+
+impl From<u64> for AppError {
+    fn from(code: u64) -> Self {
+        Self(code)
+    }
+}
+
+impl From<AppError> for u64 {
+    fn from(err: AppError) -> Self {
+        err.0
+    }
+}
+
+impl ::std::fmt::Display for AppError {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        if let Some(name) = self.name() {
+            f.write_str(name)
+        } else {
+            write!(f, "{:#x}", self.0)
+        }
+    }
+}
 
 #[derive(Clone, Debug, PartialEq, PartialOrd, Ord, Eq)]
 pub enum ConnectionError {
@@ -187,3 +262,20 @@ impl From<CloseError> for ConnectionError {
 }
 
 pub type Res<T> = std::result::Result<T, Error>;
+
+#[cfg(test)]
+mod tests {
+    use super::AppError;
+
+    #[test]
+    fn app_error_names() {
+        assert_eq!(AppError(0x100).to_string(), "H3_NO_ERROR");
+        assert_eq!(AppError(0x10c).to_string(), "H3_REQUEST_CANCELLED");
+        assert_eq!(AppError(0x110).to_string(), "H3_VERSION_FALLBACK");
+        assert_eq!(AppError(0x202).to_string(), "QPACK_DECODER_STREAM_ERROR");
+        assert_eq!(AppError(0x111).name(), None);
+        assert_eq!(AppError(0x111).to_string(), "0x111");
+        assert_eq!(AppError::from(42), AppError(42));
+        assert_eq!(u64::from(AppError(42)), 42);
+    }
+}
@@ -73,6 +73,17 @@ impl Pacer {
         }
     }
 
+    /// The rate at which credit accrues, in bytes per second, for the provided
+    /// RTT and congestion window.
+    pub fn rate(rtt: Duration, cwnd: usize) -> u64 {
+        u128::try_from(cwnd * PACER_SPEEDUP)
+            .unwrap()
+            .saturating_mul(1_000_000_000)
+            .checked_div(rtt.as_nanos())
+            .and_then(|r| u64::try_from(r).ok())
+            .unwrap_or(u64::MAX)
+    }
+
     /// Spend credit.  This cannot fail; users of this API are expected to call
     /// next() to determine when to spend.  This takes the current time (`now`),
     /// an estimate of the round trip time (`rtt`), the estimated congestion
@@ -108,6 +119,87 @@ impl Debug for Pacer {
     }
 }
 
+/// A token bucket that limits the rate at which bytes are sent, independent
+/// of the congestion window.
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// The rate at which credit accrues, in bytes per second.
+    rate: u64,
+    /// The last update time, which is set the first time this is used.
+    t: Option<Instant>,
+    /// The maximum capacity, or burst size, in bytes.
+    m: i64,
+    /// The current capacity, in bytes.  A packet that is larger than the
+    /// available credit takes this below zero, and that debt has to be
+    /// repaid before the next packet is sent.
+    c: i64,
+    /// The packet size or minimum capacity for sending, in bytes.
+    p: i64,
+}
+
+impl RateLimiter {
+    /// Create a new `RateLimiter` for `rate` bytes per second, with a maximum
+    /// capacity of `m` and a packet size of `p`, as for `Pacer`.  This starts
+    /// without any credit.
+    pub fn new(rate: u64, m: usize, p: usize) -> Self {
+        assert!(rate > 0, "rate has to be positive");
+        assert!(m >= p, "maximum capacity has to be at least one packet");
+        Self {
+            rate,
+            t: None,
+            m: i64::try_from(m).unwrap(),
+            c: 0,
+            p: i64::try_from(p).unwrap(),
+        }
+    }
+
+    /// Start accruing credit, if that hasn't already started.
+    pub fn start(&mut self, now: Instant) {
+        if self.t.is_none() {
+            self.t = Some(now);
+        }
+    }
+
+    /// Determine when the next packet will be available.  Like `Pacer::next`,
+    /// this could be in the past.  This is `None` if `start` hasn't been called.
+    pub fn next(&self) -> Option<Instant> {
+        let t = self.t?;
+        if self.c >= self.p {
+            return Some(t);
+        }
+        // Round up, so that enough credit is available at the returned time.
+        let needed = u128::try_from(self.p - self.c)
+            .unwrap()
+            .saturating_mul(1_000_000_000);
+        let rate = u128::from(self.rate);
+        let nanos = needed / rate + u128::from(needed % rate > 0);
+        let w = u64::try_from(nanos).map_or(Duration::from_secs(1), Duration::from_nanos);
+        qtrace!([self], "next wait {:?}", w);
+        Some(t + w)
+    }
+
+    /// Spend credit for `count` bytes that were sent at `now`.  All of `count`
+    /// is charged, even if that leaves the capacity below zero.
+    pub fn spend(&mut self, now: Instant, count: usize) {
+        let t = self.t.unwrap_or(now);
+        let incr = now
+            .saturating_duration_since(t)
+            .as_nanos()
+            .saturating_mul(u128::from(self.rate))
+            / 1_000_000_000;
+        let incr = i64::try_from(incr).unwrap_or(self.m);
+        let count = i64::try_from(count).unwrap_or(i64::MAX);
+        self.c = min(self.m, self.c.saturating_add(incr)).saturating_sub(count);
+        self.t = Some(now);
+    }
+}
+
+impl Display for RateLimiter {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "RateLimiter {}/{}@{}", self.c, self.p, self.rate)
+    }
+}
+
 #[cfg(tests)]
 mod tests {
     use super::Pacer;
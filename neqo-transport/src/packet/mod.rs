@@ -25,7 +25,7 @@ const PACKET_TYPE_RETRY: u8 = 0x03;
 
 pub const PACKET_BIT_LONG: u8 = 0x80;
 const PACKET_BIT_SHORT: u8 = 0x00;
-const PACKET_BIT_FIXED_QUIC: u8 = 0x40;
+pub const PACKET_BIT_FIXED_QUIC: u8 = 0x40;
 const PACKET_BIT_SPIN: u8 = 0x20;
 const PACKET_BIT_KEY_PHASE: u8 = 0x04;
 
@@ -95,6 +95,11 @@ pub enum QuicVersion {
 }
 
 impl QuicVersion {
+    /// All of the versions that are supported.
+    pub fn all() -> &'static [Self] {
+        &[Self::Draft27, Self::Draft28, Self::Draft29]
+    }
+
     pub fn as_u32(self) -> Version {
         match self {
             Self::Draft27 => 0xff00_0000 + 27,
@@ -206,6 +211,16 @@ impl PacketBuilder {
         self[first] ^= random(1)[0] & mask;
     }
 
+    /// Set the latency spin bit in a short header packet.
+    pub fn set_spin(&mut self, spin: bool) {
+        debug_assert!(!self.is_long());
+        let first = self.header.start;
+        self[first] &= !PACKET_BIT_SPIN;
+        if spin {
+            self[first] |= PACKET_BIT_SPIN;
+        }
+    }
+
     /// For an Initial packet, encode the token.
     /// If you fail to do this, then you will not get a valid packet.
     pub fn initial_token(&mut self, token: &[u8]) {
@@ -325,7 +340,7 @@ impl PacketBuilder {
     }
 
     /// Make a Version Negotiation packet.
-    pub fn version_negotiation(dcid: &[u8], scid: &[u8]) -> Vec<u8> {
+    pub fn version_negotiation(dcid: &[u8], scid: &[u8], versions: &[QuicVersion]) -> Vec<u8> {
         let mut encoder = Encoder::default();
         let mut grease = random(5);
         // This will not include the "QUIC bit" sometimes.  Intentionally.
@@ -333,9 +348,9 @@ impl PacketBuilder {
         encoder.encode(&[0; 4]); // Zero version == VN.
         encoder.encode_vec(1, dcid);
         encoder.encode_vec(1, scid);
-        encoder.encode_uint(4, QuicVersion::Draft27.as_u32());
-        encoder.encode_uint(4, QuicVersion::Draft28.as_u32());
-        encoder.encode_uint(4, QuicVersion::Draft29.as_u32());
+        for v in versions {
+            encoder.encode_uint(4, v.as_u32());
+        }
         // Add a greased version, using the randomness already generated.
         for g in &mut grease[..4] {
             *g = *g & 0xf0 | 0x0a;
@@ -549,6 +564,12 @@ impl<'a> PublicPacket<'a> {
         self.packet_type
     }
 
+    /// The value of the latency spin bit, which only short header packets carry.
+    /// Header protection doesn't cover this bit.
+    pub fn spin(&self) -> bool {
+        self.packet_type == PacketType::Short && (self.data[0] & PACKET_BIT_SPIN) != 0
+    }
+
     pub fn dcid(&self) -> &ConnectionIdRef<'a> {
         &self.dcid
     }
@@ -1137,7 +1158,7 @@ mod tests {
     #[test]
     fn build_vn() {
         fixture_init();
-        let mut vn = PacketBuilder::version_negotiation(SERVER_CID, CLIENT_CID);
+        let mut vn = PacketBuilder::version_negotiation(SERVER_CID, CLIENT_CID, QuicVersion::all());
         // Erase randomness from greasing...
         assert_eq!(vn.len(), SAMPLE_VN.len());
         vn[0] &= 0x80;
@@ -4,11 +4,18 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use std::convert::TryFrom;
 use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 
-use crate::cid::{ConnectionId, ConnectionIdRef};
+use crate::cid::{ConnectionId, ConnectionIdEntry, ConnectionIdRef};
+use crate::frame::Frame;
+use crate::packet::PacketNumber;
+use crate::pmtud::Pmtud;
+use crate::recovery::RecoveryToken;
 
-use neqo_common::Datagram;
+use neqo_common::{Datagram, IpTos, Role};
+use neqo_crypto::random;
 
 /// This is the MTU that we assume when using IPv6.
 /// We use this size for Initial packets, so we don't need to worry about probing for support.
@@ -18,6 +25,9 @@ use neqo_common::Datagram;
 pub const PATH_MTU_V6: usize = 1337;
 /// The path MTU for IPv4 can be 20 bytes larger than for v6.
 pub const PATH_MTU_V4: usize = PATH_MTU_V6 + 20;
+/// Before the address of a peer is validated, a server can send this many
+/// times the number of bytes that it has received.
+const AMPLIFICATION_FACTOR: usize = 3;
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Path {
@@ -25,7 +35,11 @@ pub struct Path {
     remote: SocketAddr,
     local_cids: Vec<ConnectionId>,
     remote_cid: ConnectionId,
+    /// The sequence number of the remote connection ID.
+    remote_seqno: u64,
     reset_token: Option<[u8; 16]>,
+    pmtud: Pmtud,
+    spin: SpinBit,
 }
 
 impl Path {
@@ -41,20 +55,57 @@ impl Path {
             remote,
             local_cids: vec![local_cid],
             remote_cid,
+            remote_seqno: 0,
             reset_token: None,
+            pmtud: Pmtud::new(&local),
+            spin: SpinBit::default(),
         }
     }
 
+    /// Make a copy of this path that uses different addresses.  If `cid` is
+    /// provided, the new path uses that connection ID, so that the peer can't
+    /// link the two paths.
+    pub fn migrated(
+        &self,
+        local: SocketAddr,
+        remote: SocketAddr,
+        cid: Option<&ConnectionIdEntry>,
+    ) -> Self {
+        let mut path = Self {
+            local,
+            remote,
+            pmtud: Pmtud::new(&local),
+            spin: SpinBit::default(),
+            ..self.clone()
+        };
+        if let Some(entry) = cid {
+            path.set_remote_cid_entry(entry);
+        }
+        path
+    }
+
     pub fn received_on(&self, d: &Datagram) -> bool {
         self.local == d.destination() && self.remote == d.source()
     }
 
+    /// The largest datagram that can be sent on this path.
     pub fn mtu(&self) -> usize {
-        if self.local.is_ipv4() {
-            PATH_MTU_V4
-        } else {
-            PATH_MTU_V6 // IPv6
-        }
+        self.pmtud.mtu()
+    }
+
+    /// Access the latency spin bit state.
+    pub fn spin(&self) -> &SpinBit {
+        &self.spin
+    }
+
+    /// Access the latency spin bit state mutably.
+    pub fn spin_mut(&mut self) -> &mut SpinBit {
+        &mut self.spin
+    }
+
+    /// Access the path MTU discovery state.
+    pub fn pmtud_mut(&mut self) -> &mut Pmtud {
+        &mut self.pmtud
     }
 
     /// Add a connection ID to the local set.
@@ -62,11 +113,6 @@ impl Path {
         self.local_cids.push(cid);
     }
 
-    /// Determine if the given connection ID is valid.
-    pub fn valid_local_cid(&self, cid: &ConnectionIdRef) -> bool {
-        self.local_cids.iter().any(|c| c == cid)
-    }
-
     /// Get the first local connection ID.
     pub fn local_cid(&self) -> &ConnectionId {
         self.local_cids.first().as_ref().unwrap()
@@ -82,6 +128,18 @@ impl Path {
         &self.remote_cid
     }
 
+    /// Use a connection ID that the peer provided in NEW_CONNECTION_ID.
+    pub fn set_remote_cid_entry(&mut self, entry: &ConnectionIdEntry) {
+        self.remote_cid = entry.connection_id().clone();
+        self.remote_seqno = entry.seqno();
+        self.reset_token = Some(*entry.reset_token());
+    }
+
+    /// The sequence number of the remote connection ID.
+    pub fn remote_seqno(&self) -> u64 {
+        self.remote_seqno
+    }
+
     /// Set the stateless reset token for the connection ID that is currently in use.
     pub fn set_reset_token(&mut self, token: [u8; 16]) {
         self.reset_token = Some(token);
@@ -97,6 +155,11 @@ impl Path {
         Datagram::new(self.local, self.remote, payload)
     }
 
+    /// Make a datagram with the given IP TOS value.
+    pub fn datagram_with_tos<V: Into<Vec<u8>>>(&self, tos: IpTos, payload: V) -> Datagram {
+        Datagram::new_with_tos(self.local, self.remote, tos, payload)
+    }
+
     /// Get local address as `SocketAddr`
     pub fn local_address(&self) -> SocketAddr {
         self.local
@@ -107,3 +170,221 @@ impl Path {
         self.remote
     }
 }
+
+/// The latency spin bit (RFC 9000, Section 17.4), which lets observers of a
+/// path measure its round-trip time.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SpinBit {
+    value: bool,
+    largest_pn: Option<PacketNumber>,
+}
+
+impl SpinBit {
+    /// The value to use in the next short header packet.
+    pub fn value(&self) -> bool {
+        self.value
+    }
+
+    /// Update the value from a short header packet.  Only the packet with the
+    /// largest packet number counts.  A server reflects the value it receives
+    /// and a client inverts it, so the value flips once per round trip.
+    pub fn on_packet_received(&mut self, role: Role, pn: PacketNumber, spin: bool) {
+        if matches!(self.largest_pn, Some(largest) if pn <= largest) {
+            return;
+        }
+        self.largest_pn = Some(pn);
+        self.value = if role == Role::Client { !spin } else { spin };
+    }
+}
+
+/// A path that is awaiting validation before the connection migrates to it.
+/// This holds the PATH_CHALLENGE that is sent on the path.
+#[derive(Debug)]
+pub struct PendingPath {
+    path: Path,
+    challenge: [u8; 8],
+    /// Whether the PATH_CHALLENGE needs to be sent (again).
+    probe: bool,
+    /// When to give up on the path.
+    deadline: Instant,
+    /// Whether a PATH_RESPONSE was received.
+    validated: bool,
+    /// Whether the peer has moved to this path.  A client only starts
+    /// validating paths that it moves to, but a server waits for the client
+    /// to send a non-probing packet on the path.
+    peer_moved: bool,
+    /// For a path that the peer moved to, the anti-amplification limit that
+    /// applies until the path is validated.
+    amplification: Option<AmplificationLimit>,
+}
+
+impl PendingPath {
+    pub fn new(path: Path, deadline: Instant) -> Self {
+        Self {
+            path,
+            challenge: <[u8; 8]>::try_from(&random(8)[..]).unwrap(),
+            probe: true,
+            deadline,
+            validated: false,
+            peer_moved: true,
+            amplification: None,
+        }
+    }
+
+    /// Validate a path that a packet of `received` bytes from the peer
+    /// arrived on.  A server uses this when the address of the client changes.
+    pub fn from_peer(path: Path, deadline: Instant, received: usize) -> Self {
+        let mut amplification = AmplificationLimit::default();
+        amplification.on_received(received);
+        Self {
+            peer_moved: false,
+            amplification: Some(amplification),
+            ..Self::new(path, deadline)
+        }
+    }
+
+    /// Access the path that is being validated.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Determine whether a PATH_CHALLENGE needs to be sent on this path.
+    pub fn probe_needed(&self) -> bool {
+        self.probe && !matches!(self.budget(), Some(0))
+    }
+
+    /// Send the PATH_CHALLENGE again, along with any PATH_RESPONSE, after
+    /// the peer probes this path again.
+    pub fn probe_again(&mut self) {
+        self.probe = true;
+    }
+
+    /// Note that a datagram arrived on this path.
+    pub fn on_received(&mut self, len: usize) {
+        if let Some(amplification) = &mut self.amplification {
+            amplification.on_received(len);
+        }
+    }
+
+    /// Note that a datagram was sent on this path.
+    pub fn on_sent(&mut self, len: usize) {
+        if let Some(amplification) = &mut self.amplification {
+            amplification.on_sent(len);
+        }
+    }
+
+    /// The number of bytes that can be sent on this path, if that is limited.
+    pub fn budget(&self) -> Option<usize> {
+        self.amplification.as_ref().map(AmplificationLimit::budget)
+    }
+
+    /// Note that the peer sent a non-probing packet on this path.
+    pub fn set_peer_moved(&mut self) {
+        self.peer_moved = true;
+    }
+
+    /// Whether the peer responded to the PATH_CHALLENGE on this path.
+    pub fn validated(&self) -> bool {
+        self.validated
+    }
+
+    /// Whether the connection can move to this path, because it is validated
+    /// and the peer has moved to it.
+    pub fn ready(&self) -> bool {
+        self.validated && self.peer_moved
+    }
+
+    /// Get the PATH_CHALLENGE frame, if it needs to be sent.
+    pub fn get_frame(&mut self, remaining: usize) -> Option<(Frame, Option<RecoveryToken>)> {
+        // A PATH_CHALLENGE is a one byte type and 8 bytes of data.
+        if self.probe && remaining >= 9 {
+            self.probe = false;
+            Some((
+                Frame::PathChallenge {
+                    data: self.challenge,
+                },
+                Some(RecoveryToken::PathChallenge(self.challenge)),
+            ))
+        } else {
+            None
+        }
+    }
+
+    /// A packet containing a PATH_CHALLENGE was lost, so send it again.
+    pub fn lost(&mut self, challenge: &[u8; 8]) {
+        if self.challenge == *challenge {
+            self.probe = true;
+        }
+    }
+
+    /// Check a PATH_RESPONSE, which validates this path if its content matches
+    /// the PATH_CHALLENGE.  Returns true if it does.
+    pub fn on_response(&mut self, response: &[u8; 8]) -> bool {
+        let matched = self.challenge == *response;
+        if matched {
+            self.validated = true;
+            self.amplification = None;
+        }
+        matched
+    }
+
+    /// The time at which validation is abandoned.
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Finish validation, returning the validated path.
+    pub fn into_path(self) -> Path {
+        self.path
+    }
+}
+
+/// The state of validation of a path, as reported by `Connection::paths`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathStatus {
+    /// The peer's address on the path is confirmed, either by the handshake
+    /// or by a response to a PATH_CHALLENGE.
+    Validated,
+    /// The peer's address on the path isn't confirmed yet.  This is the state
+    /// of a server's path until the handshake validates the client address,
+    /// and of a new path until the peer responds to a PATH_CHALLENGE.
+    Validating,
+    /// The path was in use before the connection migrated.  Packets that
+    /// arrive on this path are still accepted, but nothing is sent on it.
+    Abandoned,
+}
+
+/// A snapshot of a path that a connection is using or has used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PathInfo {
+    pub local: SocketAddr,
+    pub remote: SocketAddr,
+    pub status: PathStatus,
+    /// The smoothed round trip time, which is only measured on the active path.
+    pub rtt: Option<Duration>,
+    /// Whether the connection sends packets on this path.
+    pub active: bool,
+}
+
+/// The bytes that a server has received from and sent to a peer whose address
+/// is not yet validated.  This enforces the anti-amplification limit.
+#[derive(Debug, Default)]
+pub struct AmplificationLimit {
+    received: usize,
+    sent: usize,
+}
+
+impl AmplificationLimit {
+    pub fn on_received(&mut self, len: usize) {
+        self.received += len;
+    }
+
+    pub fn on_sent(&mut self, len: usize) {
+        self.sent += len;
+    }
+
+    /// The number of bytes that can be sent before more is received.
+    pub fn budget(&self) -> usize {
+        (self.received * AMPLIFICATION_FACTOR).saturating_sub(self.sent)
+    }
+}
@@ -0,0 +1,326 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Datagram Packetization Layer Path MTU Discovery (RFC 8899)
+#![deny(clippy::pedantic)]
+
+use neqo_common::{qdebug, qinfo};
+
+use std::cmp::{max, min};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::path::{PATH_MTU_V4, PATH_MTU_V6};
+
+/// The IP MTUs that are probed, in increasing order.  These are common link
+/// MTUs, or values just below them.
+const SEARCH_TABLE: &[usize] = &[
+    1280, 1380, 1420, 1472, 1500, 2047, 4095, 8191, 16383, 32767, 65535,
+];
+/// The size of IPv4 and UDP headers.
+const HEADER_SIZE_V4: usize = 20 + 8;
+/// The size of IPv6 and UDP headers.
+const HEADER_SIZE_V6: usize = 40 + 8;
+/// The number of times that a probe of a given size is sent before concluding
+/// that the path does not support that size.
+const MAX_PROBES: usize = 3;
+/// The number of packets larger than the base size that can be lost in a row,
+/// without any being acknowledged, before the path is considered to be a black hole.
+const BLACK_HOLE_THRESHOLD: usize = 3;
+/// How long to wait after a search completes before searching again.
+const PMTU_RAISE_TIMER: Duration = Duration::from_secs(600);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ProbeState {
+    /// A probe needs to be sent.
+    Needed,
+    /// A probe is outstanding.
+    Sent,
+    /// The search is complete.  Searching resumes after the raise timer.
+    Complete(Option<Instant>),
+}
+
+/// The state of PMTU discovery for a path.  This tracks the largest datagram
+/// (UDP payload) that is known to be supported by the path.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Pmtud {
+    /// The size of the IP and UDP headers.
+    header_size: usize,
+    /// The size that is assumed to work on every path.
+    base: usize,
+    /// The current maximum datagram size.
+    mtu: usize,
+    /// The index into `SEARCH_TABLE` of the size that is being probed.
+    probe_index: usize,
+    probe_state: ProbeState,
+    /// The number of probes of the current size that have been sent.
+    probe_count: usize,
+    /// The number of large packets lost since one was acknowledged.
+    loss_count: usize,
+}
+
+impl Pmtud {
+    pub fn new(local: &SocketAddr) -> Self {
+        let (header_size, base) = if local.is_ipv4() {
+            (HEADER_SIZE_V4, PATH_MTU_V4)
+        } else {
+            (HEADER_SIZE_V6, PATH_MTU_V6)
+        };
+        let mut pmtud = Self {
+            header_size,
+            base,
+            mtu: base,
+            probe_index: 0,
+            probe_state: ProbeState::Needed,
+            probe_count: 0,
+            loss_count: 0,
+        };
+        pmtud.next_probe(None);
+        pmtud
+    }
+
+    /// The largest datagram that can be sent on the path.
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    fn probe_target(&self) -> usize {
+        SEARCH_TABLE[self.probe_index] - self.header_size
+    }
+
+    /// Move on to the next size that is larger than the current MTU, or end
+    /// the search if there are none left.
+    fn next_probe(&mut self, now: Option<Instant>) {
+        self.probe_count = 0;
+        while self.probe_index < SEARCH_TABLE.len() && self.probe_target() <= self.mtu {
+            self.probe_index += 1;
+        }
+        if self.probe_index < SEARCH_TABLE.len() {
+            self.probe_state = ProbeState::Needed;
+        } else {
+            self.complete(now);
+        }
+    }
+
+    fn complete(&mut self, now: Option<Instant>) {
+        qinfo!([self], "Search complete");
+        self.probe_state = ProbeState::Complete(now.map(|t| t + PMTU_RAISE_TIMER));
+    }
+
+    /// The size of the probe to send, if one is needed.
+    pub fn probe_size(&mut self, now: Instant) -> Option<usize> {
+        match self.probe_state {
+            ProbeState::Needed => Some(self.probe_target()),
+            ProbeState::Sent => None,
+            ProbeState::Complete(None) => {
+                // The raise timer starts when the search is first seen to be complete.
+                self.probe_state = ProbeState::Complete(Some(now + PMTU_RAISE_TIMER));
+                None
+            }
+            ProbeState::Complete(Some(t)) => {
+                if now < t {
+                    return None;
+                }
+                qdebug!([self], "Raise timer expired");
+                self.probe_index = 0;
+                self.next_probe(Some(now));
+                self.probe_size(now)
+            }
+        }
+    }
+
+    /// The peer won't accept datagrams as large as the next probe, so end the
+    /// search at the current size.
+    pub fn probe_too_large(&mut self, now: Instant) {
+        debug_assert_eq!(self.probe_state, ProbeState::Needed);
+        qdebug!(
+            [self],
+            "Probe of {} exceeds the peer limit",
+            self.probe_target()
+        );
+        self.complete(Some(now));
+    }
+
+    /// Note that a probe was sent.
+    pub fn probe_sent(&mut self) {
+        debug_assert_eq!(self.probe_state, ProbeState::Needed);
+        self.probe_state = ProbeState::Sent;
+        self.probe_count += 1;
+    }
+
+    fn probe_outstanding(&self, size: usize) -> bool {
+        self.probe_state == ProbeState::Sent && size == self.probe_target()
+    }
+
+    /// A probe was acknowledged, so the path supports datagrams of that size.
+    pub fn probe_acked(&mut self, size: usize, now: Instant) {
+        if !self.probe_outstanding(size) {
+            return;
+        }
+        qinfo!([self], "Probe of {} acknowledged", size);
+        self.mtu = size;
+        self.loss_count = 0;
+        self.next_probe(Some(now));
+    }
+
+    /// A probe was lost, which might indicate that the path can't carry datagrams
+    /// of that size.
+    pub fn probe_lost(&mut self, size: usize, now: Instant) {
+        if !self.probe_outstanding(size) {
+            return;
+        }
+        qdebug!([self], "Probe of {} lost", size);
+        if self.probe_count < MAX_PROBES {
+            self.probe_state = ProbeState::Needed;
+        } else {
+            self.complete(Some(now));
+        }
+    }
+
+    /// A packet, other than a probe, was acknowledged.
+    pub fn packet_acked(&mut self, size: usize) {
+        if size > self.base {
+            self.loss_count = 0;
+        }
+    }
+
+    /// A packet, other than a probe, was lost.  If too many packets that are larger
+    /// than the base size are lost in a row, the path is treated as a black hole
+    /// for those packets and the MTU is reduced.
+    pub fn packet_lost(&mut self, size: usize, now: Instant) {
+        if size <= self.base || self.mtu == self.base {
+            return;
+        }
+        self.loss_count += 1;
+        if self.loss_count >= BLACK_HOLE_THRESHOLD {
+            qinfo!([self], "Black hole detected, reverting to {}", self.base);
+            self.mtu = self.base;
+            self.loss_count = 0;
+            self.complete(Some(now));
+        }
+    }
+
+    /// An ICMP message indicated that the path only supports an MTU of `mtu`.
+    /// That `mtu` is the size of IP packets, including headers.
+    pub fn packet_too_big(&mut self, mtu: usize, now: Instant) {
+        let limit = max(self.base, mtu.saturating_sub(self.header_size));
+        if limit < self.mtu {
+            qinfo!([self], "Packet too big, reducing to {}", limit);
+        }
+        self.mtu = min(self.mtu, limit);
+        self.loss_count = 0;
+        self.complete(Some(now));
+    }
+}
+
+impl ::std::fmt::Display for Pmtud {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "Pmtud {}", self.mtu)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Pmtud, HEADER_SIZE_V6, MAX_PROBES, PMTU_RAISE_TIMER, SEARCH_TABLE};
+    use crate::path::PATH_MTU_V6;
+    use test_fixture::{loopback, now};
+
+    /// Run a search on a path that supports the given IP MTU.
+    fn search(pmtud: &mut Pmtud, path_mtu: usize) {
+        let now = now();
+        while let Some(size) = pmtud.probe_size(now) {
+            pmtud.probe_sent();
+            if size + HEADER_SIZE_V6 <= path_mtu {
+                pmtud.probe_acked(size, now);
+            } else {
+                pmtud.probe_lost(size, now);
+            }
+        }
+    }
+
+    #[test]
+    fn grow() {
+        let mut pmtud = Pmtud::new(&loopback());
+        assert_eq!(pmtud.mtu(), PATH_MTU_V6);
+        search(&mut pmtud, 1500);
+        assert_eq!(pmtud.mtu(), 1500 - HEADER_SIZE_V6);
+    }
+
+    #[test]
+    fn no_growth() {
+        let mut pmtud = Pmtud::new(&loopback());
+        search(&mut pmtud, 1280);
+        assert_eq!(pmtud.mtu(), PATH_MTU_V6);
+    }
+
+    #[test]
+    fn probe_retry() {
+        let mut pmtud = Pmtud::new(&loopback());
+        let now = now();
+        let size = pmtud.probe_size(now).unwrap();
+        for _ in 1..MAX_PROBES {
+            pmtud.probe_sent();
+            pmtud.probe_lost(size, now);
+            assert_eq!(pmtud.probe_size(now), Some(size));
+        }
+        // The last probe is acknowledged.
+        pmtud.probe_sent();
+        assert_eq!(pmtud.probe_size(now), None);
+        pmtud.probe_acked(size, now);
+        assert_eq!(pmtud.mtu(), size);
+        assert!(pmtud.probe_size(now).unwrap() > size);
+    }
+
+    #[test]
+    fn black_hole() {
+        let mut pmtud = Pmtud::new(&loopback());
+        search(&mut pmtud, 1500);
+        let mtu = pmtud.mtu();
+        pmtud.packet_lost(mtu, now());
+        pmtud.packet_lost(mtu, now());
+        // An acknowledgment resets the count.
+        pmtud.packet_acked(mtu);
+        pmtud.packet_lost(mtu, now());
+        pmtud.packet_lost(mtu, now());
+        // Losing small packets doesn't count.
+        pmtud.packet_lost(PATH_MTU_V6, now());
+        assert_eq!(pmtud.mtu(), mtu);
+        pmtud.packet_lost(mtu, now());
+        assert_eq!(pmtud.mtu(), PATH_MTU_V6);
+    }
+
+    #[test]
+    fn raise_timer() {
+        let mut pmtud = Pmtud::new(&loopback());
+        search(&mut pmtud, 1500);
+        let mtu = pmtud.mtu();
+        pmtud.packet_too_big(1400, now());
+        assert_eq!(pmtud.mtu(), 1400 - HEADER_SIZE_V6);
+        // Never lower than the base size.
+        pmtud.packet_too_big(1000, now());
+        assert_eq!(pmtud.mtu(), PATH_MTU_V6);
+
+        assert_eq!(pmtud.probe_size(now()), None);
+        assert_eq!(
+            pmtud.probe_size(now() + PMTU_RAISE_TIMER),
+            Some(SEARCH_TABLE[2] - HEADER_SIZE_V6)
+        );
+        search(&mut pmtud, 1500);
+        assert_eq!(pmtud.mtu(), mtu);
+    }
+
+    #[test]
+    fn peer_limit() {
+        let mut pmtud = Pmtud::new(&loopback());
+        let now = now();
+        assert!(pmtud.probe_size(now).is_some());
+        pmtud.probe_too_large(now);
+        assert_eq!(pmtud.mtu(), PATH_MTU_V6);
+        // No more probes until the raise timer expires.
+        assert_eq!(pmtud.probe_size(now), None);
+        assert!(pmtud.probe_size(now + PMTU_RAISE_TIMER).is_some());
+    }
+}
@@ -350,6 +350,7 @@ fn frame_to_qlogframe(frame: &Frame) -> QuicFrame {
             ack_delay,
             first_ack_range,
             ack_ranges,
+            ecn_count,
         } => {
             let ranges =
                 Frame::decode_ack_frame(*largest_acknowledged, *first_ack_range, ack_ranges).ok();
@@ -361,9 +362,9 @@ fn frame_to_qlogframe(frame: &Frame) -> QuicFrame {
                         .map(RangeInclusive::into_inner)
                         .collect::<Vec<_>>()
                 }),
-                None,
-                None,
-                None,
+                ecn_count.map(|c| c.ect1.to_string()),
+                ecn_count.map(|c| c.ect0.to_string()),
+                ecn_count.map(|c| c.ce.to_string()),
             )
         }
         Frame::ResetStream {
@@ -372,13 +373,13 @@ fn frame_to_qlogframe(frame: &Frame) -> QuicFrame {
             final_size,
         } => QuicFrame::reset_stream(
             stream_id.as_u64().to_string(),
-            *application_error_code,
+            application_error_code.0,
             final_size.to_string(),
         ),
         Frame::StopSending {
             stream_id,
             application_error_code,
-        } => QuicFrame::stop_sending(stream_id.as_u64().to_string(), *application_error_code),
+        } => QuicFrame::stop_sending(stream_id.as_u64().to_string(), application_error_code.0),
         Frame::Crypto { offset, data } => {
             QuicFrame::crypto(offset.to_string(), data.len().to_string())
         }
@@ -464,6 +465,9 @@ fn frame_to_qlogframe(frame: &Frame) -> QuicFrame {
             Some(frame_type.to_string()),
         ),
         Frame::HandshakeDone => QuicFrame::handshake_done(),
+        Frame::AckFrequency { .. } | Frame::Datagram { .. } | Frame::StreamGap { .. } => {
+            QuicFrame::unknown(frame.get_type())
+        }
     }
 }
 
@@ -0,0 +1,102 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Sending unreliable datagrams (RFC 9221).
+#![deny(clippy::pedantic)]
+
+use neqo_common::{qdebug, Encoder};
+
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+
+use crate::frame::Frame;
+use crate::recovery::RecoveryToken;
+
+/// The number of datagrams that can be waiting to be sent.  Once this many are
+/// queued, the oldest is dropped to make room for a new one.
+pub const MAX_QUEUED_DATAGRAMS: usize = 10;
+
+/// The size of a `DATAGRAM` frame that carries `len` bytes, including the
+/// frame type and length.
+pub fn frame_size(len: usize) -> usize {
+    1 + Encoder::varint_len(u64::try_from(len).unwrap()) + len
+}
+
+/// The largest payload that fits in a `DATAGRAM` frame of `frame_size` bytes.
+pub fn max_payload(frame_size: usize) -> usize {
+    let header = 1 + Encoder::varint_len(u64::try_from(frame_size).unwrap());
+    frame_size.saturating_sub(header)
+}
+
+/// Datagrams that are waiting to be sent.
+#[derive(Debug, Default)]
+pub struct QuicDatagrams {
+    queue: VecDeque<Vec<u8>>,
+}
+
+impl QuicDatagrams {
+    /// Queue a datagram for sending.  The caller is responsible for checking
+    /// that it is not too large.  Returns true if the oldest datagram was
+    /// dropped to make room.
+    pub fn add(&mut self, data: &[u8]) -> bool {
+        let dropped = self.queue.len() >= MAX_QUEUED_DATAGRAMS;
+        if dropped {
+            qdebug!("Too many datagrams queued, dropping the oldest");
+            self.queue.pop_front();
+        }
+        self.queue.push_back(data.to_vec());
+        dropped
+    }
+
+    /// Write a `DATAGRAM` frame, if the next datagram fits in `remaining` bytes.
+    /// Datagrams are not retransmitted, so these have no recovery token.
+    pub fn get_frame(&mut self, remaining: usize) -> Option<(Frame, Option<RecoveryToken>)> {
+        if frame_size(self.queue.front()?.len()) > remaining {
+            return None;
+        }
+        let data = self.queue.pop_front().unwrap();
+        Some((Frame::Datagram { data }, None))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{frame_size, max_payload, Frame, QuicDatagrams, MAX_QUEUED_DATAGRAMS};
+    use std::convert::TryFrom;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(frame_size(0), 2);
+        assert_eq!(frame_size(63), 65);
+        assert_eq!(frame_size(64), 67);
+        assert_eq!(max_payload(65), 62);
+        assert_eq!(max_payload(1000), 997);
+        assert_eq!(frame_size(max_payload(1000)), 1000);
+        assert_eq!(max_payload(1), 0);
+    }
+
+    #[test]
+    fn queue() {
+        let mut datagrams = QuicDatagrams::default();
+        assert!(datagrams.get_frame(100).is_none());
+        for i in 0..MAX_QUEUED_DATAGRAMS {
+            assert!(!datagrams.add(&[u8::try_from(i).unwrap(); 10]));
+        }
+        assert!(datagrams.add(&[u8::try_from(MAX_QUEUED_DATAGRAMS).unwrap(); 10]));
+
+        // A datagram that doesn't fit is held until there is space.
+        assert!(datagrams.get_frame(11).is_none());
+        // The first datagram was dropped.
+        match datagrams.get_frame(12) {
+            Some((Frame::Datagram { data }, None)) => assert_eq!(data, vec![1; 10]),
+            _ => panic!("expected a DATAGRAM frame"),
+        }
+        for _ in 1..MAX_QUEUED_DATAGRAMS {
+            assert!(datagrams.get_frame(100).is_some());
+        }
+        assert!(datagrams.get_frame(100).is_none());
+    }
+}
@@ -18,9 +18,10 @@ use smallvec::{smallvec, SmallVec};
 
 use neqo_common::{qdebug, qinfo, qlog::NeqoQlog, qtrace};
 
-use crate::cc::CongestionControl;
+use crate::cc::{CongestionControl, NewReno, MAX_DATAGRAM_SIZE, PACING_BURST_SIZE};
 use crate::crypto::CryptoRecoveryToken;
 use crate::flow_mgr::FlowControlRecoveryToken;
+use crate::pace::{Pacer, RateLimiter};
 use crate::qlog::{self, QlogMetric};
 use crate::send_stream::StreamRecoveryToken;
 use crate::stats::{Stats, StatsCell};
@@ -46,10 +47,34 @@ pub const PTO_PACKET_COUNT: usize = 2;
 pub enum RecoveryToken {
     Ack(AckToken),
     Stream(StreamRecoveryToken),
+    /// A `STREAM_GAP` frame for the given range.
+    StreamGap(StreamRecoveryToken),
     Crypto(CryptoRecoveryToken),
     Flow(FlowControlRecoveryToken),
     HandshakeDone,
     NewToken(usize),
+    PathChallenge([u8; 8]),
+    /// A PMTUD probe of the given size.
+    PmtudProbe(usize),
+    /// An `ACK_FREQUENCY` frame with the given sequence number and delay.
+    AckFrequency(u64, Duration),
+    /// A `NEW_CONNECTION_ID` frame with the given sequence number.
+    NewConnectionId(u64),
+    /// A `RETIRE_CONNECTION_ID` frame with the given sequence number.
+    RetireConnectionId(u64),
+}
+
+/// The round trip time estimates for a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RttEstimate {
+    /// The smoothed round trip time.
+    pub smoothed: Duration,
+    /// The most recent round trip time sample, adjusted for ACK delay.
+    pub latest: Duration,
+    /// The smallest round trip time sample.
+    pub min: Duration,
+    /// The variation in round trip time samples.
+    pub variance: Duration,
 }
 
 #[derive(Debug)]
@@ -126,6 +151,15 @@ impl RttVals {
         self.smoothed_rtt
     }
 
+    fn estimate(&self) -> RttEstimate {
+        RttEstimate {
+            smoothed: self.smoothed_rtt,
+            latest: self.latest_rtt,
+            min: self.min_rtt,
+            variance: self.rttvar,
+        }
+    }
+
     fn pto(&self, pn_space: PNSpace) -> Duration {
         self.rtt()
             + max(4 * self.rttvar, GRANULARITY)
@@ -212,6 +246,11 @@ impl SendProfile {
         self.limit < ACK_ONLY_SIZE_LIMIT || self.pto.map_or(false, |sp| space < sp)
     }
 
+    /// Reduce the limit so that no more than `limit` bytes are sent.
+    pub fn restrict(&mut self, limit: usize) {
+        self.limit = min(self.limit, limit);
+    }
+
     pub fn paced(&self) -> bool {
         self.paced
     }
@@ -586,13 +625,20 @@ impl PtoState {
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub(crate) struct LossRecovery {
     /// When the handshake was confirmed, if it has been.
     confirmed_time: Option<Instant>,
     pto_state: Option<PtoState>,
     rtt_vals: RttVals,
-    cc: CongestionControl,
+    cc: Box<dyn CongestionControl>,
+    /// The initial congestion window, if it was changed from the default.
+    initial_cwnd: Option<usize>,
+    pacer: Option<Pacer>,
+    /// Whether sending is paced.
+    pacing: bool,
+    /// A limit on the sending rate, if one is set.
+    rate_limit: Option<RateLimiter>,
 
     spaces: LossRecoverySpaces,
 
@@ -603,16 +649,52 @@ pub(crate) struct LossRecovery {
 impl LossRecovery {
     pub fn new(stats: StatsCell) -> Self {
         Self {
+            confirmed_time: None,
+            pto_state: None,
+            rtt_vals: RttVals::default(),
+            cc: Box::new(NewReno::default()),
+            initial_cwnd: None,
+            pacer: None,
+            pacing: true,
+            rate_limit: None,
+            spaces: LossRecoverySpaces::default(),
+            qlog: NeqoQlog::disabled(),
             stats,
-            ..Self::default()
         }
     }
 
-    #[cfg(test)]
+    /// Respond to an increase in the count of packets that the peer received
+    /// with the ECN-CE codepoint.  `sent_time` is when the largest acknowledged
+    /// packet was sent.
+    pub fn on_ecn_ce(&mut self, sent_time: Instant, now: Instant) {
+        self.stats.borrow_mut().ce_rx += 1;
+        self.cc.on_congestion_event(now, sent_time);
+    }
+
+    /// Replace the congestion controller.
+    pub fn set_congestion_control(&mut self, mut cc: Box<dyn CongestionControl>) {
+        cc.set_qlog(self.qlog.clone());
+        if let Some(cwnd) = self.initial_cwnd {
+            cc.set_initial_cwnd(cwnd);
+        }
+        self.cc = cc;
+    }
+
+    /// Set the initial congestion window, which is kept if the congestion
+    /// controller is replaced.
+    pub fn set_initial_cwnd(&mut self, cwnd: usize) {
+        self.initial_cwnd = Some(cwnd);
+        self.cc.set_initial_cwnd(cwnd);
+    }
+
     pub fn cwnd(&self) -> usize {
         self.cc.cwnd()
     }
 
+    pub fn bytes_in_flight(&self) -> usize {
+        self.cc.bytes_in_flight()
+    }
+
     #[cfg(test)]
     pub fn ssthresh(&self) -> usize {
         self.cc.ssthresh()
@@ -622,6 +704,10 @@ impl LossRecovery {
         self.rtt_vals.rtt()
     }
 
+    pub fn rtt_estimate(&self) -> RttEstimate {
+        self.rtt_vals.estimate()
+    }
+
     pub fn set_initial_rtt(&mut self, rtt: Duration) {
         self.rtt_vals.set_initial_rtt(rtt)
     }
@@ -665,7 +751,16 @@ impl LossRecovery {
         qdebug!([self], "packet {}-{} sent", pn_space, sent_packet.pn);
         let rtt = self.rtt();
         if let Some(space) = self.spaces.get_mut(pn_space) {
-            self.cc.on_packet_sent(&sent_packet, rtt);
+            self.pacer.as_mut().unwrap().spend(
+                sent_packet.time_sent,
+                rtt,
+                self.cc.cwnd(),
+                sent_packet.size,
+            );
+            if let Some(rl) = self.rate_limit.as_mut() {
+                rl.spend(sent_packet.time_sent, sent_packet.size);
+            }
+            self.cc.on_packet_sent(&sent_packet);
             space.on_packet_sent(sent_packet);
         } else {
             qinfo!(
@@ -760,7 +855,7 @@ impl LossRecovery {
         // This must happen after on_packets_lost. If in recovery, this could
         // take us out, and then lost packets will start a new recovery period
         // when it shouldn't.
-        self.cc.on_packets_acked(&acked_packets);
+        self.cc.on_packets_acked(&acked_packets, now);
 
         self.pto_state = None;
 
@@ -903,6 +998,7 @@ impl LossRecovery {
     }
 
     fn fire_pto(&mut self, pn_space: PNSpace, allow_probes: PNSpaceSet) {
+        self.stats.borrow_mut().pto_count += 1;
         if let Some(st) = &mut self.pto_state {
             st.pto(pn_space, allow_probes);
         } else {
@@ -972,20 +1068,68 @@ impl LossRecovery {
 
     /// Start the packet pacer.
     pub fn start_pacer(&mut self, now: Instant) {
-        self.cc.start_pacer(now);
+        // Start the pacer with a small burst size.
+        self.pacer = Some(Pacer::new(
+            now,
+            MAX_DATAGRAM_SIZE * PACING_BURST_SIZE,
+            MAX_DATAGRAM_SIZE,
+        ));
+    }
+
+    /// Enable or disable pacing.
+    pub fn set_pacing(&mut self, pacing: bool) {
+        self.pacing = pacing;
+    }
+
+    /// Limit the sending rate to `rate` bytes per second, or remove the limit.
+    /// A new limit starts without any credit.
+    pub fn set_max_send_rate(&mut self, rate: Option<u64>) {
+        self.rate_limit = rate
+            .map(|r| RateLimiter::new(r, MAX_DATAGRAM_SIZE * PACING_BURST_SIZE, MAX_DATAGRAM_SIZE));
+    }
+
+    /// The rate at which the pacer currently releases packets, in bytes per second.
+    /// This is `None` if pacing is disabled or the pacer hasn't been started.
+    pub fn pacing_rate(&self) -> Option<u64> {
+        if self.pacing && self.pacer.is_some() {
+            Some(Pacer::rate(self.rtt(), self.cc.cwnd()))
+        } else {
+            None
+        }
     }
 
-    /// Get the next time that a paced packet might be sent.
+    /// Get the next time that a paced packet might be sent.  This is the later
+    /// of the times allowed by the pacer and any rate limit.
     pub fn next_paced(&self) -> Option<Instant> {
-        self.cc.next_paced(self.rtt())
+        // Only pace if enabled and there are bytes in flight.
+        let paced = if self.pacing && self.cc.bytes_in_flight() > 0 {
+            Some(
+                self.pacer
+                    .as_ref()
+                    .unwrap()
+                    .next(self.rtt(), self.cc.cwnd()),
+            )
+        } else {
+            None
+        };
+        max(paced, self.rate_limit.as_ref().and_then(RateLimiter::next))
     }
 
     /// Check how packets should be sent, based on whether there is a PTO,
     /// what the current congestion window is, and what the pacer says.
     pub fn send_profile(&mut self, now: Instant, mtu: usize) -> SendProfile {
         qdebug!([self], "get send profile {:?}", now);
+        if let Some(rl) = self.rate_limit.as_mut() {
+            rl.start(now);
+        }
         if let Some(pto) = self.pto_state.as_mut() {
+            // Probes ignore the rate limit, so that loss recovery isn't held up.
+            // They are still charged against it, which delays what follows.
             pto.send_profile(mtu)
+        } else if matches!(self.rate_limit.as_ref().and_then(RateLimiter::next), Some(t) if t > now)
+        {
+            // The rate limit applies even if the congestion window is small.
+            SendProfile::new_paced()
         } else {
             let cwnd = self.cwnd_avail();
             if cwnd > mtu {
@@ -1002,6 +1146,12 @@ impl LossRecovery {
     }
 }
 
+impl Default for LossRecovery {
+    fn default() -> Self {
+        Self::new(StatsCell::default())
+    }
+}
+
 impl ::std::fmt::Display for LossRecovery {
     fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
         write!(f, "LossRecovery")
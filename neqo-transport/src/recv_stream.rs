@@ -21,7 +21,7 @@ use crate::events::ConnectionEvents;
 use crate::flow_mgr::FlowMgr;
 use crate::stream_id::StreamId;
 use crate::{AppError, Error, Res};
-use neqo_common::qtrace;
+use neqo_common::{qdebug, qtrace};
 
 const RX_STREAM_DATA_WINDOW: u64 = 0x10_0000; // 1MiB
 
@@ -176,6 +176,26 @@ impl RxStreamOrderer {
         Ok(())
     }
 
+    /// The parts of `start..end` that haven't been received or read.
+    fn missing(&self, start: u64, end: u64) -> Vec<(u64, u64)> {
+        let mut missing = Vec::new();
+        let mut next = max(start, self.retired);
+        for (&range_start, data) in self.data_ranges.range(..end) {
+            let range_end = range_start + data.len() as u64;
+            if range_end <= next {
+                continue;
+            }
+            if range_start > next {
+                missing.push((next, range_start));
+            }
+            next = range_end;
+        }
+        if next < end {
+            missing.push((next, end));
+        }
+        missing
+    }
+
     /// Are any bytes readable?
     pub fn data_ready(&self) -> bool {
         self.data_ranges
@@ -278,6 +298,18 @@ impl RxStreamOrderer {
 }
 
 /// QUIC receiving states, based on -transport 3.2.
+/// The state of the receiving part of a stream, as reported by `Connection::streams`.
+/// These are the states from -transport 3.2, except that the application can't
+/// tell when a reset has been read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvStreamStatus {
+    Recv,
+    SizeKnown,
+    DataRecvd,
+    DataRead,
+    ResetRecvd,
+}
+
 #[derive(Debug)]
 #[allow(dead_code)]
 // Because a dead_code warning is easier than clippy::unused_self, see https://github.com/rust-lang/rust/issues/68408
@@ -286,6 +318,8 @@ enum RecvStreamState {
         recv_buf: RxStreamOrderer,
         max_bytes: u64, // Maximum size of recv_buf
         max_stream_data: u64,
+        max_window: u64,   // The limit for growing max_bytes
+        window_full: bool, // The peer used all of the credit we gave it
     },
     SizeKnown {
         recv_buf: RxStreamOrderer,
@@ -300,11 +334,23 @@ enum RecvStreamState {
 }
 
 impl RecvStreamState {
-    fn new(max_bytes: u64) -> Self {
+    fn new(max_bytes: u64, max_window: u64) -> Self {
         Self::Recv {
             recv_buf: RxStreamOrderer::new(),
             max_bytes,
             max_stream_data: max_bytes,
+            max_window,
+            window_full: false,
+        }
+    }
+
+    fn status(&self) -> RecvStreamStatus {
+        match self {
+            Self::Recv { .. } => RecvStreamStatus::Recv,
+            Self::SizeKnown { .. } => RecvStreamStatus::SizeKnown,
+            Self::DataRecvd { .. } => RecvStreamStatus::DataRecvd,
+            Self::DataRead => RecvStreamStatus::DataRead,
+            Self::ResetRecvd => RecvStreamStatus::ResetRecvd,
         }
     }
 
@@ -351,20 +397,32 @@ pub struct RecvStream {
     state: RecvStreamState,
     flow_mgr: Rc<RefCell<FlowMgr>>,
     conn_events: ConnectionEvents,
+    /// The highest offset received, which counts against the connection-level
+    /// flow control limit.
+    received: u64,
+    /// Whether a `RecvStreamReadable` event was queued and the application
+    /// has not yet read all the available data since.
+    readable: bool,
 }
 
 impl RecvStream {
+    /// Create a stream with a receive window of `max_stream_data`.  The window
+    /// grows, up to `max_window`, if the peer is limited by it when the application
+    /// is reading all the data that arrives.
     pub fn new(
         stream_id: StreamId,
         max_stream_data: u64,
+        max_window: u64,
         flow_mgr: Rc<RefCell<FlowMgr>>,
         conn_events: ConnectionEvents,
     ) -> Self {
         Self {
             stream_id,
-            state: RecvStreamState::new(max_stream_data),
+            state: RecvStreamState::new(max_stream_data, max_window),
             flow_mgr,
             conn_events,
+            received: 0,
+            readable: false,
         }
     }
 
@@ -403,16 +461,23 @@ impl RecvStream {
             }
         }
 
+        self.count_received(new_end)?;
+
+        let was_complete = matches!(self.state, RecvStreamState::DataRecvd { .. });
         match &mut self.state {
             RecvStreamState::Recv {
                 recv_buf,
                 max_stream_data,
+                window_full,
                 ..
             } => {
                 if new_end > *max_stream_data {
                     qtrace!("Stream RX window {} exceeded: {}", max_stream_data, new_end);
                     return Err(Error::FlowControlError);
                 }
+                if new_end == *max_stream_data {
+                    *window_full = true;
+                }
 
                 if fin {
                     let final_size = offset + data.len() as u64;
@@ -451,24 +516,89 @@ impl RecvStream {
             }
         }
 
-        if self.data_ready() || self.needs_to_inform_app_about_fin() {
+        // The readable event is level-triggered: once it is queued, no other is
+        // queued until the application has read everything that is available.
+        if !self.readable && (self.data_ready() || self.needs_to_inform_app_about_fin()) {
+            self.readable = true;
             self.conn_events.recv_stream_readable(self.stream_id)
         }
+        if !was_complete && matches!(self.state, RecvStreamState::DataRecvd { .. }) {
+            self.conn_events.recv_stream_fin(self.stream_id);
+        }
 
         Ok(())
     }
 
-    pub fn reset(&mut self, application_error_code: AppError) {
+    /// The peer won't send `offset..offset + length` again, so fill whatever
+    /// didn't arrive with zeros.
+    /// # Errors
+    /// `FinalSizeError` or `FlowControlError` if the range is past the end of the
+    /// stream or the receive window.
+    pub fn inbound_gap(&mut self, offset: u64, length: u64) -> Res<()> {
+        let end = offset + length;
+        if matches!(self.state.final_size(), Some(final_size) if end > final_size) {
+            return Err(Error::FinalSizeError);
+        }
+        if matches!(self.state.max_stream_data(), Some(max) if end > max) {
+            return Err(Error::FlowControlError);
+        }
+        let missing = if let Some(recv_buf) = self.state.recv_buf() {
+            recv_buf.missing(offset, end)
+        } else {
+            return Ok(());
+        };
+        if !missing.is_empty() {
+            self.conn_events
+                .recv_stream_gap(self.stream_id, offset, length);
+        }
+        for (start, end) in missing {
+            let zeros = vec![0; usize::try_from(end - start)?];
+            self.inbound_stream_frame(false, start, zeros)?;
+        }
+        Ok(())
+    }
+
+    /// Count data up to `end` against the connection-level flow control limit.
+    /// Data that arrives after the stream is reset or read is discarded at once.
+    fn count_received(&mut self, end: u64) -> Res<()> {
+        if end <= self.received {
+            return Ok(());
+        }
+        let amount = end - self.received;
+        self.received = end;
+        let mut flow_mgr = self.flow_mgr.borrow_mut();
+        flow_mgr.conn_data_received(amount)?;
+        if self.state.recv_buf().is_none() {
+            flow_mgr.conn_data_retired(amount);
+        }
+        Ok(())
+    }
+
+    /// Release the connection-level credit used by data that the application
+    /// hasn't read, because it is being discarded.
+    fn discard_unread(&mut self) {
+        if let Some(recv_buf) = self.state.recv_buf() {
+            let unread = self.received - recv_buf.retired();
+            self.flow_mgr.borrow_mut().conn_data_retired(unread);
+        }
+    }
+
+    /// # Errors
+    /// `FlowControlError` if `final_size` exceeds the connection-level limit.
+    pub fn reset(&mut self, application_error_code: AppError, final_size: u64) -> Res<()> {
+        self.count_received(final_size)?;
         match self.state {
             RecvStreamState::Recv { .. } | RecvStreamState::SizeKnown { .. } => {
                 self.conn_events
                     .recv_stream_reset(self.stream_id, application_error_code);
+                self.discard_unread();
                 self.set_state(RecvStreamState::ResetRecvd);
             }
             _ => {
                 // Ignore reset if in DataRecvd, DataRead, or ResetRecvd
             }
         }
+        Ok(())
     }
 
     /// If we should tell the sender they have more credit, return an offset
@@ -478,6 +608,8 @@ impl RecvStream {
             max_bytes,
             max_stream_data,
             recv_buf,
+            max_window,
+            window_full,
         } = &mut self.state
         {
             // Algo: send an update if app has consumed more than half
@@ -485,8 +617,21 @@ impl RecvStream {
             // TODO(agrover@mozilla.com): This algo is not great but
             // should prevent Silly Window Syndrome. Spec refers to using
             // highest seen offset somehow? RTT maybe?
-            let maybe_new_max = recv_buf.retired() + *max_bytes;
+            let mut maybe_new_max = recv_buf.retired() + *max_bytes;
             if maybe_new_max > (*max_bytes / 2) + *max_stream_data {
+                // If the peer used the whole window before the last update
+                // reached it, and the application is keeping up, the window
+                // is smaller than the bandwidth-delay product.  Grow it.
+                if *window_full && recv_buf.buffered() == 0 && *max_bytes < *max_window {
+                    *max_bytes = min(*max_bytes * 2, *max_window);
+                    qdebug!(
+                        "RecvStream {} window increased to {}",
+                        self.stream_id.as_u64(),
+                        max_bytes
+                    );
+                    maybe_new_max = recv_buf.retired() + *max_bytes;
+                }
+                *window_full = false;
                 *max_stream_data = maybe_new_max;
                 self.flow_mgr
                     .borrow_mut()
@@ -499,6 +644,21 @@ impl RecvStream {
         self.state.max_stream_data()
     }
 
+    pub fn status(&self) -> RecvStreamStatus {
+        self.state.status()
+    }
+
+    /// The number of bytes that have been received but not read, including
+    /// any that can't be read until missing data arrives.
+    pub fn buffered(&self) -> u64 {
+        match &self.state {
+            RecvStreamState::Recv { recv_buf, .. }
+            | RecvStreamState::SizeKnown { recv_buf, .. }
+            | RecvStreamState::DataRecvd { recv_buf } => recv_buf.buffered(),
+            RecvStreamState::DataRead | RecvStreamState::ResetRecvd => 0,
+        }
+    }
+
     pub fn is_terminal(&self) -> bool {
         matches!(
             self.state,
@@ -506,6 +666,12 @@ impl RecvStream {
         )
     }
 
+    /// Whether `read` has something to report: data, the end of the stream,
+    /// or an error because the stream was reset or has been read completely.
+    pub fn can_read(&self) -> bool {
+        self.data_ready() || self.needs_to_inform_app_about_fin() || self.is_terminal()
+    }
+
     // App got all data but did not get the fin signal.
     fn needs_to_inform_app_about_fin(&self) -> bool {
         matches!(self.state, RecvStreamState::DataRecvd { .. })
@@ -533,28 +699,46 @@ impl RecvStream {
             }
             RecvStreamState::DataRead | RecvStreamState::ResetRecvd => Err(Error::NoMoreData),
         };
+        if let Ok((bytes_read, _)) = res {
+            self.flow_mgr
+                .borrow_mut()
+                .conn_data_retired(u64::try_from(bytes_read).unwrap());
+        }
+        if !self.data_ready() {
+            self.readable = false;
+        }
         self.maybe_send_flowc_update();
         res
     }
 
-    pub fn stop_sending(&mut self, err: AppError) {
+    /// Ask the peer to stop sending, discarding any data that has been received.
+    /// # Errors
+    /// `StreamStateError` if the stream was already reset.
+    pub fn stop_sending(&mut self, err: AppError) -> Res<()> {
         qtrace!("stop_sending called when in state {}", self.state.name());
         match &self.state {
             RecvStreamState::Recv { .. } | RecvStreamState::SizeKnown { .. } => {
+                self.discard_unread();
                 self.set_state(RecvStreamState::ResetRecvd);
                 self.flow_mgr.borrow_mut().stop_sending(self.stream_id, err)
             }
-            RecvStreamState::DataRecvd { .. } => self.set_state(RecvStreamState::DataRead),
-            RecvStreamState::DataRead | RecvStreamState::ResetRecvd => {
+            RecvStreamState::DataRecvd { .. } => {
+                self.discard_unread();
+                self.set_state(RecvStreamState::DataRead)
+            }
+            RecvStreamState::DataRead => {
                 // Already in terminal state
             }
+            RecvStreamState::ResetRecvd => return Err(Error::StreamStateError),
         }
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::events::ConnectionEvent;
     use crate::frame::Frame;
     use std::ops::Range;
 
@@ -643,6 +827,22 @@ mod tests {
         recv_ranges(&[10..18, 19..20, 17..25, 0..10], 25);
     }
 
+    #[test]
+    fn recv_missing() {
+        let mut s = RxStreamOrderer::default();
+        s.inbound_frame(0, vec![0; 5]).unwrap();
+        s.inbound_frame(10, vec![0; 5]).unwrap();
+        assert_eq!(s.missing(0, 20), vec![(5, 10), (15, 20)]);
+        assert_eq!(s.missing(12, 15), vec![]);
+        assert_eq!(s.missing(2, 12), vec![(5, 10)]);
+
+        // Data that has been read isn't missing either.
+        let mut buf = [0; 5];
+        assert_eq!(s.read(&mut buf), 5);
+        s.inbound_frame(5, vec![0; 5]).unwrap();
+        assert_eq!(s.missing(0, 20), vec![(15, 20)]);
+    }
+
     /// Complete overlaps with the start of a 10..20 range of bytes.
     #[test]
     fn recv_overlap_complete() {
@@ -688,7 +888,7 @@ mod tests {
         let flow_mgr = Rc::new(RefCell::new(FlowMgr::default()));
         let conn_events = ConnectionEvents::default();
 
-        let mut s = RecvStream::new(567.into(), 1024, Rc::clone(&flow_mgr), conn_events);
+        let mut s = RecvStream::new(567.into(), 1024, 1024, Rc::clone(&flow_mgr), conn_events);
 
         // test receiving a contig frame and reading it works
         s.inbound_stream_frame(false, 0, vec![1; 10]).unwrap();
@@ -735,13 +935,44 @@ mod tests {
         s.read(&mut buf).unwrap_err();
     }
 
+    #[test]
+    fn fin_after_gap() {
+        let flow_mgr = Rc::new(RefCell::new(FlowMgr::default()));
+        let conn_events = ConnectionEvents::default();
+        let mut s = RecvStream::new(
+            567.into(),
+            1024,
+            1024,
+            Rc::clone(&flow_mgr),
+            conn_events.clone(),
+        );
+        let fin =
+            |e: &ConnectionEvent| matches!(e, ConnectionEvent::RecvStreamFin { stream_id: 567 });
+
+        // The end of the stream arrives first.
+        s.inbound_stream_frame(true, 10, vec![1; 10]).unwrap();
+        assert!(!conn_events.events().any(|e| fin(&e)));
+        s.inbound_stream_frame(false, 5, vec![1; 5]).unwrap();
+        assert!(!conn_events.events().any(|e| fin(&e)));
+
+        // Filling the gap completes the stream, which is only reported once.
+        s.inbound_stream_frame(false, 0, vec![1; 5]).unwrap();
+        let events = conn_events.events().collect::<Vec<_>>();
+        assert_eq!(events.iter().filter(|e| fin(e)).count(), 1);
+        s.inbound_stream_frame(false, 0, vec![1; 5]).unwrap();
+        assert!(!conn_events.events().any(|e| fin(&e)));
+
+        let mut buf = [0; 20];
+        assert_eq!(s.read(&mut buf).unwrap(), (20, true));
+    }
+
     #[test]
     #[allow(clippy::cognitive_complexity)]
     fn test_stream_rx_dedupe() {
         let flow_mgr = Rc::new(RefCell::new(FlowMgr::default()));
         let conn_events = ConnectionEvents::default();
 
-        let mut s = RecvStream::new(3.into(), 1024, Rc::clone(&flow_mgr), conn_events);
+        let mut s = RecvStream::new(3.into(), 1024, 1024, Rc::clone(&flow_mgr), conn_events);
 
         let mut buf = vec![0u8; 100];
 
@@ -835,6 +1066,7 @@ mod tests {
         let mut s = RecvStream::new(
             4.into(),
             RX_STREAM_DATA_WINDOW,
+            RX_STREAM_DATA_WINDOW,
             Rc::clone(&flow_mgr),
             conn_events,
         );
@@ -864,6 +1096,51 @@ mod tests {
         assert_eq!(s.flow_mgr.borrow().peek(), None);
     }
 
+    #[test]
+    fn conn_flowc_discard() {
+        let flow_mgr = Rc::new(RefCell::new(FlowMgr::default()));
+        flow_mgr
+            .borrow_mut()
+            .conn_set_recv_window(RX_STREAM_DATA_WINDOW);
+        let mut s = RecvStream::new(
+            4.into(),
+            RX_STREAM_DATA_WINDOW,
+            RX_STREAM_DATA_WINDOW,
+            Rc::clone(&flow_mgr),
+            ConnectionEvents::default(),
+        );
+
+        let frame1 = vec![0; RX_STREAM_DATA_WINDOW as usize];
+        s.inbound_stream_frame(false, 0, frame1).unwrap();
+        assert_eq!(flow_mgr.borrow().peek(), None);
+
+        // Discarding the unread data gives the credit back to the peer.
+        s.stop_sending(AppError(0)).unwrap();
+        let max_data = flow_mgr
+            .borrow_mut()
+            .find(|f| matches!(f, Frame::MaxData { .. }));
+        assert_eq!(
+            max_data,
+            Some(Frame::MaxData {
+                maximum_data: RX_STREAM_DATA_WINDOW * 2
+            })
+        );
+
+        // Data that arrives later still counts against the limit.
+        s.inbound_stream_frame(false, RX_STREAM_DATA_WINDOW, vec![0; 1])
+            .unwrap();
+        assert_eq!(
+            flow_mgr
+                .borrow_mut()
+                .conn_data_received(RX_STREAM_DATA_WINDOW - 1),
+            Ok(())
+        );
+        assert_eq!(
+            flow_mgr.borrow_mut().conn_data_received(1),
+            Err(Error::FlowControlError)
+        );
+    }
+
     #[test]
     fn test_stream_max_stream_data() {
         let flow_mgr = Rc::new(RefCell::new(FlowMgr::default()));
@@ -874,6 +1151,7 @@ mod tests {
         let mut s = RecvStream::new(
             67.into(),
             RX_STREAM_DATA_WINDOW,
+            RX_STREAM_DATA_WINDOW,
             Rc::clone(&flow_mgr),
             conn_events,
         );
@@ -927,6 +1205,7 @@ mod tests {
         let mut s = RecvStream::new(
             67.into(),
             RX_STREAM_DATA_WINDOW,
+            RX_STREAM_DATA_WINDOW,
             Rc::clone(&flow_mgr),
             conn_events,
         );
@@ -949,6 +1228,7 @@ mod tests {
         let mut s = RecvStream::new(
             67.into(),
             RX_STREAM_DATA_WINDOW,
+            RX_STREAM_DATA_WINDOW,
             Rc::clone(&flow_mgr),
             conn_events,
         );
@@ -970,4 +1250,52 @@ mod tests {
         flow_mgr.borrow_mut().max_stream_data(67.into(), 100);
         assert!(matches!(s.flow_mgr.borrow_mut().next().unwrap(), Frame::MaxStreamData{..}));
     }
+
+    /// Fill the window, then read everything.  Returns how much the limit increased.
+    fn fill_and_read(s: &mut RecvStream) -> u64 {
+        let limit = s.max_stream_data().unwrap();
+        let start = s.state.recv_buf().unwrap().retired();
+        let len = usize::try_from(limit - start).unwrap();
+        s.inbound_stream_frame(false, start, vec![0; len]).unwrap();
+        let mut buf = vec![0; len];
+        assert_eq!(s.read(&mut buf).unwrap(), (len, false));
+        let new_limit = s.max_stream_data().unwrap();
+        new_limit - limit
+    }
+
+    #[test]
+    fn window_autotune() {
+        let flow_mgr = Rc::new(RefCell::new(FlowMgr::default()));
+        let mut s = RecvStream::new(
+            4.into(),
+            1024,
+            4096,
+            Rc::clone(&flow_mgr),
+            ConnectionEvents::default(),
+        );
+
+        // Each time the peer fills the window and the application reads it
+        // all, the window doubles, until it reaches the limit.
+        assert_eq!(fill_and_read(&mut s), 2048);
+        assert_eq!(fill_and_read(&mut s), 4096);
+        assert_eq!(fill_and_read(&mut s), 4096);
+    }
+
+    #[test]
+    fn window_autotune_slow_reader() {
+        let flow_mgr = Rc::new(RefCell::new(FlowMgr::default()));
+        let mut s = RecvStream::new(
+            4.into(),
+            1024,
+            4096,
+            Rc::clone(&flow_mgr),
+            ConnectionEvents::default(),
+        );
+
+        // The window doesn't grow if the application doesn't read everything.
+        s.inbound_stream_frame(false, 0, vec![0; 1024]).unwrap();
+        let mut buf = vec![0; 1000];
+        assert_eq!(s.read(&mut buf).unwrap(), (1000, false));
+        assert_eq!(s.max_stream_data(), Some(2024));
+    }
 }
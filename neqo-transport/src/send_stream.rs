@@ -12,11 +12,12 @@ use std::collections::{BTreeMap, VecDeque};
 use std::convert::{TryFrom, TryInto};
 use std::mem;
 use std::rc::Rc;
+use std::time::{Duration, Instant};
 
 use indexmap::IndexMap;
 use smallvec::SmallVec;
 
-use neqo_common::{qdebug, qerror, qinfo, qtrace};
+use neqo_common::{qdebug, qerror, qinfo, qtrace, Encoder};
 
 use crate::events::ConnectionEvents;
 use crate::flow_mgr::FlowMgr;
@@ -27,6 +28,9 @@ use crate::tracking::PNSpace;
 use crate::{AppError, Error, Res};
 
 pub const SEND_BUFFER_SIZE: usize = 0x10_0000; // 1 MiB
+/// The least urgent priority level; see RFC 9218.
+pub const MAX_URGENCY: u8 = 7;
+const DEFAULT_URGENCY: u8 = 3;
 
 #[derive(Debug, PartialEq, Clone, Copy)]
 enum RangeState {
@@ -280,31 +284,45 @@ impl RangeTracker {
 }
 
 /// Buffer to contain queued bytes and track their state.
-#[derive(Debug, Default, PartialEq)]
+#[derive(Debug, PartialEq)]
 pub struct TxBuffer {
     retired: u64,           // contig acked bytes, no longer in buffer
     send_buf: VecDeque<u8>, // buffer of not-acked bytes
     ranges: RangeTracker,   // ranges in buffer that have been sent or acked
+    limit: usize,           // the most bytes that can be buffered
 }
 
 impl TxBuffer {
     pub fn new() -> Self {
+        Self::with_limit(SEND_BUFFER_SIZE)
+    }
+
+    /// Make a buffer that holds at most `limit` bytes.
+    pub fn with_limit(limit: usize) -> Self {
         Self {
-            send_buf: VecDeque::with_capacity(SEND_BUFFER_SIZE),
-            ..Self::default()
+            retired: 0,
+            send_buf: VecDeque::with_capacity(min(limit, SEND_BUFFER_SIZE)),
+            ranges: RangeTracker::default(),
+            limit,
         }
     }
 
     /// Attempt to add some or all of the passed-in buffer to the TxBuffer.
     pub fn send(&mut self, buf: &[u8]) -> usize {
-        let can_buffer = min(SEND_BUFFER_SIZE - self.buffered(), buf.len());
+        let can_buffer = min(self.avail(), buf.len());
         if can_buffer > 0 {
             self.send_buf.extend(&buf[..can_buffer]);
-            assert!(self.send_buf.len() <= SEND_BUFFER_SIZE);
+            assert!(self.send_buf.len() <= self.limit);
         }
         can_buffer
     }
 
+    /// Change the most bytes that can be buffered.  If this is less than what
+    /// is already buffered, nothing more is accepted until enough is acknowledged.
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+    }
+
     pub fn next_bytes(&self) -> Option<(u64, &[u8])> {
         let (start, maybe_len) = self.ranges.first_unmarked_range();
 
@@ -375,7 +393,7 @@ impl TxBuffer {
     }
 
     fn avail(&self) -> usize {
-        SEND_BUFFER_SIZE - self.buffered()
+        self.limit.saturating_sub(self.buffered())
     }
 
     pub fn highest_sent(&self) -> u64 {
@@ -383,6 +401,24 @@ impl TxBuffer {
     }
 }
 
+impl Default for TxBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The state of the sending part of a stream, as reported by `Connection::streams`.
+/// These are the states from -transport 3.1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendStreamStatus {
+    Ready,
+    Send,
+    DataSent,
+    DataRecvd,
+    ResetSent,
+    ResetRecvd,
+}
+
 /// QUIC sending stream states, based on -transport 3.1.
 #[derive(Debug, PartialEq)]
 enum SendStreamState {
@@ -403,6 +439,17 @@ enum SendStreamState {
 }
 
 impl SendStreamState {
+    fn status(&self) -> SendStreamStatus {
+        match self {
+            Self::Ready => SendStreamStatus::Ready,
+            Self::Send { .. } => SendStreamStatus::Send,
+            Self::DataSent { .. } => SendStreamStatus::DataSent,
+            Self::DataRecvd { .. } => SendStreamStatus::DataRecvd,
+            Self::ResetSent => SendStreamStatus::ResetSent,
+            Self::ResetRecvd => SendStreamStatus::ResetRecvd,
+        }
+    }
+
     fn tx_buf(&self) -> Option<&TxBuffer> {
         match self {
             Self::Send { send_buf } | Self::DataSent { send_buf, .. } => Some(send_buf),
@@ -417,10 +464,10 @@ impl SendStreamState {
         }
     }
 
-    fn tx_avail(&self) -> u64 {
+    fn tx_avail(&self, limit: usize) -> u64 {
         match self {
             // In Ready, TxBuffer not yet allocated but size is known
-            Self::Ready => SEND_BUFFER_SIZE.try_into().unwrap(),
+            Self::Ready => limit.try_into().unwrap(),
             Self::Send { send_buf } | Self::DataSent { send_buf, .. } => {
                 send_buf.avail().try_into().unwrap()
             }
@@ -452,12 +499,41 @@ impl SendStreamState {
     }
 }
 
+/// The sending priority of a stream, with the semantics of RFC 9218.
+/// Streams with a lower urgency are sent first.  Incremental streams with the
+/// same urgency take turns; other streams are sent one at a time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct StreamPriority {
+    urgency: u8,
+    incremental: bool,
+}
+
+impl Default for StreamPriority {
+    fn default() -> Self {
+        Self {
+            urgency: DEFAULT_URGENCY,
+            incremental: false,
+        }
+    }
+}
+
 /// Implement a QUIC send stream.
 #[derive(Debug)]
 pub struct SendStream {
     stream_id: StreamId,
     max_stream_data: u64,
     state: SendStreamState,
+    priority: StreamPriority,
+    send_buffer_limit: usize,
+    /// Whether the peer has acknowledged the end of the stream.
+    fin_acked: bool,
+    /// How long lost data is retransmitted for, if there is a limit.
+    unreliable_after: Option<Duration>,
+    /// When data was first sent, as the end of each run of bytes and the
+    /// time that it was sent.  This is only kept if `unreliable_after` is set.
+    first_sent: VecDeque<(u64, Instant)>,
+    /// Lost data that won't be retransmitted, which the peer needs to hear about.
+    gaps: VecDeque<(u64, usize)>,
     flow_mgr: Rc<RefCell<FlowMgr>>,
     conn_events: ConnectionEvents,
 }
@@ -473,6 +549,12 @@ impl SendStream {
             stream_id,
             max_stream_data,
             state: SendStreamState::Ready,
+            priority: StreamPriority::default(),
+            send_buffer_limit: SEND_BUFFER_SIZE,
+            fin_acked: false,
+            unreliable_after: None,
+            first_sent: VecDeque::new(),
+            gaps: VecDeque::new(),
             flow_mgr,
             conn_events,
         };
@@ -509,6 +591,86 @@ impl SendStream {
         }
     }
 
+    /// Make a STREAM_GAP frame for data that won't be retransmitted.
+    fn get_gap_frame(&mut self, remaining: usize) -> Option<(Frame, Option<RecoveryToken>)> {
+        self.state.tx_buf()?;
+        let (offset, length) = *self.gaps.front()?;
+        let frame = Frame::StreamGap {
+            stream_id: self.stream_id,
+            offset,
+            length: u64::try_from(length).unwrap(),
+        };
+        let mut enc = Encoder::default();
+        frame.marshal(&mut enc);
+        if enc.len() > remaining {
+            return None;
+        }
+        qdebug!(
+            "Stream {} sending gap {}-{}",
+            self.stream_id.as_u64(),
+            offset,
+            offset + length as u64,
+        );
+        self.gaps.pop_front();
+        Some((
+            frame,
+            Some(RecoveryToken::StreamGap(StreamRecoveryToken {
+                id: self.stream_id,
+                offset,
+                length,
+                fin: false,
+            })),
+        ))
+    }
+
+    /// Make a STREAM frame for the next range to be sent, if any, and mark that
+    /// range as sent.
+    fn get_frame(
+        &mut self,
+        space: PNSpace,
+        remaining: usize,
+        now: Instant,
+    ) -> Option<(Frame, Option<RecoveryToken>)> {
+        if let Some(gap) = self.get_gap_frame(remaining) {
+            return Some(gap);
+        }
+        let stream_id = self.stream_id;
+        let final_size = self.final_size();
+        let (offset, data) = self.next_bytes()?;
+        let data_len = u64::try_from(data.len()).unwrap();
+        let range_has_fin = final_size
+            .map(|fs| fs == offset + data_len)
+            .unwrap_or(false);
+        let (frame, length) =
+            Frame::new_stream(stream_id.as_u64(), offset, data, range_has_fin, remaining)?;
+        qdebug!(
+            "Stream {} sending bytes {}-{}, space {:?}",
+            stream_id.as_u64(),
+            offset,
+            offset + length as u64,
+            space,
+        );
+        let fin = range_has_fin && length == data.len();
+        debug_assert!(!fin || matches!(frame, Frame::Stream{fin: true, .. }));
+        self.mark_as_sent(offset, length, fin);
+        if self.unreliable_after.is_some() {
+            let end = offset + length as u64;
+            if self.first_sent.back().map_or(0, |&(e, _)| e) < end {
+                self.first_sent.push_back((end, now));
+            }
+        }
+
+        Some((
+            frame,
+            Some(RecoveryToken::Stream(StreamRecoveryToken {
+                id: stream_id,
+                offset,
+                length,
+                fin,
+            })),
+        ))
+    }
+
     pub fn mark_as_sent(&mut self, offset: u64, len: usize, fin: bool) {
         if let Some(buf) = self.state.tx_buf_mut() {
             buf.mark_as_sent(offset, len);
@@ -523,6 +685,7 @@ impl SendStream {
     }
 
     pub fn mark_as_acked(&mut self, offset: u64, len: usize, fin: bool) {
+        self.fin_acked |= fin;
         match self.state {
             SendStreamState::Send { ref mut send_buf } => {
                 send_buf.mark_as_acked(offset, len);
@@ -536,7 +699,7 @@ impl SendStream {
                 ..
             } => {
                 send_buf.mark_as_acked(offset, len);
-                if fin && send_buf.buffered() == 0 {
+                if self.fin_acked && send_buf.buffered() == 0 {
                     self.conn_events.send_stream_complete(self.stream_id);
                     self.state
                         .transition(SendStreamState::DataRecvd { final_size });
@@ -544,11 +707,46 @@ impl SendStream {
             }
             _ => qtrace!("mark_as_acked called from state {}", self.state.name()),
         }
+        if let Some(retired) = self.state.tx_buf().map(|buf| buf.retired) {
+            while matches!(self.first_sent.front(), Some(&(end, _)) if end <= retired) {
+                self.first_sent.pop_front();
+            }
+        }
+    }
+
+    /// Whether the byte at `offset` was first sent longer ago than `unreliable_after`.
+    fn stale(&self, offset: u64, now: Instant) -> bool {
+        if let Some(limit) = self.unreliable_after {
+            matches!(
+                self.first_sent.iter().find(|(end, _)| *end > offset),
+                Some(&(_, sent)) if sent + limit <= now
+            )
+        } else {
+            false
+        }
     }
 
-    pub fn mark_as_lost(&mut self, offset: u64, len: usize, fin: bool) {
+    /// Lost data is sent again, unless all of it has gone stale.  Stale data is
+    /// left as sent, and a STREAM_GAP frame takes its place.  Acknowledgment of
+    /// that frame releases the data.
+    pub fn mark_as_lost(&mut self, offset: u64, len: usize, fin: bool, now: Instant) {
+        let stale = len > 0 && self.stale(offset + len as u64 - 1, now);
         if let Some(buf) = self.state.tx_buf_mut() {
-            buf.mark_as_lost(offset, len);
+            if stale {
+                qdebug!(
+                    "Stream {} skipping stale bytes {}-{}",
+                    self.stream_id.as_u64(),
+                    offset,
+                    offset + len as u64,
+                );
+                self.gaps.push_back((offset, len));
+            } else {
+                buf.mark_as_lost(offset, len);
+                if len > 0 {
+                    self.conn_events
+                        .stream_data_lost(self.stream_id, offset, len);
+                }
+            }
         }
 
         if fin {
@@ -558,10 +756,44 @@ impl SendStream {
         }
     }
 
+    /// A STREAM_GAP frame was lost, so send it again.
+    pub fn gap_lost(&mut self, offset: u64, len: usize) {
+        if self.state.tx_buf().is_some() {
+            self.gaps.push_back((offset, len));
+        }
+    }
+
+    /// Stop retransmitting data that was first sent more than `limit` ago.
+    pub fn set_unreliable_after(&mut self, limit: Duration) {
+        self.unreliable_after = Some(limit);
+    }
+
     pub fn final_size(&self) -> Option<u64> {
         self.state.final_size()
     }
 
+    pub fn status(&self) -> SendStreamStatus {
+        self.state.status()
+    }
+
+    /// The number of bytes that are held until the peer acknowledges them.
+    pub fn buffered(&self) -> usize {
+        self.state.tx_buf().map_or(0, TxBuffer::buffered)
+    }
+
+    /// The number of bytes written to the stream and the number of those that
+    /// the peer has acknowledged.  `None` once the stream is reset.
+    pub fn progress(&self) -> Option<(u64, u64)> {
+        match &self.state {
+            SendStreamState::Ready => Some((0, 0)),
+            SendStreamState::Send { send_buf } | SendStreamState::DataSent { send_buf, .. } => {
+                Some((send_buf.data_limit(), send_buf.retired))
+            }
+            SendStreamState::DataRecvd { final_size } => Some((*final_size, *final_size)),
+            SendStreamState::ResetSent | SendStreamState::ResetRecvd => None,
+        }
+    }
+
     /// Stream credit available
     pub fn credit_avail(&self) -> u64 {
         if self.state == SendStreamState::Ready {
@@ -577,7 +809,10 @@ impl SendStream {
     /// connection credit available, and space in the tx buffer.
     pub fn avail(&self) -> usize {
         min(
-            min(self.state.tx_avail(), self.credit_avail()),
+            min(
+                self.state.tx_avail(self.send_buffer_limit),
+                self.credit_avail(),
+            ),
             self.flow_mgr.borrow().conn_credit_avail(),
         )
         .try_into()
@@ -588,6 +823,40 @@ impl SendStream {
         self.max_stream_data
     }
 
+    /// Set the priority of the stream.
+    /// # Errors
+    /// `InvalidInput` if `urgency` is greater than `MAX_URGENCY`.
+    pub fn set_priority(&mut self, urgency: u8, incremental: bool) -> Res<()> {
+        if urgency > MAX_URGENCY {
+            return Err(Error::InvalidInput);
+        }
+        self.priority = StreamPriority {
+            urgency,
+            incremental,
+        };
+        Ok(())
+    }
+
+    /// Set the most data that can be buffered for sending.  Once this much is
+    /// buffered, `send` accepts nothing more until some is acknowledged, at
+    /// which point a `SendStreamWritable` event is generated.
+    /// # Errors
+    /// `InvalidInput` if `limit` is zero.
+    pub fn set_send_buffer_limit(&mut self, limit: usize) -> Res<()> {
+        if limit == 0 {
+            return Err(Error::InvalidInput);
+        }
+        let stream_was_blocked = self.avail() == 0;
+        self.send_buffer_limit = limit;
+        if let Some(buf) = self.state.tx_buf_mut() {
+            buf.set_limit(limit);
+        }
+        if stream_was_blocked && self.avail() > 0 {
+            self.conn_events.send_stream_writable(self.stream_id)
+        }
+        Ok(())
+    }
+
     pub fn set_max_stream_data(&mut self, value: u64) {
         let stream_was_blocked = self.avail() == 0;
         self.max_stream_data = max(self.max_stream_data, value);
@@ -596,6 +865,19 @@ impl SendStream {
         }
     }
 
+    /// How much of the stream-level flow control credit has been used.
+    pub fn credit_used(&self) -> u64 {
+        self.max_stream_data - self.credit_avail()
+    }
+
+    /// Replace the limit on stream data with one from a server that rejected
+    /// 0-RTT.  Unlike `set_max_stream_data`, this can lower the limit, but not
+    /// below what the stream has already used.
+    pub fn replace_max_stream_data(&mut self, value: u64) {
+        debug_assert!(self.credit_used() <= value);
+        self.max_stream_data = value;
+    }
+
     pub fn reset_acked(&mut self) {
         match self.state {
             SendStreamState::Ready
@@ -621,6 +903,27 @@ impl SendStream {
         self.send_internal(buf, true)
     }
 
+    /// Send the concatenation of `bufs`, without the caller having to copy them together.
+    pub fn send_vectored(&mut self, bufs: &[&[u8]]) -> Res<usize> {
+        let mut sent = 0;
+        for buf in bufs.iter().filter(|b| !b.is_empty()) {
+            // Stop where a single write would stop, before signaling that the stream is blocked.
+            if sent > 0 && self.avail() == 0 {
+                break;
+            }
+            let amount = self.send(buf)?;
+            sent += amount;
+            if amount < buf.len() {
+                break;
+            }
+        }
+        if sent == 0 && bufs.iter().all(|b| b.is_empty()) {
+            // Report an empty write in the same way as `send`.
+            return self.send(&[]);
+        }
+        Ok(sent)
+    }
+
     fn send_blocked_if_space_needed(&mut self, needed_space: u64) {
         if self.credit_avail() <= needed_space {
             self.flow_mgr
@@ -641,7 +944,7 @@ impl SendStream {
 
         if let SendStreamState::Ready = self.state {
             self.state.transition(SendStreamState::Send {
-                send_buf: TxBuffer::new(),
+                send_buf: TxBuffer::with_limit(self.send_buffer_limit),
             });
         }
 
@@ -650,6 +953,10 @@ impl SendStream {
         }
 
         let buf = if buf.is_empty() || (self.avail() == 0) {
+            if self.state.tx_avail(self.send_buffer_limit) > 0 {
+                // Only flow control prevents this write.
+                self.conn_events.send_stream_blocked(self.stream_id);
+            }
             return Ok(0);
         } else if self.avail() < buf.len() {
             if atomic {
@@ -679,14 +986,14 @@ impl SendStream {
         match &mut self.state {
             SendStreamState::Ready => {
                 self.state.transition(SendStreamState::DataSent {
-                    send_buf: TxBuffer::new(),
+                    send_buf: TxBuffer::with_limit(self.send_buffer_limit),
                     final_size: 0,
                     fin_sent: false,
                 });
             }
             SendStreamState::Send { send_buf } => {
                 let final_size = send_buf.retired + send_buf.buffered() as u64;
-                let owned_buf = mem::replace(send_buf, TxBuffer::new());
+                let owned_buf = mem::take(send_buf);
                 self.state.transition(SendStreamState::DataSent {
                     send_buf: owned_buf,
                     final_size,
@@ -760,9 +1067,21 @@ impl SendStreams {
         }
     }
 
-    pub fn lost(&mut self, token: &StreamRecoveryToken) {
+    pub fn lost(&mut self, token: &StreamRecoveryToken, now: Instant) {
+        if let Some(ss) = self.0.get_mut(&token.id) {
+            ss.mark_as_lost(token.offset, token.length, token.fin, now);
+        }
+    }
+
+    pub fn gap_acked(&mut self, token: &StreamRecoveryToken) {
+        if let Some(ss) = self.0.get_mut(&token.id) {
+            ss.mark_as_acked(token.offset, token.length, false);
+        }
+    }
+
+    pub fn gap_lost(&mut self, token: &StreamRecoveryToken) {
         if let Some(ss) = self.0.get_mut(&token.id) {
-            ss.mark_as_lost(token.offset, token.length, token.fin);
+            ss.gap_lost(token.offset, token.length);
         }
     }
 
@@ -774,46 +1093,42 @@ impl SendStreams {
         self.0.retain(|_, stream| !stream.is_terminal())
     }
 
+    pub fn iter(&self) -> indexmap::map::Iter<'_, StreamId, SendStream> {
+        self.0.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> indexmap::map::IterMut<'_, StreamId, SendStream> {
+        self.0.iter_mut()
+    }
+
     pub(crate) fn get_frame(
         &mut self,
         space: PNSpace,
         remaining: usize,
+        now: Instant,
     ) -> Option<(Frame, Option<RecoveryToken>)> {
         if space != PNSpace::ApplicationData {
             return None;
         }
 
-        for (stream_id, stream) in self {
-            let final_size = stream.final_size();
-            if let Some((offset, data)) = stream.next_bytes() {
-                let data_len = u64::try_from(data.len()).unwrap();
-                let range_has_fin = final_size
-                    .map(|fs| fs == offset + data_len)
-                    .unwrap_or(false);
-                if let Some((frame, length)) =
-                    Frame::new_stream(stream_id.as_u64(), offset, data, range_has_fin, remaining)
-                {
-                    qdebug!(
-                        "Stream {} sending bytes {}-{}, space {:?}",
-                        stream_id.as_u64(),
-                        offset,
-                        offset + length as u64,
-                        space,
-                    );
-                    let fin = range_has_fin && length == data.len();
-                    debug_assert!(!fin || matches!(frame, Frame::Stream{fin: true, .. }));
-                    stream.mark_as_sent(offset, length, fin);
-
-                    return Some((
-                        frame,
-                        Some(RecoveryToken::Stream(StreamRecoveryToken {
-                            id: *stream_id,
-                            offset,
-                            length,
-                            fin,
-                        })),
-                    ));
+        // Streams are visited in the order they were created, most urgent first.
+        for urgency in 0..=MAX_URGENCY {
+            let sent = self
+                .0
+                .values_mut()
+                .filter(|stream| stream.priority.urgency == urgency)
+                .find_map(|stream| {
+                    stream
+                        .get_frame(space, remaining, now)
+                        .map(|res| (stream.stream_id, stream.priority.incremental, res))
+                });
+            if let Some((stream_id, incremental, res)) = sent {
+                if incremental {
+                    // Move to the back so that other streams get a turn.
+                    let stream = self.0.shift_remove(&stream_id).unwrap();
+                    self.0.insert(stream_id, stream);
                 }
+                return Some(res);
             }
         }
         None
@@ -842,6 +1157,7 @@ mod tests {
     use super::*;
 
     use crate::events::ConnectionEvent;
+    use test_fixture::now;
 
     #[test]
     fn test_mark_range() {
@@ -1141,6 +1457,67 @@ mod tests {
         s.mark_as_acked(0, 40, false);
     }
 
+    #[test]
+    fn send_vectored() {
+        let vectored: &[&[u8]] = &[b"abc", b"", b"defgh", b"ijklmn"];
+        let concatenated = vectored.concat();
+        let contents = |bufs: &[&[u8]]| {
+            let flow_mgr = Rc::new(RefCell::new(FlowMgr::default()));
+            flow_mgr.borrow_mut().conn_increase_max_credit(4096);
+            let mut s = SendStream::new(4.into(), 10, flow_mgr, ConnectionEvents::default());
+            let sent = s.send_vectored(bufs).unwrap();
+            let (offset, data) = s.state.tx_buf().unwrap().next_bytes().unwrap();
+            assert_eq!(offset, 0);
+            (sent, data.to_vec())
+        };
+
+        // The stream only has credit for 10 bytes.
+        let (sent, data) = contents(vectored);
+        assert_eq!(sent, 10);
+        assert_eq!(&data[..], &concatenated[..10]);
+        assert_eq!(contents(&[&concatenated]), (sent, data));
+
+        let flow_mgr = Rc::new(RefCell::new(FlowMgr::default()));
+        let mut s = SendStream::new(4.into(), 10, flow_mgr, ConnectionEvents::default());
+        assert_eq!(s.send_vectored(&[b"", b""]), Err(Error::InvalidInput));
+    }
+
+    #[test]
+    fn send_buffer_limit() {
+        let flow_mgr = Rc::new(RefCell::new(FlowMgr::default()));
+        flow_mgr.borrow_mut().conn_increase_max_credit(4096);
+        let conn_events = ConnectionEvents::default();
+        let mut s = SendStream::new(4.into(), 4096, flow_mgr, conn_events.clone());
+        let _ = conn_events.events().count();
+
+        s.set_send_buffer_limit(10).unwrap();
+        assert_eq!(s.avail(), 10);
+        assert_eq!(s.send(&[1; 20]).unwrap(), 10);
+        assert_eq!(s.send(&[1; 20]).unwrap(), 0);
+
+        // A lower limit leaves the stream blocked until enough is acknowledged.
+        s.set_send_buffer_limit(5).unwrap();
+        s.mark_as_sent(0, 10, false);
+        s.mark_as_acked(0, 4, false);
+        assert_eq!(s.avail(), 0);
+        assert_eq!(conn_events.events().count(), 0);
+        s.mark_as_acked(4, 2, false);
+        assert_eq!(s.avail(), 1);
+        assert!(matches!(
+            conn_events.events().next(),
+            Some(ConnectionEvent::SendStreamWritable { .. })
+        ));
+
+        // Raising the limit makes space immediately.
+        assert_eq!(s.send(&[1; 20]).unwrap(), 1);
+        s.set_send_buffer_limit(100).unwrap();
+        assert!(matches!(
+            conn_events.events().next(),
+            Some(ConnectionEvent::SendStreamWritable { .. })
+        ));
+        assert_eq!(s.send(&[1; 20]).unwrap(), 20);
+    }
+
     #[test]
     fn test_tx_buffer_acks() {
         let mut tx = TxBuffer::new();
@@ -1168,6 +1545,9 @@ mod tests {
         // Stream is initially blocked (conn:2, stream:0)
         // and will not accept data.
         assert_eq!(s.send(b"hi").unwrap(), 0);
+        let evts = conn_events.events().collect::<Vec<_>>();
+        assert_eq!(evts.len(), 1);
+        assert!(matches!(evts[0], ConnectionEvent::SendStreamBlocked { stream_id: 4 }));
 
         // increasing to (conn:2, stream:2) will allow 2 bytes, and also
         // generate a SendStreamWritable event.
@@ -1183,6 +1563,10 @@ mod tests {
         let evts = conn_events.events().collect::<Vec<_>>();
         assert_eq!(evts.len(), 0);
         assert_eq!(s.send(b"hello").unwrap(), 0);
+        // The write was refused because of connection flow control.
+        let evts = conn_events.events().collect::<Vec<_>>();
+        assert_eq!(evts.len(), 1);
+        assert!(matches!(evts[0], ConnectionEvent::SendStreamBlocked { stream_id: 4 }));
 
         // Increasing conn max (conn:4, stream:4) will unblock but not emit
         // event b/c that happens in Connection::emit_frame() (tested in
@@ -1219,6 +1603,8 @@ mod tests {
         let evts = conn_events.events().collect::<Vec<_>>();
         assert_eq!(evts.len(), 0);
         assert_eq!(s.send(b"hello").unwrap(), 0);
+        // A full buffer doesn't block the stream.
+        assert_eq!(conn_events.events().count(), 0);
     }
 
     #[test]
@@ -1250,13 +1636,13 @@ mod tests {
         let mut ss = SendStreams::default();
         ss.insert(0.into(), s);
 
-        let (_f1, f1_token) = ss.get_frame(PNSpace::ApplicationData, 6).unwrap();
+        let (_f1, f1_token) = ss.get_frame(PNSpace::ApplicationData, 6, now()).unwrap();
         assert!(matches!(&f1_token, Some(RecoveryToken::Stream(x)) if !x.fin));
-        let (_f2, f2_token) = ss.get_frame(PNSpace::ApplicationData, 100).unwrap();
+        let (_f2, f2_token) = ss.get_frame(PNSpace::ApplicationData, 100, now()).unwrap();
         assert!(matches!(&f2_token, Some(RecoveryToken::Stream(x)) if x.fin));
 
         // Should be no more data to frame
-        let f3 = ss.get_frame(PNSpace::ApplicationData, 100);
+        let f3 = ss.get_frame(PNSpace::ApplicationData, 100, now());
         assert!(matches!(f3, None));
 
         // Mark frame 1 as lost
@@ -1264,11 +1650,11 @@ mod tests {
             Some(RecoveryToken::Stream(rt)) => rt,
             _ => panic!(),
         };
-        ss.lost(&f1_token);
+        ss.lost(&f1_token, now());
 
         // Next frame should not set fin even though stream has fin but frame
         // does not include end of stream
-        let (_f4, f4_token) = ss.get_frame(PNSpace::ApplicationData, 100).unwrap();
+        let (_f4, f4_token) = ss.get_frame(PNSpace::ApplicationData, 100, now()).unwrap();
         assert!(matches!(f4_token, Some(RecoveryToken::Stream(x)) if !x.fin));
 
         // Mark frame 2 as lost
@@ -1276,13 +1662,101 @@ mod tests {
             Some(RecoveryToken::Stream(rt)) => rt,
             _ => panic!(),
         };
-        ss.lost(&f2_token);
+        ss.lost(&f2_token, now());
 
         // Next frame should set fin because it includes end of stream
-        let (_f5, f5_token) = ss.get_frame(PNSpace::ApplicationData, 100).unwrap();
+        let (_f5, f5_token) = ss.get_frame(PNSpace::ApplicationData, 100, now()).unwrap();
         assert!(matches!(f5_token, Some(RecoveryToken::Stream(x)) if x.fin));
     }
 
+    fn frame_stream_id(frame: Option<(Frame, Option<RecoveryToken>)>) -> u64 {
+        match frame {
+            Some((_, Some(RecoveryToken::Stream(token)))) => token.id.as_u64(),
+            _ => panic!("expected a stream frame"),
+        }
+    }
+
+    #[test]
+    fn send_stream_priority_urgency() {
+        let flow_mgr = Rc::new(RefCell::new(FlowMgr::default()));
+        flow_mgr.borrow_mut().conn_increase_max_credit(1000);
+        let conn_events = ConnectionEvents::default();
+
+        // A bulk stream with plenty of credit.
+        let mut bulk = SendStream::new(0.into(), 1000, Rc::clone(&flow_mgr), conn_events.clone());
+        assert_eq!(bulk.send(&[0; 500]).unwrap(), 500);
+        bulk.set_priority(MAX_URGENCY, false).unwrap();
+        // An urgent stream that is blocked by a small window.
+        let mut urgent = SendStream::new(4.into(), 2, Rc::clone(&flow_mgr), conn_events);
+        assert_eq!(urgent.send(&[0; 10]).unwrap(), 2);
+        urgent.set_priority(0, false).unwrap();
+        assert_eq!(
+            urgent.set_priority(MAX_URGENCY + 1, false),
+            Err(Error::InvalidInput)
+        );
+
+        let mut ss = SendStreams::default();
+        ss.insert(0.into(), bulk);
+        ss.insert(4.into(), urgent);
+
+        assert_eq!(
+            frame_stream_id(ss.get_frame(PNSpace::ApplicationData, 100, now())),
+            4
+        );
+        // The urgent stream is out of credit, so the bulk stream can send.
+        assert_eq!(
+            frame_stream_id(ss.get_frame(PNSpace::ApplicationData, 100, now())),
+            0
+        );
+
+        // Once there is more credit, the urgent stream goes first again.
+        let urgent = ss.get_mut(4.into()).unwrap();
+        urgent.set_max_stream_data(100);
+        assert_eq!(urgent.send(&[0; 10]).unwrap(), 10);
+        assert_eq!(
+            frame_stream_id(ss.get_frame(PNSpace::ApplicationData, 100, now())),
+            4
+        );
+        assert_eq!(
+            frame_stream_id(ss.get_frame(PNSpace::ApplicationData, 100, now())),
+            0
+        );
+    }
+
+    #[test]
+    fn send_stream_priority_incremental() {
+        let flow_mgr = Rc::new(RefCell::new(FlowMgr::default()));
+        flow_mgr.borrow_mut().conn_increase_max_credit(1000);
+        let conn_events = ConnectionEvents::default();
+
+        let mut ss = SendStreams::default();
+        for id in &[0_u64, 4, 8] {
+            let id = StreamId::from(*id);
+            let mut s = SendStream::new(id, 100, Rc::clone(&flow_mgr), conn_events.clone());
+            assert_eq!(s.send(&[0; 100]).unwrap(), 100);
+            s.set_priority(DEFAULT_URGENCY, true).unwrap();
+            ss.insert(id, s);
+        }
+
+        // Incremental streams take turns.
+        let next_frames = |ss: &mut SendStreams| {
+            (0..6)
+                .map(|_| frame_stream_id(ss.get_frame(PNSpace::ApplicationData, 20, now())))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(next_frames(&mut ss), vec![0, 4, 8, 0, 4, 8]);
+
+        // Other streams are sent one at a time, so stream 4 has to wait until
+        // stream 0 has sent all of its data.
+        for id in &[0_u64, 4, 8] {
+            ss.get_mut(StreamId::from(*id))
+                .unwrap()
+                .set_priority(DEFAULT_URGENCY, false)
+                .unwrap();
+        }
+        assert_eq!(next_frames(&mut ss), vec![0, 0, 0, 0, 4, 4]);
+    }
+
     #[test]
     #[allow(clippy::cognitive_complexity)]
     // Verify lost frames handle fin properly with zero length fin
@@ -1297,18 +1771,18 @@ mod tests {
         let mut ss = SendStreams::default();
         ss.insert(0.into(), s);
 
-        let (_f1, f1_token) = ss.get_frame(PNSpace::ApplicationData, 100).unwrap();
+        let (_f1, f1_token) = ss.get_frame(PNSpace::ApplicationData, 100, now()).unwrap();
         assert!(matches!(&f1_token, Some(RecoveryToken::Stream(x)) if x.offset == 0));
         assert!(matches!(&f1_token, Some(RecoveryToken::Stream(x)) if x.length == 10));
         assert!(matches!(&f1_token, Some(RecoveryToken::Stream(x)) if !x.fin));
 
         // Should be no more data to frame
-        let f2 = ss.get_frame(PNSpace::ApplicationData, 100);
+        let f2 = ss.get_frame(PNSpace::ApplicationData, 100, now());
         assert!(matches!(f2, None));
 
         ss.get_mut(0.into()).unwrap().close();
 
-        let (_f2, f2_token) = ss.get_frame(PNSpace::ApplicationData, 100).unwrap();
+        let (_f2, f2_token) = ss.get_frame(PNSpace::ApplicationData, 100, now()).unwrap();
         assert!(matches!(&f2_token, Some(RecoveryToken::Stream(x)) if x.offset == 10));
         assert!(matches!(&f2_token, Some(RecoveryToken::Stream(x)) if x.length == 0));
         assert!(matches!(&f2_token, Some(RecoveryToken::Stream(x)) if x.fin));
@@ -1318,10 +1792,10 @@ mod tests {
             Some(RecoveryToken::Stream(rt)) => rt,
             _ => panic!(),
         };
-        ss.lost(&f2_token);
+        ss.lost(&f2_token, now());
 
         // Next frame should set fin
-        let (_f3, f3_token) = ss.get_frame(PNSpace::ApplicationData, 100).unwrap();
+        let (_f3, f3_token) = ss.get_frame(PNSpace::ApplicationData, 100, now()).unwrap();
         assert!(matches!(&f3_token, Some(RecoveryToken::Stream(x)) if x.offset == 10));
         assert!(matches!(&f3_token, Some(RecoveryToken::Stream(x)) if x.length == 0));
         assert!(matches!(&f3_token, Some(RecoveryToken::Stream(x)) if x.fin));
@@ -1331,10 +1805,10 @@ mod tests {
             Some(RecoveryToken::Stream(rt)) => rt,
             _ => panic!(),
         };
-        ss.lost(&f1_token);
+        ss.lost(&f1_token, now());
 
         // Next frame should set fin and include all data
-        let (_f4, f4_token) = ss.get_frame(PNSpace::ApplicationData, 100).unwrap();
+        let (_f4, f4_token) = ss.get_frame(PNSpace::ApplicationData, 100, now()).unwrap();
         assert!(matches!(&f4_token, Some(RecoveryToken::Stream(x)) if x.offset == 0));
         assert!(matches!(&f4_token, Some(RecoveryToken::Stream(x)) if x.length == 10));
         assert!(matches!(&f4_token, Some(RecoveryToken::Stream(x)) if x.fin));
@@ -8,18 +8,24 @@
 
 use neqo_common::{
     self as common, hex, qdebug, qerror, qinfo, qlog::NeqoQlog, qtrace, qwarn, timer::Timer,
-    Datagram, Decoder, Role,
+    Datagram, Decoder, Encoder, Role,
 };
-use neqo_crypto::{AntiReplay, ZeroRttCheckResult, ZeroRttChecker};
+use neqo_crypto::{random, AntiReplay, ZeroRttCheckResult, ZeroRttChecker};
 
 pub use crate::addr_valid::ValidateAddress;
 use crate::addr_valid::{AddressValidation, AddressValidationResult};
-use crate::cid::{ConnectionId, ConnectionIdDecoder, ConnectionIdManager, ConnectionIdRef};
+use crate::cid::{
+    ConnectionId, ConnectionIdDecoder, ConnectionIdManager, ConnectionIdRef, StatelessResetTokens,
+    STATELESS_RESET_TOKEN_LEN,
+};
 use crate::connection::{Connection, Output, State};
-use crate::packet::{PacketBuilder, PacketType, PublicPacket};
-use crate::{QuicVersion, Res};
+use crate::crypto::{CryptoDxDirection, CryptoDxState, SERVER_INITIAL_LABEL};
+use crate::frame::{CloseError, Frame};
+use crate::packet::{PacketBuilder, PacketType, PublicPacket, PACKET_BIT_FIXED_QUIC};
+use crate::{Error, QuicVersion, Res};
 
 use std::cell::RefCell;
+use std::cmp::min;
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::fs::OpenOptions;
 use std::mem;
@@ -35,11 +41,32 @@ pub enum InitialResult {
     Retry(Vec<u8>),
 }
 
+/// What to do with an Initial packet that would start a new connection, as
+/// decided by the filter that is passed to `Server::set_initial_filter`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitialDecision {
+    /// Continue as normal, subject to the address validation policy.
+    Accept,
+    /// Drop the packet without creating a connection.
+    Reject,
+    /// Send a Retry, unless the packet carries a valid Retry token.
+    Retry,
+}
+
+/// A filter for Initial packets.  This is passed the source address and the
+/// destination connection ID of the packet.
+pub type InitialFilter = Box<dyn FnMut(SocketAddr, &ConnectionId) -> InitialDecision>;
+
 /// MIN_INITIAL_PACKET_SIZE is the smallest packet that can be used to establish
 /// a new connection across all QUIC versions this server supports.
 const MIN_INITIAL_PACKET_SIZE: usize = 1200;
 const TIMER_GRANULARITY: Duration = Duration::from_millis(10);
 const TIMER_CAPACITY: usize = 16384;
+/// The smallest stateless reset that can be sent: a short header with a
+/// minimal connection ID and packet number, some payload, and the token.
+const MIN_STATELESS_RESET_SIZE: usize = 5 + STATELESS_RESET_TOKEN_LEN;
+/// The largest stateless reset that is sent.
+const MAX_STATELESS_RESET_SIZE: usize = 42;
 
 type StateRef = Rc<RefCell<ServerConnectionState>>;
 type CidMgr = Rc<RefCell<dyn ConnectionIdManager>>;
@@ -143,6 +170,14 @@ pub struct Server {
     address_validation: Rc<RefCell<AddressValidation>>,
     /// Directory to create qlog traces in
     qlog_dir: Option<PathBuf>,
+    /// Generates the stateless reset tokens for connections.
+    reset_tokens: Rc<StatelessResetTokens>,
+    /// The versions that connections can use.
+    versions: Vec<QuicVersion>,
+    /// Whether connections tell clients not to migrate.
+    disable_migration: bool,
+    /// Decides whether to accept Initial packets before any connection state is created.
+    initial_filter: Option<InitialFilter>,
 }
 
 impl Server {
@@ -178,6 +213,10 @@ impl Server {
             timers: Timer::new(now, TIMER_GRANULARITY, TIMER_CAPACITY),
             address_validation: Rc::new(RefCell::new(validation)),
             qlog_dir: None,
+            reset_tokens: Rc::new(StatelessResetTokens::new()?),
+            versions: QuicVersion::all().to_vec(),
+            disable_migration: false,
+            initial_filter: None,
         })
     }
 
@@ -191,6 +230,47 @@ impl Server {
         self.address_validation.borrow_mut().set_validation(v);
     }
 
+    /// Set the key that is used to generate stateless reset tokens.  Servers
+    /// that share a key are able to reset connections that any of them
+    /// accepted, as long as they also share a connection ID format.  This only
+    /// affects connections that are accepted after it is set.
+    /// # Errors
+    /// When the key can't be imported.
+    pub fn set_stateless_reset_key(&mut self, key: &[u8]) -> Res<()> {
+        self.reset_tokens = Rc::new(StatelessResetTokens::from_bytes(key)?);
+        Ok(())
+    }
+
+    /// Limit the versions that connections can use.  Clients that use another
+    /// version are sent a Version Negotiation packet that lists these.
+    /// # Errors
+    /// `InvalidInput` if `versions` is empty.
+    pub fn set_versions(&mut self, versions: &[QuicVersion]) -> Res<()> {
+        if versions.is_empty() {
+            return Err(Error::InvalidInput);
+        }
+        self.versions = versions.to_vec();
+        Ok(())
+    }
+
+    /// Tell clients not to migrate connections to a new path, using the
+    /// disable_active_migration transport parameter.  Packets that clients
+    /// send from a different address are then dropped.  This only affects
+    /// connections that are accepted after it is set.
+    pub fn set_disable_migration(&mut self, disable: bool) {
+        self.disable_migration = disable;
+    }
+
+    /// Set or clear a filter that is called for Initial packets that don't
+    /// belong to an established connection, before any work is done to create
+    /// a connection.  This includes Initial packets that the client retransmits
+    /// before the handshake completes.  A filter can be used to limit the rate
+    /// at which connections are accepted, or to require address validation for
+    /// some clients only.
+    pub fn set_initial_filter(&mut self, filter: Option<InitialFilter>) {
+        self.initial_filter = filter;
+    }
+
     fn remove_timer(&mut self, c: &StateRef) {
         let last = c.borrow().last_timer;
         self.timers.remove(last, |t| Rc::ptr_eq(t, c));
@@ -258,49 +338,120 @@ impl Server {
         now: Instant,
     ) -> Option<Datagram> {
         qdebug!([self], "Handle initial");
+        let decision = if let Some(filter) = &mut self.initial_filter {
+            filter(dgram.source(), &initial.dst_cid)
+        } else {
+            InitialDecision::Accept
+        };
+        if decision == InitialDecision::Reject {
+            qdebug!([self], "Initial rejected by filter");
+            return None;
+        }
         let res = self
             .address_validation
             .borrow()
             .validate(&initial.token, dgram.source(), now);
         match res {
-            AddressValidationResult::Invalid => None,
+            AddressValidationResult::Invalid => self.reject_token(&initial, &dgram),
+            AddressValidationResult::Pass if decision == InitialDecision::Retry => {
+                self.send_retry(&initial, &dgram, now)
+            }
             AddressValidationResult::Pass => self.connection_attempt(initial, dgram, None, now),
             AddressValidationResult::ValidRetry(orig_dcid) => {
                 self.connection_attempt(initial, dgram, Some(orig_dcid), now)
             }
-            AddressValidationResult::Validate => {
-                qinfo!([self], "Send retry for {:?}", initial.dst_cid);
+            AddressValidationResult::Validate => self.send_retry(&initial, &dgram, now),
+        }
+    }
 
-                let res = self.address_validation.borrow().generate_retry_token(
-                    &initial.dst_cid,
-                    dgram.source(),
-                    now,
-                );
-                let token = if let Ok(t) = res {
-                    t
-                } else {
-                    qerror!([self], "unable to generate token, dropping packet");
-                    return None;
-                };
-                let new_dcid = self.cid_manager.borrow_mut().generate_cid();
-                let packet = PacketBuilder::retry(
-                    initial.quic_version,
-                    &initial.src_cid,
-                    &new_dcid,
-                    &token,
-                    &initial.dst_cid,
-                );
-                if let Ok(p) = packet {
-                    let retry = Datagram::new(dgram.destination(), dgram.source(), p);
-                    Some(retry)
-                } else {
-                    qerror!([self], "unable to encode retry, dropping packet");
-                    None
-                }
-            }
+    fn send_retry(
+        &self,
+        initial: &InitialDetails,
+        dgram: &Datagram,
+        now: Instant,
+    ) -> Option<Datagram> {
+        qinfo!([self], "Send retry for {:?}", initial.dst_cid);
+
+        let res = self.address_validation.borrow().generate_retry_token(
+            &initial.dst_cid,
+            dgram.source(),
+            now,
+        );
+        let token = if let Ok(t) = res {
+            t
+        } else {
+            qerror!([self], "unable to generate token, dropping packet");
+            return None;
+        };
+        let new_dcid = self.cid_manager.borrow_mut().generate_cid();
+        let packet = PacketBuilder::retry(
+            initial.quic_version,
+            &initial.src_cid,
+            &new_dcid,
+            &token,
+            &initial.dst_cid,
+        );
+        if let Ok(p) = packet {
+            let retry = Datagram::new(dgram.destination(), dgram.source(), p);
+            Some(retry)
+        } else {
+            qerror!([self], "unable to encode retry, dropping packet");
+            None
         }
     }
 
+    /// Reject an Initial that carries an invalid or expired Retry token.
+    /// A client won't accept a second Retry, so rather than leave it to time out,
+    /// close the connection statelessly with an INVALID_TOKEN error.
+    fn reject_token(&self, initial: &InitialDetails, dgram: &Datagram) -> Option<Datagram> {
+        qinfo!([self], "Reject invalid token for {:?}", initial.dst_cid);
+
+        let mut tx = CryptoDxState::new_initial(
+            initial.quic_version,
+            CryptoDxDirection::Write,
+            SERVER_INITIAL_LABEL,
+            &initial.dst_cid,
+        );
+        let mut builder = PacketBuilder::long(
+            Encoder::default(),
+            PacketType::Initial,
+            initial.quic_version,
+            &initial.src_cid,
+            &initial.dst_cid,
+        );
+        builder.initial_token(&[]);
+        builder.pn(0, 1);
+        Frame::ConnectionClose {
+            error_code: CloseError::Transport(Error::InvalidToken.code()),
+            frame_type: 0,
+            reason_phrase: Vec::new(),
+        }
+        .marshal(&mut builder);
+        if let Ok(p) = builder.build(&mut tx) {
+            Some(Datagram::new(dgram.destination(), dgram.source(), p))
+        } else {
+            qerror!([self], "unable to encode close, dropping packet");
+            None
+        }
+    }
+
+    /// Build a stateless reset in response to `dgram`, which was sent to `cid`.
+    /// The reset is smaller than `dgram`, so that two endpoints that have both
+    /// lost state can't keep sending resets to each other.
+    fn stateless_reset(&self, cid: &ConnectionId, dgram: &Datagram) -> Option<Datagram> {
+        let len = min(dgram.len() - 1, MAX_STATELESS_RESET_SIZE);
+        if len < MIN_STATELESS_RESET_SIZE {
+            qtrace!([self], "Too small to send a stateless reset");
+            return None;
+        }
+        qdebug!([self], "Sending stateless reset for {}", cid);
+        let mut reset = random(len - STATELESS_RESET_TOKEN_LEN);
+        // Look like a short header packet.
+        reset[0] = PACKET_BIT_FIXED_QUIC | (reset[0] & 0x3f);
+        reset.extend_from_slice(&self.reset_tokens.token(cid));
+        Some(Datagram::new(dgram.destination(), dgram.source(), reset))
+    }
+
     fn connection_attempt(
         &mut self,
         initial: InitialDetails,
@@ -409,7 +560,14 @@ impl Server {
                 // There was a retry, so set the connection IDs for.
                 c.set_retry_cids(odcid, initial.src_cid, initial.dst_cid);
             }
+            if c.set_versions(&self.versions).is_err() {
+                qwarn!([self], "Unable to set versions");
+            }
             c.set_validation(Rc::clone(&self.address_validation));
+            c.set_reset_tokens(Rc::clone(&self.reset_tokens));
+            if self.disable_migration && c.set_disable_migration().is_err() {
+                qwarn!([self], "Unable to disable migration");
+            }
             c.set_qlog(self.create_qlog_trace(&attempt_key));
             let c = Rc::new(RefCell::new(ServerConnectionState {
                 c,
@@ -446,9 +604,9 @@ impl Server {
         }
 
         if packet.packet_type() == PacketType::Short {
-            // TODO send a stateless reset here.
             qtrace!([self], "Short header packet for an unknown connection");
-            return None;
+            let cid = ConnectionId::from(packet.dcid());
+            return self.stateless_reset(&cid, &dgram);
         }
 
         if dgram.len() < MIN_INITIAL_PACKET_SIZE {
@@ -456,13 +614,17 @@ impl Server {
             return None;
         }
         match packet.packet_type() {
-            PacketType::Initial => {
+            PacketType::Initial if self.versions.contains(&packet.version().unwrap()) => {
                 // Copy values from `packet` because they are currently still borrowing from `dgram`.
                 let initial = InitialDetails::new(&packet);
                 self.handle_initial(initial, dgram, now)
             }
-            PacketType::OtherVersion => {
-                let vn = PacketBuilder::version_negotiation(packet.scid(), packet.dcid());
+            PacketType::Initial | PacketType::OtherVersion => {
+                let vn = PacketBuilder::version_negotiation(
+                    packet.scid(),
+                    packet.dcid(),
+                    &self.versions,
+                );
                 Some(Datagram::new(dgram.destination(), dgram.source(), vn))
             }
             _ => {
@@ -614,6 +776,12 @@ impl ConnectionIdManager for ServerConnectionIdManager {
     fn as_decoder(&self) -> &dyn ConnectionIdDecoder {
         self
     }
+
+    fn retire_cid(&mut self, cid: &ConnectionIdRef) {
+        qtrace!("ServerConnectionIdManager removing cid {}", cid);
+        self.saved_cids.retain(|c| c != cid);
+        self.connections.borrow_mut().remove(&cid[..]);
+    }
 }
 
 impl ::std::fmt::Display for Server {
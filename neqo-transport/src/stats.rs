@@ -12,6 +12,7 @@ use std::cell::RefCell;
 use std::fmt::{self, Debug};
 use std::ops::Deref;
 use std::rc::Rc;
+use std::time::Duration;
 
 /// Connection statistics
 #[derive(Default, Clone)]
@@ -27,9 +28,13 @@ pub struct Stats {
     pub dropped_rx: usize,
     /// The number of packet that were saved for later processing.
     pub saved_datagrams: usize,
+    /// Total bytes received, counting whole datagrams.
+    pub bytes_rx: usize,
 
     /// Total packets sent.
     pub packets_tx: usize,
+    /// Total bytes sent, counting whole datagrams.
+    pub bytes_tx: usize,
     /// Total number of packets that are declared lost.
     pub lost: usize,
     /// Late acknowledgments, for packets that were declared lost already.
@@ -37,6 +42,15 @@ pub struct Stats {
     /// Acknowledgments for packets that contained data that was marked
     /// for retransmission when the PTO timer popped.
     pub pto_ack: usize,
+    /// The number of times that the PTO timer fired.
+    pub pto_count: usize,
+    /// The number of times that the peer reported new ECN-CE marks.
+    pub ce_rx: usize,
+
+    /// The smoothed round trip time, as of when these statistics were taken.
+    pub rtt: Duration,
+    /// The congestion window, as of when these statistics were taken.
+    pub cwnd: usize,
 
     /// Whether the connection was resumed successfully.
     pub resumed: bool,
@@ -63,14 +77,21 @@ impl Debug for Stats {
         writeln!(f, "stats for {}", self.info)?;
         writeln!(
             f,
-            "  rx: {} drop {} dup {} saved {}",
-            self.packets_rx, self.dropped_rx, self.dups_rx, self.saved_datagrams
+            "  rx: {} ({} bytes) drop {} dup {} saved {}",
+            self.packets_rx, self.bytes_rx, self.dropped_rx, self.dups_rx, self.saved_datagrams
         )?;
         writeln!(
             f,
-            "  tx: {} lost {} lateack {} ptoack {}",
-            self.packets_tx, self.lost, self.late_ack, self.pto_ack
+            "  tx: {} ({} bytes) lost {} lateack {} ptoack {} pto {} ce {}",
+            self.packets_tx,
+            self.bytes_tx,
+            self.lost,
+            self.late_ack,
+            self.pto_ack,
+            self.pto_count,
+            self.ce_rx
         )?;
+        writeln!(f, "  rtt: {:?} cwnd: {}", self.rtt, self.cwnd)?;
         write!(f, "  resumed: {} ", self.resumed)
     }
 }
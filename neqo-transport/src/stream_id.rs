@@ -12,6 +12,8 @@ use neqo_common::Role;
 
 use crate::connection::{LOCAL_STREAM_LIMIT_BIDI, LOCAL_STREAM_LIMIT_UNI};
 use crate::frame::StreamType;
+use crate::recv_stream::RecvStreamStatus;
+use crate::send_stream::SendStreamStatus;
 
 pub struct StreamIndexes {
     pub local_max_stream_uni: StreamIndex,
@@ -122,6 +124,23 @@ impl ::std::fmt::Display for StreamId {
     }
 }
 
+/// A snapshot of a stream, as reported by `Connection::streams`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamInfo {
+    pub id: StreamId,
+    pub stream_type: StreamType,
+    /// The state of the sending part, which is `None` for a stream that this
+    /// endpoint can't send on or once the sending part is finished.
+    pub send: Option<SendStreamStatus>,
+    /// The state of the receiving part, which is `None` for a stream that this
+    /// endpoint can't receive on or once the receiving part is finished.
+    pub recv: Option<RecvStreamStatus>,
+    /// Bytes that were written by the application and not yet acknowledged.
+    pub send_buffered: usize,
+    /// Bytes that were received and not yet read by the application.
+    pub recv_buffered: u64,
+}
+
 #[derive(Debug, Eq, PartialEq, Clone, Copy, Ord, PartialOrd, Hash)]
 pub struct StreamIndex(u64);
 
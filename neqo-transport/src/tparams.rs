@@ -14,6 +14,7 @@ use neqo_crypto::ext::{ExtensionHandler, ExtensionHandlerResult, ExtensionWriter
 use neqo_crypto::{HandshakeMessage, ZeroRttCheckResult, ZeroRttChecker};
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::rc::Rc;
 
 struct PreferredAddress {
@@ -44,7 +45,12 @@ tpids! {
     ACTIVE_CONNECTION_ID_LIMIT = 0x0e,
     INITIAL_SOURCE_CONNECTION_ID = 0x0f,
     RETRY_SOURCE_CONNECTION_ID = 0x10,
+    VERSION_INFORMATION = 0x11,
+    MAX_DATAGRAM_FRAME_SIZE = 0x20,
     GREASE_QUIC_BIT = 0x2ab2,
+    MIN_ACK_DELAY = 0xff02_de1a,
+    // Private; see `Connection::stream_set_unreliable_after`.
+    STREAM_GAPS = 0x00ff_5a7e,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -52,6 +58,12 @@ pub enum TransportParameter {
     Bytes(Vec<u8>),
     Integer(u64),
     Empty,
+    /// The `version_information` parameter of RFC 9368: the version that is
+    /// in use, then the versions that the endpoint supports.
+    Versions {
+        current: u32,
+        other: Vec<u32>,
+    },
 }
 
 impl TransportParameter {
@@ -70,6 +82,14 @@ impl TransportParameter {
             Self::Empty => {
                 enc.encode_varint(0_u64);
             }
+            Self::Versions { current, other } => {
+                enc.encode_vvec_with(|enc_inner| {
+                    enc_inner.encode_uint(4, *current);
+                    for v in other {
+                        enc_inner.encode_uint(4, *v);
+                    }
+                });
+            }
         };
     }
 
@@ -99,7 +119,9 @@ impl TransportParameter {
             | INITIAL_MAX_STREAM_DATA_BIDI_LOCAL
             | INITIAL_MAX_STREAM_DATA_BIDI_REMOTE
             | INITIAL_MAX_STREAM_DATA_UNI
-            | MAX_ACK_DELAY => match d.decode_varint() {
+            | MAX_ACK_DELAY
+            | MAX_DATAGRAM_FRAME_SIZE
+            | MIN_ACK_DELAY => match d.decode_varint() {
                 Some(v) => Self::Integer(v),
                 None => return Err(Error::TransportParameterError),
             },
@@ -123,7 +145,9 @@ impl TransportParameter {
                 _ => return Err(Error::TransportParameterError),
             },
 
-            DISABLE_MIGRATION | GREASE_QUIC_BIT => Self::Empty,
+            DISABLE_MIGRATION | GREASE_QUIC_BIT | STREAM_GAPS => Self::Empty,
+
+            VERSION_INFORMATION => Self::decode_versions(&mut d)?,
             // Skip.
             _ => return Ok(None),
         };
@@ -133,6 +157,23 @@ impl TransportParameter {
         qdebug!("TP decoded; type 0x{:02x} val {:?}", tp, value);
         Ok(Some((tp, value)))
     }
+
+    fn decode_versions(dec: &mut Decoder) -> Res<Self> {
+        fn decode_version(dec: &mut Decoder) -> Res<u32> {
+            match dec.decode_uint(4) {
+                Some(v) if v != 0 => Ok(u32::try_from(v).unwrap()),
+                _ => Err(Error::TransportParameterError),
+            }
+        }
+
+        // This fails if there are fewer than 4 bytes left at any point.
+        let current = decode_version(dec)?;
+        let mut other = Vec::new();
+        while dec.remaining() > 0 {
+            other.push(decode_version(dec)?);
+        }
+        Ok(Self::Versions { current, other })
+    }
 }
 
 #[derive(Clone, Debug, Default, PartialEq)]
@@ -184,7 +225,9 @@ impl TransportParameters {
             | INITIAL_MAX_STREAM_DATA_BIDI_REMOTE
             | INITIAL_MAX_STREAM_DATA_UNI
             | INITIAL_MAX_STREAMS_BIDI
-            | INITIAL_MAX_STREAMS_UNI => 0,
+            | INITIAL_MAX_STREAMS_UNI
+            | MAX_DATAGRAM_FRAME_SIZE
+            | MIN_ACK_DELAY => 0,
             MAX_UDP_PAYLOAD_SIZE => 65527,
             ACK_DELAY_EXPONENT => 3,
             MAX_ACK_DELAY => 25,
@@ -211,7 +254,9 @@ impl TransportParameters {
             | MAX_UDP_PAYLOAD_SIZE
             | ACK_DELAY_EXPONENT
             | MAX_ACK_DELAY
-            | ACTIVE_CONNECTION_ID_LIMIT => {
+            | ACTIVE_CONNECTION_ID_LIMIT
+            | MAX_DATAGRAM_FRAME_SIZE
+            | MIN_ACK_DELAY => {
                 self.set(tp, TransportParameter::Integer(value));
             }
             _ => panic!("Transport parameter not known"),
@@ -248,13 +293,34 @@ impl TransportParameters {
 
     pub fn set_empty(&mut self, tp: TransportParameterId) {
         match tp {
-            DISABLE_MIGRATION | GREASE_QUIC_BIT => {
+            DISABLE_MIGRATION | GREASE_QUIC_BIT | STREAM_GAPS => {
                 self.set(tp, TransportParameter::Empty);
             }
             _ => panic!("Transport parameter not known or not type empty"),
         }
     }
 
+    /// Set the `version_information` parameter.
+    pub fn set_versions(&mut self, current: u32, other: &[u32]) {
+        self.set(
+            VERSION_INFORMATION,
+            TransportParameter::Versions {
+                current,
+                other: other.to_vec(),
+            },
+        );
+    }
+
+    /// Get the version that is in use and the versions that are supported,
+    /// if `version_information` was included.
+    pub fn get_versions(&self) -> Option<(u32, &[u32])> {
+        match self.params.get(&VERSION_INFORMATION) {
+            None => None,
+            Some(TransportParameter::Versions { current, other }) => Some((*current, other)),
+            _ => panic!("Internal error"),
+        }
+    }
+
     pub fn get_empty(&self, tipe: TransportParameterId) -> bool {
         match self.params.get(&tipe) {
             None => false,
@@ -274,11 +340,13 @@ impl TransportParameters {
                 ORIGINAL_DESTINATION_CONNECTION_ID
                     | INITIAL_SOURCE_CONNECTION_ID
                     | RETRY_SOURCE_CONNECTION_ID
+                    | VERSION_INFORMATION
                     | STATELESS_RESET_TOKEN
                     | IDLE_TIMEOUT
                     | ACK_DELAY_EXPONENT
                     | MAX_ACK_DELAY
                     | ACTIVE_CONNECTION_ID_LIMIT
+                    | MIN_ACK_DELAY
             ) {
                 continue;
             }
@@ -298,7 +366,8 @@ impl TransportParameters {
         true
     }
 
-    fn was_sent(&self, tp: TransportParameterId) -> bool {
+    /// Determine whether a value was included for the given parameter.
+    pub fn was_sent(&self, tp: TransportParameterId) -> bool {
         self.params.contains_key(&tp)
     }
 }
@@ -521,4 +590,39 @@ mod tests {
             assert!(!tps_b.ok_for_0rtt(&tps_a));
         }
     }
+
+    #[test]
+    fn versions() {
+        let mut tps = TransportParameters::default();
+        tps.set_versions(0xff00_001d, &[0xff00_001d, 0xff00_001c]);
+        let mut enc = Encoder::default();
+        tps.encode(&mut enc);
+        let tps2 = TransportParameters::decode(&mut enc.as_decoder()).expect("Couldn't decode");
+        assert_eq!(
+            tps2.get_versions(),
+            Some((0xff00_001d, &[0xff00_001d, 0xff00_001c][..]))
+        );
+    }
+
+    #[test]
+    fn versions_bad() {
+        fn decode(value: &[u8]) -> Res<TransportParameters> {
+            let mut enc = Encoder::default();
+            enc.encode_varint(VERSION_INFORMATION);
+            enc.encode_vvec(value);
+            TransportParameters::decode(&mut enc.as_decoder())
+        }
+
+        assert!(decode(&[0xff, 0, 0, 0x1d]).is_ok());
+        // Empty, not a multiple of 4 bytes, or containing a zero version.
+        for value in &[
+            &[][..],
+            &[0xff, 0, 0],
+            &[0xff, 0, 0, 0x1d, 0],
+            &[0, 0, 0, 0, 0xff, 0, 0, 0x1d],
+            &[0xff, 0, 0, 0x1d, 0, 0, 0, 0],
+        ] {
+            assert_eq!(decode(value).unwrap_err(), Error::TransportParameterError);
+        }
+    }
 }
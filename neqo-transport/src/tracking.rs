@@ -9,14 +9,15 @@
 #![deny(clippy::pedantic)]
 
 use std::collections::VecDeque;
-use std::convert::TryInto;
+use std::convert::{TryFrom, TryInto};
 use std::ops::{Index, IndexMut};
 use std::rc::Rc;
 use std::time::{Duration, Instant};
 
-use neqo_common::{qdebug, qinfo, qtrace, qwarn};
+use neqo_common::{qdebug, qinfo, qtrace, qwarn, IpTos};
 use neqo_crypto::{Epoch, TLS_EPOCH_HANDSHAKE, TLS_EPOCH_INITIAL};
 
+use crate::ecn::EcnCount;
 use crate::frame::{AckRange, Frame};
 use crate::packet::{PacketNumber, PacketType};
 use crate::recovery::RecoveryToken;
@@ -120,6 +121,26 @@ impl std::fmt::Debug for PNSpaceSet {
     }
 }
 
+/// The packet numbers in use in one packet number space.  Each is `None` if no such packet
+/// exists, or once the space is discarded.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PacketNumberSpaceInfo {
+    /// The packet number of the next packet sent in this space.
+    pub next_sent: Option<PacketNumber>,
+    /// The largest packet number that the peer has acknowledged.
+    pub largest_acked: Option<PacketNumber>,
+    /// The largest packet number that has been received.
+    pub largest_received: Option<PacketNumber>,
+}
+
+/// The packet numbers in use in each packet number space of a connection.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PacketNumberInfo {
+    pub initial: PacketNumberSpaceInfo,
+    pub handshake: PacketNumberSpaceInfo,
+    pub application: PacketNumberSpaceInfo,
+}
+
 #[derive(Debug, Clone)]
 pub struct SentPacket {
     pub pt: PacketType,
@@ -131,11 +152,14 @@ pub struct SentPacket {
     time_declared_lost: Option<Instant>,
     /// After a PTO, this is true when the packet has been released.
     pto: bool,
+    /// The ECN codepoint of the datagram that carried the packet.
+    ecn_mark: IpTos,
 
     pub size: usize,
 }
 
 impl SentPacket {
+    #[must_use]
     pub fn new(
         pt: PacketType,
         pn: PacketNumber,
@@ -152,16 +176,38 @@ impl SentPacket {
             tokens,
             time_declared_lost: None,
             pto: false,
+            ecn_mark: IpTos::default(),
             size,
         }
     }
 
+    /// Record the ECN codepoint that the packet was sent with.
+    pub fn set_ecn_mark(&mut self, tos: IpTos) {
+        self.ecn_mark = tos.ecn();
+    }
+
+    /// The ECN codepoint that the packet was sent with.
+    #[must_use]
+    pub fn ecn_mark(&self) -> IpTos {
+        self.ecn_mark
+    }
+
     /// Returns `true` if the packet will elicit an ACK.
+    #[must_use]
     pub fn ack_eliciting(&self) -> bool {
         self.ack_eliciting
     }
 
+    /// Whether the packet is a PMTUD probe.
+    #[must_use]
+    pub fn is_pmtud_probe(&self) -> bool {
+        self.tokens
+            .iter()
+            .any(|t| matches!(t, RecoveryToken::PmtudProbe(_)))
+    }
+
     /// Whether the packet has been declared lost.
+    #[must_use]
     pub fn lost(&self) -> bool {
         self.time_declared_lost.is_some()
     }
@@ -172,6 +218,7 @@ impl SentPacket {
     /// and has not previously been declared lost.
     /// Note that this should count packets that contain only ACK and PADDING,
     /// but we don't send PADDING, so we don't track that.
+    #[must_use]
     pub fn cc_outstanding(&self) -> bool {
         self.ack_eliciting() && !self.lost()
     }
@@ -188,6 +235,7 @@ impl SentPacket {
 
     /// Ask whether this tracked packet has been declared lost for long enough
     /// that it can be expired and no longer tracked.
+    #[must_use]
     pub fn expired(&self, now: Instant, expiration_period: Duration) -> bool {
         if let Some(loss_time) = self.time_declared_lost {
             (loss_time + expiration_period) <= now
@@ -197,6 +245,7 @@ impl SentPacket {
     }
 
     /// Whether the packet contents were cleared out after a PTO.
+    #[must_use]
     pub fn pto_fired(&self) -> bool {
         self.pto
     }
@@ -311,6 +360,8 @@ impl ::std::fmt::Display for PacketRange {
 
 /// The ACK delay we use.
 pub const ACK_DELAY: Duration = Duration::from_millis(20); // 20ms
+/// The smallest ACK delay that a peer can ask us to use.
+pub const MIN_ACK_DELAY: Duration = Duration::from_millis(1);
 pub const MAX_UNACKED_PKTS: usize = 1;
 const MAX_TRACKED_RANGES: usize = 32;
 const MAX_ACKS_PER_FRAME: usize = 32;
@@ -335,6 +386,17 @@ pub struct RecvdPackets {
     // The time that we should be sending an ACK.
     ack_time: Option<Instant>,
     pkts_since_last_ack: usize,
+    /// The number of ack-eliciting packets that can be received without
+    /// sending an ACK immediately.
+    unacked_limit: usize,
+    /// How long an ACK can be delayed.
+    ack_delay: Duration,
+    /// Whether to send an ACK immediately when packets arrive out of order.
+    ignore_order: bool,
+    /// The sequence number of the last `ACK_FREQUENCY` frame that was applied.
+    ack_frequency_seqno: Option<u64>,
+    /// The ECN codepoints of packets that were received.
+    ecn_count: EcnCount,
 }
 
 impl RecvdPackets {
@@ -347,9 +409,34 @@ impl RecvdPackets {
             largest_pn_time: None,
             ack_time: None,
             pkts_since_last_ack: 0,
+            unacked_limit: MAX_UNACKED_PKTS,
+            ack_delay: ACK_DELAY,
+            ignore_order: false,
+            ack_frequency_seqno: None,
+            ecn_count: EcnCount::default(),
         }
     }
 
+    /// Change how acknowledgments are sent, following an `ACK_FREQUENCY` frame.
+    /// Frames that arrive out of order, based on `seqno`, are ignored.
+    fn ack_freq(&mut self, seqno: u64, tolerance: u64, delay: Duration, ignore_order: bool) {
+        if matches!(self.ack_frequency_seqno, Some(s) if s >= seqno) {
+            return;
+        }
+        self.ack_frequency_seqno = Some(seqno);
+        // The tolerance counts the packet that triggers the ACK.
+        self.unacked_limit = usize::try_from(tolerance - 1).unwrap_or(usize::MAX);
+        self.ack_delay = delay;
+        self.ignore_order = ignore_order;
+        qdebug!(
+            [self],
+            "ACK frequency set: tolerance {} delay {:?} ignore order {}",
+            tolerance,
+            delay,
+            ignore_order
+        );
+    }
+
     /// Get the time at which the next ACK should be sent.
     pub fn ack_time(&self) -> Option<Instant> {
         self.ack_time
@@ -425,18 +512,18 @@ impl RecvdPackets {
         if ack_eliciting {
             self.pkts_since_last_ack += 1;
 
-            // Send ACK right away if out-of-order
+            // Send ACK right away if out-of-order (unless the peer asked us not to).
             // On the first in-order ack-eliciting packet since sending an ACK,
             // set a delay.
-            // Count packets until we exceed MAX_UNACKED_PKTS, then remove the
-            // delay.
-            if pn != next_in_order_pn {
+            // Count packets until we exceed the limit on unacknowledged packets,
+            // then remove the delay.
+            if pn != next_in_order_pn && !self.ignore_order {
                 self.ack_time = Some(now);
             } else if self.space == PNSpace::ApplicationData {
-                match &mut self.pkts_since_last_ack {
+                match self.pkts_since_last_ack {
                     0 => unreachable!(),
-                    1 => self.ack_time = Some(now + ACK_DELAY),
-                    x if *x > MAX_UNACKED_PKTS => self.ack_time = Some(now),
+                    x if x > self.unacked_limit => self.ack_time = Some(now),
+                    1 => self.ack_time = Some(now + self.ack_delay),
                     _ => debug_assert!(self.ack_time.is_some()),
                 }
             } else {
@@ -446,6 +533,16 @@ impl RecvdPackets {
         }
     }
 
+    /// Count the ECN codepoint of a packet that was received.
+    pub fn ecn_received(&mut self, tos: IpTos) {
+        self.ecn_count.add(tos);
+    }
+
+    /// Get the largest packet number that has been received.
+    pub fn largest_pn(&self) -> Option<PacketNumber> {
+        self.ranges.front().map(|r| r.largest)
+    }
+
     /// Check if the packet is a duplicate.
     pub fn is_duplicate(&self, pn: PacketNumber) -> bool {
         if pn < self.min_tracked {
@@ -532,6 +629,7 @@ impl RecvdPackets {
                 ack_delay: delay,
                 first_ack_range: first.len() - 1,
                 ack_ranges,
+                ecn_count: Some(self.ecn_count).filter(|c| !c.is_empty()),
             };
             let token = RecoveryToken::Ack(AckToken {
                 space: self.space,
@@ -576,12 +674,20 @@ impl AckTracker {
         assert_eq!(sp.unwrap().space, space, "dropping spaces out of order");
     }
 
-    pub fn get_mut(&mut self, space: PNSpace) -> Option<&mut RecvdPackets> {
-        self.spaces.get_mut(match space {
+    fn index(space: PNSpace) -> usize {
+        match space {
             PNSpace::ApplicationData => 0,
             PNSpace::Handshake => 1,
             PNSpace::Initial => 2,
-        })
+        }
+    }
+
+    pub fn get(&self, space: PNSpace) -> Option<&RecvdPackets> {
+        self.spaces.get(Self::index(space))
+    }
+
+    pub fn get_mut(&mut self, space: PNSpace) -> Option<&mut RecvdPackets> {
+        self.spaces.get_mut(Self::index(space))
     }
 
     /// Determine the earliest time that an ACK might be needed.
@@ -607,6 +713,17 @@ impl AckTracker {
         }
     }
 
+    /// Apply an `ACK_FREQUENCY` frame from the peer.
+    pub fn ack_freq(&mut self, seqno: u64, tolerance: u64, delay: Duration, ignore_order: bool) {
+        // Only ApplicationData ever delays ACK.
+        self.get_mut(PNSpace::ApplicationData).unwrap().ack_freq(
+            seqno,
+            tolerance,
+            delay,
+            ignore_order,
+        );
+    }
+
     pub(crate) fn get_frame(
         &mut self,
         now: Instant,
@@ -769,6 +886,27 @@ mod tests {
         }
     }
 
+    #[test]
+    fn ack_frequency() {
+        const DELAY: Duration = Duration::from_millis(50);
+        let mut rp = RecvdPackets::new(PNSpace::ApplicationData);
+        rp.ack_freq(1, 5, DELAY, true);
+
+        // Four packets are received, with a gap, without needing an immediate ACK.
+        for pn in &[0, 1, 3, 4] {
+            rp.set_received(*NOW, *pn, true);
+            assert_eq!(Some(*NOW + DELAY), rp.ack_time());
+        }
+        // The fifth needs an ACK.
+        rp.set_received(*NOW, 5, true);
+        assert_eq!(Some(*NOW), rp.ack_time());
+
+        // An older frame is ignored.
+        rp.ack_freq(0, 1, ACK_DELAY, false);
+        assert_eq!(rp.unacked_limit, 4);
+        assert_eq!(rp.ack_delay, DELAY);
+    }
+
     #[test]
     fn aggregate_ack_time() {
         let mut tracker = AckTracker::default();
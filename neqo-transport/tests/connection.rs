@@ -7,14 +7,36 @@
 #![cfg_attr(feature = "deny-warnings", deny(warnings))]
 #![warn(clippy::use_self)]
 
-use neqo_common::Datagram;
-use test_fixture::{self, default_client, default_server, now};
+use neqo_common::{Datagram, Decoder};
+use neqo_crypto::{set_rng, SeededRng};
+use neqo_transport::State;
+use std::time::Duration;
+use test_fixture::{self, default_client, default_server, now, VirtualTime};
 
 #[test]
 fn connect() {
     let (_client, _server) = test_fixture::connect();
 }
 
+/// The idle timer starts from the time on the clock that drove the handshake.
+#[test]
+fn handshake_with_clock() {
+    let clock = VirtualTime::new();
+    clock.advance(Duration::from_secs(60));
+    let mut client = default_client();
+    let mut server = default_server();
+    test_fixture::handshake_with_clock(&mut client, &mut server, &clock);
+    assert_eq!(*client.state(), State::Confirmed);
+
+    clock.advance(Duration::from_secs(29));
+    let _ = client.process(None, clock.now());
+    assert_eq!(*client.state(), State::Confirmed);
+
+    clock.advance(Duration::from_secs(2));
+    let _ = client.process(None, clock.now());
+    assert!(matches!(client.state(), State::Closed(_)));
+}
+
 #[test]
 fn truncate_long_packet() {
     let mut client = default_client();
@@ -43,3 +65,27 @@ fn truncate_long_packet() {
     assert!(dgram.is_some());
     assert!(server.state().connected());
 }
+
+/// Get the destination and source connection IDs from a client Initial.
+fn initial_cids(dgram: &Datagram) -> (Vec<u8>, Vec<u8>) {
+    // Skip the first byte, which is masked by header protection, and the version.
+    let mut dec = Decoder::from(&dgram[5..]);
+    let dcid = dec.decode_vec(1).unwrap().to_vec();
+    let scid = dec.decode_vec(1).unwrap().to_vec();
+    (dcid, scid)
+}
+
+#[test]
+fn seeded_connection_ids() {
+    let seeded_cids = |seed| {
+        set_rng(Some(Box::new(SeededRng::new(seed))));
+        let mut client = default_client();
+        let dgram = client.process(None, now()).dgram().unwrap();
+        set_rng(None);
+        initial_cids(&dgram)
+    };
+
+    let first = seeded_cids(1);
+    assert_eq!(first, seeded_cids(1));
+    assert_ne!(first, seeded_cids(2));
+}
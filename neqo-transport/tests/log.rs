@@ -0,0 +1,73 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Check that connections identify themselves in log messages.
+#![cfg_attr(feature = "deny-warnings", deny(warnings))]
+#![warn(clippy::use_self)]
+
+use lazy_static::lazy_static;
+use log::{LevelFilter, Log, Metadata, Record};
+use std::sync::Mutex;
+use test_fixture::{self, default_client, default_server, handshake};
+
+lazy_static! {
+    /// The target and message of every log record.
+    static ref RECORDS: Mutex<Vec<(String, String)>> = Mutex::default();
+}
+
+struct Capture;
+
+impl Log for Capture {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        RECORDS
+            .lock()
+            .unwrap()
+            .push((record.target().to_string(), record.args().to_string()));
+    }
+
+    fn flush(&self) {}
+}
+
+static CAPTURE: Capture = Capture;
+
+#[test]
+fn connection_id_prefix() {
+    log::set_logger(&CAPTURE).unwrap();
+    log::set_max_level(LevelFilter::Trace);
+
+    let mut client = default_client();
+    let mut server = default_server();
+    // Until the server receives a packet, it uses its own connection ID.
+    let server_before = format!("[{}]", server);
+    assert!(server.odcid().is_none());
+    handshake(&mut client, &mut server);
+
+    // After that, both peers use the connection ID that the client chose.
+    let cid = client.odcid().unwrap();
+    assert_eq!(server.odcid(), Some(cid));
+    let prefixes = [client.to_string(), server.to_string()]
+        .iter()
+        .map(|label| format!("[{}]", label))
+        .chain(Some(server_before))
+        .collect::<Vec<_>>();
+    assert!(prefixes[..2].iter().all(|p| p.contains(&cid.to_string())));
+
+    let messages = RECORDS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(target, _)| target.starts_with("neqo_transport::connection"))
+        .map(|(_, msg)| msg.clone())
+        .collect::<Vec<_>>();
+    assert!(!messages.is_empty());
+    for msg in &messages {
+        assert!(prefixes.iter().any(|p| msg.starts_with(p)), "{}", msg);
+    }
+}
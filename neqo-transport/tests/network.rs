@@ -12,8 +12,8 @@ mod sim;
 
 use neqo_transport::{ConnectionError, Error, State};
 use sim::{
-    connection::{ConnectionNode, ReachState, ReceiveData, SendData},
-    network::{Delay, Drop, TailDrop},
+    connection::{ConnectionNode, ObserveLoss, ReachState, ReceiveData, SendData},
+    network::{Delay, Drop, Reorder, TailDrop},
     Simulator,
 };
 use std::ops::Range;
@@ -126,6 +126,19 @@ simulate!(
     ],
 );
 
+simulate!(
+    transfer_drop_reorder,
+    [
+        ConnectionNode::new_client(boxed![SendData::new(TRANSFER_AMOUNT), ObserveLoss::new(1)]),
+        Drop::percentage(10),
+        Reorder::new(4, JITTER),
+        Delay::new(DELAY_RANGE),
+        ConnectionNode::new_server(boxed![ReceiveData::new(TRANSFER_AMOUNT)]),
+        Drop::percentage(10),
+        Delay::new(DELAY_RANGE),
+    ],
+);
+
 simulate!(
     transfer_taildrop,
     [
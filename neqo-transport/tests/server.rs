@@ -16,9 +16,9 @@ use neqo_crypto::{
     AllowZeroRtt, AuthenticationStatus,
 };
 use neqo_transport::{
-    server::{ActiveConnectionRef, Server, ValidateAddress},
-    Connection, ConnectionError, Error, FixedConnectionIdManager, Output, QuicVersion, State,
-    StreamType,
+    server::{ActiveConnectionRef, InitialDecision, Server, ValidateAddress},
+    CloseOrigin, Connection, ConnectionError, ConnectionEvent, Error, FixedConnectionIdManager,
+    Output, QuicVersion, State, StreamType,
 };
 use test_fixture::{self, assertions, default_client, now};
 
@@ -252,6 +252,38 @@ fn retry_basic() {
     connected_server(&mut server);
 }
 
+#[test]
+fn server_odcid() {
+    let mut server = default_server();
+    let mut client = default_client();
+    let server_conn = connect(&mut client, &mut server);
+    assert!(client.odcid().is_some());
+    assert_eq!(server_conn.borrow().odcid(), client.odcid());
+}
+
+/// After a Retry, the server learns the original destination connection ID
+/// from the token.
+#[test]
+fn retry_odcid() {
+    let mut server = default_server();
+    server.set_validation(ValidateAddress::Always);
+    let mut client = default_client();
+    let server_conn = complete_connection(&mut client, &mut server, None);
+    assert!(client.odcid().is_some());
+    assert_eq!(server_conn.borrow().odcid(), client.odcid());
+}
+
+/// Check that the server rejected the token that the client used by closing
+/// the connection with `INVALID_TOKEN`.
+fn assert_invalid_token(client: &mut Connection, close: Option<Datagram>) {
+    assert!(close.is_some());
+    client.process_input(close.unwrap(), now());
+    assert!(matches!(
+        *client.state(),
+        State::Draining { error: ConnectionError::Transport(Error::PeerError(code)), .. } if code == Error::InvalidToken.code()
+    ));
+}
+
 #[test]
 fn retry_expired() {
     let mut server = default_server();
@@ -270,8 +302,8 @@ fn retry_expired() {
     assert!(dgram.is_some());
 
     now += Duration::from_secs(60); // Too long for Retry.
-    let dgram = server.process(dgram, now).dgram(); // Initial, HS
-    assert!(dgram.is_none());
+    let dgram = server.process(dgram, now).dgram(); // CONNECTION_CLOSE
+    assert_invalid_token(&mut client, dgram);
 }
 
 fn get_ticket(server: &mut Server) -> Vec<u8> {
@@ -378,7 +410,11 @@ fn retry_different_ip() {
     let other_addr = SocketAddr::new(other_v4, 443);
     let from_other = Datagram::new(other_addr, dgram.destination(), &dgram[..]);
     let dgram = server.process(Some(from_other), now()).dgram();
-    assert!(dgram.is_none());
+    // The token is rejected, so the server only sends back a small CONNECTION_CLOSE.
+    let close = dgram.unwrap();
+    assert_eq!(close.destination(), other_addr);
+    assert!(close.len() < 100);
+    assert!(server.active_connections().is_empty());
 }
 
 #[test]
@@ -450,6 +486,39 @@ fn new_token_expired() {
     assertions::assert_retry(dgram.as_ref().unwrap());
 }
 
+/// Replace the address validation token in a resumption token.
+fn replace_token(resumption: &[u8], token: &[u8]) -> Vec<u8> {
+    let mut dec = Decoder::from(resumption);
+    let mut enc = Encoder::default();
+    enc.encode_varint(dec.decode_varint().unwrap());
+    enc.encode_vvec(dec.decode_vvec().unwrap());
+    dec.decode_vvec().unwrap();
+    enc.encode_vvec(token);
+    enc.encode(dec.decode_remainder());
+    enc.into()
+}
+
+#[test]
+fn new_token_unusable() {
+    let mut server = default_server();
+    let resumption = get_ticket(&mut server);
+    server.set_validation(ValidateAddress::NoToken);
+
+    // Neither a short token nor one that isn't ours is rejected.
+    // The server sends a Retry, as it would if there were no token.
+    for token in &[&[0x42; 3][..], &[0; 40][..]] {
+        let mut client = default_client();
+        client
+            .enable_resumption(now(), &replace_token(&resumption, token))
+            .unwrap();
+        let dgram = client.process(None, now()).dgram(); // Initial
+        assert!(dgram.is_some());
+        let dgram = server.process(dgram, now()).dgram(); // Retry
+        assert!(dgram.is_some());
+        assertions::assert_retry(dgram.as_ref().unwrap());
+    }
+}
+
 #[test]
 fn retry_after_initial() {
     let mut server = default_server();
@@ -531,7 +600,8 @@ fn retry_bad_token() {
     assert!(client_initial2.is_some());
 
     let dgram = server.process(client_initial2, now()).dgram();
-    assert!(dgram.is_none());
+    assert_invalid_token(&mut client, dgram);
+    assert!(server.active_connections().is_empty());
 }
 
 // This is really a client test, but we need a server with Retry to test it.
@@ -877,6 +947,71 @@ fn version_negotiation() {
     assert_eq!(client.state(), &State::WaitInitial);
 }
 
+#[test]
+fn version_negotiation_downgrade() {
+    let mut server = default_server();
+    server.set_versions(&[QuicVersion::Draft28]).unwrap();
+    let mut client = default_client();
+    assert_eq!(client.set_versions(&[]), Err(Error::InvalidInput));
+    client
+        .set_versions(&[QuicVersion::Draft29, QuicVersion::Draft28])
+        .unwrap();
+    assert_eq!(client.version(), QuicVersion::Draft29);
+
+    let dgram = client.process(None, now()).dgram(); // Initial
+    let vn = server.process(dgram, now()).dgram();
+    let vn = vn.expect("a vn packet");
+    assert_eq!(vn[1..5], [0; 4]);
+
+    // The client restarts the handshake with the version the server supports.
+    let dgram = client.process(Some(vn), now()).dgram();
+    assert_eq!(client.version(), QuicVersion::Draft28);
+    let dgram = dgram.expect("an Initial");
+    assert_eq!(dgram[1..5], QuicVersion::Draft28.as_u32().to_be_bytes());
+
+    let dgram = server.process(Some(dgram), now()).dgram();
+    let server_conn = complete_connection(&mut client, &mut server, dgram);
+    assert_eq!(server_conn.borrow().version(), QuicVersion::Draft28);
+    assert_eq!(
+        client.set_versions(&[QuicVersion::Draft29]),
+        Err(Error::ConnectionState)
+    );
+}
+
+/// A forged Version Negotiation packet can't make a client use a version that
+/// it likes less than one that the server supports.
+#[test]
+fn version_negotiation_forged() {
+    let mut server = default_server();
+    let mut attacker = default_server();
+    attacker.set_versions(&[QuicVersion::Draft28]).unwrap();
+    let mut client = default_client();
+    client
+        .set_versions(&[QuicVersion::Draft29, QuicVersion::Draft28])
+        .unwrap();
+
+    let dgram = client.process(None, now()).dgram();
+    let vn = attacker.process(dgram, now()).dgram();
+    let mut dgram = client.process(vn, now()).dgram();
+    assert_eq!(client.version(), QuicVersion::Draft28);
+
+    // The server lists Draft29 in its transport parameters, so the client
+    // closes the connection once the handshake completes.
+    while !matches!(client.state(), State::Closing { .. } | State::Confirmed) {
+        let _ = test_fixture::maybe_authenticate(&mut client);
+        dgram = server.process(dgram, now()).dgram();
+        dgram = client.process(dgram, now()).dgram();
+    }
+    assert!(matches!(
+        client.state(),
+        State::Closing {
+            error: ConnectionError::Transport(Error::VersionNegotiation),
+            ..
+        }
+    ));
+    assert_eq!(Error::VersionNegotiation.code(), 0x11);
+}
+
 #[test]
 fn closed() {
     // Let a server connection idle and it should be removed.
@@ -895,3 +1030,197 @@ fn closed() {
     let res = server.process(None, now() + Duration::from_secs(60));
     assert_eq!(res, Output::None);
 }
+
+const RESET_KEY: &[u8] = &[0x5a; 16];
+
+/// Connect to a server, then replace it with a new server that uses `key`,
+/// as though the server instance was recycled.  Return the response of the
+/// new server to a packet from the client.
+fn send_to_new_server(client: &mut Connection, key: &[u8]) -> Option<Datagram> {
+    let mut server = default_server();
+    server.set_stateless_reset_key(RESET_KEY).unwrap();
+    connect(client, &mut server);
+
+    let mut server = default_server();
+    server.set_stateless_reset_key(key).unwrap();
+    let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+    client.stream_send(stream_id, &[1; 100]).unwrap();
+    let dgram = client.process(None, now()).dgram();
+    assert!(dgram.is_some());
+    server.process(dgram, now()).dgram()
+}
+
+#[test]
+fn stateless_reset() {
+    let mut client = default_client();
+    let reset = send_to_new_server(&mut client, RESET_KEY);
+    let reset = reset.expect("server should send a stateless reset");
+    client.process_input(reset, now());
+
+    let is_reset = |s: &State| {
+        matches!(s, State::Draining {
+            error: ConnectionError::Transport(Error::StatelessReset),
+            ..
+        })
+    };
+    assert!(is_reset(client.state()));
+    let events = client.events().collect::<Vec<_>>();
+    assert!(events.iter().any(|e| matches!(
+        e,
+        ConnectionEvent::ConnectionClosed {
+            origin: CloseOrigin::StatelessReset,
+            ..
+        }
+    )));
+    assert!(events
+        .iter()
+        .any(|e| matches!(e, ConnectionEvent::StateChange(s) if is_reset(s))));
+}
+
+#[test]
+fn stateless_reset_wrong_key() {
+    let mut client = default_client();
+    let reset = send_to_new_server(&mut client, &[0xa5; 16]);
+    // The reset is sent, but the client doesn't recognize it.
+    client.process_input(reset.unwrap(), now());
+    assert_eq!(*client.state(), State::Confirmed);
+}
+
+/// The stateless reset token that a server with `RESET_KEY` uses for `cid`.
+fn reset_token(cid: &[u8]) -> Vec<u8> {
+    let key = hkdf::import_key(TLS_VERSION_1_3, TLS_AES_128_GCM_SHA256, RESET_KEY).unwrap();
+    let secret = hkdf::extract(TLS_VERSION_1_3, TLS_AES_128_GCM_SHA256, None, &key).unwrap();
+    let token = hkdf::expand_label(
+        TLS_VERSION_1_3,
+        TLS_AES_128_GCM_SHA256,
+        &secret,
+        cid,
+        "neqo reset",
+    )
+    .unwrap();
+    token.as_bytes().unwrap()[..16].to_vec()
+}
+
+#[test]
+fn retired_cid_removed() {
+    let mut server = default_server();
+    server.set_stateless_reset_key(RESET_KEY).unwrap();
+    let mut client = default_client();
+    connect(&mut client, &mut server);
+
+    // Hold on to a packet that uses the connection ID from the handshake.
+    let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+    client.stream_send(stream_id, &[1; 100]).unwrap();
+    let old = client.process(None, now()).dgram().unwrap();
+    let old_cid = old[1..10].to_vec();
+
+    // Migrating makes the client retire that connection ID.
+    let loopback = test_fixture::loopback();
+    let new_local = SocketAddr::new(loopback.ip(), loopback.port() + 1);
+    client.migrate(new_local, loopback, now()).unwrap();
+    let mut dgram = client.process(None, now()).dgram();
+    while dgram.is_some() {
+        dgram = server.process(dgram, now()).dgram();
+        dgram = client.process(dgram, now()).dgram();
+    }
+
+    // The server no longer knows the connection ID, so it resets.
+    let reset = server.process(Some(old), now()).dgram().unwrap();
+    assert_eq!(&reset[reset.len() - 16..], &reset_token(&old_cid)[..]);
+}
+
+#[test]
+fn disable_migration() {
+    let mut server = default_server();
+    server.set_disable_migration(true);
+    let mut client = default_client();
+    connect(&mut client, &mut server);
+
+    let loopback = test_fixture::loopback();
+    let new_local = SocketAddr::new(loopback.ip(), loopback.port() + 1);
+    assert_eq!(
+        client.migrate(new_local, loopback, now()),
+        Err(Error::InvalidMigration)
+    );
+}
+
+#[test]
+fn new_token_event() {
+    let mut server = default_server();
+    let mut client = default_client();
+    let mut server_conn = connect(&mut client, &mut server);
+    server_conn.borrow_mut().send_ticket(now(), &[]).unwrap();
+    let dgram = server.process(None, now()).dgram();
+    client.process_input(dgram.unwrap(), now());
+    let token = client
+        .events()
+        .find_map(|e| {
+            if let ConnectionEvent::NewToken { token } = e {
+                Some(token)
+            } else {
+                None
+            }
+        })
+        .expect("should have a NEW_TOKEN");
+
+    // A new client that uses the token is not sent a Retry.
+    server.set_validation(ValidateAddress::NoToken);
+    let mut client = default_client();
+    assert_eq!(client.set_initial_token(&[]), Err(Error::InvalidInput));
+    client.set_initial_token(&token).unwrap();
+    let dgram = client.process(None, now()).dgram();
+    assertions::assert_initial(dgram.as_ref().unwrap(), true);
+    let dgram = server.process(dgram, now()).dgram();
+    assertions::assert_initial(dgram.as_ref().unwrap(), false);
+    client.process_input(dgram.unwrap(), now());
+    assert!(test_fixture::maybe_authenticate(&mut client));
+    assert_eq!(
+        client.set_initial_token(&token),
+        Err(Error::ConnectionState)
+    );
+}
+
+#[test]
+fn initial_filter_reject() {
+    let mut server = default_server();
+    let calls = Rc::new(RefCell::new(0));
+    let counter = Rc::clone(&calls);
+    server.set_initial_filter(Some(Box::new(move |addr, _dcid| {
+        assert_eq!(addr, test_fixture::loopback());
+        *counter.borrow_mut() += 1;
+        InitialDecision::Reject
+    })));
+
+    for _ in 0..20 {
+        let mut client = default_client();
+        let dgram = client.process(None, now()).dgram();
+        assert!(dgram.is_some());
+        assert!(server.process(dgram, now()).dgram().is_none());
+    }
+    assert_eq!(*calls.borrow(), 20);
+    assert!(server.active_connections().is_empty());
+    assert_eq!(server.process(None, now()), Output::None);
+
+    // Without the filter, connections are accepted again.
+    server.set_initial_filter(None);
+    let mut client = default_client();
+    connect(&mut client, &mut server);
+}
+
+#[test]
+fn initial_filter_retry() {
+    let mut server = default_server();
+    server.set_initial_filter(Some(Box::new(|_, _| InitialDecision::Retry)));
+    let mut client = default_client();
+
+    let dgram = client.process(None, now()).dgram();
+    let dgram = server.process(dgram, now()).dgram();
+    assertions::assert_retry(dgram.as_ref().unwrap());
+    assert!(server.active_connections().is_empty());
+
+    // The Initial that carries the Retry token is accepted.
+    let dgram = client.process(dgram, now()).dgram();
+    let dgram = server.process(dgram, now()).dgram();
+    assert!(dgram.is_some());
+    complete_connection(&mut client, &mut server, dgram);
+}
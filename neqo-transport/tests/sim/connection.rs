@@ -297,3 +297,41 @@ impl ConnectionGoal for ReceiveData {
         }
     }
 }
+
+/// Wait until the connection has declared packets lost.  This is only
+/// accomplished if some packets need to be retransmitted.
+#[derive(Debug)]
+pub struct ObserveLoss {
+    min: usize,
+}
+
+impl ObserveLoss {
+    pub fn new(min: usize) -> Self {
+        Self { min }
+    }
+
+    fn check(&self, c: &Connection) -> GoalStatus {
+        let lost = c.stats().lost;
+        if lost >= self.min {
+            qdebug!([c], "observed {} lost packets", lost);
+            GoalStatus::Done
+        } else {
+            GoalStatus::Waiting
+        }
+    }
+}
+
+impl ConnectionGoal for ObserveLoss {
+    fn process(&mut self, c: &mut Connection, _now: Instant) -> GoalStatus {
+        self.check(c)
+    }
+
+    fn handle_event(
+        &mut self,
+        c: &mut Connection,
+        _e: &ConnectionEvent,
+        _now: Instant,
+    ) -> GoalStatus {
+        self.check(c)
+    }
+}
@@ -11,6 +11,7 @@
 pub mod connection;
 mod delay;
 mod drop;
+mod reorder;
 pub mod rng;
 mod taildrop;
 
@@ -23,13 +24,14 @@ use std::convert::TryFrom;
 use std::fmt::Debug;
 use std::rc::Rc;
 use std::time::{Duration, Instant};
-use test_fixture::{self, now};
+use test_fixture::{self, VirtualTime};
 
 use NodeState::{Active, Idle, Waiting};
 
 pub mod network {
     pub use super::delay::Delay;
     pub use super::drop::Drop;
+    pub use super::reorder::Reorder;
     pub use super::taildrop::TailDrop;
 }
 
@@ -118,6 +120,7 @@ pub struct Simulator {
     name: String,
     nodes: Vec<NodeHolder>,
     rng: Rng,
+    clock: VirtualTime,
 }
 
 impl Simulator {
@@ -138,6 +141,7 @@ impl Simulator {
             name,
             nodes,
             rng: Rc::default(),
+            clock: VirtualTime::new(),
         }
     }
 
@@ -167,18 +171,20 @@ impl Simulator {
     }
 
     /// Runs the simulation.
+    /// Time only moves when every node is waiting, and then it jumps
+    /// straight to the next timer.
     pub fn run(mut self) -> Duration {
-        let start = now();
-        let mut now = start;
+        let start = self.clock.now();
         let mut dgram = None;
 
         for n in &mut self.nodes {
-            n.node.init(self.rng.clone(), now);
+            n.node.init(self.rng.clone(), start);
         }
         println!("{}: seed {}", self.name, self.rng.borrow().seed_str());
 
         let real_start = Instant::now();
         loop {
+            let now = self.clock.now();
             for n in &mut self.nodes {
                 if dgram.is_none() && !n.ready(now) {
                     qdebug!([self.name], "skipping {:?}", n.node);
@@ -218,7 +224,8 @@ impl Simulator {
             }
 
             if dgram.is_none() {
-                now = self.next_time(now);
+                let next = self.next_time(now);
+                self.clock.set(next);
             }
         }
     }
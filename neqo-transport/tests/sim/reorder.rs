@@ -0,0 +1,70 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+#![allow(clippy::module_name_repetitions)]
+
+use super::{Node, Rng};
+use neqo_common::{qtrace, Datagram};
+use neqo_transport::Output;
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::fmt::{self, Debug};
+use std::time::{Duration, Instant};
+
+/// A node that reorders datagrams.  This holds up to `window` datagrams.
+/// When another datagram arrives, one of the held datagrams is chosen at
+/// random and passed on.  No datagram is held for longer than `limit`.
+pub struct Reorder {
+    window: usize,
+    limit: Duration,
+    held: VecDeque<(Instant, Datagram)>,
+    rng: Option<Rng>,
+}
+
+impl Reorder {
+    pub fn new(window: usize, limit: Duration) -> Self {
+        Self {
+            window,
+            limit,
+            held: VecDeque::new(),
+            rng: None,
+        }
+    }
+
+    fn release(&mut self) -> Datagram {
+        let mut rng = self.rng.as_ref().unwrap().borrow_mut();
+        let i = rng.random_from(0..u64::try_from(self.held.len()).unwrap());
+        let i = usize::try_from(i).unwrap();
+        qtrace!("reorder: releasing {} of {}", i, self.held.len());
+        self.held.remove(i).unwrap().1
+    }
+}
+
+impl Node for Reorder {
+    fn init(&mut self, rng: Rng, _now: Instant) {
+        self.rng = Some(rng);
+    }
+
+    fn process(&mut self, d: Option<Datagram>, now: Instant) -> Output {
+        if let Some(dgram) = d {
+            self.held.push_back((now + self.limit, dgram));
+            if self.held.len() > self.window {
+                return Output::Datagram(self.release());
+            }
+        }
+        match self.held.front() {
+            Some(&(t, _)) if t <= now => Output::Datagram(self.held.pop_front().unwrap().1),
+            Some(&(t, _)) => Output::Callback(t - now),
+            None => Output::None,
+        }
+    }
+}
+
+impl Debug for Reorder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("reorder")
+    }
+}
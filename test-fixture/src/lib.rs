@@ -12,7 +12,7 @@ use neqo_http3::{Http3Client, Http3Parameters, Http3Server};
 use neqo_qpack::QpackSettings;
 use neqo_transport::{Connection, ConnectionEvent, FixedConnectionIdManager, QuicVersion, State};
 
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::mem;
 use std::net::{IpAddr, Ipv6Addr, SocketAddr};
 use std::rc::Rc;
@@ -52,6 +52,44 @@ pub fn now() -> Instant {
     earlier().checked_add(ANTI_REPLAY_WINDOW).unwrap()
 }
 
+/// A virtual clock for tests, which only moves when it is told to.
+/// Clones share the same time.
+#[derive(Debug, Clone)]
+pub struct VirtualTime(Rc<Cell<Instant>>);
+
+impl VirtualTime {
+    /// Create a clock that starts at `now()`.
+    #[must_use]
+    pub fn new() -> Self {
+        Self(Rc::new(Cell::new(now())))
+    }
+
+    /// The current time on the clock.
+    #[must_use]
+    pub fn now(&self) -> Instant {
+        self.0.get()
+    }
+
+    /// Move the clock forward by `d`.
+    pub fn advance(&self, d: Duration) {
+        self.0.set(self.0.get() + d);
+    }
+
+    /// Move the clock to `t`.
+    /// # Panics
+    /// If `t` is in the past.
+    pub fn set(&self, t: Instant) {
+        assert!(t >= self.0.get(), "time can't go backwards");
+        self.0.set(t);
+    }
+}
+
+impl Default for VirtualTime {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 // Create a default anti-replay context.
 #[must_use]
 pub fn anti_replay() -> AntiReplay {
@@ -107,22 +145,31 @@ pub fn default_server() -> Connection {
 /// This funstion will consume all outstanding events on the connection.
 #[must_use]
 pub fn maybe_authenticate(conn: &mut Connection) -> bool {
+    maybe_authenticate_at(conn, now())
+}
+
+fn maybe_authenticate_at(conn: &mut Connection, now: Instant) -> bool {
     let authentication_needed = |e| matches!(e, ConnectionEvent::AuthenticationNeeded);
     if conn.events().any(authentication_needed) {
-        conn.authenticated(AuthenticationStatus::Ok, now());
+        conn.authenticated(AuthenticationStatus::Ok, now);
         return true;
     }
     false
 }
 
 pub fn handshake(client: &mut Connection, server: &mut Connection) {
+    handshake_with_clock(client, server, &VirtualTime::new());
+}
+
+/// Run the handshake, taking the time from `clock`.
+pub fn handshake_with_clock(client: &mut Connection, server: &mut Connection, clock: &VirtualTime) {
     let mut a = client;
     let mut b = server;
     let mut datagram = None;
     let is_done = |c: &Connection| matches!(c.state(), State::Confirmed | State::Closing { .. } | State::Closed(..));
     while !is_done(a) {
-        let _ = maybe_authenticate(a);
-        let d = a.process(datagram, now());
+        let _ = maybe_authenticate_at(a, clock.now());
+        let d = a.process(datagram, clock.now());
         datagram = d.dgram();
         mem::swap(&mut a, &mut b);
     }
@@ -138,9 +185,31 @@ pub fn connect() -> (Connection, Connection) {
     (client, server)
 }
 
+/// The parameters of the default http3 client.
+#[must_use]
+pub fn default_http3_parameters() -> Http3Parameters {
+    Http3Parameters {
+        qpack_settings: QpackSettings {
+            max_table_size_encoder: 100,
+            max_table_size_decoder: 100,
+            max_blocked_streams: 100,
+        },
+        max_concurrent_push_streams: 10,
+        grease: false,
+        max_header_list_size: None,
+        incremental_headers: false,
+    }
+}
+
 /// Create a http3 client with default configuration.
 #[must_use]
 pub fn default_http3_client() -> Http3Client {
+    http3_client_with_params(&default_http3_parameters())
+}
+
+/// Create a http3 client with the given parameters.
+#[must_use]
+pub fn http3_client_with_params(params: &Http3Parameters) -> Http3Client {
     fixture_init();
     Http3Client::new(
         DEFAULT_SERVER_NAME,
@@ -149,21 +218,24 @@ pub fn default_http3_client() -> Http3Client {
         loopback(),
         loopback(),
         QuicVersion::default(),
-        &Http3Parameters {
-            qpack_settings: QpackSettings {
-                max_table_size_encoder: 100,
-                max_table_size_decoder: 100,
-                max_blocked_streams: 100,
-            },
-            max_concurrent_push_streams: 10,
-        },
+        params,
     )
-    .expect("create a default client")
+    .expect("create a client")
 }
 
 /// Create a http3 server with default configuration.
 #[must_use]
 pub fn default_http3_server() -> Http3Server {
+    http3_server_with_settings(QpackSettings {
+        max_table_size_encoder: 100,
+        max_table_size_decoder: 100,
+        max_blocked_streams: 100,
+    })
+}
+
+/// Create a http3 server with the given QPACK settings.
+#[must_use]
+pub fn http3_server_with_settings(qpack_settings: QpackSettings) -> Http3Server {
     fixture_init();
     Http3Server::new(
         now(),
@@ -171,11 +243,7 @@ pub fn default_http3_server() -> Http3Server {
         DEFAULT_ALPN,
         anti_replay(),
         Rc::new(RefCell::new(FixedConnectionIdManager::new(5))),
-        QpackSettings {
-            max_table_size_encoder: 100,
-            max_table_size_decoder: 100,
-            max_blocked_streams: 100,
-        },
+        qpack_settings,
     )
-    .expect("create a default server")
+    .expect("create a server")
 }